@@ -0,0 +1,142 @@
+use rocket::request::{self, FromRequest, Request};
+use rocket::Outcome;
+
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+/// The raw `Accept-Language` header of a request, if it had one
+#[derive(Debug, Clone, PartialEq)]
+pub struct AcceptLanguage(pub Option<String>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for AcceptLanguage {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<AcceptLanguage, ()> {
+        let header = request.headers().get_one("Accept-Language");
+        Outcome::Success(AcceptLanguage(header.map(|h| h.to_string())))
+    }
+}
+
+impl AcceptLanguage {
+    /// Pick the url in `localized` that best matches the client's preferences.
+    /// Returns `None` when the default url should be used.
+    pub fn best_match<'m>(&self, localized: &'m BTreeMap<String, String>) -> Option<&'m str> {
+        let header = match self.0 {
+            Some(ref h) if !localized.is_empty() => h,
+            _ => return None,
+        };
+        for range in parse(header) {
+            // any language is fine, so use the default
+            if range == "*" {
+                return None;
+            }
+            if let Some(url) = localized
+                .iter()
+                .find(|&(tag, _)| language_matches(&range, tag))
+                .map(|(_, url)| url.as_str())
+            {
+                return Some(url);
+            }
+        }
+        None
+    }
+}
+
+/// Parse an `Accept-Language` header into its language ranges, most preferred first.
+/// Ranges with a quality of 0 are not acceptable to the client and are dropped.
+fn parse(header: &str) -> Vec<String> {
+    let mut ranges: Vec<(String, f32)> = header
+        .split(',')
+        .filter_map(|part| {
+            let mut params = part.split(';');
+            let range = params.next()?.trim();
+            if range.is_empty() {
+                return None;
+            }
+            let quality = params
+                .filter_map(|p| {
+                    let p = p.trim();
+                    if p.starts_with("q=") {
+                        p[2..].trim().parse::<f32>().ok()
+                    } else {
+                        None
+                    }
+                })
+                .next()
+                .unwrap_or(1.0);
+            Some((range.to_lowercase(), quality))
+        })
+        .filter(|&(_, q)| q > 0.0)
+        .collect();
+
+    // stable sort keeps header order for equal qualities
+    ranges.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+    ranges.into_iter().map(|(range, _)| range).collect()
+}
+
+/// Whether the language `range` from the header matches the language `tag` of a localized url,
+/// either exactly or with one being a more specific subtag of the other (`ja` and `ja-JP`)
+fn language_matches(range: &str, tag: &str) -> bool {
+    let tag = tag.to_lowercase();
+    range == tag
+        || tag.starts_with(&format!("{}-", range))
+        || range.starts_with(&format!("{}-", tag))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn localized() -> BTreeMap<String, String> {
+        let mut map = BTreeMap::new();
+        map.insert("ja".to_string(), "https://example.com/ja".to_string());
+        map.insert("zh-CN".to_string(), "https://example.com/zh-cn".to_string());
+        map
+    }
+
+    fn best(header: &str) -> Option<String> {
+        let localized = localized();
+        AcceptLanguage(Some(header.to_string()))
+            .best_match(&localized)
+            .map(|s| s.to_string())
+    }
+
+    #[test]
+    fn parse_quality_order() {
+        assert_eq!(
+            parse("en;q=0.5, ja, fr;q=0.8, de;q=0"),
+            vec!["ja", "fr", "en"]
+        );
+    }
+
+    #[test]
+    fn exact_and_subtag_matches() {
+        assert_eq!(best("ja"), Some("https://example.com/ja".to_string()));
+        assert_eq!(best("ja-JP"), Some("https://example.com/ja".to_string()));
+        assert_eq!(best("zh-cn"), Some("https://example.com/zh-cn".to_string()));
+        assert_eq!(best("zh"), Some("https://example.com/zh-cn".to_string()));
+    }
+
+    #[test]
+    fn prefers_higher_quality() {
+        assert_eq!(
+            best("ja;q=0.4, zh-CN;q=0.9"),
+            Some("https://example.com/zh-cn".to_string())
+        );
+        // languages without a localized url are skipped
+        assert_eq!(
+            best("en, ja;q=0.5"),
+            Some("https://example.com/ja".to_string())
+        );
+        assert_eq!(best("en, *;q=0.8, ja;q=0.5"), None);
+    }
+
+    #[test]
+    fn wildcard_and_unknown_locales() {
+        assert_eq!(best("*"), None);
+        assert_eq!(best("fr-CA, de"), None);
+        assert_eq!(best("ja;q=0"), None);
+        assert_eq!(best(""), None);
+        assert_eq!(AcceptLanguage(None).best_match(&localized()), None);
+    }
+}
@@ -0,0 +1,342 @@
+use {read_redirects, DnsApi, Error, RedirectData, RedirectMap, RedirectStorage, StatusMap};
+
+use github_event::constant_time_eq;
+use metrics::CloudflareMetrics;
+use redirect_utils::{self, ConfigHistory, DnsOptions, SiteRedirect, UpdateSummary, VerifyOptions};
+use request_id;
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::content;
+use rocket::response::status::Custom;
+use rocket::{Outcome, State};
+use rocket_contrib::Json;
+use serde_json;
+use shutdown::Busy;
+use store;
+
+use std::result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::PoisonError;
+
+/// The token required to use the admin routes, read from the `admin_token` env var.
+/// The admin routes are disabled if it isn't set.
+pub struct AdminToken(pub Option<String>);
+
+/// While on, every redirect route responds with 503 Service Unavailable.
+/// Toggled with `POST /admin/maintenance?on=true|false`.
+#[derive(Debug, Default)]
+pub struct MaintenanceMode(AtomicBool);
+
+impl MaintenanceMode {
+    pub fn new() -> MaintenanceMode {
+        MaintenanceMode::default()
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    pub fn set(&self, on: bool) {
+        self.0.store(on, Ordering::SeqCst)
+    }
+}
+
+/// Request guard for admin routes: requires an `Authorization: Bearer <admin_token>` header
+pub struct Admin;
+
+impl<'a, 'r> FromRequest<'a, 'r> for Admin {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Admin, ()> {
+        let expected = match request.guard::<State<AdminToken>>() {
+            Outcome::Success(token) => token.0.clone(),
+            _ => None,
+        };
+        let provided = request.headers().get_one("Authorization");
+        match (expected, provided) {
+            (Some(ref token), Some(header))
+                if constant_time_eq(header.as_bytes(), format!("Bearer {}", token).as_bytes()) =>
+            {
+                Outcome::Success(Admin)
+            }
+            _ => Outcome::Failure((Status::Unauthorized, ())),
+        }
+    }
+}
+
+pub type AdminResult<T> = result::Result<T, Custom<String>>;
+
+fn internal_error(err: Error) -> Custom<String> {
+    Custom(err.status(), err.to_string())
+}
+
+fn lock_error<T>(err: PoisonError<T>) -> Custom<String> {
+    internal_error(err.into())
+}
+
+fn already_exists(short: &str) -> Custom<String> {
+    Custom(Status::Conflict, format!("{} already exists", short))
+}
+
+/// Save `redirects` to `store` with the served `[meta]` table, then serve them. The store
+/// is written first, so if that fails both it and the served redirects stay as they were.
+/// Returns the number of redirects served.
+fn save_and_serve(
+    data: &mut RedirectData,
+    redirects: &[SiteRedirect],
+    store: &RedirectStorage,
+) -> AdminResult<usize> {
+    let meta = data.meta.clone();
+    store::update(store, |store| store.replace_all(redirects, meta.as_ref()))
+        .map_err(internal_error)?;
+    data.set_redirects(redirects);
+    Ok(data.map.len())
+}
+
+/// Body of a request adding a redirect
+#[derive(Debug, Deserialize)]
+pub struct NewRedirect {
+    pub short: String,
+    pub url: String,
+}
+
+/// Number of redirects after an admin change
+#[derive(Debug, Serialize)]
+pub struct EntryCount {
+    pub count: usize,
+}
+
+/// Add a single redirect at runtime.
+///
+/// The redirect is validated, its CNAME record is created, then it is saved to the
+/// redirect store and added to the redirect map.
+#[post("/redirect", format = "application/json", data = "<new_redirect>")]
+pub fn add_redirect(
+    _admin: Admin,
+    new_redirect: Json<NewRedirect>,
+    redirs: State<RedirectMap>,
+    dns: State<DnsApi>,
+    store: State<RedirectStorage>,
+) -> AdminResult<Json<EntryCount>> {
+    let new_redirect = new_redirect.into_inner();
+    let redirect = SiteRedirect {
+        short: new_redirect.short,
+        url: new_redirect.url,
+        ..SiteRedirect::default()
+    };
+
+    // cheap check before doing any network requests
+    let exists = redirs
+        .read()
+        .map_err(lock_error)?
+        .map
+        .contains_key(&redirect.short);
+    if exists {
+        return Err(already_exists(&redirect.short));
+    }
+    redirect_utils::verify_redirect(&redirect)
+        .map_err(|e| Custom(Status::UnprocessableEntity, e.to_string()))?;
+
+    // the DNS provider is slow, so the redirects stay readable until the record exists
+    match *dns {
+        Some(ref dns) => {
+            redirect_utils::create_cname(dns, &redirect.short).map_err(internal_error)?
+        }
+        None => println!(
+            "{}Warning: no DNS provider configured, skipping DNS updates",
+            request_id::log_prefix()
+        ),
+    }
+
+    let mut data = redirs.write().map_err(lock_error)?;
+    // another request may have added it while we were checking the URL
+    if data.map.contains_key(&redirect.short) {
+        return Err(already_exists(&redirect.short));
+    }
+    let mut redirects: Vec<SiteRedirect> = data.sorted_redirects().into_iter().cloned().collect();
+    redirects.push(redirect.clone());
+    redirects.sort();
+    // save what is served, so the store can't end up with a different list
+    let count = save_and_serve(&mut data, &redirects, &store)?;
+    drop(data);
+    println!("{}added redirect {}", request_id::log_prefix(), redirect.short);
+    Ok(Json(EntryCount { count }))
+}
+
+/// Remove a single redirect at runtime, deleting its CNAME record
+#[delete("/redirect/<short>")]
+pub fn delete_redirect(
+    _admin: Admin,
+    short: String,
+    redirs: State<RedirectMap>,
+    dns: State<DnsApi>,
+    store: State<RedirectStorage>,
+) -> AdminResult<Json<EntryCount>> {
+    let exists = redirs
+        .read()
+        .map_err(lock_error)?
+        .map
+        .contains_key(&short);
+    if !exists {
+        return Err(Custom(Status::NotFound, format!("{} does not exist", short)));
+    }
+    match *dns {
+        Some(ref dns) => redirect_utils::delete_cname(dns, &short).map_err(internal_error)?,
+        None => println!(
+            "{}Warning: no DNS provider configured, skipping DNS updates",
+            request_id::log_prefix()
+        ),
+    }
+
+    let mut data = redirs.write().map_err(lock_error)?;
+    let redirects: Vec<SiteRedirect> = data
+        .sorted_redirects()
+        .into_iter()
+        .filter(|r| r.short != short)
+        .cloned()
+        .collect();
+    let count = save_and_serve(&mut data, &redirects, &store)?;
+    drop(data);
+    println!("{}deleted redirect {}", request_id::log_prefix(), short);
+    Ok(Json(EntryCount { count }))
+}
+
+/// Dump the parsed redirect config as pretty JSON, for troubleshooting.
+///
+/// Unlike `/api/redirects` this is the internal representation: every field of every
+/// redirect, the category grouping, and the config's `[meta]` table.
+#[get("/config")]
+pub fn config(_admin: Admin, redirs: State<RedirectMap>) -> AdminResult<content::Json<String>> {
+    let data = read_redirects(&redirs);
+    serde_json::to_string_pretty(&*data)
+        .map(content::Json)
+        .map_err(|e| Custom(Status::InternalServerError, e.to_string()))
+}
+
+/// Outcome of `POST /admin/warmup`
+#[derive(Debug, Serialize)]
+pub struct WarmupSummary {
+    /// Number of redirect URLs checked
+    pub checked: usize,
+    pub reachable: usize,
+    /// Shorts whose URL wasn't reachable, sorted
+    pub unreachable: Vec<String>,
+}
+
+/// Check the URL of every redirect now instead of waiting for the background re-check, so
+/// `/api/status` is filled in right after a deploy. At most `url_check_concurrency` URLs are
+/// checked at once.
+#[post("/warmup")]
+pub fn warmup(
+    _admin: Admin,
+    redirs: State<RedirectMap>,
+    statuses: State<StatusMap>,
+) -> AdminResult<Json<WarmupSummary>> {
+    redirect_utils::recheck_urls(&redirs, &statuses, &VerifyOptions::from_env())
+        .map_err(internal_error)?;
+
+    let data = read_redirects(&redirs);
+    let statuses = statuses.read().map_err(lock_error)?;
+    let mut summary = WarmupSummary {
+        checked: 0,
+        reachable: 0,
+        unreachable: Vec::new(),
+    };
+    for short in data.map.keys() {
+        match statuses.get(short) {
+            Some(status) if status.reachable => summary.reachable += 1,
+            Some(_) => summary.unreachable.push(short.clone()),
+            None => continue,
+        }
+        summary.checked += 1;
+    }
+    summary.unreachable.sort();
+    println!("{}warmed up the URL statuses: {:?}", request_id::log_prefix(), summary);
+    Ok(Json(summary))
+}
+
+/// Query of `POST /admin/maintenance`
+#[derive(Debug, FromForm)]
+pub struct MaintenanceQuery {
+    on: bool,
+}
+
+/// Whether maintenance mode is on after an admin change
+#[derive(Debug, Serialize)]
+pub struct MaintenanceStatus {
+    pub maintenance: bool,
+}
+
+/// Turn maintenance mode on or off, e.g. during a risky config migration.
+/// The index, API and metrics keep working.
+#[post("/maintenance?<query>")]
+pub fn maintenance(
+    _admin: Admin,
+    query: MaintenanceQuery,
+    mode: State<MaintenanceMode>,
+) -> Json<MaintenanceStatus> {
+    mode.set(query.on);
+    let state = if query.on { "on" } else { "off" };
+    println!("{}maintenance mode {}", request_id::log_prefix(), state);
+    Json(MaintenanceStatus {
+        maintenance: query.on,
+    })
+}
+
+/// Query of `POST /admin/rollback`
+#[derive(Debug, FromForm)]
+pub struct RollbackQuery {
+    save: bool,
+}
+
+/// Serve the config from before the last webhook update again, updating the CNAMEs to match.
+/// Responds with what changed, or 409 if there is no previous config left or a webhook
+/// update is running. With `?save=true` the restored config is also saved to the redirect
+/// store, otherwise the next restart loads the config the webhook saved.
+#[post("/rollback?<query>")]
+pub fn rollback_save(
+    _admin: Admin,
+    _busy: Busy,
+    query: RollbackQuery,
+    redirs: State<RedirectMap>,
+    history: State<ConfigHistory>,
+    dns: State<DnsApi>,
+    cf_metrics: State<CloudflareMetrics>,
+    store: State<RedirectStorage>,
+) -> AdminResult<Json<UpdateSummary>> {
+    let save = if query.save { Some(store.inner()) } else { None };
+    rollback_config(&redirs, &history, &dns, &cf_metrics, save)
+}
+
+#[post("/rollback", rank = 2)]
+pub fn rollback(
+    _admin: Admin,
+    _busy: Busy,
+    redirs: State<RedirectMap>,
+    history: State<ConfigHistory>,
+    dns: State<DnsApi>,
+    cf_metrics: State<CloudflareMetrics>,
+) -> AdminResult<Json<UpdateSummary>> {
+    rollback_config(&redirs, &history, &dns, &cf_metrics, None)
+}
+
+fn rollback_config(
+    redirs: &RedirectMap,
+    history: &ConfigHistory,
+    dns: &DnsApi,
+    cf_metrics: &CloudflareMetrics,
+    save: Option<&RedirectStorage>,
+) -> AdminResult<Json<UpdateSummary>> {
+    let dns_options = DnsOptions::from_env();
+    let summary = redirect_utils::rollback(redirs, history, dns.as_ref(), cf_metrics, &dns_options)
+        .map_err(internal_error)?
+        .ok_or_else(|| Custom(Status::Conflict, "no previous config to roll back to".into()))?;
+    if let Some(store) = save {
+        let data = read_redirects(redirs);
+        let redirects: Vec<SiteRedirect> = data.sorted_redirects().into_iter().cloned().collect();
+        store::update(store, |store| store.replace_all(&redirects, data.meta.as_ref()))
+            .map_err(internal_error)?;
+    }
+    println!("{}rolled back to the previous config: {:?}", request_id::log_prefix(), summary);
+    Ok(Json(summary))
+}
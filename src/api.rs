@@ -0,0 +1,181 @@
+use {Error, RedirectMap, Result, StatusMap};
+
+use admin::Admin;
+use github_event;
+use metrics::{HitCounter, TopKeys};
+use read_redirects;
+use redirect_utils::{self, SiteRedirect, UrlCheck, UrlStatus, VerifyOptions};
+use responders::{ETagged, ValidationReport};
+use rocket::data::Data;
+use rocket::http::Status;
+use rocket::response::content;
+use rocket::response::status::Custom;
+use rocket::State;
+use rocket_contrib::Json;
+use schema;
+use serde_json::Value;
+
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::result;
+
+/// Largest config accepted by the validation endpoint: 1 MB
+const MAX_CONFIG_BYTES: u64 = 1024 * 1024;
+
+/// Most URLs the verify endpoint checks in one request
+pub const MAX_VERIFY_URLS: usize = 100;
+
+/// Number of keys returned by `/api/stats/top` without an `n`
+pub const DEFAULT_TOP_KEYS: usize = 10;
+
+/// Most keys returned by `/api/stats/top`
+pub const MAX_TOP_KEYS: usize = 100;
+
+/// Query of a page of `/api/redirects`. Without a `limit` the page runs to the end.
+#[derive(Debug, FromForm)]
+pub struct PageQuery {
+    offset: Option<usize>,
+    limit: Option<usize>,
+}
+
+/// A page of the redirects sorted by `short`
+#[derive(Debug, Serialize)]
+pub struct RedirectPage {
+    pub redirects: Vec<SiteRedirect>,
+    /// Number of redirects across all pages
+    pub total: usize,
+    /// `offset` of the next page, `None` on the last one
+    pub next_offset: Option<usize>,
+}
+
+impl RedirectPage {
+    /// The `limit` redirects after the first `offset` of `sorted`. Empty if `offset` is past
+    /// the end.
+    pub fn of(sorted: Vec<&SiteRedirect>, offset: usize, limit: Option<usize>) -> RedirectPage {
+        let total = sorted.len();
+        let start = offset.min(total);
+        let end = limit.map_or(total, |limit| start.saturating_add(limit).min(total));
+        RedirectPage {
+            redirects: sorted.into_iter().skip(start).take(end - start).cloned().collect(),
+            total,
+            next_offset: if end < total { Some(end) } else { None },
+        }
+    }
+}
+
+/// Query of `/api/stats/top`
+#[derive(Debug, FromForm)]
+pub struct TopQuery {
+    n: usize,
+}
+
+/// Return all redirects but wildcards, sorted by `short`, with an `ETag` so clients can poll
+/// using `If-None-Match` and get a 304 when nothing changed
+#[get("/redirects", rank = 2)]
+pub fn redirects(redirs: State<RedirectMap>) -> ETagged<Json<Vec<SiteRedirect>>> {
+    let data = read_redirects(&redirs);
+    ETagged {
+        etag: data.etag.clone(),
+        inner: Json(data.listed_redirects().into_iter().cloned().collect()),
+    }
+}
+
+/// A page of `/api/redirects`, or 400 for a `limit` of 0
+type PageResult = result::Result<ETagged<Json<RedirectPage>>, Custom<String>>;
+
+/// Return a page of the redirects but wildcards, sorted by `short`, with the total and the
+/// offset of the next page, for clients that don't want the whole table at once.
+/// A `limit` of 0 is a 400, since its pages would never get past `offset`.
+#[get("/redirects?<query>")]
+pub fn redirects_page(query: PageQuery, redirs: State<RedirectMap>) -> PageResult {
+    if query.limit == Some(0) {
+        return Err(Custom(Status::BadRequest, "limit must be at least 1".to_string()));
+    }
+    let data = read_redirects(&redirs);
+    let page = RedirectPage::of(data.listed_redirects(), query.offset.unwrap_or(0), query.limit);
+    Ok(ETagged {
+        etag: data.etag.clone(),
+        inner: Json(page),
+    })
+}
+
+/// Return all redirects but wildcards, sorted by `short`, as plain text, one `short<tab>url`
+/// per line
+#[get("/redirects.txt")]
+pub fn redirects_txt(redirs: State<RedirectMap>) -> content::Plain<String> {
+    let data = read_redirects(&redirs);
+    let mut listing = String::new();
+    for redir in data.listed_redirects() {
+        listing.push_str(&format!("{}\t{}\n", redir.short, redir.url));
+    }
+    content::Plain(listing)
+}
+
+/// Return the result of the last reachability check for each redirect
+#[get("/status")]
+pub fn status(statuses: State<StatusMap>) -> Result<Json<HashMap<String, UrlStatus>>> {
+    let statuses = statuses.read()?;
+    Ok(Json(statuses.clone()))
+}
+
+/// Return the `DEFAULT_TOP_KEYS` most requested redirects, and unknown keys, by hits
+#[get("/stats/top", rank = 2)]
+pub fn stats_top(hits: State<HitCounter>) -> Json<TopKeys> {
+    Json(hits.top(DEFAULT_TOP_KEYS))
+}
+
+/// Return the `n` (at most `MAX_TOP_KEYS`) most requested redirects, and unknown keys
+#[get("/stats/top?<query>")]
+pub fn stats_top_n(query: TopQuery, hits: State<HitCounter>) -> Json<TopKeys> {
+    Json(hits.top(query.n.min(MAX_TOP_KEYS)))
+}
+
+/// Return every requested key that isn't a redirect with how often it was requested, to
+/// help decide which redirects to add. At most `MAX_TRACKED_MISSES` keys are tracked.
+#[get("/stats/misses")]
+pub fn stats_misses(hits: State<HitCounter>) -> Json<BTreeMap<String, usize>> {
+    Json(hits.all_misses())
+}
+
+/// Results of the verify endpoint, or 413 if there were too many URLs
+type VerifyResult = Result<result::Result<Json<Vec<UrlCheck>>, Custom<String>>>;
+
+/// Check a JSON array of URLs like redirect targets, e.g. before saving them in an editor,
+/// without touching the live config. Responds with `{url, ok, status, error}` per URL in
+/// the same order, or 413 for more than `MAX_VERIFY_URLS` URLs.
+/// Needs the admin token, since every URL is fetched by the server.
+#[post("/verify", format = "application/json", data = "<urls>")]
+pub fn verify(_admin: Admin, urls: Json<Vec<String>>) -> VerifyResult {
+    if urls.len() > MAX_VERIFY_URLS {
+        let message = format!("at most {} URLs can be checked at once", MAX_VERIFY_URLS);
+        return Ok(Err(Custom(Status::PayloadTooLarge, message)));
+    }
+    let checks = redirect_utils::check_urls(&urls, &VerifyOptions::from_env())?;
+    Ok(Ok(Json(checks)))
+}
+
+/// Return a JSON Schema of redirects.toml that editors can validate configs against
+#[get("/schema")]
+pub fn schema() -> Json<Value> {
+    Json(schema::config_schema())
+}
+
+/// The validation report, or 413 if the config is larger than `MAX_CONFIG_BYTES`
+type ValidateResult = result::Result<ValidationReport, Custom<String>>;
+
+/// Validate the posted redirects.toml without applying it (dry run)
+#[post("/validate", data = "<config>")]
+pub fn validate(config: Data) -> ValidateResult {
+    let body = match github_event::read_limited(config.open(), MAX_CONFIG_BYTES) {
+        Ok(Some(body)) => body,
+        Ok(None) => {
+            let message = format!("the config can be at most {} bytes", MAX_CONFIG_BYTES);
+            return Err(Custom(Status::PayloadTooLarge, message));
+        }
+        Err(e) => return Ok(ValidationReport::from_result::<()>(Err(e.into()))),
+    };
+    let result = String::from_utf8(body)
+        .map_err(|e| Error::from(io::Error::new(io::ErrorKind::InvalidData, e)))
+        .and_then(|toml_str| redirect_utils::validate_str(&toml_str));
+    Ok(ValidationReport::from_result(result))
+}
@@ -0,0 +1,199 @@
+use flate2::write::{DeflateEncoder, GzEncoder};
+use flate2::Compression as Level;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::StatusClass;
+use rocket::{Request, Response};
+
+use std::io::{self, Cursor, Write};
+
+/// Bodies smaller than this aren't worth compressing
+pub const MIN_COMPRESS_BYTES: usize = 512;
+
+/// Content codings the `Compression` fairing can produce
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    /// Name of the coding in `Accept-Encoding` and `Content-Encoding`
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    fn encode(&self, body: &[u8]) -> io::Result<Vec<u8>> {
+        match *self {
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Level::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+            Encoding::Deflate => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), Level::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// Compresses HTML and JSON responses with gzip or deflate, whichever the client's
+/// `Accept-Encoding` prefers. Redirects and bodies under `MIN_COMPRESS_BYTES` are left alone.
+pub struct Compression;
+
+impl Fairing for Compression {
+    fn info(&self) -> Info {
+        Info {
+            name: "Response compression",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        if response.status().class() == StatusClass::Redirection
+            || response.headers().contains("Content-Encoding")
+        {
+            return;
+        }
+        let compressible = response
+            .content_type()
+            .map_or(false, |ct| ct.is_html() || ct.is_json());
+        if !compressible {
+            return;
+        }
+        let accept: Vec<&str> = request.headers().get("Accept-Encoding").collect();
+        let encoding = match preferred_encoding(&accept.join(",")) {
+            Some(encoding) => encoding,
+            None => return,
+        };
+        let body = match response.body_bytes() {
+            Some(body) => body,
+            None => return,
+        };
+        // whether the body is compressed or not, caches must keep the versions apart
+        add_vary(response, "Accept-Encoding");
+        if body.len() < MIN_COMPRESS_BYTES {
+            response.set_sized_body(Cursor::new(body));
+            return;
+        }
+        match encoding.encode(&body) {
+            Ok(compressed) => {
+                response.set_raw_header("Content-Encoding", encoding.name());
+                response.set_sized_body(Cursor::new(compressed));
+            }
+            Err(e) => {
+                println!("Warning: compressing a response failed: {}", e);
+                response.set_sized_body(Cursor::new(body));
+            }
+        }
+    }
+}
+
+/// Add `field` to the response's `Vary` header, keeping the fields already in it, so the
+/// response ends up with a single `Vary` header
+fn add_vary(response: &mut Response, field: &str) {
+    let mut fields: Vec<String> = response
+        .headers()
+        .get("Vary")
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .collect();
+    if !fields.iter().any(|name| name.eq_ignore_ascii_case(field)) {
+        fields.push(field.to_string());
+    }
+    response.set_raw_header("Vary", fields.join(", "));
+}
+
+/// The coding in an `Accept-Encoding` header with the highest quality, gzip if tied.
+/// `None` if the client accepts neither gzip nor deflate.
+fn preferred_encoding(header: &str) -> Option<Encoding> {
+    let mut best: Option<(Encoding, (f32, bool))> = None;
+    for part in header.split(',') {
+        let mut params = part.split(';');
+        let coding = params.next().unwrap_or("").trim().to_ascii_lowercase();
+        let quality = params
+            .filter_map(|p| {
+                let p = p.trim();
+                if p.starts_with("q=") {
+                    p[2..].trim().parse::<f32>().ok()
+                } else {
+                    None
+                }
+            })
+            .next()
+            .unwrap_or(1.0);
+        let encoding = match coding.as_str() {
+            "gzip" | "x-gzip" | "*" => Encoding::Gzip,
+            "deflate" => Encoding::Deflate,
+            _ => continue,
+        };
+        if quality <= 0.0 {
+            continue;
+        }
+        // ties go to gzip
+        let rank = (quality, encoding == Encoding::Gzip);
+        if best.map_or(true, |(_, best_rank)| rank > best_rank) {
+            best = Some((encoding, rank));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn encoding_preference() {
+        assert_eq!(
+            preferred_encoding("gzip, deflate, br"),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(preferred_encoding("deflate, gzip"), Some(Encoding::Gzip));
+        assert_eq!(preferred_encoding("deflate"), Some(Encoding::Deflate));
+        assert_eq!(
+            preferred_encoding("gzip;q=0.5, deflate;q=0.8"),
+            Some(Encoding::Deflate)
+        );
+        assert_eq!(preferred_encoding("*"), Some(Encoding::Gzip));
+        assert_eq!(preferred_encoding("gzip;q=0, deflate;q=0"), None);
+        assert_eq!(preferred_encoding("identity"), None);
+        assert_eq!(preferred_encoding(""), None);
+    }
+
+    #[test]
+    fn vary_merged() {
+        let mut response = Response::build().raw_header("Vary", "Accept").finalize();
+        add_vary(&mut response, "Accept-Encoding");
+        let vary: Vec<&str> = response.headers().get("Vary").collect();
+        assert_eq!(vary, vec!["Accept, Accept-Encoding"]);
+
+        // already listed, in any case
+        let mut response = Response::build().raw_header("Vary", "accept-encoding").finalize();
+        add_vary(&mut response, "Accept-Encoding");
+        assert_eq!(response.headers().get_one("Vary"), Some("accept-encoding"));
+
+        let mut response = Response::new();
+        add_vary(&mut response, "Accept-Encoding");
+        assert_eq!(response.headers().get_one("Vary"), Some("Accept-Encoding"));
+    }
+
+    #[test]
+    fn gzip_round_trip() {
+        let body = "rustref ".repeat(100);
+        let compressed = Encoding::Gzip.encode(body.as_bytes()).unwrap();
+        assert!(compressed.len() < body.len());
+        let mut decoded = String::new();
+        GzDecoder::new(&compressed[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, body);
+    }
+}
@@ -0,0 +1,355 @@
+use errors::{CloudflareError, CloudflareErrorKind};
+use {Error, Result};
+
+use redirect_utils::{self, verify_apex};
+use cloudflare::zones::{self, dns};
+use cloudflare::Cloudflare;
+use dotenv;
+use reqwest::header::Headers;
+use serde_json;
+use tls;
+
+/// A CNAME record as the DNS host lists it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CnameRecord {
+    /// ID the host gave the record, used to change or delete it. Hosts without record IDs
+    /// use the name.
+    pub id: String,
+    /// Full record name like `std.rustref.com`
+    pub name: String,
+    /// The name the record points at
+    pub content: String,
+    /// Whether the host proxies the record, `None` for hosts without a proxy
+    pub proxied: Option<bool>,
+}
+
+/// A DNS host that manages the redirect CNAME records.
+///
+/// Names are full record names like `std.rustref.com`.
+pub trait DnsProvider {
+    /// ID of the zone named `zone`, passed to the other methods
+    fn zone_id(&self, zone: &str) -> Result<String>;
+
+    /// Every CNAME record in the zone
+    fn list_cnames(&self, zone_id: &str) -> Result<Vec<CnameRecord>>;
+
+    /// Create a CNAME record `name` pointing at `target`, proxied if the host supports it
+    /// and is configured to
+    fn create_cname(&self, zone_id: &str, name: &str, target: &str) -> Result<()>;
+
+    /// Delete the CNAME `record`, as listed by `list_cnames`
+    fn delete_cname(&self, zone_id: &str, record: &CnameRecord) -> Result<()>;
+
+    /// Point the existing CNAME `record` at `target`, with the proxy status `create_cname`
+    /// would give it. By default the record is deleted and created again.
+    fn update_cname(&self, zone_id: &str, record: &CnameRecord, target: &str) -> Result<()> {
+        self.delete_cname(zone_id, record)?;
+        self.create_cname(zone_id, &record.name, target)
+    }
+
+    /// Whether the records `create_cname` makes are proxied, `None` for hosts without a proxy
+    fn proxied(&self) -> Option<bool> {
+        None
+    }
+
+    /// Drop the responses cached for the zone, so changed redirects are picked up
+    fn purge_cache(&self, zone_id: &str) -> Result<()>;
+
+    /// Make sure the zone `zone` has a record for its apex, since every redirect CNAME
+    /// points there. Hosts that can't list the apex records accept every zone.
+    fn check_apex(&self, _zone_id: &str, _zone: &str) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<D: DnsProvider + ?Sized> DnsProvider for Box<D> {
+    fn zone_id(&self, zone: &str) -> Result<String> {
+        (**self).zone_id(zone)
+    }
+
+    fn list_cnames(&self, zone_id: &str) -> Result<Vec<CnameRecord>> {
+        (**self).list_cnames(zone_id)
+    }
+
+    fn create_cname(&self, zone_id: &str, name: &str, target: &str) -> Result<()> {
+        (**self).create_cname(zone_id, name, target)
+    }
+
+    fn delete_cname(&self, zone_id: &str, record: &CnameRecord) -> Result<()> {
+        (**self).delete_cname(zone_id, record)
+    }
+
+    fn update_cname(&self, zone_id: &str, record: &CnameRecord, target: &str) -> Result<()> {
+        (**self).update_cname(zone_id, record, target)
+    }
+
+    fn proxied(&self) -> Option<bool> {
+        (**self).proxied()
+    }
+
+    fn purge_cache(&self, zone_id: &str) -> Result<()> {
+        (**self).purge_cache(zone_id)
+    }
+
+    fn check_apex(&self, zone_id: &str, zone: &str) -> Result<()> {
+        (**self).check_apex(zone_id, zone)
+    }
+}
+
+/// Base URL of the Cloudflare API, ending in `/`
+pub const CLOUDFLARE_API_URL: &str = "https://api.cloudflare.com/client/v4/";
+
+/// What the Cloudflare API is called with
+#[derive(Debug, Clone)]
+pub struct CloudflareCredentials {
+    pub key: String,
+    pub email: String,
+    /// Base URL of the API ending in `/`, `CLOUDFLARE_API_URL` outside of tests
+    pub base_url: String,
+}
+
+/// Cloudflare DNS, creating either proxied (orange cloud) or DNS-only (grey cloud) CNAMEs
+pub struct CloudflareDns {
+    api: Cloudflare,
+    /// Kept for the calls the client doesn't have, like editing a record
+    credentials: CloudflareCredentials,
+    proxied: bool,
+}
+
+impl CloudflareDns {
+    pub fn new(credentials: CloudflareCredentials, proxied: bool) -> Result<CloudflareDns> {
+        let api = Cloudflare::new(&credentials.key, &credentials.email, &credentials.base_url)?;
+        Ok(CloudflareDns {
+            api,
+            credentials,
+            proxied,
+        })
+    }
+
+    /// Read whether to proxy the CNAMEs from the `cloudflare_proxied` env var, `true` unless
+    /// it is set to `false`
+    pub fn from_env(credentials: CloudflareCredentials) -> Result<CloudflareDns> {
+        let proxied = dotenv::var("cloudflare_proxied")
+            .map(|s| s != "false")
+            .unwrap_or(true);
+        CloudflareDns::new(credentials, proxied)
+    }
+
+    /// Probe the API's host for TLS 1.2 or newer before calling it, like the other outbound
+    /// requests. Both the `cloudflare` crate and `update_cname` use reqwest 0.8, so this is
+    /// only a probe, see `tls::probe_min_version`.
+    fn probe_api(&self) -> Result<()> {
+        Ok(tls::probe_min_version(&self.credentials.base_url)?)
+    }
+
+    /// Headers authenticating a request made without the client
+    fn auth_headers(&self) -> Headers {
+        let mut headers = Headers::new();
+        headers.set_raw("X-Auth-Key", self.credentials.key.clone());
+        headers.set_raw("X-Auth-Email", self.credentials.email.clone());
+        headers
+    }
+}
+
+impl DnsProvider for CloudflareDns {
+    fn zone_id(&self, zone: &str) -> Result<String> {
+        self.probe_api()?;
+        Ok(zones::get_zoneid(&self.api, zone)?)
+    }
+
+    fn list_cnames(&self, zone_id: &str) -> Result<Vec<CnameRecord>> {
+        self.probe_api()?;
+        let records = dns::list_dns_of_type(&self.api, zone_id, dns::RecordType::CNAME)?;
+        Ok(records
+            .into_iter()
+            .map(|r| CnameRecord {
+                id: r.id,
+                name: r.name,
+                content: r.content,
+                proxied: Some(r.proxied),
+            })
+            .collect())
+    }
+
+    fn create_cname(&self, zone_id: &str, name: &str, target: &str) -> Result<()> {
+        self.probe_api()?;
+        let record_type = dns::RecordType::CNAME;
+        if self.proxied {
+            dns::create_proxied_dns_entry(&self.api, zone_id, record_type, name, target)?;
+        } else {
+            dns::create_dns_entry(&self.api, zone_id, record_type, name, target)?;
+        }
+        Ok(())
+    }
+
+    fn delete_cname(&self, zone_id: &str, record: &CnameRecord) -> Result<()> {
+        self.probe_api()?;
+        dns::delete_dns_entry(&self.api, zone_id, &record.id)?;
+        Ok(())
+    }
+
+    fn update_cname(&self, zone_id: &str, record: &CnameRecord, target: &str) -> Result<()> {
+        self.probe_api()?;
+        // the client can't edit records, so the record is overwritten in place by its ID
+        let url = format!(
+            "{}zones/{}/dns_records/{}",
+            self.credentials.base_url, zone_id, record.id
+        );
+        let body = json!({
+            "type": "CNAME",
+            "name": record.name,
+            "content": target,
+            "proxied": self.proxied,
+        });
+        let mut response = redirect_utils::http_client(false)
+            .put(&url)
+            .headers(self.auth_headers())
+            .json(&body)
+            .send()?;
+        // errors from in front of the API, like a gateway timeout, aren't JSON, so the body
+        // is only parsed after the status is checked and is reported as it was sent
+        let reply = response.text()?;
+        let updated = response.status().is_success()
+            && serde_json::from_str::<serde_json::Value>(&reply)
+                .map(|reply| reply["success"] == true)
+                .unwrap_or(false);
+        if updated {
+            Ok(())
+        } else {
+            let message = format!("{} updating {}: {}", response.status(), record.name, reply);
+            Err(Error::Cloudflare(CloudflareError {
+                kind: CloudflareErrorKind::classify(&message),
+                message,
+            }))
+        }
+    }
+
+    fn purge_cache(&self, zone_id: &str) -> Result<()> {
+        self.probe_api()?;
+        zones::purge::purge_everything(&self.api, zone_id)?;
+        Ok(())
+    }
+
+    fn proxied(&self) -> Option<bool> {
+        Some(self.proxied)
+    }
+
+    fn check_apex(&self, zone_id: &str, zone: &str) -> Result<()> {
+        self.probe_api()?;
+        let mut names = Vec::new();
+        for record_type in vec![dns::RecordType::A, dns::RecordType::AAAA, dns::RecordType::CNAME] {
+            let records = dns::list_dns_of_type(&self.api, zone_id, record_type)?;
+            names.extend(records.into_iter().map(|r| r.name));
+        }
+        verify_apex(zone, &names)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_utils::{http_response, mock_server, recording_mock_server};
+
+    /// A provider calling the API at `server`
+    fn provider_at(server: &str, proxied: bool) -> CloudflareDns {
+        let credentials = CloudflareCredentials {
+            key: "key".to_string(),
+            email: "user@example.com".to_string(),
+            base_url: format!("{}/", server),
+        };
+        CloudflareDns::new(credentials, proxied).unwrap()
+    }
+
+    /// Body of the request creating a CNAME with `proxied` set
+    fn create_request_body(proxied: bool) -> String {
+        let body = r#"{"success":true,"errors":[],"messages":[],"result":null}"#;
+        let (server, requests) = recording_mock_server(move |_path| {
+            http_response("200 OK", &[("Content-Type", "application/json")], body)
+        });
+        let provider = provider_at(&server, proxied);
+        // the mock's reply isn't a valid record, only the request matters here
+        let _ = provider.create_cname("zone", "std.rustref.com", "rustref.com");
+        let requests = requests.lock().unwrap();
+        let request = requests
+            .iter()
+            .find(|r| r.starts_with("POST"))
+            .expect("a record creation request");
+        request.splitn(2, "\r\n\r\n").nth(1).unwrap_or("").replace(' ', "")
+    }
+
+    #[test]
+    fn proxied_flag_sent() {
+        let proxied = create_request_body(true);
+        assert!(proxied.contains(r#""name":"std.rustref.com""#));
+        assert!(proxied.contains(r#""proxied":true"#));
+
+        let dns_only = create_request_body(false);
+        assert!(dns_only.contains(r#""name":"std.rustref.com""#));
+        assert!(!dns_only.contains(r#""proxied":true"#));
+    }
+
+    #[test]
+    fn record_updated_by_id() {
+        let body = r#"{"success":true,"errors":[],"messages":[],"result":null}"#;
+        let (server, requests) = recording_mock_server(move |_path| {
+            http_response("200 OK", &[("Content-Type", "application/json")], body)
+        });
+        let provider = provider_at(&server, true);
+        let record = CnameRecord {
+            id: "372e6795".to_string(),
+            name: "std.rustref.com".to_string(),
+            content: "example.com".to_string(),
+            proxied: Some(false),
+        };
+        provider.update_cname("zone", &record, "rustref.com").unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        let request = &requests[0];
+        assert!(request.starts_with("PUT /zones/zone/dns_records/372e6795 "));
+        let lower = request.to_lowercase();
+        assert!(lower.contains("x-auth-key: key"));
+        assert!(lower.contains("x-auth-email: user@example.com"));
+        let body = request.splitn(2, "\r\n\r\n").nth(1).unwrap_or("").replace(' ', "");
+        assert!(body.contains(r#""content":"rustref.com""#));
+        assert!(body.contains(r#""proxied":true"#));
+    }
+
+    #[test]
+    fn failed_update_reported() {
+        let body = r#"{"success":false,"errors":[{"code":81044,"message":"Record not found"}]}"#;
+        let server = mock_server(move |_path| {
+            http_response("404 Not Found", &[("Content-Type", "application/json")], body)
+        });
+        let record = CnameRecord {
+            id: "gone".to_string(),
+            name: "std.rustref.com".to_string(),
+            content: "rustref.com".to_string(),
+            proxied: Some(true),
+        };
+        match provider_at(&server, true).update_cname("zone", &record, "rustref.com") {
+            Err(Error::Cloudflare(ref e)) => assert_eq!(e.kind, CloudflareErrorKind::NotFound),
+            other => panic!("expected a Cloudflare error, got {:?}", other),
+        }
+    }
+    #[test]
+    fn non_json_failure_reported() {
+        let body = "<html><body>504 Gateway Time-out</body></html>";
+        let server = mock_server(move |_path| {
+            http_response("504 Gateway Time-out", &[("Content-Type", "text/html")], body)
+        });
+        let record = CnameRecord {
+            id: "372e6795".to_string(),
+            name: "std.rustref.com".to_string(),
+            content: "rustref.com".to_string(),
+            proxied: Some(true),
+        };
+        match provider_at(&server, true).update_cname("zone", &record, "rustref.com") {
+            Err(Error::Cloudflare(ref e)) => {
+                assert!(e.message.contains("504"));
+                assert!(e.message.contains(body));
+            }
+            other => panic!("expected a Cloudflare error, got {:?}", other),
+        }
+    }
+}
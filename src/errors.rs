@@ -1,32 +1,209 @@
+use std::fmt;
+use std::path::PathBuf;
 use std::result;
 use cloudflare;
+use rayon;
+#[cfg(feature = "sqlite")]
+use rusqlite;
+#[cfg(feature = "sqlite")]
+use serde_json;
 use toml;
 use reqwest;
+use rocket::http::Status;
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
 use std;
+use redirect_utils::SUPPORTED_CONFIG_VERSION;
 
 pub type Result<T> = result::Result<T, Error>;
 
 #[derive(Debug)]
 pub enum Error {
-    Cloudflare(cloudflare::Error),
+    Cloudflare(CloudflareError),
+    /// The Cloudflare zone has no record for its apex, which the redirect CNAMEs point at
+    MissingApex(String),
+    /// Error from a DNS provider other than Cloudflare
+    Dns(String),
+    /// Some DNS changes of an update failed, so the redirects were left as they were
+    DnsUpdate(Vec<String>),
     Reqwest(reqwest::Error),
+    /// Downloading the config from the url (first) failed with the HTTP status (second)
+    ConfigDownload(String, u16),
+    /// The Github contents API response from the url has no base64 encoded UTF-8 `content`
+    ConfigContent(String),
     Toml(toml::de::Error),
+    /// Config files include each other, the first and last paths are the same file
+    IncludeCycle(Vec<PathBuf>),
+    /// An include is an absolute path or leads out of the top config file's directory
+    IncludeOutsideRoot(String),
+    /// The config's `[meta]` version isn't supported by this server
+    UnsupportedVersion(u32),
+    /// The config has no redirects and `allow_empty_config` is off
+    EmptyConfig,
+    TomlSer(toml::ser::Error),
     Lock(String),
     Io(std::io::Error),
+    ThreadPool(rayon::ThreadPoolBuildError),
+    #[cfg(feature = "sqlite")]
+    Sqlite(rusqlite::Error),
+    /// A redirect stored as JSON couldn't be read or written
+    #[cfg(feature = "sqlite")]
+    Json(serde_json::Error),
     RedirectError(RedirectError),
     RedirectErrors(Vec<RedirectError>),
 }
 
+/// A failed Cloudflare API call, classified by what went wrong
+#[derive(Debug)]
+pub struct CloudflareError {
+    pub kind: CloudflareErrorKind,
+    /// Everything the Cloudflare client reported, for the logs
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloudflareErrorKind {
+    /// The API key or email was rejected, or lacks permission
+    Auth,
+    /// Too many requests, the call can be retried later
+    RateLimited,
+    /// The zone or record doesn't exist
+    NotFound,
+    Other,
+}
+
+/// Cloudflare API error codes, see https://api.cloudflare.com/#getting-started-responses
+const CLOUDFLARE_AUTH_CODES: &[u32] = &[6003, 6103, 9103, 9106, 9107, 9109, 10000, 10001];
+const CLOUDFLARE_RATE_LIMIT_CODES: &[u32] = &[971, 10013];
+const CLOUDFLARE_NOT_FOUND_CODES: &[u32] = &[1001, 7000, 7003, 81044];
+
+impl CloudflareErrorKind {
+    /// Classify an error reported by the Cloudflare client by the API error codes in it,
+    /// or by its wording if it has none of the known codes
+    pub fn classify(message: &str) -> CloudflareErrorKind {
+        let by_code = error_codes(message).into_iter().filter_map(|code| {
+            if CLOUDFLARE_AUTH_CODES.contains(&code) {
+                Some(CloudflareErrorKind::Auth)
+            } else if CLOUDFLARE_RATE_LIMIT_CODES.contains(&code) {
+                Some(CloudflareErrorKind::RateLimited)
+            } else if CLOUDFLARE_NOT_FOUND_CODES.contains(&code) {
+                Some(CloudflareErrorKind::NotFound)
+            } else {
+                None
+            }
+        });
+        if let Some(kind) = by_code.min_by_key(|kind| *kind as u8) {
+            return kind;
+        }
+        let message = message.to_lowercase();
+        if message.contains("authentication") {
+            CloudflareErrorKind::Auth
+        } else if message.contains("too many requests") || message.contains("rate limit") {
+            CloudflareErrorKind::RateLimited
+        } else if message.contains("not found") {
+            CloudflareErrorKind::NotFound
+        } else {
+            CloudflareErrorKind::Other
+        }
+    }
+
+    /// Whether the same call may succeed when retried later
+    pub fn is_transient(self) -> bool {
+        self == CloudflareErrorKind::RateLimited
+    }
+}
+
+/// The numbers after each `code` in `message`, e.g. 10000 in `"code":10000` or `code: 10000`
+fn error_codes(message: &str) -> Vec<u32> {
+    message
+        .split("code")
+        .skip(1)
+        .filter_map(|rest| {
+            let digits: String = rest
+                .trim_left_matches(|c: char| c == '"' || c == ':' || c == ' ')
+                .chars()
+                .take_while(|c| c.is_ascii_digit())
+                .collect();
+            digits.parse().ok()
+        })
+        .collect()
+}
+
 #[derive(Debug)]
 pub enum RedirectError {
     BadUrl(String),
     InvalidPage(String),
     DuplicateRule(String),
+    /// The short can't be used as a DNS label
+    InvalidShort(String),
+    /// The URL (first) redirects to a different URL (second)
+    Indirect(String, String),
+    /// The URL's host isn't in the allowlist
+    DisallowedHost(String),
+    /// The redirect (first) has a `status` (second) that isn't a redirect status
+    InvalidStatus(String, u16),
+    /// The URL is a bare host or path without `http://` or `https://`
+    MissingScheme(String),
+    /// The short is one of the reserved shorts, which could shadow the server's own routes
+    ReservedShort(String),
+    /// The redirect (first) has an `accept_status` entry (second) that isn't a status or range
+    InvalidAcceptStatus(String, String),
+    /// The URL's host failed a TLS handshake that only offers TLS 1.2 or newer, the second
+    /// field says why
+    OutdatedTls(String, String),
+    /// The URL's host couldn't be reached to probe its TLS version, the second field says why
+    TlsProbeFailed(String, String),
+    /// The URL has the `{wildcard}` placeholder in its scheme or host instead of its path
+    WildcardInHost(String),
+}
+
+impl RedirectError {
+    /// Name of the kind of problem, e.g. `DuplicateRule`
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            RedirectError::BadUrl(_) => "BadUrl",
+            RedirectError::InvalidPage(_) => "InvalidPage",
+            RedirectError::DuplicateRule(_) => "DuplicateRule",
+            RedirectError::InvalidShort(_) => "InvalidShort",
+            RedirectError::Indirect(..) => "Indirect",
+            RedirectError::DisallowedHost(_) => "DisallowedHost",
+            RedirectError::InvalidStatus(..) => "InvalidStatus",
+            RedirectError::MissingScheme(_) => "MissingScheme",
+            RedirectError::ReservedShort(_) => "ReservedShort",
+            RedirectError::InvalidAcceptStatus(..) => "InvalidAcceptStatus",
+            RedirectError::OutdatedTls(..) => "OutdatedTls",
+            RedirectError::TlsProbeFailed(..) => "TlsProbeFailed",
+            RedirectError::WildcardInHost(_) => "WildcardInHost",
+        }
+    }
+
+    /// The short or URL the problem is about
+    pub fn subject(&self) -> &str {
+        match *self {
+            RedirectError::BadUrl(ref s)
+            | RedirectError::InvalidPage(ref s)
+            | RedirectError::DuplicateRule(ref s)
+            | RedirectError::InvalidShort(ref s)
+            | RedirectError::Indirect(ref s, _)
+            | RedirectError::DisallowedHost(ref s)
+            | RedirectError::InvalidStatus(ref s, _)
+            | RedirectError::MissingScheme(ref s)
+            | RedirectError::ReservedShort(ref s)
+            | RedirectError::InvalidAcceptStatus(ref s, _)
+            | RedirectError::OutdatedTls(ref s, _)
+            | RedirectError::TlsProbeFailed(ref s, _)
+            | RedirectError::WildcardInHost(ref s) => s,
+        }
+    }
 }
 
 impl From<cloudflare::Error> for Error {
     fn from(err: cloudflare::Error) -> Error {
-        Error::Cloudflare(err)
+        let message = format!("{:?}", err);
+        Error::Cloudflare(CloudflareError {
+            kind: CloudflareErrorKind::classify(&message),
+            message,
+        })
     }
 }
 
@@ -42,6 +219,12 @@ impl From<toml::de::Error> for Error {
     }
 }
 
+impl From<toml::ser::Error> for Error {
+    fn from(err: toml::ser::Error) -> Error {
+        Error::TomlSer(err)
+    }
+}
+
 impl<T> From<std::sync::PoisonError<T>> for Error {
     fn from(_err: std::sync::PoisonError<T>) -> Error {
         Error::Lock("ReadWrite lock was poisoned!".to_string())
@@ -54,6 +237,26 @@ impl From<std::io::Error> for Error {
     }
 }
 
+impl From<rayon::ThreadPoolBuildError> for Error {
+    fn from(err: rayon::ThreadPoolBuildError) -> Error {
+        Error::ThreadPool(err)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Error {
+        Error::Sqlite(err)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Error {
+        Error::Json(err)
+    }
+}
+
 impl From<RedirectError> for Error {
     fn from(err: RedirectError) -> Error {
         Error::RedirectError(err)
@@ -65,3 +268,217 @@ impl From<Vec<RedirectError>> for Error {
         Error::RedirectErrors(err)
     }
 }
+
+impl Error {
+    /// HTTP status to answer with when a request fails with this error. Cloudflare failures
+    /// are a bad gateway, or unavailable for now when they're transient.
+    pub fn status(&self) -> Status {
+        match *self {
+            Error::Cloudflare(ref e) if e.kind.is_transient() => Status::ServiceUnavailable,
+            Error::Cloudflare(_) => Status::BadGateway,
+            _ => Status::InternalServerError,
+        }
+    }
+}
+
+impl<'r> Responder<'r> for Error {
+    fn respond_to(self, req: &Request) -> response::Result<'r> {
+        let status = self.status();
+        Response::build_from(self.to_string().respond_to(req)?)
+            .status(status)
+            .ok()
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Cloudflare(ref e) => {
+                let problem = match e.kind {
+                    CloudflareErrorKind::Auth => "authentication failed",
+                    CloudflareErrorKind::RateLimited => "rate limited",
+                    CloudflareErrorKind::NotFound => "zone or record not found",
+                    CloudflareErrorKind::Other => "error",
+                };
+                write!(f, "Cloudflare {}: {}", problem, e.message)
+            }
+            Error::MissingApex(ref zone) => write!(
+                f,
+                "Cloudflare zone {0} has no A, AAAA or CNAME record for {0}, \
+                 which every redirect CNAME points at",
+                zone
+            ),
+            Error::Dns(ref e) => write!(f, "{}", e),
+            Error::DnsUpdate(ref errors) => write!(
+                f,
+                "DNS update failed, redirects left unchanged: {}",
+                errors.join("; ")
+            ),
+            Error::Reqwest(ref e) => write!(f, "request error: {}", e),
+            Error::ConfigDownload(ref url, status) => {
+                write!(f, "downloading config from {} failed with status {}", url, status)
+            }
+            Error::ConfigContent(ref url) => {
+                write!(f, "the config from {} has no readable content", url)
+            }
+            Error::Toml(ref e) => match e.line_col() {
+                Some((line, col)) => write!(
+                    f,
+                    "invalid TOML at line {}, column {}: {}",
+                    line + 1,
+                    col + 1,
+                    e
+                ),
+                None => write!(f, "invalid TOML: {}", e),
+            },
+            Error::IncludeCycle(ref cycle) => {
+                let paths: Vec<String> = cycle.iter().map(|p| p.display().to_string()).collect();
+                write!(f, "config files include each other: {}", paths.join(" -> "))
+            }
+            Error::IncludeOutsideRoot(ref include) => write!(
+                f,
+                "include {} must be a relative path inside the config's directory",
+                include
+            ),
+            Error::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported config version {}, expected 1 to {}",
+                version, SUPPORTED_CONFIG_VERSION
+            ),
+            Error::EmptyConfig => write!(
+                f,
+                "config has no redirects, set allow_empty_config=true to serve it anyway"
+            ),
+            Error::TomlSer(ref e) => write!(f, "could not write TOML: {}", e),
+            Error::Lock(ref e) => write!(f, "lock error: {}", e),
+            Error::Io(ref e) => write!(f, "io error: {}", e),
+            Error::ThreadPool(ref e) => write!(f, "thread pool error: {}", e),
+            #[cfg(feature = "sqlite")]
+            Error::Sqlite(ref e) => write!(f, "SQLite error: {}", e),
+            #[cfg(feature = "sqlite")]
+            Error::Json(ref e) => write!(f, "invalid stored redirect: {}", e),
+            Error::RedirectError(ref e) => write!(f, "{}", e),
+            // one problem per line, so a broken config reads like a report
+            Error::RedirectErrors(ref errors) => {
+                write!(f, "{} problem(s) found:", errors.len())?;
+                for e in errors {
+                    write!(f, "\n  - {}", e)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl fmt::Display for RedirectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RedirectError::BadUrl(ref url) => write!(f, "invalid URL: {}", url),
+            RedirectError::InvalidPage(ref page) => write!(f, "page not reachable: {}", page),
+            RedirectError::DuplicateRule(ref short) => write!(f, "duplicate redirect rule: {}", short),
+            RedirectError::InvalidShort(ref short) => {
+                write!(f, "short is not a valid DNS label: {}", short)
+            }
+            RedirectError::Indirect(ref from, ref to) => write!(
+                f,
+                "{} redirects to {}, consider using the final URL directly",
+                from, to
+            ),
+            RedirectError::DisallowedHost(ref url) => {
+                write!(f, "host of {} is not in the allowed hosts", url)
+            }
+            RedirectError::InvalidStatus(ref short, status) => write!(
+                f,
+                "status {} of {} is not a redirect status (300-303, 307 or 308)",
+                status, short
+            ),
+            RedirectError::MissingScheme(ref url) => {
+                write!(f, "missing scheme, did you mean https://{}?", url)
+            }
+            RedirectError::OutdatedTls(ref url, ref reason) => {
+                write!(f, "{} doesn't support TLS 1.2 or newer: {}", url, reason)
+            }
+            RedirectError::TlsProbeFailed(ref url, ref reason) => {
+                write!(f, "{} couldn't be reached to probe for TLS 1.2 or newer: {}", url, reason)
+            }
+            RedirectError::ReservedShort(ref short) => {
+                write!(f, "short is reserved for the server's own routes: {}", short)
+            }
+            RedirectError::InvalidAcceptStatus(ref short, ref entry) => write!(
+                f,
+                "accept_status `{}` of {} is not a status or a range like 401-403",
+                entry, short
+            ),
+            RedirectError::WildcardInHost(ref url) => {
+                write!(f, "{{wildcard}} can only be used in the path of {}", url)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cloudflare_error_classification() {
+        let payload = |code: u32, message: &str| {
+            format!(
+                r#"{{"success":false,"errors":[{{"code":{},"message":"{}"}}],"messages":[]}}"#,
+                code, message
+            )
+        };
+        let cases = vec![
+            (payload(10000, "Authentication error"), CloudflareErrorKind::Auth),
+            (payload(9103, "Unknown X-Auth-Key or X-Auth-Email"), CloudflareErrorKind::Auth),
+            (payload(971, "Please wait and consider throttling"), CloudflareErrorKind::RateLimited),
+            (payload(7003, "Could not route to /zones/a429"), CloudflareErrorKind::NotFound),
+            (payload(81044, "Record does not exist."), CloudflareErrorKind::NotFound),
+            (payload(81057, "Record already exists."), CloudflareErrorKind::Other),
+            // Debug output of the client's error types
+            (
+                r#"ApiError { code: 9109, message: "Invalid access token" }"#.to_string(),
+                CloudflareErrorKind::Auth,
+            ),
+            ("Http(429 Too Many Requests)".to_string(), CloudflareErrorKind::RateLimited),
+            ("Io(connection reset)".to_string(), CloudflareErrorKind::Other),
+        ];
+        for (message, kind) in cases {
+            assert_eq!(CloudflareErrorKind::classify(&message), kind, "{}", message);
+        }
+    }
+
+    #[test]
+    fn cloudflare_error_statuses() {
+        let error = |kind: CloudflareErrorKind| {
+            Error::Cloudflare(CloudflareError {
+                kind,
+                message: "ApiErrors".to_string(),
+            })
+        };
+        assert_eq!(error(CloudflareErrorKind::RateLimited).status(), Status::ServiceUnavailable);
+        assert_eq!(error(CloudflareErrorKind::Auth).status(), Status::BadGateway);
+        assert_eq!(error(CloudflareErrorKind::NotFound).status(), Status::BadGateway);
+        assert_eq!(Error::Lock("poisoned".to_string()).status(), Status::InternalServerError);
+        assert_eq!(
+            error(CloudflareErrorKind::Auth).to_string(),
+            "Cloudflare authentication failed: ApiErrors"
+        );
+    }
+
+    #[test]
+    fn redirect_errors_report() {
+        let errors = Error::RedirectErrors(vec![
+            RedirectError::DuplicateRule("std".to_string()),
+            RedirectError::InvalidStatus("api".to_string(), 200),
+            RedirectError::MissingScheme("doc.rust-lang.org".to_string()),
+        ]);
+        assert_eq!(
+            errors.to_string(),
+            "3 problem(s) found:\n  \
+             - duplicate redirect rule: std\n  \
+             - status 200 of api is not a redirect status (300-303, 307 or 308)\n  \
+             - missing scheme, did you mean https://doc.rust-lang.org?"
+        );
+    }
+}
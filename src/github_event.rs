@@ -1,14 +1,86 @@
-use GH_SECRET;
+use {GH_REPO, GH_SECRET, GH_SECRET_OLD};
 
+use dotenv;
 use hmac::{Hmac, Mac};
 use sha1::Sha1;
+use sha2::Sha256;
+use request_id;
 use rocket::data::{self, Data, FromData};
 use rocket::http::{ContentType, Status};
 use rocket::request::Request;
+use rocket::State;
 use rocket::Outcome::{self, *};
 use serde_json::{self, Value};
 
-use std::io::Read;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, Read};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Default maximum size of a webhook body: 5 MB
+const DEFAULT_MAX_BODY_BYTES: u64 = 5 * 1024 * 1024;
+
+lazy_static! {
+    /// Maximum size of a webhook body in bytes, configurable with the `webhook_max_bytes` env var
+    static ref MAX_BODY_BYTES: u64 = dotenv::var("webhook_max_bytes")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(DEFAULT_MAX_BODY_BYTES);
+}
+
+/// The Github repository that holds the redirect config
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GithubRepo {
+    pub owner: String,
+    pub name: String,
+    pub branch: String,
+}
+
+impl GithubRepo {
+    /// Read the repository from the `github_owner`, `github_repo`, and `github_branch`
+    /// env vars, defaulting to nocduro/rustref on master
+    pub fn from_env() -> GithubRepo {
+        GithubRepo {
+            owner: dotenv::var("github_owner").unwrap_or_else(|_| "nocduro".to_string()),
+            name: dotenv::var("github_repo").unwrap_or_else(|_| "rustref".to_string()),
+            branch: dotenv::var("github_branch").unwrap_or_else(|_| "master".to_string()),
+        }
+    }
+
+    /// URL to download the raw contents of `file` on the configured branch
+    pub fn raw_url(&self, file: &str) -> String {
+        format!(
+            "https://raw.githubusercontent.com/{}/{}/{}/{}",
+            self.owner, self.name, self.branch, file
+        )
+    }
+
+    /// Github contents API URL of `file` on the configured branch, which has its blob SHA
+    pub fn contents_url(&self, file: &str) -> String {
+        format!(
+            "https://api.github.com/repos/{}/{}/contents/{}?ref={}",
+            self.owner, self.name, file, self.branch
+        )
+    }
+
+    /// The git ref of the configured branch, as it appears in a `PushEvent`
+    pub fn branch_ref(&self) -> String {
+        format!("refs/heads/{}", self.branch)
+    }
+
+    /// `owner/name`, as it appears in a `PushEvent`'s `repository.full_name`
+    pub fn full_name(&self) -> String {
+        format!("{}/{}", self.owner, self.name)
+    }
+
+    /// Whether `event` is a push to this repository. Github names are case insensitive.
+    pub fn is_source_of(&self, event: &PushEvent) -> bool {
+        event
+            .repository_name()
+            .map_or(false, |name| name.eq_ignore_ascii_case(&self.full_name()))
+    }
+}
 
 /// Represents a Github user that is passed in by the Github webhook API
 #[derive(Debug, Deserialize, Clone, PartialEq, Eq, Serialize)]
@@ -50,6 +122,11 @@ pub struct PushEvent {
 }
 
 impl PushEvent {
+    /// `owner/name` of the repository that was pushed to
+    pub fn repository_name(&self) -> Option<&str> {
+        self.repository["full_name"].as_str()
+    }
+
     /// Checks each modified file in the PushEvent to see if `filename` was modified
     pub fn file_modified(&self, filename: &str) -> bool {
         for commit in &self.commits {
@@ -61,7 +138,183 @@ impl PushEvent {
     }
 }
 
-pub struct SignedPushEvent(pub PushEvent);
+/// A `PushEvent` whose signature was verified, with the delivery it came from
+pub struct SignedPushEvent {
+    pub event: PushEvent,
+    pub delivery: Delivery,
+}
+
+/// The `X-GitHub-Delivery` ID Github sends with every webhook. Included in the log lines
+/// of a webhook request so they can be matched with Github's delivery log.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Delivery(pub Option<String>);
+
+impl Delivery {
+    pub fn from_request(req: &Request) -> Delivery {
+        Delivery(req.headers().get_one("X-GitHub-Delivery").map(String::from))
+    }
+}
+
+/// How many delivery IDs are remembered for replay protection
+pub const DEFAULT_SEEN_CAPACITY: usize = 1000;
+
+/// How long a delivery ID is remembered for replay protection: 1 day
+pub const DEFAULT_SEEN_TTL_SECONDS: u64 = 24 * 60 * 60;
+
+/// Delivery IDs of recently processed webhooks. A captured payload keeps its valid signature,
+/// so replaying it is rejected by refusing delivery IDs that were already seen.
+/// At most `capacity` IDs are kept, each for at most `ttl`.
+#[derive(Debug)]
+pub struct SeenDeliveries {
+    /// IDs with the time they were seen, oldest first
+    seen: Mutex<VecDeque<(String, Instant)>>,
+    capacity: usize,
+    ttl: Duration,
+}
+
+impl SeenDeliveries {
+    pub fn new(capacity: usize, ttl: Duration) -> SeenDeliveries {
+        SeenDeliveries {
+            seen: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            ttl,
+        }
+    }
+
+    /// Whether `id` was seen within the TTL
+    pub fn contains(&self, id: &str) -> bool {
+        let mut seen = self.seen.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        self.forget_expired(&mut seen, Instant::now());
+        seen.iter().any(|&(ref seen_id, _)| seen_id == id)
+    }
+
+    /// Remember `id`. Returns `false` if it was already seen within the TTL.
+    pub fn insert(&self, id: &str) -> bool {
+        let mut seen = self.seen.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let now = Instant::now();
+        self.forget_expired(&mut seen, now);
+        if seen.iter().any(|&(ref seen_id, _)| seen_id == id) {
+            return false;
+        }
+        if seen.len() >= self.capacity {
+            seen.pop_front();
+        }
+        seen.push_back((id.to_string(), now));
+        true
+    }
+
+    fn forget_expired(&self, seen: &mut VecDeque<(String, Instant)>, now: Instant) {
+        while seen
+            .front()
+            .map_or(false, |&(_, at)| now.duration_since(at) >= self.ttl)
+        {
+            seen.pop_front();
+        }
+    }
+}
+
+/// Minimum time between two webhook-triggered redirect updates, used when the
+/// `webhook_cooldown_seconds` env var isn't set. 0 disables the rate limit.
+pub const DEFAULT_WEBHOOK_COOLDOWN_SECONDS: u64 = 0;
+
+/// Rate limits the redirect updates triggered by webhooks. Webhooks that would start an
+/// update less than `cooldown` after the last one are refused.
+#[derive(Debug)]
+pub struct WebhookCooldown {
+    cooldown: Duration,
+    /// When the last successful update started
+    last_run: Mutex<Option<Instant>>,
+}
+
+impl WebhookCooldown {
+    pub fn new(cooldown: Duration) -> WebhookCooldown {
+        WebhookCooldown {
+            cooldown,
+            last_run: Mutex::new(None),
+        }
+    }
+
+    /// Read the cooldown from the `webhook_cooldown_seconds` env var, falling back to
+    /// `DEFAULT_WEBHOOK_COOLDOWN_SECONDS`
+    pub fn from_env() -> WebhookCooldown {
+        let seconds = dotenv::var("webhook_cooldown_seconds")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(DEFAULT_WEBHOOK_COOLDOWN_SECONDS);
+        WebhookCooldown::new(Duration::from_secs(seconds))
+    }
+
+    /// Whether an update may start now. If the last successful one started less than the
+    /// cooldown ago, returns the time left until the next update is allowed.
+    pub fn check(&self) -> Result<(), Duration> {
+        let last_run = self.last_run.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        match *last_run {
+            Some(last) => {
+                let elapsed = last.elapsed();
+                if elapsed < self.cooldown {
+                    Err(self.cooldown - elapsed)
+                } else {
+                    Ok(())
+                }
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Record that an update which started at `started` succeeded. Failed updates aren't
+    /// recorded, so a fixed config can be pushed again right away.
+    pub fn record(&self, started: Instant) {
+        let mut last_run = self.last_run.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        *last_run = Some(started);
+    }
+}
+
+impl fmt::Display for Delivery {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(request) = request_id::current() {
+            write!(f, "[request {}] ", request)?;
+        }
+        match self.0 {
+            Some(ref id) => write!(f, "[delivery {}]", id),
+            None => write!(f, "[delivery unknown]"),
+        }
+    }
+}
+
+/// Signature header sent by Github, preferring the newer SHA-256 version when present
+enum Signature<'a> {
+    Sha256(&'a str),
+    Sha1(&'a str),
+}
+
+impl<'a> Signature<'a> {
+    fn from_request(req: &'a Request) -> Option<Signature<'a>> {
+        let headers = req.headers();
+        if let Some(h) = headers.get_one("X-Hub-Signature-256") {
+            return Some(Signature::Sha256(h));
+        }
+        headers.get_one("X-Hub-Signature").map(Signature::Sha1)
+    }
+
+    /// Check that this signature matches `payload` signed with `secret`
+    fn verify(&self, secret: &str, payload: &str) -> bool {
+        let (expected, hash) = match *self {
+            Signature::Sha256(hash) => (generate_github_hash_256(secret, payload), hash),
+            Signature::Sha1(hash) => (generate_github_hash(secret, payload), hash),
+        };
+        constant_time_eq(expected.as_bytes(), hash.as_bytes())
+    }
+
+    /// Check that this signature matches `payload` signed with any of `secrets`
+    fn verify_any<'s, I>(&self, secrets: I, payload: &str) -> bool
+    where
+        I: IntoIterator<Item = &'s str>,
+    {
+        secrets
+            .into_iter()
+            .any(|secret| self.verify(secret, payload))
+    }
+}
 
 impl FromData for SignedPushEvent {
     type Error = String;
@@ -70,43 +323,150 @@ impl FromData for SignedPushEvent {
         if req.content_type() != Some(&ContentType::JSON) {
             return Outcome::Forward(data);
         }
-        let gh_hash = match req.headers().get_one("X-Hub-Signature") {
-            Some(h) => h,
-            None => return Failure((Status::InternalServerError, "No signature".into())),
+        let delivery = Delivery::from_request(req);
+        let reject = |status: Status, reason: String| {
+            println!("{} rejected webhook: {}", delivery, reason);
+            Failure((status, reason))
         };
-
-        let mut data_str = String::new();
-        if let Err(e) = data.open().read_to_string(&mut data_str) {
-            return Failure((Status::InternalServerError, format!("{:?}", e)));
+        let signature = Signature::from_request(req);
+        if signature.is_none() && !cfg!(feature = "insecure-test") {
+            // most likely a misconfigured webhook, log what was sent to help track it down
+            let headers: Vec<String> = req
+                .headers()
+                .iter()
+                .map(|h| describe_header(h.name(), h.value()))
+                .collect();
+            println!("{} unsigned webhook headers: {}", delivery, headers.join(", "));
+            return reject(Status::BadRequest, "No signature".into());
         }
 
+        // never read more than the size limit into memory
+        let body = match read_limited(data.open(), *MAX_BODY_BYTES) {
+            Ok(Some(body)) => body,
+            Ok(None) => return reject(Status::PayloadTooLarge, "payload too large".into()),
+            Err(e) => return reject(Status::InternalServerError, format!("{:?}", e)),
+        };
+        let data_str = match String::from_utf8(body) {
+            Ok(s) => s,
+            Err(e) => return reject(Status::BadRequest, format!("{:?}", e)),
+        };
+
         // bail if signature doesn't match
-        if generate_github_hash(&GH_SECRET, &data_str) != gh_hash {
-            return Failure((Status::Forbidden, "signature mismatch".into()));
+        if !is_signed(signature.as_ref(), &data_str) {
+            return reject(Status::Forbidden, "signature mismatch".into());
         }
 
         // verified content, parse and return PushEvent
         let event: PushEvent = match serde_json::from_str(&data_str) {
             Ok(ev) => ev,
-            Err(e) => return Failure((Status::InternalServerError, format!("{:?}", e))),
+            Err(e) => return reject(Status::InternalServerError, format!("{:?}", e)),
+        };
+
+        // a fork keeps the webhook config, so a push to it must not update the redirects.
+        // this is also where a renamed or transferred repository shows up
+        if !GH_REPO.is_source_of(&event) {
+            let reason = format!(
+                "push to unexpected repository {}, expected {} (update github_owner and \
+                 github_repo if the repository was renamed or transferred)",
+                event.repository_name().unwrap_or("<unknown>"),
+                GH_REPO.full_name()
+            );
+            return reject(Status::Forbidden, reason);
+        }
+
+        // the signature only covers the body, so a replayed payload needs its delivery ID
+        let id = match delivery.0 {
+            Some(ref id) => id,
+            None => return reject(Status::BadRequest, "No delivery ID".into()),
+        };
+        let seen = match req.guard::<State<SeenDeliveries>>() {
+            Success(seen) => seen,
+            _ => return reject(Status::InternalServerError, "delivery IDs not tracked".into()),
         };
+        // the ID is only recorded once the webhook succeeded, so Github can redeliver a
+        // webhook that failed or was rate limited
+        if seen.contains(id) {
+            return reject(Status::Conflict, "delivery was already processed".into());
+        }
+
+        println!("{} received push to {}", delivery, event.refs);
+        Success(SignedPushEvent {
+            event,
+            delivery: delivery.clone(),
+        })
+    }
+}
+
+/// Whether `payload` is signed with the Github secret, or the old one during a rotation
+#[cfg(not(feature = "insecure-test"))]
+fn is_signed(signature: Option<&Signature>, payload: &str) -> bool {
+    let secrets = Some(GH_SECRET.as_str())
+        .into_iter()
+        .chain(GH_SECRET_OLD.as_ref().map(String::as_str));
+    signature.map_or(false, |s| s.verify_any(secrets, payload))
+}
+
+/// Signature checks are skipped with the `insecure-test` feature so test harnesses can
+/// post raw payloads. The feature can't be enabled in release builds, see `main.rs`.
+#[cfg(feature = "insecure-test")]
+fn is_signed(_signature: Option<&Signature>, _payload: &str) -> bool {
+    true
+}
 
-        Success(SignedPushEvent(event))
+/// `name: value` for the log, with the values of headers that could hold credentials hidden
+fn describe_header(name: &str, value: &str) -> String {
+    let secret = ["authorization", "cookie", "proxy-authorization"]
+        .iter()
+        .any(|s| name.eq_ignore_ascii_case(s));
+    if secret {
+        format!("{}: <redacted>", name)
+    } else {
+        format!("{}: {}", name, value)
     }
 }
 
+/// Read at most `limit` bytes from `reader`.
+/// Returns `Ok(None)` if there was more data than `limit` allows.
+pub fn read_limited<R: Read>(reader: R, limit: u64) -> io::Result<Option<Vec<u8>>> {
+    let mut body = Vec::new();
+    reader.take(limit + 1).read_to_end(&mut body)?;
+    if body.len() as u64 > limit {
+        Ok(None)
+    } else {
+        Ok(Some(body))
+    }
+}
+
+/// Generate the legacy `X-Hub-Signature` value (HMAC-SHA1) for `json_str`
 pub fn generate_github_hash(secret: &str, json_str: &str) -> String {
     let mut mac = Hmac::<Sha1>::new_varkey(secret.as_bytes()).expect("Hmac creation");
     mac.input(json_str.as_bytes());
     let hmac_result = mac.result().code();
+    hex_hash("sha1=", hmac_result.as_slice())
+}
+
+/// Generate the `X-Hub-Signature-256` value (HMAC-SHA256) for `json_str`
+pub fn generate_github_hash_256(secret: &str, json_str: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes()).expect("Hmac creation");
+    mac.input(json_str.as_bytes());
+    let hmac_result = mac.result().code();
+    hex_hash("sha256=", hmac_result.as_slice())
+}
 
-    let mut hash = "sha1=".to_string();
+fn hex_hash(prefix: &str, bytes: &[u8]) -> String {
+    let mut hash = prefix.to_string();
 
     // hmac produces result as bytes. convert it to a hex string representation
-    hash.extend(hmac_result.as_slice().iter().map(|x| format!("{:02x}", x)));
+    hash.extend(bytes.iter().map(|x| format!("{:02x}", x)));
     hash
 }
 
+/// Whether `a` and `b` are equal, comparing every byte so the response time doesn't tell
+/// how much of a guessed signature or token was right
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -122,4 +482,185 @@ mod tests {
             "sha1=604b8100cfe1aeaee448759c1450f080f41d41db"
         );
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn sha256_hash() {
+        // test vector from Github's webhook documentation
+        let secret = "It's a Secret to Everybody".to_string();
+        let payload = "Hello, World!".to_string();
+        assert_eq!(
+            generate_github_hash_256(&secret, &payload),
+            "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17"
+        );
+    }
+
+    #[test]
+    fn verify_either_signature() {
+        let secret = "hello";
+        let payload = "this is an example payload of what we want to sign.";
+        let sha1 = generate_github_hash(secret, payload);
+        let sha256 = generate_github_hash_256(secret, payload);
+        assert!(Signature::Sha1(&sha1).verify(secret, payload));
+        assert!(Signature::Sha256(&sha256).verify(secret, payload));
+        // a SHA-1 hash in the SHA-256 header must not verify
+        assert!(!Signature::Sha256(&sha1).verify(secret, payload));
+    }
+
+    #[test]
+    fn constant_time_comparison() {
+        assert!(constant_time_eq(b"sha256=abc", b"sha256=abc"));
+        assert!(!constant_time_eq(b"sha256=abc", b"sha256=abd"));
+        assert!(!constant_time_eq(b"sha256=abc", b"sha256=ab"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[test]
+    fn verify_rotated_secrets() {
+        let payload = "this is an example payload of what we want to sign.";
+        let secrets = ["new secret", "old secret"];
+        for secret in &secrets {
+            let sha256 = generate_github_hash_256(secret, payload);
+            assert!(Signature::Sha256(&sha256).verify_any(secrets.iter().cloned(), payload));
+            let sha1 = generate_github_hash(secret, payload);
+            assert!(Signature::Sha1(&sha1).verify_any(secrets.iter().cloned(), payload));
+        }
+
+        let other = generate_github_hash_256("some other secret", payload);
+        assert!(!Signature::Sha256(&other).verify_any(secrets.iter().cloned(), payload));
+        // once the old secret is removed, only the new one verifies
+        let old = generate_github_hash_256("old secret", payload);
+        assert!(!Signature::Sha256(&old).verify_any(Some("new secret"), payload));
+    }
+
+    #[test]
+    fn github_repo_urls() {
+        let repo = GithubRepo {
+            owner: "someone".to_string(),
+            name: "rustref-fork".to_string(),
+            branch: "main".to_string(),
+        };
+        assert_eq!(
+            repo.raw_url("redirects.toml"),
+            "https://raw.githubusercontent.com/someone/rustref-fork/main/redirects.toml"
+        );
+        assert_eq!(repo.branch_ref(), "refs/heads/main");
+        assert_eq!(repo.full_name(), "someone/rustref-fork");
+    }
+
+    #[test]
+    fn push_from_unexpected_repository() {
+        let json_str = include_str!("../test_data/readme_updated.json");
+        let repo = GithubRepo {
+            owner: "nocduro".to_string(),
+            name: "rustref".to_string(),
+            branch: "master".to_string(),
+        };
+        let mut event: PushEvent = serde_json::from_str(json_str).unwrap();
+        assert_eq!(event.repository_name(), Some("nocduro/rustref"));
+        assert!(repo.is_source_of(&event));
+
+        event.repository["full_name"] = Value::from("NoCduro/RustRef");
+        assert!(repo.is_source_of(&event));
+
+        event.repository["full_name"] = Value::from("someone/rustref-fork");
+        assert!(!repo.is_source_of(&event));
+
+        event.repository = Value::Null;
+        assert!(!repo.is_source_of(&event));
+    }
+
+    #[test]
+    fn delivery_id_from_headers() {
+        use rocket;
+        use rocket::http::Header;
+        use rocket::local::Client;
+
+        let client = Client::new(rocket::ignite()).expect("valid rocket instance");
+        let request = client.post("/github/webhook").header(Header::new(
+            "X-GitHub-Delivery",
+            "72d3162e-cc78-11e3-81ab-4c9367dc0958",
+        ));
+        let delivery = Delivery::from_request(request.inner());
+        assert_eq!(
+            delivery,
+            Delivery(Some("72d3162e-cc78-11e3-81ab-4c9367dc0958".to_string()))
+        );
+        assert_eq!(
+            delivery.to_string(),
+            "[delivery 72d3162e-cc78-11e3-81ab-4c9367dc0958]"
+        );
+
+        let request = client.post("/github/webhook");
+        let delivery = Delivery::from_request(request.inner());
+        assert_eq!(delivery, Delivery(None));
+        assert_eq!(delivery.to_string(), "[delivery unknown]");
+    }
+
+    #[test]
+    fn seen_deliveries_reject_repeats() {
+        let ttl = Duration::from_secs(DEFAULT_SEEN_TTL_SECONDS);
+        let seen = SeenDeliveries::new(DEFAULT_SEEN_CAPACITY, ttl);
+        assert!(!seen.contains("a"));
+        assert!(seen.insert("a"));
+        assert!(seen.insert("b"));
+        assert!(seen.contains("a"));
+        assert!(!seen.insert("a"));
+
+        // the oldest ID is forgotten once the set is full
+        let seen = SeenDeliveries::new(2, ttl);
+        assert!(seen.insert("a"));
+        assert!(seen.insert("b"));
+        assert!(seen.insert("c"));
+        assert!(seen.insert("a"));
+        assert!(!seen.insert("c"));
+
+        // and after the TTL
+        let seen = SeenDeliveries::new(DEFAULT_SEEN_CAPACITY, Duration::from_secs(0));
+        assert!(seen.insert("a"));
+        assert!(!seen.contains("a"));
+        assert!(seen.insert("a"));
+    }
+
+    #[test]
+    fn cooldown_after_success() {
+        let cooldown = WebhookCooldown::new(Duration::from_secs(60));
+        assert_eq!(cooldown.check(), Ok(()));
+        // a failed update isn't recorded, so checking again is still fine
+        assert_eq!(cooldown.check(), Ok(()));
+
+        cooldown.record(Instant::now());
+        let remaining = cooldown.check().unwrap_err();
+        assert!(remaining <= Duration::from_secs(60));
+        assert!(remaining > Duration::from_secs(55));
+
+        // without a cooldown every update is allowed
+        let cooldown = WebhookCooldown::new(Duration::from_secs(0));
+        cooldown.record(Instant::now());
+        assert_eq!(cooldown.check(), Ok(()));
+    }
+
+    #[test]
+    fn body_within_limit() {
+        let payload = b"{\"ref\": \"refs/heads/master\"}";
+        let body = read_limited(&payload[..], 1024).unwrap();
+        assert_eq!(body, Some(payload.to_vec()));
+    }
+
+    #[test]
+    fn oversized_body_rejected() {
+        // an endless stream must be cut off at the limit instead of read into memory
+        let body = read_limited(io::repeat(b'a'), 1024).unwrap();
+        assert!(body.is_none());
+    }
+
+    #[test]
+    fn credential_headers_redacted() {
+        assert_eq!(describe_header("User-Agent", "curl/7.58.0"), "User-Agent: curl/7.58.0");
+        assert_eq!(describe_header("X-GitHub-Event", "push"), "X-GitHub-Event: push");
+        assert_eq!(
+            describe_header("Authorization", "Bearer hunter2"),
+            "Authorization: <redacted>"
+        );
+        assert_eq!(describe_header("cookie", "session=abc"), "cookie: <redacted>");
+    }
+}
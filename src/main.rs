@@ -1,151 +1,1233 @@
 #![feature(plugin)]
 #![plugin(rocket_codegen)]
+// the `json!` in schema.rs is large enough to need more than the default limit
+#![recursion_limit = "256"]
 
+// the insecure-test feature disables webhook signature checks, never allow it in production
+#[cfg(all(feature = "insecure-test", not(debug_assertions)))]
+compile_error!("the `insecure-test` feature must not be enabled in release builds");
+
+extern crate base64;
 extern crate cloudflare;
+extern crate ctrlc;
 extern crate dotenv;
+extern crate flate2;
+#[cfg(feature = "async-check")]
+extern crate futures;
 extern crate hmac;
 #[macro_use]
 extern crate lazy_static;
+extern crate native_tls;
 extern crate rayon;
 extern crate reqwest;
 extern crate rocket;
 extern crate rocket_contrib;
+#[cfg(feature = "route53")]
+extern crate rusoto_core;
+#[cfg(all(test, feature = "route53"))]
+extern crate rusoto_mock;
+#[cfg(feature = "route53")]
+extern crate rusoto_route53;
+#[cfg(feature = "sqlite")]
+extern crate rusqlite;
+extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+#[macro_use]
 extern crate serde_json;
 extern crate sha1;
+extern crate sha2;
+#[cfg(feature = "async-check")]
+extern crate tokio_core;
 extern crate toml;
+extern crate uuid;
 
-use cloudflare::Cloudflare;
-use rocket::http::RawStr;
-use rocket::response::{NamedFile, Redirect};
-use rocket::State;
-use rocket_contrib::Template;
+use rocket::http::uri::Segments;
+use rocket::http::Status;
+use rocket::request::Request;
+use rocket::response::status::NotFound;
+use rocket::response::{self, content, NamedFile, Responder};
+use rocket::config::{ConfigError, RocketConfig};
+use rocket::{Config, State};
+use rocket_contrib::{Json, Template};
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs;
+use std::io;
 use std::path::{Path, PathBuf};
-use std::sync::{Mutex, RwLock};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
+use std::time::{Duration, Instant};
 
+mod accept_language;
+mod admin;
+mod api;
+mod compression;
+mod dns_provider;
 mod errors;
 mod github_event;
+mod metrics;
+mod prefers_json;
+mod prefix;
 mod redirect_utils;
+mod request_id;
+mod responders;
+#[cfg(feature = "route53")]
+mod route53;
+mod schema;
+mod security_headers;
+mod shutdown;
+mod store;
+mod subdomain;
+#[cfg(test)]
+mod test_utils;
+mod tls;
 
 pub use errors::{Error, Result};
-use github_event::{PushEvent, SignedPushEvent};
+use accept_language::AcceptLanguage;
+use admin::{AdminToken, MaintenanceMode};
+use compression::Compression;
+use dns_provider::{CloudflareCredentials, CloudflareDns, DnsProvider, CLOUDFLARE_API_URL};
+use github_event::{GithubRepo, PushEvent, SeenDeliveries, SignedPushEvent, WebhookCooldown};
+use metrics::{CloudflareMetrics, HitCounter, LatencyFairing, LatencyHistogram};
+use prefers_json::PrefersJson;
+use prefix::PrefixRedirect;
+use redirect_utils::{
+    ConfigHistory, ConfigMeta, ConfigReload, DnsCheck, RedirectTarget, SiteRedirect,
+    UpdateSummary, UrlStatus,
+};
+use request_id::RequestIdFairing;
+use responders::{
+    CachedRedirect, IndexResponse, MethodNotAllowed, TooManyRequests, UnderMaintenance,
+};
+use security_headers::SecurityHeaders;
+use shutdown::{Busy, Shutdown};
+use store::RedirectStore;
+use subdomain::Subdomain;
 
-type RedirectMap = RwLock<RedirectData>;
-type CloudflareApi = Mutex<Cloudflare>;
+type RedirectMap = Arc<RwLock<RedirectData>>;
+type StatusMap = Arc<RwLock<HashMap<String, UrlStatus>>>;
+/// The DNS provider the redirect CNAMEs are managed with, if one is configured
+type DnsApi = Option<Mutex<Box<DnsProvider + Send>>>;
+/// Where changes to the redirects are saved, if anywhere
+type RedirectStorage = Option<Mutex<Box<RedirectStore + Send>>>;
 
 lazy_static! {
-    static ref GH_SECRET: String = dotenv::var("github_secret").expect("github secret ENV not found!");
+    static ref GH_SECRET: String = secret_var("github_secret").expect("github secret ENV not found!");
+    /// Previous webhook secret, still accepted while rotating to a new `github_secret`
+    static ref GH_SECRET_OLD: Option<String> =
+        secret_var("github_secret_old").filter(|s| !s.is_empty());
+    static ref GH_REPO: GithubRepo = GithubRepo::from_env();
+    /// Path of the redirect config, loaded at startup and written back after updates
+    static ref REDIRECTS_FILE: String =
+        dotenv::var("redirects_file").unwrap_or_else(|_| "redirects.toml".to_string());
+    /// Send `X-Robots-Tag: noindex` with redirects unless `noindex_redirects=false`
+    static ref NOINDEX_REDIRECTS: bool =
+        dotenv::var("noindex_redirects").map(|s| s != "false").unwrap_or(true);
+}
+
+/// The secret `name`: the trimmed contents of the file named by the `<name>_file` env var
+/// if it's set, as with Docker and Kubernetes secrets, otherwise the `name` env var.
+/// Exits if the file can't be read instead of silently falling back to the env var.
+fn secret_var(name: &str) -> Option<String> {
+    let file = dotenv::var(format!("{}_file", name)).ok();
+    match read_secret(file.as_ref().map(String::as_str), dotenv::var(name).ok()) {
+        Ok(secret) => secret,
+        Err(e) => {
+            eprintln!("error: could not read {}_file: {}", name, e);
+            process::exit(1);
+        }
+    }
+}
+
+/// The trimmed contents of `file` if there is one, `value` otherwise
+fn read_secret(file: Option<&str>, value: Option<String>) -> io::Result<Option<String>> {
+    match file {
+        Some(path) => Ok(Some(fs::read_to_string(path)?.trim().to_string())),
+        None => Ok(value),
+    }
 }
 
 #[derive(Debug, Serialize)]
 pub struct RedirectData {
-    map: HashMap<String, String>,
+    map: HashMap<String, RedirectTarget>,
+    categories: BTreeMap<String, Vec<SiteRedirect>>,
     commit_hash: String,
     commit_url: String,
+    /// The config's `[meta]` table, shown in the index page footer
+    meta: Option<ConfigMeta>,
+    /// `ETag` of the current redirects, see `redirect_utils::redirects_etag`
+    #[serde(skip)]
+    etag: String,
+    /// Github blob SHAs of redirects.toml and its includes by path, as the redirects were
+    /// downloaded, see `redirect_utils::skip_applied_blobs`. Cleared whenever the redirects
+    /// are replaced.
+    #[serde(skip)]
+    config_blobs: Option<BTreeMap<String, String>>,
+    /// Paths of redirects.toml and the files it includes, like they are in the repository.
+    /// A push that modifies any of them reloads the config.
+    #[serde(skip)]
+    config_files: Vec<String>,
 }
 
-/// Update the servers redirect map whenever `redirects.toml` is updated in the
-/// master branch on Github.
+impl RedirectData {
+    fn new(redirects: &[SiteRedirect], commit_hash: &str, commit_url: &str) -> RedirectData {
+        let mut data = RedirectData {
+            map: HashMap::new(),
+            categories: BTreeMap::new(),
+            commit_hash: commit_hash.to_string(),
+            commit_url: commit_url.to_string(),
+            meta: None,
+            etag: String::new(),
+            config_blobs: None,
+            config_files: Vec::new(),
+        };
+        data.set_redirects(redirects);
+        data
+    }
+
+    /// Replace all redirects with `redirects`
+    fn set_redirects(&mut self, redirects: &[SiteRedirect]) {
+        self.map = redirect_utils::vec_redirects_to_hashmap(redirects);
+        self.categories = redirect_utils::group_by_category(redirects);
+        let etag = redirect_utils::redirects_etag(&self.sorted_redirects());
+        self.etag = etag;
+        self.config_blobs = None;
+    }
+
+    /// All redirects, sorted by `short`
+    fn sorted_redirects(&self) -> Vec<&SiteRedirect> {
+        let mut redirects: Vec<&SiteRedirect> =
+            self.categories.values().flat_map(|v| v.iter()).collect();
+        redirects.sort_by(|a, b| a.short.cmp(&b.short));
+        redirects
+    }
+
+    /// The redirects shown in listings, sorted by `short`. Wildcards like `blog-*` are left
+    /// out, since their shorts can't be followed as they are written.
+    fn listed_redirects(&self) -> Vec<&SiteRedirect> {
+        let mut redirects = self.sorted_redirects();
+        redirects.retain(|r| !redirect_utils::is_wildcard_short(&r.short));
+        redirects
+    }
+}
+
+/// Set once the poisoned redirect lock has been reported, so the log isn't flooded
+static POISON_LOGGED: AtomicBool = AtomicBool::new(false);
+
+/// Acquire a read lock on the redirect data.
+///
+/// If a thread panicked while holding the write lock the data is recovered from the
+/// poisoned lock instead of panicking, so the server keeps serving the last known redirects.
+fn read_redirects(redirs: &RedirectMap) -> RwLockReadGuard<RedirectData> {
+    redirs.read().unwrap_or_else(|poisoned| {
+        if !POISON_LOGGED.swap(true, Ordering::SeqCst) {
+            println!(
+                "{}Warning: redirect map lock was poisoned, serving last known redirects",
+                request_id::log_prefix()
+            );
+        }
+        poisoned.into_inner()
+    })
+}
+
+/// Body of the webhook response, shown in Github's delivery log
+#[derive(Debug, Serialize)]
+struct WebhookResponse {
+    message: &'static str,
+    /// What changed, if the redirects were updated
+    summary: Option<UpdateSummary>,
+}
+
+impl WebhookResponse {
+    fn ignored(message: &'static str) -> WebhookResponse {
+        WebhookResponse {
+            message,
+            summary: None,
+        }
+    }
+}
+
+/// Responds with the JSON body, with `207 Multi-Status` instead of `200 OK` if the redirects
+/// were updated but some DNS changes failed
+impl<'r> Responder<'r> for WebhookResponse {
+    fn respond_to(self, req: &Request) -> response::Result<'r> {
+        let warnings = self.summary.as_ref().map_or(false, UpdateSummary::has_warnings);
+        let mut response = Json(self).respond_to(req)?;
+        if warnings {
+            response.set_status(Status::MultiStatus);
+        }
+        Ok(response)
+    }
+}
+
+/// The webhook response, `TooManyRequests` if the update was rate limited
+type WebhookResult = Result<std::result::Result<WebhookResponse, TooManyRequests>>;
+
+/// Whether `push` modifies redirects.toml or one of the files the served config includes
+fn config_modified(push: &PushEvent, data: &RedirectData) -> bool {
+    push.file_modified("redirects.toml")
+        || data.config_files.iter().any(|file| push.file_modified(file))
+}
+
+/// Update the servers redirect map whenever `redirects.toml` or a file it includes is
+/// updated in the configured branch on Github (master by default).
 ///
 /// Called by Github's servers whenever there is a `push` event in the Github repository.
-/// Returns 200 with message if everything went ok, otherwise a 500 internal error if
-/// something went wrong when updating the redirect map
+/// Returns 200 with a message and a summary of the changes if everything went ok, 207 if
+/// the redirects were updated but some DNS changes failed (listed in the summary), otherwise
+/// a 500 internal error if something went wrong when updating the redirect map. With
+/// `abort_on_dns_errors=true` failed DNS changes are a 500 and the redirects are kept as is.
+/// While the server is shutting down new webhooks get a 503 instead, while another update
+/// or a rollback is running a 409, and updates less than `webhook_cooldown_seconds` apart
+/// get a 429 with a `Retry-After` header.
 #[post("/github/webhook", data = "<event>")]
+// every argument is a request guard or managed state Rocket passes in
+#[cfg_attr(feature = "cargo-clippy", allow(too_many_arguments))]
 fn webhook(
     event: SignedPushEvent,
+    _busy: Busy,
     redirs: State<RedirectMap>,
-    cf: State<CloudflareApi>,
-) -> Result<&'static str> {
-    let push: PushEvent = event.0;
+    statuses: State<StatusMap>,
+    dns: State<DnsApi>,
+    cf_metrics: State<CloudflareMetrics>,
+    cooldown: State<WebhookCooldown>,
+    seen: State<SeenDeliveries>,
+    history: State<ConfigHistory>,
+    store: State<RedirectStorage>,
+) -> WebhookResult {
+    let push: PushEvent = event.event;
+    let delivery = event.delivery;
+    // replays of a delivery are refused once it was processed, failed ones can be retried
+    let processed = || {
+        if let Some(ref id) = delivery.0 {
+            seen.insert(id);
+        }
+    };
+
+    // check if this is a push to the configured branch. if not, return early
+    if push.refs != GH_REPO.branch_ref() {
+        println!("{} push is not on the configured branch, ignoring", delivery);
+        processed();
+        return Ok(Ok(WebhookResponse::ignored("Event not on the configured branch, ignoring")));
+    }
+
+    // check that the redirects file or one of its includes was actually modified
+    if !config_modified(&push, &read_redirects(&redirs)) {
+        println!("{} redirects.toml and its includes were not modified, ignoring", delivery);
+        processed();
+        return Ok(Ok(WebhookResponse::ignored(
+            "redirects.toml and its includes were not modified, ignoring",
+        )));
+    }
+
+    let started = Instant::now();
+    if let Err(remaining) = cooldown.check() {
+        println!("{} rate limited, next update allowed in {:?}", delivery, remaining);
+        return Ok(Err(TooManyRequests(remaining)));
+    }
+
+    let reload = redirect_utils::update_redirect_map(
+        redirs,
+        statuses,
+        dns,
+        cf_metrics,
+        history,
+        store,
+        &delivery,
+    ).map_err(|e| {
+        println!("{} updating redirects failed: {}", delivery, e);
+        e
+    })?;
+    cooldown.record(started);
+    processed();
+    match reload {
+        ConfigReload::Unchanged => {
+            Ok(Ok(WebhookResponse::ignored("redirects.toml is unchanged, nothing to update")))
+        }
+        ConfigReload::Updated(summary) => {
+            println!("{} redirects updated: {:?}", delivery, summary);
+            let message = if summary.has_warnings() {
+                "Redirects updated, but some DNS changes failed"
+            } else {
+                "Redirects Updated!"
+            };
+            Ok(Ok(WebhookResponse {
+                message,
+                summary: Some(summary),
+            }))
+        }
+    }
+}
+
+/// Context of the `index` template
+#[derive(Serialize)]
+struct IndexContext<'a> {
+    /// Redirects by category, categories and the redirects in them in alphabetic order
+    categories: BTreeMap<&'a str, Vec<&'a SiteRedirect>>,
+    meta: Option<&'a ConfigMeta>,
+}
+
+impl<'a> IndexContext<'a> {
+    fn new(data: &'a RedirectData) -> IndexContext<'a> {
+        let categories = data
+            .categories
+            .iter()
+            .map(|(category, redirects)| {
+                let mut redirects: Vec<&SiteRedirect> = redirects
+                    .iter()
+                    .filter(|r| !redirect_utils::is_wildcard_short(&r.short))
+                    .collect();
+                redirects.sort_by(|a, b| a.short.cmp(&b.short));
+                (category.as_str(), redirects)
+            })
+            .filter(|&(_, ref redirects)| !redirects.is_empty())
+            .collect();
+        IndexContext {
+            categories,
+            meta: data.meta.as_ref(),
+        }
+    }
+}
+
+/// Return a page listing all current redirects grouped by category, in alphabetic order,
+/// or the redirects as JSON if the client asks for `application/json`.
+/// Ranked after `host_redirect_bare`, which takes requests to the redirect subdomains.
+#[get("/", rank = 1)]
+fn index(json: PrefersJson, redirs: State<RedirectMap>) -> IndexResponse {
+    let data = read_redirects(&redirs);
+    if json.0 {
+        IndexResponse::Redirects(data.listed_redirects().into_iter().cloned().collect())
+    } else {
+        IndexResponse::Page(Template::render("index", IndexContext::new(&data)))
+    }
+}
+
+/// Ask crawlers to skip the `/redirect/` routes and point them at the sitemap
+#[get("/robots.txt")]
+fn robots_txt() -> content::Plain<String> {
+    content::Plain(redirect_utils::robots_txt())
+}
+
+/// List the index page and every redirect host for search engines
+#[get("/sitemap.xml")]
+fn sitemap(redirs: State<RedirectMap>) -> content::Xml<String> {
+    let data = read_redirects(&redirs);
+    content::Xml(redirect_utils::sitemap(&data.sorted_redirects()))
+}
+
+/// A redirect, or a 404 page suggesting similar redirects if the key doesn't exist
+type RedirectResult = std::result::Result<CachedRedirect, NotFound<Template>>;
 
-    // check if this is a push to master. if not, return early
-    if push.refs != "refs/heads/master" {
-        return Ok("Event not on master branch, ignoring\n");
+/// A redirect route's response, or 503 while in maintenance mode
+type Maintained<T> = std::result::Result<T, UnderMaintenance>;
+
+/// `Err(UnderMaintenance)` if maintenance mode is on, the result of `respond` otherwise
+fn unless_maintenance<T, F: FnOnce() -> T>(mode: &MaintenanceMode, respond: F) -> Maintained<T> {
+    if mode.is_on() {
+        Err(UnderMaintenance)
+    } else {
+        Ok(respond())
     }
+}
+
+/// Context of the `not_found` template
+#[derive(Serialize)]
+struct NotFoundContext<'a> {
+    key: &'a str,
+    suggestions: Vec<String>,
+}
 
-    // check that the redirects file was actually modified
-    if !push.file_modified("redirects.toml") {
-        return Ok("redirects.toml was not modified, ignoring\n");
+/// The redirect key for `key`: `www.cook` (from www.cook.rustref.com) is the same as `cook`
+fn normalize_key(key: &str) -> &str {
+    if key.starts_with("www.") {
+        &key[4..]
+    } else {
+        key
     }
+}
 
-    redirect_utils::update_redirect_map(redirs, cf).map(|_| Ok("Redirects Updated!\n"))?
+/// Find the redirect for `key`, appending `path` to the target url if there is one.
+/// Unknown keys go to the config's `fallback_search_url` if it has one.
+/// The request is counted in `hits` if given.
+fn find_redirect(
+    key: &str,
+    path: Option<&str>,
+    lang: &AcceptLanguage,
+    redirs: &RedirectMap,
+    hits: Option<&HitCounter>,
+) -> RedirectResult {
+    let key = normalize_key(key);
+    let (result, matched) = lookup_redirect(key, path, lang, redirs);
+    // counted once the lookup released the read lock, so config updates don't wait on it
+    if let Some(hits) = hits {
+        match matched {
+            Some(short) => hits.hit(&short),
+            None => hits.miss(key),
+        }
+    }
+    result
 }
 
-/// Return a page listing all current redirects in alphabetic order
-#[get("/")]
-fn index(redirs: State<RedirectMap>) -> Template {
-    let data: &RedirectData = &*redirs.read().expect("rlock failed");
-    Template::render("index", data)
+/// The response for the normalized `key` and the short of the redirect that matched it
+fn lookup_redirect(
+    key: &str,
+    path: Option<&str>,
+    lang: &AcceptLanguage,
+    redirs: &RedirectMap,
+) -> (RedirectResult, Option<String>) {
+    let data = read_redirects(redirs);
+    let map: &HashMap<String, RedirectTarget> = &data.map;
+    // exact matches take precedence over wildcards. a key spelled like a wildcard only
+    // names the pattern, it isn't a redirect of its own
+    let exact = if redirect_utils::is_wildcard_short(key) {
+        None
+    } else {
+        map.get(key).map(|target| (key, target))
+    };
+    let wildcard = if exact.is_none() {
+        redirect_utils::find_wildcard(map, key)
+    } else {
+        None
+    };
+    let found = exact.or_else(|| {
+        wildcard
+            .as_ref()
+            .map(|&(ref short, ref target)| (short.as_str(), target))
+    });
+    let (short, target) = match found {
+        Some(found) => found,
+        None => {
+            if let Some(url) = data.meta.as_ref().and_then(|meta| meta.search_url(key)) {
+                return (Ok(CachedRedirect::found(&url).robots("noindex")), None);
+            }
+            let context = NotFoundContext {
+                key,
+                suggestions: redirect_utils::suggest_shorts(
+                    key,
+                    map.keys().filter(|short| !redirect_utils::is_wildcard_short(short)),
+                ),
+            };
+            return (Err(NotFound(Template::render("not_found", &context))), None);
+        }
+    };
+    // drop the target's `strip_prefix` before appending the rest of the path
+    let url = target.url_with_path(lang, path.map_or("", |path| target.strip_path(path)));
+    let redirect = target.redirect_to(&url).matched(short, &data.commit_hash);
+    // keep the redirect endpoints themselves out of search engines
+    if *NOINDEX_REDIRECTS && !target.indexable {
+        (Ok(redirect.robots("noindex")), Some(short.to_string()))
+    } else {
+        (Ok(redirect), Some(short.to_string()))
+    }
 }
 
-/// Redirect a subdomain to its matching page via 302 redirect.
-/// If `key` is not in the redirect map return 404.
+/// List the keys that can be used under `/redirect/`, linking to each of them, or the
+/// served redirects as JSON for clients that ask for `application/json`.
+/// Disabled redirects aren't listed since they can't be followed.
+#[get("/redirect")]
+fn redirect_index(json: PrefersJson, redirs: State<RedirectMap>) -> IndexResponse {
+    let data = read_redirects(&redirs);
+    if json.0 {
+        let enabled = data.listed_redirects().into_iter().filter(|r| r.enabled);
+        return IndexResponse::Redirects(enabled.cloned().collect());
+    }
+    let index = IndexContext::new(&data);
+    let context = IndexContext {
+        categories: index
+            .categories
+            .into_iter()
+            .map(|(category, redirects)| {
+                let enabled: Vec<&SiteRedirect> =
+                    redirects.into_iter().filter(|r| r.enabled).collect();
+                (category, enabled)
+            })
+            .filter(|&(_, ref redirects)| !redirects.is_empty())
+            .collect(),
+        meta: index.meta,
+    };
+    IndexResponse::Page(Template::render("redirect_index", &context))
+}
+
+/// Redirect a subdomain to its matching page via 302 redirect (301 for permanent redirects).
+/// A localized page is picked if one matches the client's `Accept-Language`.
+/// If `key` is not in the redirect map return a 404 page suggesting similar redirects.
 ///
 /// Example: cook.rustref.com => https://doc.rust-lang.org/cargo/
 #[get("/redirect/<key>")]
-fn redirect_bare(key: String, redirs: State<RedirectMap>) -> Option<Redirect> {
-    let map: &HashMap<String, String> = &redirs.read().expect("could not lock rlock").map;
-    match map.get(&key) {
-        Some(url) => Some(Redirect::found(url)),
-        None => None,
-    }
+fn redirect_bare(
+    key: String,
+    lang: AcceptLanguage,
+    redirs: State<RedirectMap>,
+    hits: State<HitCounter>,
+    maintenance: State<MaintenanceMode>,
+) -> Maintained<RedirectResult> {
+    unless_maintenance(&maintenance, || {
+        find_redirect(&key, None, &lang, &redirs, Some(hits.inner()))
+    })
 }
 
-/// Redirect a subdomain to its matching page via 302 redirect, preserving path.
-/// If `key` is not in the redirect map return 404.
+/// Redirect a subdomain to its matching page via 302 redirect (301 for permanent redirects),
+/// preserving path, minus the redirect's `strip_prefix` if it has one.
+/// If `key` is not in the redirect map return a 404 page suggesting similar redirects.
 ///
 /// Example: ex.rustref.com/primitives.html =>
 ///     https://doc.rust-lang.org/stable/rust-by-example/primitives.html
-#[get("/redirect/<key>/<path>")]
-fn redirect(key: String, path: &RawStr, redirs: State<RedirectMap>) -> Option<Redirect> {
-    let map = &redirs.read().expect("could not lock rlock").map;
-    match map.get(&key) {
-        Some(url) => Some(Redirect::found(&format!("{}/{}", url, path))),
-        None => None,
+#[get("/redirect/<key>/<path..>")]
+fn redirect(
+    key: String,
+    path: Segments,
+    lang: AcceptLanguage,
+    redirs: State<RedirectMap>,
+    hits: State<HitCounter>,
+    maintenance: State<MaintenanceMode>,
+) -> Maintained<RedirectResult> {
+    unless_maintenance(&maintenance, || {
+        find_redirect(&key, Some(&join_segments(path)), &lang, &redirs, Some(hits.inner()))
+    })
+}
+
+/// Redirect a subdomain straight from the request's `Host` header, for when requests aren't
+/// rewritten to `/redirect/<key>` in front of the server. Requests to other hosts are
+/// forwarded to `index`.
+///
+/// Example: cook.rustref.com => https://doc.rust-lang.org/cargo/
+#[get("/", rank = 0)]
+fn host_redirect_bare(
+    subdomain: Subdomain,
+    lang: AcceptLanguage,
+    redirs: State<RedirectMap>,
+    hits: State<HitCounter>,
+    maintenance: State<MaintenanceMode>,
+) -> Maintained<RedirectResult> {
+    unless_maintenance(&maintenance, || {
+        find_redirect(&subdomain.0, None, &lang, &redirs, Some(hits.inner()))
+    })
+}
+
+/// `redirect` for the subdomain in the `Host` header. Ranked after the other routes, so
+/// paths like `/redirect/<key>` or `/api/redirects` still work on any host, and before
+/// `prefix_redirect` and `files`, which serve the rest of the paths for other hosts.
+#[get("/<path..>", rank = 1)]
+fn host_redirect(
+    subdomain: Subdomain,
+    path: Segments,
+    lang: AcceptLanguage,
+    redirs: State<RedirectMap>,
+    hits: State<HitCounter>,
+    maintenance: State<MaintenanceMode>,
+) -> Maintained<RedirectResult> {
+    unless_maintenance(&maintenance, || {
+        let path = join_segments(path);
+        find_redirect(&subdomain.0, Some(&path), &lang, &redirs, Some(hits.inner()))
+    })
+}
+
+/// `HEAD` version of `redirect_bare`: same status and headers, no body.
+/// `HEAD` requests are mostly link checkers, so they aren't counted as hits.
+#[head("/redirect/<key>")]
+fn redirect_bare_head(
+    key: String,
+    lang: AcceptLanguage,
+    redirs: State<RedirectMap>,
+    maintenance: State<MaintenanceMode>,
+) -> Maintained<RedirectResult> {
+    unless_maintenance(&maintenance, || find_redirect(&key, None, &lang, &redirs, None))
+}
+
+/// `HEAD` version of `redirect`: same status and headers, no body, not counted as a hit
+#[head("/redirect/<key>/<path..>")]
+fn redirect_head(
+    key: String,
+    path: Segments,
+    lang: AcceptLanguage,
+    redirs: State<RedirectMap>,
+    maintenance: State<MaintenanceMode>,
+) -> Maintained<RedirectResult> {
+    unless_maintenance(&maintenance, || {
+        find_redirect(&key, Some(&join_segments(path)), &lang, &redirs, None)
+    })
+}
+
+/// The raw (still percent-encoded) path segments joined back together
+fn join_segments(segments: Segments) -> String {
+    segments.collect::<Vec<&str>>().join("/")
+}
+
+/// Only `GET` is supported on the index and most redirect routes, anything else gets a 405
+#[post("/")]
+fn index_post() -> MethodNotAllowed {
+    MethodNotAllowed("GET")
+}
+
+#[put("/")]
+fn index_put() -> MethodNotAllowed {
+    MethodNotAllowed("GET")
+}
+
+#[delete("/")]
+fn index_delete() -> MethodNotAllowed {
+    MethodNotAllowed("GET")
+}
+
+#[patch("/")]
+fn index_patch() -> MethodNotAllowed {
+    MethodNotAllowed("GET")
+}
+
+/// A method-preserving redirect, or 405 if the redirect doesn't allow other methods than GET
+type PreservedRedirect = std::result::Result<CachedRedirect, MethodNotAllowed>;
+
+/// Redirect a non-`GET` request with a 307/308 if the redirect for the first segment of
+/// `path` has `preserve_method` set, so the client keeps the method and body.
+/// Other redirects only support `GET`.
+fn preserve_method_redirect(
+    path: Segments,
+    lang: &AcceptLanguage,
+    redirs: &RedirectMap,
+) -> PreservedRedirect {
+    let path = join_segments(path);
+    let mut parts = path.splitn(2, '/');
+    let key = normalize_key(parts.next().unwrap_or(""));
+    let rest = parts.next();
+    let preserves = read_redirects(redirs)
+        .map
+        .get(key)
+        .map_or(false, |target| target.preserve_method);
+    if !preserves {
+        return Err(MethodNotAllowed("GET"));
     }
+    find_redirect(key, rest, lang, redirs, None).map_err(|_| MethodNotAllowed("GET"))
+}
+
+#[post("/redirect/<path..>")]
+fn redirect_post(
+    path: Segments,
+    lang: AcceptLanguage,
+    redirs: State<RedirectMap>,
+    maintenance: State<MaintenanceMode>,
+) -> Maintained<PreservedRedirect> {
+    unless_maintenance(&maintenance, || preserve_method_redirect(path, &lang, &redirs))
+}
+
+#[put("/redirect/<path..>")]
+fn redirect_put(
+    path: Segments,
+    lang: AcceptLanguage,
+    redirs: State<RedirectMap>,
+    maintenance: State<MaintenanceMode>,
+) -> Maintained<PreservedRedirect> {
+    unless_maintenance(&maintenance, || preserve_method_redirect(path, &lang, &redirs))
 }
 
-#[get("/<file..>", rank = 2)]
-fn files(file: PathBuf) -> Option<NamedFile> {
-    NamedFile::open(Path::new("static/").join(file)).ok()
+#[delete("/redirect/<path..>")]
+fn redirect_delete(
+    path: Segments,
+    lang: AcceptLanguage,
+    redirs: State<RedirectMap>,
+    maintenance: State<MaintenanceMode>,
+) -> Maintained<PreservedRedirect> {
+    unless_maintenance(&maintenance, || preserve_method_redirect(path, &lang, &redirs))
+}
+
+#[patch("/redirect/<path..>")]
+fn redirect_patch(
+    path: Segments,
+    lang: AcceptLanguage,
+    redirs: State<RedirectMap>,
+    maintenance: State<MaintenanceMode>,
+) -> Maintained<PreservedRedirect> {
+    unless_maintenance(&maintenance, || preserve_method_redirect(path, &lang, &redirs))
 }
 
-fn rocket() -> rocket::Rocket {
-    let redirects = redirect_utils::redirects_from_file("redirects.toml")
-        .expect("error reading redirects from file");
+/// Directory the `files` route serves static assets from
+struct StaticRoot(PathBuf);
 
-    let redirect_data = RedirectData {
-        map: redirects,
-        commit_hash: ".toml".into(),
-        commit_url: "".into(),
+/// Redirect by the longest prefix of the path in the config's `redirect_prefix_map`.
+/// Ranked before `files`, so a prefix takes over any static files under it.
+#[get("/<_path..>", rank = 2)]
+fn prefix_redirect(
+    _path: Segments,
+    target: PrefixRedirect,
+    maintenance: State<MaintenanceMode>,
+) -> Maintained<CachedRedirect> {
+    unless_maintenance(&maintenance, || CachedRedirect::found(&target.0).robots("noindex"))
+}
+
+#[get("/<file..>", rank = 3)]
+fn files(file: PathBuf, root: State<StaticRoot>) -> Option<NamedFile> {
+    NamedFile::open(static_file_path(&root.0, &file)?).ok()
+}
+
+/// Resolve `file` inside `root`, or `None` if it doesn't exist or resolves to somewhere
+/// outside of `root` (through `..` or a symlink). Rocket already resolves `..` segments,
+/// this keeps `files` safe if the routing ever changes.
+fn static_file_path(root: &Path, file: &Path) -> Option<PathBuf> {
+    let root = root.canonicalize().ok()?;
+    let path = root.join(file).canonicalize().ok()?;
+    if path.starts_with(&root) {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+/// Read the Cloudflare credentials from the `cloudflare_key` and `cloudflare_email` env vars,
+/// or the files named by `cloudflare_key_file` and `cloudflare_email_file`.
+/// Returns `None` if either is missing so the redirect server can run without credentials.
+fn cloudflare_from_env() -> Option<CloudflareCredentials> {
+    Some(CloudflareCredentials {
+        key: secret_var("cloudflare_key")?,
+        email: secret_var("cloudflare_email")?,
+        base_url: CLOUDFLARE_API_URL.to_string(),
+    })
+}
+
+/// Load the redirects from `REDIRECTS_FILE`, which can also be a directory of TOML files,
+/// or from the `store_backend` store. Returns the store changes are saved to along with them.
+fn load_redirect_data() -> (RedirectData, RedirectStorage) {
+    let path = Path::new(&*REDIRECTS_FILE);
+    let allow_empty = redirect_utils::allow_empty_config_from_env();
+    if path.is_dir() {
+        let redirects = redirect_utils::redirects_from_dir(path)
+            .and_then(|redirects| {
+                redirect_utils::check_not_empty(&redirects, allow_empty)?;
+                Ok(redirects)
+            })
+            .unwrap_or_else(|e| panic!("error reading redirects from {}: {}", path.display(), e));
+        // there's no single file to write updates back to
+        println!("Warning: redirects loaded from a directory are not written back on updates");
+        return (RedirectData::new(&redirects, ".toml", ""), None);
+    }
+    let store = store::store_from_env(path);
+    let (data, store) = load_store_data(store, path, allow_empty);
+    (data, Some(Mutex::new(store)))
+}
+
+/// Load the redirects from `store`. A store other than the redirects file at `path` is
+/// filled from the file first if it's empty, so switching to it keeps the redirects.
+/// The `[meta]` table is always read from the file.
+fn load_store_data(
+    mut store: Box<RedirectStore + Send>,
+    path: &Path,
+    allow_empty: bool,
+) -> (RedirectData, Box<RedirectStore + Send>) {
+    let (config, config_files) = if path.is_file() {
+        let (config, files) = redirect_utils::config_and_paths_from_file(path)
+            .unwrap_or_else(|e| panic!("error reading redirects from {}: {}", path.display(), e));
+        (Some(config), files)
+    } else {
+        (None, Vec::new())
     };
+    let redirects = store
+        .load()
+        .and_then(|stored| match config {
+            Some(ref config) if stored.is_empty() && !config.redirect.is_empty() => {
+                println!("store is empty, filling it from {}", path.display());
+                store.replace_all(&config.redirect, config.meta.as_ref())?;
+                Ok(config.redirect.clone())
+            }
+            _ => Ok(stored),
+        })
+        .and_then(|redirects| {
+            redirect_utils::check_not_empty(&redirects, allow_empty)?;
+            Ok(redirects)
+        })
+        .unwrap_or_else(|e| panic!("error reading redirects: {}", e));
+
+    let mut redirect_data = RedirectData::new(&redirects, ".toml", "");
+    redirect_data.meta = config.and_then(|config| config.meta);
+    redirect_data.config_files = config_files;
+    (redirect_data, store)
+}
+
+/// Build the DNS provider named by the `dns_provider` env var: `cloudflare` (the default)
+/// or `route53`, which needs the `route53` feature. `None` disables DNS updates.
+fn dns_from_env() -> Option<Box<DnsProvider + Send>> {
+    let check = DnsCheck::from_env();
+    let provider = dotenv::var("dns_provider").unwrap_or_else(|_| "cloudflare".to_string());
+    let dns: Box<DnsProvider + Send> = match provider.as_str() {
+        "cloudflare" => {
+            let credentials = match cloudflare_from_env() {
+                Some(credentials) => credentials,
+                None => {
+                    println!("Warning: Cloudflare credentials not found, DNS updates are disabled");
+                    return None;
+                }
+            };
+            Box::new(
+                CloudflareDns::from_env(credentials).expect("failed to create cloudflare client"),
+            )
+        }
+        #[cfg(feature = "route53")]
+        "route53" => Box::new(route53::Route53Provider::from_env()),
+        other => {
+            eprintln!(
+                "error: unknown dns_provider `{}`, expected `cloudflare` or `route53` \
+                 (with the `route53` feature)",
+                other
+            );
+            process::exit(1);
+        }
+    };
+    if let Err(e) = redirect_utils::check_dns_access(&*dns, check) {
+        eprintln!("error: DNS zone check failed, check the credentials and records: {}", e);
+        process::exit(1);
+    }
+    Some(dns)
+}
 
-    let cf_api_key: String = dotenv::var("cloudflare_key").expect("no cloudflare key found!");
-    let cf_email: String = dotenv::var("cloudflare_email").expect("no cloudflare email found!");
-    let cf_api = Cloudflare::new(
-        &cf_api_key,
-        &cf_email,
-        "https://api.cloudflare.com/client/v4/",
-    ).expect("failed to create cloudflare client");
-
-    rocket::ignite()
-        .mount("/", routes![index, files, redirect, redirect_bare, webhook])
-        .manage(RwLock::new(redirect_data))
-        .manage(Mutex::new(cf_api))
+fn rocket(
+    redirs: RedirectMap,
+    statuses: StatusMap,
+    shutdown: Arc<Shutdown>,
+    store: RedirectStorage,
+) -> rocket::Rocket {
+    let dns = dns_from_env();
+
+    let admin_token = dotenv::var("admin_token").ok();
+    if admin_token.is_none() {
+        println!("Warning: no admin token set, admin routes are disabled");
+    }
+
+    let static_root = dotenv::var("static_root").unwrap_or_else(|_| DEFAULT_STATIC_ROOT.into());
+
+    // without `bind_address` or `port` Rocket reads Rocket.toml and the `ROCKET_` env vars
+    let address = dotenv::var("bind_address").ok();
+    let port = dotenv::var("port").ok();
+    let config = if address.is_some() || port.is_some() {
+        let config = rocket_config().and_then(|base| server_config(&base, address, port));
+        Some(config.unwrap_or_else(|e| {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        }))
+    } else {
+        None
+    };
+
+    let options = ServerOptions {
+        dns,
+        store,
+        admin_token,
+        static_root: PathBuf::from(static_root),
+        config,
+    };
+    rocket_with(redirs, statuses, shutdown, options)
+}
+
+/// The config Rocket reads from Rocket.toml and the `ROCKET_` env vars. Without a
+/// Rocket.toml only `ROCKET_ENV` is read, picking the environment whose defaults are used.
+fn rocket_config() -> std::result::Result<Config, String> {
+    match RocketConfig::read() {
+        Ok(config) => Ok(config.active().clone()),
+        Err(ConfigError::NotFound) => {
+            Config::active().map_err(|e| format!("invalid Rocket config: {:?}", e))
+        }
+        Err(e) => Err(format!("can't read Rocket.toml: {:?}", e)),
+    }
+}
+
+/// `base` with the address and port the server binds to replaced by `address` and `port`,
+/// if they are given
+fn server_config(
+    base: &Config,
+    address: Option<String>,
+    port: Option<String>,
+) -> std::result::Result<Config, String> {
+    let mut config = base.clone();
+    if let Some(address) = address {
+        config
+            .set_address(address.as_str())
+            .map_err(|e| format!("invalid bind_address {}: {:?}", address, e))?;
+    }
+    if let Some(port) = port {
+        let port = port.parse().map_err(|_| format!("invalid port {}", port))?;
+        config.set_port(port);
+    }
+    Ok(config)
+}
+
+/// Where static files are served from when the `static_root` env var isn't set
+const DEFAULT_STATIC_ROOT: &str = "static/";
+
+/// What `rocket_with` builds the instance with besides the state shared with other threads.
+/// The default has no DNS provider, store or admin token.
+struct ServerOptions {
+    dns: Option<Box<DnsProvider + Send>>,
+    store: RedirectStorage,
+    admin_token: Option<String>,
+    static_root: PathBuf,
+    /// Used instead of the config Rocket reads from Rocket.toml and the environment
+    config: Option<Config>,
+}
+
+impl Default for ServerOptions {
+    fn default() -> ServerOptions {
+        ServerOptions {
+            dns: None,
+            store: None,
+            admin_token: None,
+            static_root: PathBuf::from(DEFAULT_STATIC_ROOT),
+            config: None,
+        }
+    }
+}
+
+/// Build the rocket instance serving `redirs`
+fn rocket_with(
+    redirs: RedirectMap,
+    statuses: StatusMap,
+    shutdown: Arc<Shutdown>,
+    options: ServerOptions,
+) -> rocket::Rocket {
+    let ServerOptions {
+        dns,
+        store,
+        admin_token,
+        static_root,
+        config,
+    } = options;
+    let rocket = match config {
+        Some(config) => rocket::custom(config, true),
+        None => rocket::ignite(),
+    };
+    rocket
+        .mount(
+            "/",
+            routes![
+                index,
+                files,
+                host_redirect,
+                host_redirect_bare,
+                prefix_redirect,
+                robots_txt,
+                sitemap,
+                redirect,
+                redirect_bare,
+                redirect_index,
+                redirect_head,
+                redirect_bare_head,
+                webhook,
+                metrics::metrics
+            ],
+        )
+        .mount(
+            "/",
+            routes![
+                index_post,
+                index_put,
+                index_delete,
+                index_patch,
+                redirect_post,
+                redirect_put,
+                redirect_delete,
+                redirect_patch
+            ],
+        )
+        .mount(
+            "/api",
+            routes![
+                api::redirects,
+                api::redirects_page,
+                api::redirects_txt,
+                api::status,
+                api::validate,
+                api::verify,
+                api::stats_top,
+                api::stats_top_n,
+                api::stats_misses,
+                api::schema,
+            ],
+        )
+        .mount(
+            "/admin",
+            routes![
+                admin::add_redirect,
+                admin::delete_redirect,
+                admin::config,
+                admin::maintenance,
+                admin::warmup,
+                admin::rollback,
+                admin::rollback_save,
+            ],
+        )
+        .manage(redirs)
+        .manage(statuses)
+        .manage(shutdown)
+        .manage(dns.map(Mutex::new))
+        .manage(store)
+        .manage(AdminToken(admin_token))
+        .manage(SeenDeliveries::new(
+            github_event::DEFAULT_SEEN_CAPACITY,
+            Duration::from_secs(github_event::DEFAULT_SEEN_TTL_SECONDS),
+        ))
+        .manage(WebhookCooldown::from_env())
+        .manage(StaticRoot(static_root))
+        .manage(LatencyHistogram::from_env())
+        .manage(CloudflareMetrics::new())
+        .manage(HitCounter::from_env())
+        .manage(MaintenanceMode::new())
+        .manage(ConfigHistory::from_env())
         .attach(Template::fairing())
+        .attach(RequestIdFairing)
+        .attach(LatencyFairing)
+        .attach(SecurityHeaders::from_env())
+        .attach(Compression)
+}
+
+/// Validate a local redirects file and print a report.
+/// Returns the exit code for the process: 0 if the file is valid, otherwise 1.
+fn validate_config(path: &str) -> i32 {
+    println!("validating {}...", path);
+    match redirect_utils::validate_file(path) {
+        Ok(count) => {
+            println!("PASS: {} redirects are valid", count);
+            0
+        }
+        Err(e @ Error::RedirectErrors(_)) => {
+            eprintln!("FAIL: {}", e);
+            1
+        }
+        Err(e) => {
+            eprintln!("FAIL: could not load {}: {}", path, e);
+            if let Error::Toml(ref toml_err) = e {
+                let snippet = fs::read_to_string(path)
+                    .ok()
+                    .and_then(|source| redirect_utils::toml_error_snippet(&source, toml_err));
+                if let Some(snippet) = snippet {
+                    eprintln!("{}", snippet);
+                }
+            }
+            1
+        }
+    }
+}
+
+/// Print the CNAME records the redirects in `path` need, as a zone file, without contacting
+/// the DNS provider. Returns the exit code for the process.
+fn export_dns(path: &str) -> i32 {
+    let path = Path::new(path);
+    let redirects = if path.is_dir() {
+        redirect_utils::redirects_from_dir(path)
+    } else {
+        redirect_utils::redirects_from_file(path)
+    };
+    match redirects {
+        Ok(redirects) => {
+            print!("{}", redirect_utils::zonefile(&redirects));
+            0
+        }
+        Err(e) => {
+            eprintln!("error: could not load {}: {}", path.display(), e);
+            1
+        }
+    }
+}
+
+/// Check every target of the loaded redirects and prefixes before launching, so the server
+/// doesn't start with known-broken redirects. Returns the exit code to fail with if any
+/// check failed.
+fn check_all_urls(data: &RedirectData) -> Option<i32> {
+    let redirects: Vec<SiteRedirect> = data.sorted_redirects().into_iter().cloned().collect();
+    println!("checking all {} redirect targets...", redirects.len());
+    match redirect_utils::validate_loaded(&redirects, data.meta.as_ref()) {
+        Ok(_) => None,
+        Err(Error::RedirectErrors(errors)) => {
+            eprintln!("error: {} problem(s) found, refusing to start", errors.len());
+            for e in errors {
+                eprintln!("  {}", e);
+            }
+            Some(1)
+        }
+        Err(e) => {
+            eprintln!("error: could not check the redirects, refusing to start: {}", e);
+            Some(1)
+        }
+    }
 }
 
 fn main() {
-    rocket().launch();
+    let mut args = env::args().skip(1);
+    let first_arg = args.next();
+    // the network checks are slow, so they only run at startup when asked for
+    let check_urls = first_arg.as_ref().map_or(false, |arg| arg == "--check-all-urls")
+        || dotenv::var("check_all_urls").map(|s| s == "true").unwrap_or(false);
+    match first_arg {
+        Some(ref flag) if flag == "--validate" => {
+            let path = args.next().unwrap_or_else(|| "redirects.toml".to_string());
+            process::exit(validate_config(&path));
+        }
+        Some(ref flag) if flag == "--export-dns" => {
+            let path = args.next().unwrap_or_else(|| REDIRECTS_FILE.to_string());
+            process::exit(export_dns(&path));
+        }
+        _ => {
+            let (redirect_data, store) = load_redirect_data();
+            if check_urls {
+                if let Some(code) = check_all_urls(&redirect_data) {
+                    process::exit(code);
+                }
+            }
+            let redirs = Arc::new(RwLock::new(redirect_data));
+            let statuses = StatusMap::default();
+            // periodically re-check the redirect URLs without blocking request serving
+            match redirect_utils::recheck_interval_from_env() {
+                Some(interval) => {
+                    redirect_utils::spawn_rechecker(
+                        interval,
+                        Arc::clone(&redirs),
+                        Arc::clone(&statuses),
+                    );
+                }
+                None => println!("Warning: URL re-checking is disabled"),
+            }
+            // let a webhook that is updating the redirects finish before exiting
+            let shutdown = Arc::new(Shutdown::new());
+            shutdown::exit_on_signal(Arc::clone(&shutdown), shutdown::timeout_from_env());
+            rocket(redirs, statuses, shutdown, store).launch();
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     extern crate serde_json;
     use super::*;
+    use rocket::http::{Accept, ContentType, Header, Status};
+    use rocket::local::Client;
+    use test_utils::{http_response, mock_server, MockStore};
+
+    const ADMIN_TOKEN: &str = "test-admin-token";
+
+    /// Client for a rocket instance serving the redirects in `toml_str`
+    fn client_with(toml_str: &str) -> Client {
+        client_with_store(toml_str, None)
+    }
+
+    /// Like `client_with`, with changes to the redirects saved to `store`
+    fn client_with_store(toml_str: &str, store: Option<MockStore>) -> Client {
+        let config = redirect_utils::config_from_str(toml_str).expect("valid redirects");
+        let mut data = RedirectData::new(&config.redirect, "", "");
+        data.meta = config.meta;
+        let store = store.map(|store| Mutex::new(Box::new(store) as Box<RedirectStore + Send>));
+        let rocket = rocket_with(
+            Arc::new(RwLock::new(data)),
+            StatusMap::default(),
+            Arc::new(Shutdown::new()),
+            ServerOptions {
+                store,
+                admin_token: Some(ADMIN_TOKEN.to_string()),
+                ..ServerOptions::default()
+            },
+        );
+        Client::new(rocket).expect("valid rocket instance")
+    }
+
+    fn admin_auth() -> Header<'static> {
+        Header::new("Authorization", format!("Bearer {}", ADMIN_TOKEN))
+    }
 
     #[test]
     fn parse_readme_webhook() {
@@ -171,4 +1253,1585 @@ mod tests {
         assert!(push.refs == "refs/heads/master");
         assert!(push.file_modified("redirects.toml"));
     }
+
+    #[test]
+    fn webhook_for_include_only_push() {
+        let mut payload: serde_json::Value =
+            serde_json::from_str(include_str!("../test_data/readme_updated.json")).unwrap();
+        payload["commits"][0]["modified"] = vec!["redirects/tools.toml"].into();
+        let push: PushEvent = serde_json::from_value(payload).unwrap();
+
+        let mut data = RedirectData::new(&[], "", "");
+        assert!(!config_modified(&push, &data));
+        // once the served config includes the file, changing only it reloads the config
+        data.config_files = vec!["redirects.toml".into(), "redirects/tools.toml".into()];
+        assert!(config_modified(&push, &data));
+    }
+
+    #[test]
+    fn read_poisoned_redirect_map() {
+        use std::thread;
+
+        let redirects = redirect_utils::redirects_from_str(
+            r#"
+            [[redirect]]
+            short = "std"
+            url = "https://doc.rust-lang.org/stable/std"
+        "#,
+        ).unwrap();
+        let redirs = Arc::new(RwLock::new(RedirectData::new(&redirects, "", "")));
+
+        // poison the lock by panicking while holding the write lock
+        let poisoner = Arc::clone(&redirs);
+        let _ = thread::spawn(move || {
+            let _guard = poisoner.write().unwrap();
+            panic!("poison the lock");
+        }).join();
+        assert!(redirs.is_poisoned());
+
+        let data = read_redirects(&redirs);
+        assert_eq!(data.map["std"].url, "https://doc.rust-lang.org/stable/std");
+    }
+
+    #[test]
+    fn wrong_method_not_allowed() {
+        let client = client_with(include_str!("../redirects.toml"));
+        for path in &["/", "/redirect/std", "/redirect/ex/primitives.html"] {
+            let response = client.post(*path).dispatch();
+            assert_eq!(response.status(), Status::MethodNotAllowed);
+            assert_eq!(response.headers().get_one("Allow"), Some("GET"));
+
+            let response = client.delete(*path).dispatch();
+            assert_eq!(response.status(), Status::MethodNotAllowed);
+        }
+    }
+
+    #[test]
+    fn cache_control_only_for_permanent() {
+        let client = client_with(
+            r#"
+            [[redirect]]
+            short = "std"
+            url = "https://doc.rust-lang.org/stable/std"
+            permanent = true
+            cache_seconds = 3600
+
+            [[redirect]]
+            short = "nightly"
+            url = "https://doc.rust-lang.org/nightly/std"
+        "#,
+        );
+
+        let response = client.get("/redirect/std").dispatch();
+        assert_eq!(response.status(), Status::MovedPermanently);
+        assert_eq!(
+            response.headers().get_one("Location"),
+            Some("https://doc.rust-lang.org/stable/std")
+        );
+        assert_eq!(response.headers().get_one("Cache-Control"), Some("max-age=3600"));
+
+        let response = client.get("/redirect/nightly/index.html").dispatch();
+        assert_eq!(response.status(), Status::Found);
+        assert_eq!(
+            response.headers().get_one("Location"),
+            Some("https://doc.rust-lang.org/nightly/std/index.html")
+        );
+        assert!(response.headers().get_one("Cache-Control").is_none());
+    }
+
+    #[test]
+    fn index_footer_shows_meta() {
+        let client = client_with(
+            r#"
+            [meta]
+            version = 1
+            maintainers = ["nocduro", "someone"]
+
+            [[redirect]]
+            short = "book"
+            url = "https://doc.rust-lang.org/book"
+        "#,
+        );
+        let body = client.get("/").dispatch().body_string().unwrap();
+        assert!(body.contains("config version 1"));
+        assert!(body.contains("nocduro, someone"));
+
+        let client = client_with(include_str!("../test_data/duplicate_redirects.toml"));
+        let body = client.get("/").dispatch().body_string().unwrap();
+        assert!(!body.contains("config version"));
+    }
+
+    #[test]
+    fn index_sorted_alphabetically() {
+        let toml_str = r#"
+            [[redirect]]
+            short = "std"
+            url = "https://doc.rust-lang.org/std"
+            category = "docs"
+
+            [[redirect]]
+            short = "book"
+            url = "https://doc.rust-lang.org/book"
+            category = "books"
+
+            [[redirect]]
+            short = "alloc"
+            url = "https://doc.rust-lang.org/alloc"
+            category = "docs"
+
+            [[redirect]]
+            short = "core"
+            url = "https://doc.rust-lang.org/core"
+            category = "docs"
+        "#;
+        let config = redirect_utils::config_from_str(toml_str).unwrap();
+        let data = RedirectData::new(&config.redirect, "", "");
+        let context = serde_json::to_value(&IndexContext::new(&data)).unwrap();
+        let categories: Vec<&String> = context["categories"].as_object().unwrap().keys().collect();
+        assert_eq!(categories, vec!["books", "docs"]);
+        let docs: Vec<&str> = context["categories"]["docs"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|redirect| redirect["short"].as_str().unwrap())
+            .collect();
+        assert_eq!(docs, vec!["alloc", "core", "std"]);
+
+        let client = client_with(toml_str);
+        let body = client.get("/").dispatch().body_string().unwrap();
+        let position = |text: &str| body.find(text).expect(text);
+        assert!(position("<h3>books</h3>") < position("<h3>docs</h3>"));
+        assert!(position("book.rustref.com") < position("alloc.rustref.com"));
+        assert!(position("alloc.rustref.com") < position("core.rustref.com"));
+        assert!(position("core.rustref.com") < position("std.rustref.com"));
+    }
+
+    #[test]
+    fn compressed_index() {
+        use flate2::read::GzDecoder;
+        use std::io::Read;
+
+        let client = client_with(include_str!("../redirects.toml"));
+        let mut response = client
+            .get("/")
+            .header(Header::new("Accept-Encoding", "gzip, deflate"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one("Content-Encoding"), Some("gzip"));
+        assert_eq!(response.headers().get_one("Vary"), Some("Accept, Accept-Encoding"));
+        let compressed = response.body_bytes().unwrap();
+        let mut body = String::new();
+        GzDecoder::new(&compressed[..]).read_to_string(&mut body).unwrap();
+        assert!(body.contains("Current redirects"));
+
+        let response = client
+            .get("/api/redirects")
+            .header(Header::new("Accept-Encoding", "deflate"))
+            .dispatch();
+        assert_eq!(response.headers().get_one("Content-Encoding"), Some("deflate"));
+
+        // not without asking for it, and never for redirects
+        let response = client.get("/").dispatch();
+        assert_eq!(response.headers().get_one("Content-Encoding"), None);
+        let response = client
+            .get("/redirect/std")
+            .header(Header::new("Accept-Encoding", "gzip"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Found);
+        assert_eq!(response.headers().get_one("Content-Encoding"), None);
+    }
+
+    #[test]
+    fn disabled_redirect_listed_but_not_served() {
+        let client = client_with(
+            r#"
+            [[redirect]]
+            short = "std"
+            url = "https://doc.rust-lang.org/std"
+
+            [[redirect]]
+            short = "down"
+            url = "https://example.com/down"
+            enabled = false
+        "#,
+        );
+        assert_eq!(client.get("/redirect/std").dispatch().status(), Status::Found);
+        assert_eq!(client.get("/redirect/down").dispatch().status(), Status::NotFound);
+        let body = client.get("/").dispatch().body_string().unwrap();
+        assert!(body.contains(r#"<span class="disabled">down.rustref.com → "#));
+        assert!(body.contains("(disabled)"));
+    }
+
+    #[test]
+    fn secret_from_file() {
+        let path = env::temp_dir().join("rustref_secret_from_file");
+        fs::write(&path, "file-secret\n").unwrap();
+        let file = path.to_str().unwrap();
+        let env_value = Some("env-secret".to_string());
+        // the file wins, without the trailing newline most editors add
+        assert_eq!(
+            read_secret(Some(file), env_value.clone()).unwrap(),
+            Some("file-secret".to_string())
+        );
+        assert_eq!(read_secret(None, env_value).unwrap(), Some("env-secret".to_string()));
+        assert_eq!(read_secret(None, None).unwrap(), None);
+        fs::remove_file(&path).unwrap();
+        // a file that can't be read is an error, not a fallback to the env var
+        assert!(read_secret(Some(file), Some("env-secret".to_string())).is_err());
+    }
+
+    #[test]
+    fn index_content_negotiation() {
+        let client = client_with(include_str!("../redirects.toml"));
+        let mut response = client.get("/").header(Accept::JSON).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::JSON));
+        assert_eq!(response.headers().get_one("Vary"), Some("Accept"));
+        let redirects: serde_json::Value =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+        let shorts: Vec<&str> = redirects
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|r| r["short"].as_str().unwrap())
+            .collect();
+        assert!(shorts.contains(&"std"));
+        let mut sorted = shorts.clone();
+        sorted.sort();
+        assert_eq!(shorts, sorted);
+
+        let accepts = vec![Some(Accept::HTML), Some(Accept::Any), None];
+        for accept in accepts {
+            let mut request = client.get("/");
+            if let Some(accept) = accept {
+                request = request.header(accept);
+            }
+            let mut response = request.dispatch();
+            assert_eq!(response.content_type(), Some(ContentType::HTML));
+            assert!(response.body_string().unwrap().contains("Current redirects"));
+        }
+    }
+
+    #[test]
+    fn static_files_stay_in_root() {
+        let root = Path::new("static/");
+        assert!(static_file_path(root, Path::new("favicon.ico")).is_some());
+        assert!(static_file_path(root, Path::new("../Cargo.toml")).is_none());
+        assert!(static_file_path(root, Path::new("../static/favicon.ico")).is_some());
+        assert!(static_file_path(root, Path::new("missing.txt")).is_none());
+
+        let client = client_with(include_str!("../redirects.toml"));
+        let response = client.get("/favicon.ico").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        for path in &["/../Cargo.toml", "/%2e%2e/Cargo.toml", "/static/../Cargo.toml"] {
+            let response = client.get(*path).dispatch();
+            assert_ne!(response.status(), Status::Ok, "{} was served", path);
+        }
+    }
+
+    #[test]
+    fn custom_static_root() {
+        let root = env::temp_dir().join("rustref_static_root");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("custom.txt"), "custom asset").unwrap();
+
+        let redirects = redirect_utils::redirects_from_str(include_str!("../redirects.toml"));
+        let data = RedirectData::new(&redirects.unwrap(), "", "");
+        let rocket = rocket_with(
+            Arc::new(RwLock::new(data)),
+            StatusMap::default(),
+            Arc::new(Shutdown::new()),
+            ServerOptions {
+                static_root: root.clone(),
+                ..ServerOptions::default()
+            },
+        );
+        let client = Client::new(rocket).expect("valid rocket instance");
+
+        let mut response = client.get("/custom.txt").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.body_string(), Some("custom asset".to_string()));
+        // the default root isn't used anymore
+        assert_eq!(client.get("/favicon.ico").dispatch().status(), Status::NotFound);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn robots_and_sitemap() {
+        let client = client_with(include_str!("../redirects.toml"));
+        let mut response = client.get("/robots.txt").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::Plain));
+        let robots = response.body_string().unwrap();
+        assert!(robots.contains("User-agent: *\nDisallow: /redirect/\n"));
+        assert!(robots.contains("Sitemap: https://rustref.com/sitemap.xml"));
+
+        let mut response = client.get("/sitemap.xml").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::XML));
+        let sitemap = response.body_string().unwrap();
+        assert!(sitemap.starts_with("<?xml"));
+        assert!(sitemap.contains("<loc>https://std.rustref.com/</loc>"));
+        assert!(!sitemap.contains("<loc>https://new.rustref.com/</loc>"));
+
+        // follows the current redirects
+        let response = client
+            .post("/admin/redirect")
+            .header(admin_auth())
+            .header(ContentType::JSON)
+            .body(r#"{"short": "new", "url": "https://doc.rust-lang.org/new"}"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let sitemap = client.get("/sitemap.xml").dispatch().body_string().unwrap();
+        assert!(sitemap.contains("<loc>https://new.rustref.com/</loc>"));
+    }
+
+    #[test]
+    fn security_headers_on_pages() {
+        let client = client_with(include_str!("../redirects.toml"));
+        let response = client.get("/").dispatch();
+        let headers = response.headers();
+        assert_eq!(headers.get_one("X-Content-Type-Options"), Some("nosniff"));
+        assert_eq!(headers.get_one("X-Frame-Options"), Some("DENY"));
+        assert_eq!(
+            headers.get_one("Content-Security-Policy"),
+            Some(security_headers::DEFAULT_CSP)
+        );
+
+        // static files aren't HTML, so they only get nosniff
+        let response = client.get("/robots.txt").dispatch();
+        assert_eq!(response.headers().get_one("X-Content-Type-Options"), Some("nosniff"));
+        assert!(response.headers().get_one("Content-Security-Policy").is_none());
+
+        let response = client.get("/redirect/std").dispatch();
+        assert!(response.headers().get_one("X-Content-Type-Options").is_none());
+        assert!(response.headers().get_one("Content-Security-Policy").is_none());
+    }
+
+    #[test]
+    fn unknown_key_suggests_similar() {
+        let client = client_with(include_str!("../redirects.toml"));
+        let mut response = client.get("/redirect/bok").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        let body = response.body_string().unwrap();
+        assert!(body.contains("Did you mean"));
+        assert!(body.contains("book.rustref.com"));
+
+        let mut response = client.get("/redirect/zzzzzzzz/page.html").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        assert!(!response.body_string().unwrap().contains("Did you mean"));
+    }
+
+    #[test]
+    fn www_prefix_ignored() {
+        let client = client_with(include_str!("../redirects.toml"));
+        let response = client.get("/redirect/www.std").dispatch();
+        assert_eq!(
+            response.headers().get_one("Location"),
+            Some("https://doc.rust-lang.org/stable/std")
+        );
+        let response = client.get("/redirect/www.std/vec/index.html").dispatch();
+        assert_eq!(
+            response.headers().get_one("Location"),
+            Some("https://doc.rust-lang.org/stable/std/vec/index.html")
+        );
+        let response = client.head("/redirect/www.std").dispatch();
+        assert_eq!(response.status(), Status::Found);
+    }
+
+    #[test]
+    fn host_header_redirect() {
+        let client = client_with(include_str!("../redirects.toml"));
+        let get = |host: &str, path: &str| {
+            client
+                .get(path.to_string())
+                .header(Header::new("Host", host.to_string()))
+                .dispatch()
+        };
+        for host in &["std.rustref.com", "www.std.rustref.com", "STD.rustref.com:8000"] {
+            let response = get(host, "/");
+            assert_eq!(response.status(), Status::Found, "{}", host);
+            assert_eq!(
+                response.headers().get_one("Location"),
+                Some("https://doc.rust-lang.org/stable/std")
+            );
+        }
+        let response = get("std.rustref.com", "/vec/index.html");
+        assert_eq!(
+            response.headers().get_one("Location"),
+            Some("https://doc.rust-lang.org/stable/std/vec/index.html")
+        );
+        assert_eq!(get("nope.rustref.com", "/").status(), Status::NotFound);
+
+        // the apex and other hosts get the index page and static files
+        for host in &["rustref.com", "www.rustref.com", "localhost:8000"] {
+            let response = get(host, "/");
+            assert_eq!(response.status(), Status::Ok, "{}", host);
+            assert!(response.headers().get_one("Location").is_none());
+        }
+        assert_eq!(get("rustref.com", "/favicon.ico").status(), Status::Ok);
+
+        // the path based routes still work on any host
+        let response = get("book.rustref.com", "/redirect/std");
+        assert_eq!(
+            response.headers().get_one("Location"),
+            Some("https://doc.rust-lang.org/stable/std")
+        );
+        assert_eq!(get("std.rustref.com", "/api/redirects").status(), Status::Ok);
+    }
+
+    #[test]
+    fn prefix_map_redirect() {
+        let client = client_with(
+            r#"
+            [meta]
+            version = 1
+
+            [meta.redirect_prefix_map]
+            "/book" = "https://doc.rust-lang.org/book"
+            "/cargo" = "https://doc.rust-lang.org/cargo"
+
+            [[redirect]]
+            short = "std"
+            url = "https://doc.rust-lang.org/stable/std"
+        "#,
+        );
+        let response = client.get("/book/ch01-00-getting-started.html").dispatch();
+        assert_eq!(response.status(), Status::Found);
+        assert_eq!(
+            response.headers().get_one("Location"),
+            Some("https://doc.rust-lang.org/book/ch01-00-getting-started.html")
+        );
+        let response = client.get("/cargo").dispatch();
+        assert_eq!(
+            response.headers().get_one("Location"),
+            Some("https://doc.rust-lang.org/cargo")
+        );
+
+        // other paths still get the static files, or a 404
+        assert_eq!(client.get("/favicon.ico").dispatch().status(), Status::Ok);
+        assert_eq!(client.get("/bookshelf").dispatch().status(), Status::NotFound);
+        // subdomains keep their own redirects
+        let response = client
+            .get("/book/index.html")
+            .header(Header::new("Host", "std.rustref.com"))
+            .dispatch();
+        assert_eq!(
+            response.headers().get_one("Location"),
+            Some("https://doc.rust-lang.org/stable/std/book/index.html")
+        );
+    }
+
+    #[test]
+    fn miss_redirects_to_search() {
+        let client = client_with(
+            r#"
+            [meta]
+            version = 1
+            fallback_search_url = "https://doc.rust-lang.org/std/?search={key}"
+
+            [[redirect]]
+            short = "std"
+            url = "https://doc.rust-lang.org/stable/std/"
+        "#,
+        );
+        let response = client.get("/redirect/unknownkey").dispatch();
+        assert_eq!(response.status(), Status::Found);
+        assert_eq!(
+            response.headers().get_one("Location"),
+            Some("https://doc.rust-lang.org/std/?search=unknownkey")
+        );
+        let response = client.get("/redirect/unknown%20key/some/path").dispatch();
+        assert_eq!(
+            response.headers().get_one("Location"),
+            Some("https://doc.rust-lang.org/std/?search=unknown%20key")
+        );
+        // known keys are unaffected
+        let response = client.get("/redirect/std").dispatch();
+        assert_eq!(
+            response.headers().get_one("Location"),
+            Some("https://doc.rust-lang.org/stable/std")
+        );
+    }
+
+    #[test]
+    fn strip_prefix_redirect() {
+        let client = client_with(
+            r#"
+            [[redirect]]
+            short = "ex"
+            url = "https://doc.rust-lang.org/stable/rust-by-example"
+            strip_prefix = "old"
+        "#,
+        );
+        let response = client.get("/redirect/ex/old/foo.html").dispatch();
+        assert_eq!(
+            response.headers().get_one("Location"),
+            Some("https://doc.rust-lang.org/stable/rust-by-example/foo.html")
+        );
+
+        let response = client.get("/redirect/ex/new/foo.html").dispatch();
+        assert_eq!(
+            response.headers().get_one("Location"),
+            Some("https://doc.rust-lang.org/stable/rust-by-example/new/foo.html")
+        );
+
+        let response = client.get("/redirect/ex/old").dispatch();
+        assert_eq!(
+            response.headers().get_one("Location"),
+            Some("https://doc.rust-lang.org/stable/rust-by-example")
+        );
+    }
+
+    #[test]
+    fn status_override() {
+        let client = client_with(
+            r#"
+            [[redirect]]
+            short = "s301"
+            url = "https://doc.rust-lang.org/std"
+            status = 301
+
+            [[redirect]]
+            short = "s302"
+            url = "https://doc.rust-lang.org/std"
+            permanent = true
+            status = 302
+
+            [[redirect]]
+            short = "s303"
+            url = "https://doc.rust-lang.org/std"
+            status = 303
+
+            [[redirect]]
+            short = "s307"
+            url = "https://doc.rust-lang.org/std"
+            status = 307
+
+            [[redirect]]
+            short = "s308"
+            url = "https://doc.rust-lang.org/std"
+            status = 308
+        "#,
+        );
+        for &(short, code, cached) in &[
+            ("s301", 301, true),
+            ("s302", 302, false),
+            ("s303", 303, false),
+            ("s307", 307, false),
+            ("s308", 308, true),
+        ] {
+            let response = client.get(format!("/redirect/{}/vec", short)).dispatch();
+            assert_eq!(response.status().code, code, "{}", short);
+            assert_eq!(
+                response.headers().get_one("Location"),
+                Some("https://doc.rust-lang.org/std/vec")
+            );
+            let cache_control = response.headers().get_one("Cache-Control");
+            assert_eq!(cache_control.is_some(), cached, "{}", short);
+        }
+    }
+
+    #[test]
+    fn trailing_slash_target() {
+        let client = client_with(
+            r#"
+            [[redirect]]
+            short = "book"
+            url = "https://doc.rust-lang.org/stable/book/"
+
+            [[redirect]]
+            short = "std"
+            url = "https://doc.rust-lang.org/stable/std"
+        "#,
+        );
+        let location = |uri: &str| {
+            let response = client.get(uri).dispatch();
+            response.headers().get_one("Location").map(String::from)
+        };
+        assert_eq!(
+            location("/redirect/book/ch01-00-getting-started.html"),
+            Some("https://doc.rust-lang.org/stable/book/ch01-00-getting-started.html".to_string())
+        );
+        assert_eq!(
+            location("/redirect/std/vec/index.html"),
+            Some("https://doc.rust-lang.org/stable/std/vec/index.html".to_string())
+        );
+        // the bare redirect keeps the configured URL as is
+        assert_eq!(
+            location("/redirect/book"),
+            Some("https://doc.rust-lang.org/stable/book/".to_string())
+        );
+    }
+
+    #[test]
+    fn matched_rule_headers() {
+        let config = redirect_utils::config_from_str(
+            r#"
+            [[redirect]]
+            short = "cook"
+            url = "https://rust-lang-nursery.github.io/rust-cookbook/"
+        "#,
+        ).unwrap();
+        let data = RedirectData::new(&config.redirect, "0123abcd", "");
+        let rocket = rocket_with(
+            Arc::new(RwLock::new(data)),
+            StatusMap::default(),
+            Arc::new(Shutdown::new()),
+            ServerOptions::default(),
+        );
+        let client = Client::new(rocket).expect("valid rocket instance");
+
+        let response = client.get("/redirect/www.cook/about.html").dispatch();
+        assert_eq!(response.status(), Status::Found);
+        assert_eq!(response.headers().get_one("X-Rustref-Key"), Some("cook"));
+        assert_eq!(response.headers().get_one("X-Rustref-Commit"), Some("0123abcd"));
+
+        let response = client.get("/redirect/nope").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        assert_eq!(response.headers().get_one("X-Rustref-Key"), None);
+    }
+
+    #[test]
+    fn meta_refresh_page() {
+        let client = client_with(
+            r#"
+            [[redirect]]
+            short = "book"
+            url = "https://doc.rust-lang.org/stable/book/"
+            permanent = true
+        "#,
+        );
+        let mut response = client
+            .get("/redirect/book/ch01-00-getting-started.html?via=html")
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one("Cache-Control"), Some("no-cache"));
+        assert_eq!(response.content_type(), Some(ContentType::HTML));
+        assert_eq!(response.headers().get_one("Location"), None);
+        assert_eq!(response.headers().get_one("X-Rustref-Key"), Some("book"));
+        // the template escapes slashes, which browsers decode again
+        let body = response.body_string().unwrap().replace("&#x2F;", "/");
+        let url = "https://doc.rust-lang.org/stable/book/ch01-00-getting-started.html";
+        assert!(body.contains(&format!(r#"<meta http-equiv="refresh" content="0; url={}">"#, url)));
+        assert!(body.contains(&format!(r#"<a href="{}">"#, url)));
+
+        let response = client.get("/redirect/book?via=json").dispatch();
+        assert_eq!(response.status(), Status::MovedPermanently);
+        assert_eq!(response.headers().get_one("Cache-Control"), Some("max-age=86400"));
+    }
+
+    #[test]
+    fn maintenance_mode() {
+        let client = client_with(
+            r#"
+            [[redirect]]
+            short = "book"
+            url = "https://doc.rust-lang.org/stable/book/"
+            preserve_method = true
+        "#,
+        );
+        let set_maintenance = |on: &str| {
+            let mut response = client
+                .post(format!("/admin/maintenance?on={}", on))
+                .header(admin_auth())
+                .dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            response.body_string().unwrap()
+        };
+
+        let response = client.post("/admin/maintenance?on=true").dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert_eq!(client.get("/redirect/book").dispatch().status(), Status::Found);
+
+        assert_eq!(set_maintenance("true"), r#"{"maintenance":true}"#);
+        let mut response = client.get("/redirect/book").dispatch();
+        assert_eq!(response.status(), Status::ServiceUnavailable);
+        assert!(response.body_string().unwrap().contains("back soon"));
+        let redirects = ["/redirect/book/ch01-00-getting-started.html", "/redirect/nope"];
+        for uri in &redirects {
+            assert_eq!(client.get(*uri).dispatch().status(), Status::ServiceUnavailable);
+        }
+        let response = client.head("/redirect/book").dispatch();
+        assert_eq!(response.status(), Status::ServiceUnavailable);
+        let response = client.post("/redirect/book").dispatch();
+        assert_eq!(response.status(), Status::ServiceUnavailable);
+        // everything but the redirects keeps working
+        assert_eq!(client.get("/").dispatch().status(), Status::Ok);
+        assert_eq!(client.get("/api/redirects").dispatch().status(), Status::Ok);
+
+        assert_eq!(set_maintenance("false"), r#"{"maintenance":false}"#);
+        assert_eq!(client.get("/redirect/book").dispatch().status(), Status::Found);
+    }
+
+    #[test]
+    fn verify_urls() {
+        use test_utils::{http_response, mock_server};
+
+        let server = mock_server(|path| {
+            if path == "/good" {
+                http_response("200 OK", &[], "ok")
+            } else {
+                http_response("404 Not Found", &[], "")
+            }
+        });
+        let urls = vec![
+            format!("{}/good", server),
+            format!("{}/missing", server),
+            "htp://nocduro.com".to_string(),
+        ];
+        let client = client_with("");
+        let response = client
+            .post("/api/verify")
+            .header(ContentType::JSON)
+            .body(serde_json::to_string(&urls).unwrap())
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+
+        let mut response = client
+            .post("/api/verify")
+            .header(ContentType::JSON)
+            .header(admin_auth())
+            .body(serde_json::to_string(&urls).unwrap())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let checks: serde_json::Value =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+        assert_eq!(checks.as_array().map(|c| c.len()), Some(3));
+
+        assert_eq!(checks[0]["url"], urls[0].as_str());
+        assert_eq!(checks[0]["ok"], true);
+        assert_eq!(checks[0]["status"], 200);
+        assert!(checks[0]["error"].is_null());
+
+        assert_eq!(checks[1]["url"], urls[1].as_str());
+        assert_eq!(checks[1]["ok"], false);
+        assert_eq!(checks[1]["status"], 404);
+        assert!(checks[1]["error"].is_string());
+
+        assert_eq!(checks[2]["ok"], false);
+        assert!(checks[2]["status"].is_null());
+        assert!(checks[2]["error"].is_string());
+
+        let too_many = vec!["https://rustref.com/"; api::MAX_VERIFY_URLS + 1];
+        let response = client
+            .post("/api/verify")
+            .header(ContentType::JSON)
+            .header(admin_auth())
+            .body(serde_json::to_string(&too_many).unwrap())
+            .dispatch();
+        assert_eq!(response.status(), Status::PayloadTooLarge);
+    }
+
+    #[test]
+    fn config_schema_served() {
+        let client = client_with("");
+        let mut response = client.get("/api/schema").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::JSON));
+        let schema: serde_json::Value =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+        let redirect = &schema["properties"]["redirect"]["items"]["properties"];
+        assert_eq!(redirect["short"]["type"], "string");
+        assert_eq!(redirect["url"]["type"], "string");
+    }
+
+    #[test]
+    fn redirect_hits_counted() {
+        let client = client_with(
+            r#"
+            [[redirect]]
+            short = "book"
+            url = "https://doc.rust-lang.org/stable/book/"
+
+            [[redirect]]
+            short = "std"
+            url = "https://doc.rust-lang.org/stable/std/"
+        "#,
+        );
+        client.get("/redirect/book").dispatch();
+        client.get("/redirect/book/ch01-00-getting-started.html").dispatch();
+        client.get("/redirect/std").dispatch();
+        client.get("/redirect/nope").dispatch();
+        // HEAD requests aren't counted
+        client.head("/redirect/std").dispatch();
+
+        let mut response = client.get("/api/stats/top?n=1").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let top: serde_json::Value =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+        assert_eq!(top["hits"].as_array().map(|hits| hits.len()), Some(1));
+        assert_eq!(top["hits"][0]["key"], "book");
+        assert_eq!(top["hits"][0]["hits"], 2);
+        assert_eq!(top["misses"][0]["key"], "nope");
+        assert_eq!(top["misses"][0]["hits"], 1);
+
+        let mut response = client.get("/api/stats/top").dispatch();
+        let top: serde_json::Value =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+        assert_eq!(top["hits"].as_array().map(|hits| hits.len()), Some(2));
+        assert_eq!(top["hits"][1]["key"], "std");
+        assert_eq!(top["hits"][1]["hits"], 1);
+    }
+
+    #[test]
+    fn misses_counted_by_key() {
+        let client = client_with(include_str!("../redirects.toml"));
+        let misses = || -> serde_json::Value {
+            let mut response = client.get("/api/stats/misses").dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            serde_json::from_str(&response.body_string().unwrap()).unwrap()
+        };
+        assert_eq!(misses(), json!({}));
+
+        client.get("/redirect/wanted").dispatch();
+        client.get("/redirect/wanted/some/page.html").dispatch();
+        client.get("/redirect/other").dispatch();
+        client.get("/redirect/std").dispatch();
+        assert_eq!(misses(), json!({"other": 1, "wanted": 2}));
+    }
+
+    #[test]
+    fn preserve_method_redirect_status() {
+        let client = client_with(
+            r#"
+            [[redirect]]
+            short = "service"
+            url = "https://api.example.com/v2"
+            permanent = true
+            preserve_method = true
+
+            [[redirect]]
+            short = "upload"
+            url = "https://uploads.example.com"
+            preserve_method = true
+        "#,
+        );
+        let response = client
+            .post("/redirect/service/items")
+            .header(ContentType::JSON)
+            .body("{}")
+            .dispatch();
+        assert_eq!(response.status(), Status::PermanentRedirect);
+        assert_eq!(
+            response.headers().get_one("Location"),
+            Some("https://api.example.com/v2/items")
+        );
+        // GET gets the same method-preserving status
+        let response = client.get("/redirect/service").dispatch();
+        assert_eq!(response.status(), Status::PermanentRedirect);
+
+        let response = client.put("/redirect/upload/file.txt").dispatch();
+        assert_eq!(response.status(), Status::TemporaryRedirect);
+        assert_eq!(
+            response.headers().get_one("Location"),
+            Some("https://uploads.example.com/file.txt")
+        );
+
+        // unknown keys still only allow GET
+        let response = client.post("/redirect/missing").dispatch();
+        assert_eq!(response.status(), Status::MethodNotAllowed);
+    }
+
+    #[test]
+    fn redirects_not_indexed() {
+        let client = client_with(
+            r#"
+            [[redirect]]
+            short = "std"
+            url = "https://doc.rust-lang.org/stable/std"
+
+            [[redirect]]
+            short = "book"
+            url = "https://doc.rust-lang.org/book"
+            permanent = true
+            indexable = true
+        "#,
+        );
+        for path in &["/redirect/std", "/redirect/std/index.html"] {
+            let response = client.get(*path).dispatch();
+            assert_eq!(response.headers().get_one("X-Robots-Tag"), Some("noindex"));
+        }
+        let response = client.get("/redirect/book").dispatch();
+        assert_eq!(response.status(), Status::MovedPermanently);
+        assert!(response.headers().get_one("X-Robots-Tag").is_none());
+    }
+
+    #[test]
+    fn localized_redirect() {
+        let client = client_with(
+            r#"
+            [[redirect]]
+            short = "book"
+            url = "https://doc.rust-lang.org/book"
+            localized = { ja = "https://doc.rust-jp.rs/book-ja" }
+        "#,
+        );
+
+        let response = client
+            .get("/redirect/book")
+            .header(Header::new("Accept-Language", "ja-JP, en;q=0.5"))
+            .dispatch();
+        assert_eq!(
+            response.headers().get_one("Location"),
+            Some("https://doc.rust-jp.rs/book-ja")
+        );
+
+        let response = client
+            .get("/redirect/book/ch01-00-getting-started.html")
+            .header(Header::new("Accept-Language", "fr"))
+            .dispatch();
+        assert_eq!(
+            response.headers().get_one("Location"),
+            Some("https://doc.rust-lang.org/book/ch01-00-getting-started.html")
+        );
+    }
+
+    #[test]
+    fn validation_report_negotiation() {
+        let client = client_with(include_str!("../redirects.toml"));
+        let config = include_str!("../test_data/duplicate_redirects.toml");
+
+        let mut response = client
+            .post("/api/validate")
+            .header(Accept::HTML)
+            .body(config)
+            .dispatch();
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+        assert_eq!(response.content_type(), Some(ContentType::HTML));
+        let body = response.body_string().unwrap();
+        assert!(body.contains("DuplicateRule"));
+        assert!(body.contains("same"));
+
+        let mut response = client
+            .post("/api/validate")
+            .header(Accept::JSON)
+            .body(config)
+            .dispatch();
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+        assert_eq!(response.content_type(), Some(ContentType::JSON));
+        let body = response.body_string().unwrap();
+        let report: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(report["valid"], false);
+        assert_eq!(report["problems"][0]["kind"], "DuplicateRule");
+    }
+
+    #[test]
+    fn validation_rejects_large_configs() {
+        let client = client_with(include_str!("../redirects.toml"));
+        let comment = format!("# {}\n", "a".repeat(1024 * 1024));
+        let response = client.post("/api/validate").body(comment).dispatch();
+        assert_eq!(response.status(), Status::PayloadTooLarge);
+    }
+
+    #[test]
+    fn redirects_txt_listing() {
+        let client = client_with(include_str!("../redirects.toml"));
+        let mut response = client.get("/api/redirects.txt").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::Plain));
+        let body = response.body_string().unwrap();
+        assert!(body.contains("std\thttps://doc.rust-lang.org/stable/std\n"));
+
+        let shorts: Vec<&str> = body.lines().map(|l| l.split('\t').next().unwrap()).collect();
+        let mut sorted = shorts.clone();
+        sorted.sort();
+        assert_eq!(shorts, sorted);
+    }
+
+    #[test]
+    fn redirects_etag() {
+        let client = client_with(include_str!("../redirects.toml"));
+        let response = client.get("/api/redirects").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let etag = response.headers().get_one("ETag").expect("ETag header").to_string();
+
+        let mut response = client
+            .get("/api/redirects")
+            .header(Header::new("If-None-Match", etag.clone()))
+            .dispatch();
+        assert_eq!(response.status(), Status::NotModified);
+        assert_eq!(response.headers().get_one("ETag"), Some(etag.as_str()));
+        assert!(response.body_string().is_none());
+
+        let response = client
+            .get("/api/redirects")
+            .header(Header::new("If-None-Match", "\"stale\""))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        // a different set of redirects gets a different ETag
+        let other = client_with(include_str!("../test_data/duplicate_redirects.toml"));
+        let response = other.get("/api/redirects").dispatch();
+        assert_ne!(response.headers().get_one("ETag"), Some(etag.as_str()));
+    }
+
+    #[test]
+    fn request_id_header() {
+        let client = client_with(include_str!("../redirects.toml"));
+        let response = client.get("/redirect/std").dispatch();
+        let generated = response.headers().get_one("X-Request-Id").expect("X-Request-Id header");
+        assert_eq!(generated.len(), 36);
+        let response = client.get("/redirect/std").dispatch();
+        assert_ne!(response.headers().get_one("X-Request-Id"), Some(generated));
+
+        let response = client
+            .get("/api/redirects")
+            .header(Header::new("X-Request-Id", "upstream-42"))
+            .dispatch();
+        assert_eq!(response.headers().get_one("X-Request-Id"), Some("upstream-42"));
+
+        // not echoed if it could mess up the logs
+        let response = client
+            .get("/api/redirects")
+            .header(Header::new("X-Request-Id", "forged] line"))
+            .dispatch();
+        let replaced = response.headers().get_one("X-Request-Id").unwrap();
+        assert_ne!(replaced, "forged] line");
+        assert!(request_id::current().is_none());
+    }
+
+    #[test]
+    fn redirects_paginated() {
+        let client = client_with(
+            r#"
+            [[redirect]]
+            short = "std"
+            url = "https://doc.rust-lang.org/std"
+
+            [[redirect]]
+            short = "book"
+            url = "https://doc.rust-lang.org/book/"
+
+            [[redirect]]
+            short = "cargo"
+            url = "https://doc.rust-lang.org/cargo/"
+        "#,
+        );
+        let page = |uri: &str| -> serde_json::Value {
+            let mut response = client.get(uri.to_string()).dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            serde_json::from_str(&response.body_string().unwrap()).unwrap()
+        };
+        let shorts = |page: &serde_json::Value| -> Vec<String> {
+            page["redirects"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|redir| redir["short"].as_str().unwrap().to_string())
+                .collect()
+        };
+
+        let first = page("/api/redirects?limit=2");
+        assert_eq!(shorts(&first), vec!["book", "cargo"]);
+        assert_eq!(first["total"], 3);
+        assert_eq!(first["next_offset"], 2);
+
+        let last = page("/api/redirects?limit=2&offset=2");
+        assert_eq!(shorts(&last), vec!["std"]);
+        assert_eq!(last["next_offset"], serde_json::Value::Null);
+
+        // a limit larger than the total is the whole table
+        let all = page("/api/redirects?limit=10");
+        assert_eq!(shorts(&all), vec!["book", "cargo", "std"]);
+        assert_eq!(all["next_offset"], serde_json::Value::Null);
+
+        // an offset past the end is an empty page
+        let past_end = page("/api/redirects?offset=5");
+        assert!(shorts(&past_end).is_empty());
+        assert_eq!(past_end["total"], 3);
+        assert_eq!(past_end["next_offset"], serde_json::Value::Null);
+
+        // an empty page would never advance
+        let response = client.get("/api/redirects?limit=0").dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+
+        // without paging the plain array is returned as before
+        let plain = page("/api/redirects");
+        assert_eq!(plain.as_array().map(|redirects| redirects.len()), Some(3));
+    }
+
+    #[test]
+    fn requests_recorded_in_latency_histogram() {
+        let client = client_with(include_str!("../redirects.toml"));
+        let request_count = |body: String| -> usize {
+            let line = body
+                .lines()
+                .find(|l| l.starts_with("rustref_request_duration_seconds_count "))
+                .expect("count line")
+                .to_string();
+            line.split(' ').nth(1).unwrap().parse().unwrap()
+        };
+
+        let mut response = client.get("/metrics").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let before = request_count(response.body_string().unwrap());
+
+        client.get("/redirect/std").dispatch();
+        let after = request_count(client.get("/metrics").dispatch().body_string().unwrap());
+        // the redirect, and the first /metrics request which is recorded after responding
+        assert_eq!(after, before + 2);
+    }
+
+    #[test]
+    fn redirect_listing() {
+        let client = client_with(
+            r#"
+            [[redirect]]
+            short = "std"
+            url = "https://doc.rust-lang.org/std/"
+
+            [[redirect]]
+            short = "down"
+            url = "https://example.com/"
+            enabled = false
+        "#,
+        );
+        let mut response = client.get("/redirect").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body = response.body_string().unwrap();
+        assert!(body.contains(r#"<a href="/redirect/std">std</a>"#));
+        assert!(!body.contains("/redirect/down"));
+
+        let mut response = client.get("/redirect").header(Accept::JSON).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let redirects: serde_json::Value =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+        assert_eq!(redirects.as_array().unwrap().len(), 1);
+        assert_eq!(redirects[0]["short"], "std");
+    }
+
+    #[test]
+    fn wildcard_redirects() {
+        let client = client_with(
+            r#"
+            [[redirect]]
+            short = "blog-*"
+            url = "https://blog.example.com/{wildcard}"
+
+            [[redirect]]
+            short = "blog-news"
+            url = "https://news.example.com/"
+        "#,
+        );
+        let response = client.get("/redirect/blog-2018").dispatch();
+        assert_eq!(response.status(), Status::Found);
+        assert_eq!(
+            response.headers().get_one("Location"),
+            Some("https://blog.example.com/2018")
+        );
+        assert_eq!(response.headers().get_one("X-Rustref-Key"), Some("blog-*"));
+
+        // exact matches win over wildcards
+        let response = client.get("/redirect/blog-news").dispatch();
+        assert_eq!(response.headers().get_one("Location"), Some("https://news.example.com/"));
+
+        let response = client.get("/redirect/blog-2018/posts").dispatch();
+        assert_eq!(
+            response.headers().get_one("Location"),
+            Some("https://blog.example.com/2018/posts")
+        );
+        assert_eq!(client.get("/redirect/news").dispatch().status(), Status::NotFound);
+
+        // the pattern itself isn't a redirect, and isn't listed
+        let response = client.get("/redirect/blog-*").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        let mut response = client.get("/api/redirects.txt").dispatch();
+        assert_eq!(
+            response.body_string(),
+            Some("blog-news\thttps://news.example.com/\n".to_string())
+        );
+        let mut response = client.get("/").dispatch();
+        let body = response.body_string().unwrap();
+        assert!(body.contains("blog-news"));
+        assert!(!body.contains("blog-*"));
+    }
+
+    #[test]
+    fn bind_config_applied() {
+        let base = Config::development().unwrap();
+        let config = server_config(&base, Some("0.0.0.0".to_string()), Some("9000".to_string()));
+        let rocket = rocket_with(
+            Arc::new(RwLock::new(RedirectData::new(&[], "", ""))),
+            StatusMap::default(),
+            Arc::new(Shutdown::new()),
+            ServerOptions {
+                config: Some(config.unwrap()),
+                ..ServerOptions::default()
+            },
+        );
+        assert_eq!(rocket.config().address, "0.0.0.0");
+        assert_eq!(rocket.config().port, 9000);
+
+        // only what's given is replaced
+        let config = server_config(&base, None, Some("8081".to_string())).unwrap();
+        assert_eq!(config.address, base.address);
+        assert_eq!(config.port, 8081);
+        assert!(server_config(&base, None, Some("http".to_string())).is_err());
+        assert!(server_config(&base, None, Some("70000".to_string())).is_err());
+
+        // the base config comes from Rocket.toml, which changes the development address
+        assert_eq!(rocket_config().unwrap().address, "127.0.0.1");
+    }
+
+    #[test]
+    fn admin_requires_token() {
+        let client = client_with(include_str!("../redirects.toml"));
+        let response = client.delete("/admin/redirect/std").dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+
+        let response = client
+            .delete("/admin/redirect/std")
+            .header(Header::new("Authorization", "Bearer wrong-token"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    #[test]
+    fn admin_add_redirect() {
+        let config = include_str!("../redirects.toml");
+        let count = redirect_utils::redirects_from_str(config).unwrap().len();
+        let store = MockStore::default();
+        let client = client_with_store(config, Some(store.clone()));
+        let server = mock_server(|_path| http_response("200 OK", &[], "ok"));
+        let url = format!("{}/cargo/", server);
+
+        let mut response = client
+            .post("/admin/redirect")
+            .header(ContentType::JSON)
+            .header(admin_auth())
+            .body(json!({"short": "cargobook", "url": url}).to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body = response.body_string().unwrap();
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["count"], count + 1);
+
+        let response = client.get("/redirect/cargobook").dispatch();
+        assert_eq!(response.headers().get_one("Location"), Some(url.as_str()));
+        // the store gets every served redirect, even though the mock store started out empty
+        assert_eq!(store.shorts().len(), count + 1);
+        assert!(store.shorts().contains(&"cargobook".to_string()));
+        assert_eq!(store.calls(), vec![format!("replace_all {}", count + 1)]);
+    }
+
+    #[test]
+    fn admin_add_duplicate_rejected() {
+        let client = client_with(include_str!("../redirects.toml"));
+        let response = client
+            .post("/admin/redirect")
+            .header(ContentType::JSON)
+            .header(admin_auth())
+            .body(r#"{"short": "std", "url": "https://doc.rust-lang.org/stable/std"}"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::Conflict);
+    }
+
+    #[test]
+    fn admin_add_invalid_short_rejected() {
+        let client = client_with(include_str!("../redirects.toml"));
+        let response = client
+            .post("/admin/redirect")
+            .header(ContentType::JSON)
+            .header(admin_auth())
+            .body(r#"{"short": "std.lib", "url": "https://doc.rust-lang.org/std"}"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+        assert_eq!(client.get("/redirect/std.lib").dispatch().status(), Status::NotFound);
+    }
+
+    #[test]
+    fn admin_delete_redirect() {
+        let config = include_str!("../redirects.toml");
+        let store = MockStore::with_redirects(&redirect_utils::redirects_from_str(config).unwrap());
+        let client = client_with_store(config, Some(store.clone()));
+        let response = client
+            .delete("/admin/redirect/std")
+            .header(admin_auth())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(client.get("/redirect/std").dispatch().status(), Status::NotFound);
+        assert!(!store.shorts().contains(&"std".to_string()));
+
+        let response = client
+            .delete("/admin/redirect/does-not-exist")
+            .header(admin_auth())
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        assert_eq!(store.calls().len(), 1);
+    }
+
+    #[test]
+    fn admin_changes_kept_out_when_store_fails() {
+        let config = include_str!("../redirects.toml");
+        let store = MockStore::read_only(&redirect_utils::redirects_from_str(config).unwrap());
+        let client = client_with_store(config, Some(store.clone()));
+        let server = mock_server(|_path| http_response("200 OK", &[], "ok"));
+
+        let response = client
+            .post("/admin/redirect")
+            .header(ContentType::JSON)
+            .header(admin_auth())
+            .body(json!({"short": "cargobook", "url": server}).to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::InternalServerError);
+        assert_eq!(client.get("/redirect/cargobook").dispatch().status(), Status::NotFound);
+
+        let response = client
+            .delete("/admin/redirect/std")
+            .header(admin_auth())
+            .dispatch();
+        assert_eq!(response.status(), Status::InternalServerError);
+        assert_eq!(client.get("/redirect/std").dispatch().status(), Status::Found);
+        assert_eq!(store.calls().len(), 2);
+    }
+
+    #[test]
+    fn admin_rollback_without_history() {
+        let client = client_with(include_str!("../redirects.toml"));
+        let response = client.post("/admin/rollback").dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+
+        let mut response = client.post("/admin/rollback").header(admin_auth()).dispatch();
+        assert_eq!(response.status(), Status::Conflict);
+        assert!(response.body_string().unwrap().contains("no previous config"));
+        assert_eq!(client.get("/redirect/std").dispatch().status(), Status::Found);
+    }
+
+    #[test]
+    fn admin_rollback_refused_during_update() {
+        let shutdown = Arc::new(Shutdown::new());
+        let rocket = rocket_with(
+            Arc::new(RwLock::new(RedirectData::new(&[], "", ""))),
+            StatusMap::default(),
+            Arc::clone(&shutdown),
+            ServerOptions {
+                admin_token: Some(ADMIN_TOKEN.to_string()),
+                ..ServerOptions::default()
+            },
+        );
+        let client = Client::new(rocket).expect("valid rocket instance");
+
+        // a webhook update holds the token until it's done
+        let update = shutdown.busy().unwrap();
+        let response = client.post("/admin/rollback").header(admin_auth()).dispatch();
+        assert_eq!(response.status(), Status::Conflict);
+        drop(update);
+
+        let mut response = client.post("/admin/rollback").header(admin_auth()).dispatch();
+        assert_eq!(response.status(), Status::Conflict);
+        assert!(response.body_string().unwrap().contains("no previous config"));
+    }
+
+    #[test]
+    fn admin_warmup_checks_every_target() {
+        let server = mock_server(|path| {
+            if path == "/good" {
+                http_response("200 OK", &[], "ok")
+            } else {
+                http_response("404 Not Found", &[], "")
+            }
+        });
+        let client = client_with(&format!(
+            r#"
+            [[redirect]]
+            short = "good"
+            url = "{0}/good"
+
+            [[redirect]]
+            short = "bad"
+            url = "{0}/bad"
+        "#,
+            server
+        ));
+        assert_eq!(client.post("/admin/warmup").dispatch().status(), Status::Unauthorized);
+
+        let mut response = client.post("/admin/warmup").header(admin_auth()).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body: serde_json::Value =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+        assert_eq!(body["checked"], 2);
+        assert_eq!(body["reachable"], 1);
+        assert_eq!(body["unreachable"], json!(["bad"]));
+
+        let mut response = client.get("/api/status").dispatch();
+        let statuses: serde_json::Value =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+        assert_eq!(statuses["good"]["reachable"], true);
+        assert_eq!(statuses["bad"]["status"], 404);
+    }
+
+    #[test]
+    fn admin_config_dump() {
+        let client = client_with(
+            r#"
+            [meta]
+            version = 1
+
+            [[redirect]]
+            short = "service"
+            url = "https://api.example.com/v2"
+            category = "tools"
+            permanent = true
+            preserve_method = true
+            strip_prefix = "v1"
+        "#,
+        );
+        let response = client.get("/admin/config").dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+
+        let mut response = client.get("/admin/config").header(admin_auth()).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::JSON));
+        let config: serde_json::Value =
+            serde_json::from_str(&response.body_string().unwrap()).unwrap();
+        let service = &config["map"]["service"];
+        assert_eq!(service["permanent"], true);
+        assert_eq!(service["preserve_method"], true);
+        assert_eq!(service["strip_prefix"], "v1");
+        assert_eq!(config["categories"]["tools"][0]["short"], "service");
+        assert_eq!(config["meta"]["version"], 1);
+    }
+
+    #[test]
+    fn head_matches_get() {
+        let client = client_with(
+            r#"
+            [[redirect]]
+            short = "book"
+            url = "https://doc.rust-lang.org/book"
+            localized = { ja = "https://doc.rust-jp.rs/book-ja" }
+
+            [[redirect]]
+            short = "std"
+            url = "https://doc.rust-lang.org/stable/std"
+        "#,
+        );
+
+        for path in &["/redirect/book", "/redirect/book/index.html", "/redirect/std"] {
+            let get = client
+                .get(*path)
+                .header(Header::new("Accept-Language", "ja"))
+                .dispatch();
+            let mut head = client
+                .head(*path)
+                .header(Header::new("Accept-Language", "ja"))
+                .dispatch();
+            assert_eq!(head.status(), get.status());
+            assert_eq!(
+                head.headers().get_one("Location"),
+                get.headers().get_one("Location")
+            );
+            assert_eq!(head.headers().get_one("Vary"), get.headers().get_one("Vary"));
+            assert!(head.body_string().map_or(true, |b| b.is_empty()));
+        }
+
+        // only redirects with localized urls depend on Accept-Language
+        let response = client.head("/redirect/book").dispatch();
+        assert_eq!(response.headers().get_one("Vary"), Some("Accept-Language"));
+        let response = client.head("/redirect/std").dispatch();
+        assert!(response.headers().get_one("Vary").is_none());
+    }
+
+    #[test]
+    #[cfg(not(feature = "insecure-test"))]
+    fn unsigned_webhook_rejected() {
+        let client = client_with(include_str!("../redirects.toml"));
+        let response = client
+            .post("/github/webhook")
+            .header(ContentType::JSON)
+            .body(include_str!("../test_data/readme_updated.json"))
+            .dispatch();
+        // a missing signature is the sender's mistake, not a server error
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+
+    #[test]
+    #[cfg(feature = "insecure-test")]
+    fn unsigned_webhook_accepted_with_insecure_test() {
+        let client = client_with(include_str!("../redirects.toml"));
+        let mut response = client
+            .post("/github/webhook")
+            .header(ContentType::JSON)
+            .header(Header::new("X-GitHub-Delivery", "unsigned-delivery"))
+            .body(include_str!("../test_data/readme_updated.json"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        // the push is to another branch, so it's parsed and then ignored
+        assert!(response.body_string().unwrap().contains("ignoring"));
+    }
+
+    #[test]
+    #[cfg(feature = "insecure-test")]
+    fn webhook_from_fork_rejected() {
+        let client = client_with(include_str!("../redirects.toml"));
+        let mut payload: serde_json::Value =
+            serde_json::from_str(include_str!("../test_data/readme_updated.json")).unwrap();
+        payload["repository"]["full_name"] = serde_json::Value::from("someone/rustref-fork");
+        let response = client
+            .post("/github/webhook")
+            .header(ContentType::JSON)
+            .header(Header::new("X-GitHub-Delivery", "fork-delivery"))
+            .body(payload.to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+    }
+
+    #[test]
+    #[cfg(feature = "insecure-test")]
+    fn replayed_delivery_rejected() {
+        let client = client_with(include_str!("../redirects.toml"));
+        let post = |delivery: &str| {
+            client
+                .post("/github/webhook")
+                .header(ContentType::JSON)
+                .header(Header::new("X-GitHub-Delivery", delivery.to_string()))
+                .body(include_str!("../test_data/readme_updated.json"))
+                .dispatch()
+                .status()
+        };
+        assert_eq!(post("72d3162e-cc78-11e3-81ab-4c9367dc0958"), Status::Ok);
+        assert_eq!(post("72d3162e-cc78-11e3-81ab-4c9367dc0958"), Status::Conflict);
+        assert_eq!(post("8a9c2b1e-cc78-11e3-81ab-4c9367dc0958"), Status::Ok);
+
+        // the delivery ID can't be left out to get around the check
+        let response = client
+            .post("/github/webhook")
+            .header(ContentType::JSON)
+            .body(include_str!("../test_data/readme_updated.json"))
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+
+    #[test]
+    fn startup_url_check() {
+        use test_utils::{http_response, mock_server};
+
+        let server = mock_server(|path| {
+            if path == "/good" {
+                http_response("200 OK", &[], "ok")
+            } else {
+                http_response("404 Not Found", &[], "")
+            }
+        });
+        let config = |paths: &[&str]| {
+            let redirects: Vec<String> = paths
+                .iter()
+                .map(|path| {
+                    format!(
+                        "[[redirect]]\nshort = \"{}\"\nurl = \"{}/{}\"\n",
+                        path, server, path
+                    )
+                })
+                .collect();
+            redirect_utils::redirects_from_str(&redirects.join("\n")).unwrap()
+        };
+
+        let good = RedirectData::new(&config(&["good"]), "", "");
+        assert_eq!(check_all_urls(&good), None);
+
+        let broken = RedirectData::new(&config(&["good", "bad"]), "", "");
+        assert_eq!(check_all_urls(&broken), Some(1));
+    }
 }
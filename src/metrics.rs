@@ -0,0 +1,443 @@
+use dotenv;
+use request_id;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::response::content;
+use rocket::{Data, Request, Response, State};
+
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Upper bounds of the latency histogram buckets in milliseconds, used when the
+/// `latency_buckets_ms` env var isn't set
+pub const DEFAULT_LATENCY_BUCKETS_MS: &[u64] = &[1, 5, 10, 25, 50, 100, 250, 500, 1000];
+
+thread_local! {
+    /// When the request currently handled by this thread started.
+    /// Rocket handles a request on a single thread, from `on_request` to `on_response`.
+    static REQUEST_START: Cell<Option<Instant>> = Cell::new(None);
+}
+
+/// Histogram of request durations, exposed in the Prometheus text format at `/metrics`
+#[derive(Debug)]
+pub struct LatencyHistogram {
+    /// Upper bound of each bucket in milliseconds, sorted ascending
+    bounds_ms: Vec<u64>,
+    /// Requests per bucket, with one extra bucket for anything slower than the last bound
+    counts: Vec<AtomicUsize>,
+    count: AtomicUsize,
+    sum_micros: AtomicUsize,
+}
+
+impl LatencyHistogram {
+    pub fn new(mut bounds_ms: Vec<u64>) -> LatencyHistogram {
+        bounds_ms.sort();
+        bounds_ms.dedup();
+        let counts = (0..bounds_ms.len() + 1)
+            .map(|_| AtomicUsize::new(0))
+            .collect();
+        LatencyHistogram {
+            bounds_ms,
+            counts,
+            count: AtomicUsize::new(0),
+            sum_micros: AtomicUsize::new(0),
+        }
+    }
+
+    /// Read the bucket bounds from the `latency_buckets_ms` env var, a comma separated list
+    /// of milliseconds like `5,50,500`. Falls back to `DEFAULT_LATENCY_BUCKETS_MS`.
+    pub fn from_env() -> LatencyHistogram {
+        let bounds = match dotenv::var("latency_buckets_ms") {
+            Ok(list) => parse_bounds(&list).unwrap_or_else(|| {
+                println!(
+                    "Warning: invalid latency_buckets_ms `{}`, using defaults",
+                    list
+                );
+                DEFAULT_LATENCY_BUCKETS_MS.to_vec()
+            }),
+            Err(_) => DEFAULT_LATENCY_BUCKETS_MS.to_vec(),
+        };
+        LatencyHistogram::new(bounds)
+    }
+
+    /// Record a request that took `duration`
+    pub fn observe(&self, duration: Duration) {
+        let micros =
+            duration.as_secs() as usize * 1_000_000 + duration.subsec_nanos() as usize / 1000;
+        let bucket = self
+            .bounds_ms
+            .iter()
+            .position(|&bound| micros <= bound as usize * 1000)
+            .unwrap_or(self.bounds_ms.len());
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    /// Number of requests recorded
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// The histogram in the Prometheus text format, with cumulative buckets in seconds
+    pub fn render(&self) -> String {
+        let name = "rustref_request_duration_seconds";
+        let mut out = String::new();
+        let _ = writeln!(out, "# HELP {} Time taken to handle a request.", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        let mut cumulative = 0;
+        for (i, bound) in self.bounds_ms.iter().enumerate() {
+            cumulative += self.counts[i].load(Ordering::Relaxed);
+            let le = *bound as f64 / 1000.0;
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, le, cumulative);
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, self.count());
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "{}_sum {}", name, sum);
+        let _ = writeln!(out, "{}_count {}", name, self.count());
+        out
+    }
+}
+
+/// Calls to the Cloudflare API, by operation and whether they succeeded
+#[derive(Debug, Default)]
+pub struct CloudflareMetrics {
+    calls: Mutex<BTreeMap<(&'static str, bool), CallStats>>,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct CallStats {
+    count: usize,
+    seconds: f64,
+}
+
+impl CloudflareMetrics {
+    pub fn new() -> CloudflareMetrics {
+        CloudflareMetrics::default()
+    }
+
+    /// Run the Cloudflare API `call`, recording its outcome and duration under `operation`
+    pub fn record<T, E, F>(&self, operation: &'static str, call: F) -> Result<T, E>
+    where
+        F: FnOnce() -> Result<T, E>,
+    {
+        let start = Instant::now();
+        let result = call();
+        let seconds = as_seconds(start.elapsed());
+        let mut calls = self
+            .calls
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let stats = calls
+            .entry((operation, result.is_ok()))
+            .or_insert_with(CallStats::default);
+        stats.count += 1;
+        stats.seconds += seconds;
+        result
+    }
+
+    /// Number of `operation` calls that succeeded, or failed
+    pub fn count(&self, operation: &str, success: bool) -> usize {
+        let calls = self
+            .calls
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        calls
+            .iter()
+            .find(|&(&(op, ok), _)| op == operation && ok == success)
+            .map_or(0, |(_, stats)| stats.count)
+    }
+
+    /// The call counts and total durations in the Prometheus text format
+    pub fn render(&self) -> String {
+        let calls = self
+            .calls
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let requests = "rustref_cloudflare_requests_total";
+        let seconds = "rustref_cloudflare_request_seconds_total";
+        let mut out = String::new();
+        let _ = writeln!(out, "# HELP {} Cloudflare API calls.", requests);
+        let _ = writeln!(out, "# TYPE {} counter", requests);
+        for (&(operation, ok), stats) in calls.iter() {
+            let labels = call_labels(operation, ok);
+            let _ = writeln!(out, "{}{{{}}} {}", requests, labels, stats.count);
+        }
+        let _ = writeln!(
+            out,
+            "# HELP {} Time spent in Cloudflare API calls.",
+            seconds
+        );
+        let _ = writeln!(out, "# TYPE {} counter", seconds);
+        for (&(operation, ok), stats) in calls.iter() {
+            let labels = call_labels(operation, ok);
+            let _ = writeln!(out, "{}{{{}}} {}", seconds, labels, stats.seconds);
+        }
+        out
+    }
+}
+
+/// Most unknown keys tracked at once, so requests for random subdomains can't grow the
+/// miss counts forever
+pub const MAX_TRACKED_MISSES: usize = 1000;
+
+/// Requests per redirect key, with the keys that aren't in the redirect map kept apart
+#[derive(Debug, Default)]
+pub struct HitCounter {
+    hits: Mutex<HashMap<String, usize>>,
+    misses: Mutex<HashMap<String, usize>>,
+    /// Log every miss, since an unknown key is a shortcut someone wanted
+    log_misses: bool,
+}
+
+/// A key and how often it was requested
+#[derive(Debug, Serialize, PartialEq)]
+pub struct KeyHits {
+    pub key: String,
+    pub hits: usize,
+}
+
+/// The most requested keys, see `HitCounter::top`
+#[derive(Debug, Serialize)]
+pub struct TopKeys {
+    pub hits: Vec<KeyHits>,
+    pub misses: Vec<KeyHits>,
+}
+
+impl HitCounter {
+    pub fn new() -> HitCounter {
+        HitCounter::default()
+    }
+
+    /// Log misses unless the `log_misses` env var is set to `false`
+    pub fn from_env() -> HitCounter {
+        HitCounter {
+            log_misses: dotenv::var("log_misses").map(|s| s != "false").unwrap_or(true),
+            ..HitCounter::default()
+        }
+    }
+
+    /// Count a request for `key`, which is in the redirect map
+    pub fn hit(&self, key: &str) {
+        let mut hits = self
+            .hits
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        *hits.entry(key.to_string()).or_insert(0) += 1;
+    }
+
+    /// Count a request for `key`, which isn't in the redirect map.
+    /// New keys are dropped once `MAX_TRACKED_MISSES` keys are tracked.
+    pub fn miss(&self, key: &str) {
+        if self.log_misses {
+            println!("{}no redirect for {:?}", request_id::log_prefix(), key);
+        }
+        let mut misses = self
+            .misses
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(count) = misses.get_mut(key) {
+            *count += 1;
+            return;
+        }
+        if misses.len() < MAX_TRACKED_MISSES {
+            misses.insert(key.to_string(), 1);
+        }
+    }
+
+    /// Number of requests for `key` while it was in the redirect map
+    #[cfg(test)]
+    pub fn hits(&self, key: &str) -> usize {
+        let hits = self
+            .hits
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        hits.get(key).cloned().unwrap_or(0)
+    }
+
+    /// Number of requests for `key` while it wasn't in the redirect map
+    #[cfg(test)]
+    pub fn misses(&self, key: &str) -> usize {
+        let misses = self
+            .misses
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        misses.get(key).cloned().unwrap_or(0)
+    }
+
+    /// Every tracked key that isn't in the redirect map, with its number of requests
+    pub fn all_misses(&self) -> BTreeMap<String, usize> {
+        let misses = self
+            .misses
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        misses.iter().map(|(key, &count)| (key.clone(), count)).collect()
+    }
+
+    /// The `n` most requested keys and unknown keys, most requested first
+    pub fn top(&self, n: usize) -> TopKeys {
+        TopKeys {
+            hits: top_keys(&self.hits, n),
+            misses: top_keys(&self.misses, n),
+        }
+    }
+}
+
+fn top_keys(counts: &Mutex<HashMap<String, usize>>, n: usize) -> Vec<KeyHits> {
+    let counts = counts
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let mut top: Vec<KeyHits> = counts
+        .iter()
+        .map(|(key, &hits)| KeyHits {
+            key: key.clone(),
+            hits,
+        })
+        .collect();
+    // ties are broken by key so the order is stable
+    top.sort_by(|a, b| b.hits.cmp(&a.hits).then_with(|| a.key.cmp(&b.key)));
+    top.truncate(n);
+    top
+}
+
+fn call_labels(operation: &str, ok: bool) -> String {
+    let result = if ok { "success" } else { "failure" };
+    format!("operation=\"{}\",result=\"{}\"", operation, result)
+}
+
+fn as_seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1_000_000_000.0
+}
+
+/// Parse a comma separated list of bucket bounds, `None` if any of them isn't a number
+fn parse_bounds(list: &str) -> Option<Vec<u64>> {
+    let bounds: Option<Vec<u64>> = list.split(',').map(|b| b.trim().parse().ok()).collect();
+    bounds.filter(|b| !b.is_empty())
+}
+
+/// Times every request and records it in the managed `LatencyHistogram`
+pub struct LatencyFairing;
+
+impl Fairing for LatencyFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request latency",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, _request: &mut Request, _data: &Data) {
+        REQUEST_START.with(|start| start.set(Some(Instant::now())));
+    }
+
+    fn on_response(&self, request: &Request, _response: &mut Response) {
+        let start = match REQUEST_START.with(|start| start.replace(None)) {
+            Some(start) => start,
+            None => return,
+        };
+        if let Some(histogram) = request.guard::<State<LatencyHistogram>>().succeeded() {
+            histogram.observe(start.elapsed());
+        }
+    }
+}
+
+/// Request latency histogram and Cloudflare API calls in the Prometheus text format
+#[get("/metrics")]
+pub fn metrics(
+    histogram: State<LatencyHistogram>,
+    cloudflare: State<CloudflareMetrics>,
+) -> content::Plain<String> {
+    content::Plain(histogram.render() + &cloudflare.render())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn observe_fills_buckets() {
+        let histogram = LatencyHistogram::new(vec![10, 1, 100]);
+        histogram.observe(Duration::from_millis(0));
+        histogram.observe(Duration::from_millis(5));
+        histogram.observe(Duration::from_millis(50));
+        histogram.observe(Duration::from_secs(2));
+        assert_eq!(histogram.count(), 4);
+
+        let text = histogram.render();
+        assert!(text.contains("rustref_request_duration_seconds_bucket{le=\"0.001\"} 1\n"));
+        assert!(text.contains("rustref_request_duration_seconds_bucket{le=\"0.01\"} 2\n"));
+        assert!(text.contains("rustref_request_duration_seconds_bucket{le=\"0.1\"} 3\n"));
+        assert!(text.contains("rustref_request_duration_seconds_bucket{le=\"+Inf\"} 4\n"));
+        assert!(text.contains("rustref_request_duration_seconds_count 4\n"));
+    }
+
+    #[test]
+    fn cloudflare_calls_counted() {
+        let metrics = CloudflareMetrics::new();
+        let ok: Result<u32, &str> = metrics.record("list_dns", || Ok(3));
+        assert_eq!(ok, Ok(3));
+        let _ = metrics.record("create_dns", || Err::<(), _>("rate limited"));
+        let _ = metrics.record("create_dns", || Err::<(), _>("rate limited"));
+
+        assert_eq!(metrics.count("list_dns", true), 1);
+        assert_eq!(metrics.count("list_dns", false), 0);
+        assert_eq!(metrics.count("create_dns", false), 2);
+        assert_eq!(metrics.count("zone_id", true), 0);
+
+        let text = metrics.render();
+        assert!(text.contains(
+            "rustref_cloudflare_requests_total{operation=\"create_dns\",result=\"failure\"} 2\n"
+        ));
+        assert!(text.contains(
+            "rustref_cloudflare_requests_total{operation=\"list_dns\",result=\"success\"} 1\n"
+        ));
+    }
+
+    #[test]
+    fn top_keys_by_hits() {
+        let counter = HitCounter::new();
+        for key in &["cook", "std", "cook", "book", "std", "cook"] {
+            counter.hit(key);
+        }
+        counter.miss("nope");
+        assert_eq!(counter.hits("cook"), 3);
+        assert_eq!(counter.hits("nope"), 0);
+
+        let top = counter.top(2);
+        let keys: Vec<(&str, usize)> = top.hits.iter().map(|k| (k.key.as_str(), k.hits)).collect();
+        assert_eq!(keys, vec![("cook", 3), ("std", 2)]);
+        assert_eq!(
+            top.misses,
+            vec![KeyHits {
+                key: "nope".to_string(),
+                hits: 1,
+            }]
+        );
+    }
+
+    #[test]
+    fn misses_capped() {
+        let counter = HitCounter::new();
+        for i in 0..MAX_TRACKED_MISSES + 10 {
+            counter.miss(&format!("key{}", i));
+        }
+        counter.miss("key0");
+        assert_eq!(counter.misses("key0"), 2);
+        assert_eq!(counter.misses(&format!("key{}", MAX_TRACKED_MISSES)), 0);
+        assert_eq!(counter.all_misses().len(), MAX_TRACKED_MISSES);
+        let top = counter.top(MAX_TRACKED_MISSES * 2);
+        assert_eq!(top.misses.len(), MAX_TRACKED_MISSES);
+        assert_eq!(top.misses[0].key, "key0");
+        assert_eq!(top.misses[0].hits, 2);
+    }
+
+    #[test]
+    fn bucket_bounds_from_list() {
+        assert_eq!(parse_bounds("5, 50,500"), Some(vec![5, 50, 500]));
+        assert_eq!(parse_bounds("5,fast"), None);
+        assert_eq!(parse_bounds(""), None);
+    }
+}
@@ -0,0 +1,18 @@
+use rocket::request::{self, FromRequest, Request};
+use rocket::Outcome;
+
+/// Whether the most preferred media type in the request's `Accept` header is JSON.
+/// Requests without an `Accept` header don't prefer it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PrefersJson(pub bool);
+
+impl<'a, 'r> FromRequest<'a, 'r> for PrefersJson {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<PrefersJson, ()> {
+        let json = request
+            .accept()
+            .map_or(false, |accept| accept.preferred().media_type().is_json());
+        Outcome::Success(PrefersJson(json))
+    }
+}
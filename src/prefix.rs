@@ -0,0 +1,29 @@
+use {read_redirects, RedirectMap};
+
+use rocket::request::{self, FromRequest, Request};
+use rocket::{Outcome, State};
+
+/// Where the request path goes by the config's `redirect_prefix_map`, e.g.
+/// `https://doc.rust-lang.org/book/ch01-00-getting-started.html` for
+/// `/book/ch01-00-getting-started.html`.
+///
+/// Forwards if no prefix matches the path.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrefixRedirect(pub String);
+
+impl<'a, 'r> FromRequest<'a, 'r> for PrefixRedirect {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<PrefixRedirect, ()> {
+        let redirs = match request.guard::<State<RedirectMap>>() {
+            Outcome::Success(redirs) => redirs,
+            _ => return Outcome::Forward(()),
+        };
+        let data = read_redirects(&redirs);
+        let path = request.uri().path();
+        match data.meta.as_ref().and_then(|meta| meta.prefix_url(path)) {
+            Some(url) => Outcome::Success(PrefixRedirect(url)),
+            None => Outcome::Forward(()),
+        }
+    }
+}
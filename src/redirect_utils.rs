@@ -1,253 +1,4214 @@
-use {CloudflareApi, Error, RedirectMap, Result};
+use {DnsApi, Error, RedirectData, RedirectMap, RedirectStorage, Result, StatusMap, GH_REPO};
 
-use cloudflare;
-use cloudflare::zones::dns;
+use accept_language::AcceptLanguage;
+use dns_provider::{CnameRecord, DnsProvider};
 use errors::RedirectError;
+use github_event::Delivery;
+use metrics::CloudflareMetrics;
+use request_id;
+use responders::CachedRedirect;
+use store;
+use tls;
+use base64;
+use dotenv;
+use serde_json;
+use sha2::{Digest, Sha256};
 use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
+use rocket::http::Status;
 use rocket::State;
 use reqwest;
+use reqwest::header::{Headers, Location, UserAgent};
+use reqwest::RedirectPolicy;
+use serde::de::{self, Deserialize, Deserializer};
 use toml;
 
 use std;
-use std::collections::HashMap;
-use std::fs::File;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::{self, File};
 use std::io::prelude::*;
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, SystemTime};
 
+#[derive(Deserialize, Serialize)]
+pub struct TomlConfig {
+    /// Other config files whose redirects are merged into this one, relative to this file
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<ConfigMeta>,
+    #[serde(default)]
+    pub redirect: Vec<SiteRedirect>,
+    /// Concise `[redirects]` table keyed by `short`, merged into `redirect` when parsing
+    #[serde(default, skip_serializing)]
+    pub redirects: BTreeMap<String, TableRedirect>,
+}
+
+/// A value of the `[redirects]` table: just the URL (`std = "https://..."`), or a table
+/// with the fields of a `[[redirect]]` except `short`, which is the key
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum TableRedirect {
+    Url(String),
+    Table(toml::value::Table),
+}
+
+impl TableRedirect {
+    /// The `SiteRedirect` for the key `short`. A `short` inside the table is overridden.
+    fn into_site_redirect(self, short: String) -> Result<SiteRedirect> {
+        let mut table = match self {
+            TableRedirect::Url(url) => {
+                let mut table = toml::value::Table::new();
+                table.insert("url".to_string(), toml::Value::String(url));
+                table
+            }
+            TableRedirect::Table(table) => table,
+        };
+        table.insert("short".to_string(), toml::Value::String(short));
+        Ok(toml::Value::Table(table).try_into()?)
+    }
+}
+
+/// Newest version of the config format this server understands
+pub const SUPPORTED_CONFIG_VERSION: u32 = 1;
+
+/// Optional `[meta]` table at the top of redirects.toml
+#[derive(Debug, Clone, Default, Deserialize, Serialize, Eq, PartialEq)]
+pub struct ConfigMeta {
+    /// Version of the config format, see `SUPPORTED_CONFIG_VERSION`
+    pub version: u32,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub maintainers: Vec<String>,
+    /// Where unknown keys are redirected instead of getting a 404, with `{key}` replaced by
+    /// the key, e.g. `https://doc.rust-lang.org/std/?search={key}`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fallback_search_url: Option<String>,
+    /// Path prefixes redirected on the apex host,
+    /// e.g. `"/book" = "https://doc.rust-lang.org/book"`.
+    /// Tables have to come after plain values when writing TOML, so keep this last.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub redirect_prefix_map: BTreeMap<String, String>,
+}
+
+impl ConfigMeta {
+    /// The `fallback_search_url` for `key`, if one is configured
+    pub fn search_url(&self, key: &str) -> Option<String> {
+        self.fallback_search_url
+            .as_ref()
+            .map(|url| url.replace("{key}", &encode_query_value(key)))
+    }
+
+    /// Where `path` goes by the longest prefix in `redirect_prefix_map` that matches whole
+    /// segments of it, with the rest of the path appended to the prefix's URL
+    pub fn prefix_url(&self, path: &str) -> Option<String> {
+        let path = path.trim_matches('/');
+        self.redirect_prefix_map
+            .iter()
+            .filter_map(|(prefix, url)| {
+                let prefix = prefix.trim_matches('/');
+                if prefix.is_empty() || !path.starts_with(prefix) {
+                    return None;
+                }
+                let rest = &path[prefix.len()..];
+                if rest.is_empty() || rest.starts_with('/') {
+                    Some((prefix.len(), url, rest))
+                } else {
+                    None
+                }
+            })
+            .max_by_key(|&(len, _, _)| len)
+            .map(|(_, url, rest)| join_url(url, rest))
+    }
+}
+
+/// Category used for redirects that don't specify one
+pub const DEFAULT_CATEGORY: &str = "Other";
+
+/// How long permanent redirects may be cached when `cache_seconds` isn't set: 1 day
+pub const DEFAULT_PERMANENT_CACHE_SECONDS: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, Deserialize, Serialize, Eq, PartialEq, Ord, PartialOrd)]
+pub struct SiteRedirect {
+    #[serde(deserialize_with = "trimmed")]
+    pub short: String,
+    #[serde(deserialize_with = "trimmed")]
+    pub url: String,
+    pub category: Option<String>,
+    /// Short explanation of where the redirect goes, shown on the index page
+    pub description: Option<String>,
+    /// Send a 301 instead of a 302
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub permanent: bool,
+    /// Send a 307 (308 if permanent) instead, so clients keep the request method and body
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub preserve_method: bool,
+    /// Let search engines index the redirect, it's sent with `X-Robots-Tag: noindex` otherwise
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub indexable: bool,
+    /// Send this 3xx status, overriding `permanent` and `preserve_method`
+    pub status: Option<u16>,
+    /// How long clients may cache a permanent redirect
+    pub cache_seconds: Option<u64>,
+    /// Leading part of the incoming path to drop before appending it to `url`
+    pub strip_prefix: Option<String>,
+    /// Statuses besides 2xx that pass the URL check, like `403` or `"401-403"`, for
+    /// targets that answer the checker with an error but work in a browser
+    #[serde(
+        default,
+        deserialize_with = "status_entries",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub accept_status: Vec<String>,
+    /// Set to `false` to keep a redirect in the config without serving it or creating its
+    /// CNAME. Disabled redirects are still listed on the index page, and their URL isn't checked.
+    #[serde(default = "enabled_default", skip_serializing_if = "is_true")]
+    pub enabled: bool,
+    /// Translated versions of `url`, keyed by language tag (`ja`, `zh-CN`).
+    /// Tables have to come after plain values when writing TOML, so keep this last.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub localized: BTreeMap<String, String>,
+}
+
+impl Default for SiteRedirect {
+    fn default() -> SiteRedirect {
+        SiteRedirect {
+            short: String::new(),
+            url: String::new(),
+            category: None,
+            description: None,
+            permanent: false,
+            preserve_method: false,
+            indexable: false,
+            status: None,
+            cache_seconds: None,
+            strip_prefix: None,
+            accept_status: Vec::new(),
+            enabled: true,
+            localized: BTreeMap::new(),
+        }
+    }
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
+fn is_true(b: &bool) -> bool {
+    *b
+}
+
+fn enabled_default() -> bool {
+    true
+}
+
+/// An `accept_status` entry as written in the config: a status like `403`, or a string with
+/// a status or a range like `"401-403"`
 #[derive(Deserialize)]
-struct TomlConfig {
-    redirect: Vec<SiteRedirect>,
+#[serde(untagged)]
+enum StatusEntry {
+    Status(u64),
+    Text(String),
+}
+
+/// Deserialize `accept_status` entries as strings, so `403` and `"403"` mean the same.
+/// Whether they are statuses is checked with the rest of the config, see `status_range`.
+fn status_entries<'de, D>(deserializer: D) -> std::result::Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let entries = Vec::<StatusEntry>::deserialize(deserializer)?;
+    Ok(entries
+        .into_iter()
+        .map(|entry| match entry {
+            StatusEntry::Status(status) => status.to_string(),
+            StatusEntry::Text(text) => text,
+        })
+        .collect())
+}
+
+/// Deserialize a string with leading and trailing whitespace removed, so a stray space in
+/// redirects.toml can't end up in a DNS label. Strings that are empty after trimming fail.
+fn trimmed<'de, D>(deserializer: D) -> std::result::Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    let s = s.trim();
+    if s.is_empty() {
+        Err(de::Error::custom("must not be empty or only whitespace"))
+    } else {
+        Ok(s.to_string())
+    }
+}
+
+/// Where a redirect sends the client, as stored in the redirect map
+#[derive(Debug, Clone, Serialize, Eq, PartialEq)]
+pub struct RedirectTarget {
+    pub url: String,
+    pub permanent: bool,
+    pub preserve_method: bool,
+    pub indexable: bool,
+    pub status: Option<u16>,
+    pub description: Option<String>,
+    pub category: Option<String>,
+    pub cache_seconds: Option<u64>,
+    pub strip_prefix: Option<String>,
+    pub accept_status: Vec<String>,
+    pub localized: BTreeMap<String, String>,
+}
+
+impl<'a> From<&'a SiteRedirect> for RedirectTarget {
+    fn from(redir: &'a SiteRedirect) -> RedirectTarget {
+        RedirectTarget {
+            url: redir.url.clone(),
+            permanent: redir.permanent,
+            preserve_method: redir.preserve_method,
+            indexable: redir.indexable,
+            status: redir.status,
+            description: redir.description.clone(),
+            category: redir.category.clone(),
+            cache_seconds: redir.cache_seconds,
+            strip_prefix: redir.strip_prefix.clone(),
+            accept_status: redir.accept_status.clone(),
+            localized: redir.localized.clone(),
+        }
+    }
+}
+
+/// Default number of URLs checked at the same time during validation
+pub const DEFAULT_CHECK_CONCURRENCY: usize = 8;
+
+/// Shorts that redirects can't use, used when the `reserved_shorts` env var isn't set.
+/// A redirect named like one of the server's route prefixes could be shadowed by, or shadow,
+/// those routes, and www.rustref.com serves the index page.
+pub const DEFAULT_RESERVED_SHORTS: &[&str] =
+    &["admin", "api", "github", "metrics", "redirect", "www"];
+
+/// Settings for `verify_redirects_with`
+#[derive(Debug, Clone)]
+pub struct VerifyOptions {
+    /// Maximum number of URLs checked at the same time
+    pub concurrency: usize,
+    /// Treat URLs that redirect somewhere else as errors instead of warnings
+    pub fail_on_indirect: bool,
+    /// Follow redirects when checking a URL. Without following, a URL that answers with a
+    /// 3xx fails the check as an `InvalidPage`, so the final URL gets used instead.
+    pub follow_redirects: bool,
+    /// Hosts redirects may point at, like `github.com` or `*.rust-lang.org` for any
+    /// subdomain. Every host is allowed when this is empty.
+    pub allowed_hosts: Vec<String>,
+    /// Shorts that fail validation with `ReservedShort`
+    pub reserved_shorts: Vec<String>,
+}
+
+impl Default for VerifyOptions {
+    fn default() -> VerifyOptions {
+        VerifyOptions {
+            concurrency: DEFAULT_CHECK_CONCURRENCY,
+            fail_on_indirect: false,
+            follow_redirects: true,
+            allowed_hosts: Vec::new(),
+            reserved_shorts: DEFAULT_RESERVED_SHORTS.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+}
+
+impl VerifyOptions {
+    /// Read the options from env, using the defaults for anything not set.
+    /// `url_check_concurrency` sets the number of URLs checked at the same time,
+    /// `fail_on_indirect=true` makes URLs that redirect elsewhere fail validation,
+    /// `follow_url_redirects=false` fails them without following the redirect,
+    /// `allowed_hosts` is a comma separated host allowlist like `*.rust-lang.org,github.com`,
+    /// `reserved_shorts` a comma separated list replacing `DEFAULT_RESERVED_SHORTS`.
+    pub fn from_env() -> VerifyOptions {
+        let defaults = VerifyOptions::default();
+        VerifyOptions {
+            concurrency: dotenv::var("url_check_concurrency")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .filter(|&n| n > 0)
+                .unwrap_or(defaults.concurrency),
+            fail_on_indirect: dotenv::var("fail_on_indirect")
+                .map(|s| s == "true")
+                .unwrap_or(defaults.fail_on_indirect),
+            follow_redirects: dotenv::var("follow_url_redirects")
+                .map(|s| s != "false")
+                .unwrap_or(defaults.follow_redirects),
+            allowed_hosts: dotenv::var("allowed_hosts")
+                .map(|s| {
+                    s.split(',')
+                        .map(|host| host.trim().to_lowercase())
+                        .filter(|host| !host.is_empty())
+                        .collect()
+                })
+                .unwrap_or(defaults.allowed_hosts),
+            reserved_shorts: dotenv::var("reserved_shorts")
+                .map(|s| {
+                    s.split(',')
+                        .map(|short| short.trim().to_lowercase())
+                        .filter(|short| !short.is_empty())
+                        .collect()
+                })
+                .unwrap_or(defaults.reserved_shorts),
+        }
+    }
+
+    /// Whether redirects can't use `short`
+    pub fn is_reserved(&self, short: &str) -> bool {
+        self.reserved_shorts
+            .iter()
+            .any(|reserved| reserved.eq_ignore_ascii_case(short))
+    }
+
+    /// Whether `host` is in `allowed_hosts`, or the allowlist is empty
+    pub fn host_allowed(&self, host: &str) -> bool {
+        let host = host.to_lowercase();
+        self.allowed_hosts.is_empty() || self.allowed_hosts.iter().any(|allowed| {
+            if allowed.starts_with("*.") {
+                // `*.rust-lang.org` matches any subdomain, but not rust-lang.org itself
+                host.ends_with(&allowed[1..])
+            } else {
+                host == *allowed
+            }
+        })
+    }
+}
+
+/// How updates change the DNS records
+#[derive(Debug, Clone, Default)]
+pub struct DnsOptions {
+    /// Keep serving the old redirects if any DNS change fails
+    pub abort_on_errors: bool,
+    /// Delete the CNAMEs of redirects that are no longer in the config. Off by default, so a
+    /// config that lost redirects by mistake can't take their records down with it.
+    pub delete_removed: bool,
+}
+
+impl DnsOptions {
+    /// Read the options from env: `abort_on_dns_errors=true` and `delete_removed_cnames=true`
+    /// turn them on
+    pub fn from_env() -> DnsOptions {
+        DnsOptions {
+            abort_on_errors: dotenv::var("abort_on_dns_errors")
+                .map(|s| s == "true")
+                .unwrap_or(false),
+            delete_removed: dotenv::var("delete_removed_cnames")
+                .map(|s| s == "true")
+                .unwrap_or(false),
+        }
+    }
+}
+
+/// How often the background checker re-checks every redirect's URL: 6 hours
+pub const DEFAULT_RECHECK_SECONDS: u64 = 6 * 60 * 60;
+
+/// Interval of the background URL re-check, from env `url_recheck_seconds`.
+/// `None` if it was set to 0 to disable re-checking.
+pub fn recheck_interval_from_env() -> Option<Duration> {
+    let seconds = dotenv::var("url_recheck_seconds")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(DEFAULT_RECHECK_SECONDS);
+    if seconds == 0 {
+        None
+    } else {
+        Some(Duration::from_secs(seconds))
+    }
+}
+
+/// Outcome of the last reachability check of a redirect's URL
+#[derive(Debug, Clone, Serialize)]
+pub struct UrlStatus {
+    pub last_checked: SystemTime,
+    pub reachable: bool,
+    /// HTTP status code of the response, if there was one
+    pub status: Option<u16>,
+    /// Where the URL ended up after following redirects, if that's somewhere else
+    pub redirected_to: Option<String>,
+}
+
+impl RedirectTarget {
+    /// How long clients may cache this redirect. Temporary redirects are never cached.
+    pub fn max_age(&self) -> Option<Duration> {
+        let permanent = match self.status {
+            Some(status) => status == 301 || status == 308,
+            None => self.permanent,
+        };
+        if permanent {
+            let secs = self.cache_seconds.unwrap_or(DEFAULT_PERMANENT_CACHE_SECONDS);
+            Some(Duration::from_secs(secs))
+        } else {
+            None
+        }
+    }
+
+    /// The url to send a client to, picking a localized url based on their `Accept-Language`
+    pub fn url_for(&self, lang: &AcceptLanguage) -> &str {
+        lang.best_match(&self.localized).unwrap_or(&self.url)
+    }
+
+    /// `path` without `strip_prefix`, if it starts with that prefix.
+    /// Only whole segments match, so `old` is stripped from `old/foo.html` but not `older.html`.
+    pub fn strip_path<'a>(&self, path: &'a str) -> &'a str {
+        let prefix = match self.strip_prefix {
+            Some(ref prefix) => prefix.trim_matches('/'),
+            None => return path,
+        };
+        if prefix.is_empty() || !path.starts_with(prefix) {
+            return path;
+        }
+        let rest = &path[prefix.len()..];
+        if rest.is_empty() {
+            rest
+        } else if rest.starts_with('/') {
+            &rest[1..]
+        } else {
+            path
+        }
+    }
+
+    /// The target URL for `lang` with `path` appended, or just the URL if `path` is empty
+    pub fn url_with_path(&self, lang: &AcceptLanguage, path: &str) -> String {
+        join_url(self.url_for(lang), path)
+    }
+
+    /// Build the response redirecting to `url` with this target's settings
+    pub fn redirect_to(&self, url: &str) -> CachedRedirect {
+        let status = self.status.and_then(Status::from_code);
+        let redirect = match (status, self.max_age(), self.preserve_method) {
+            (Some(status), max_age, _) => CachedRedirect::with_status(url, status, max_age),
+            (None, Some(max_age), false) => CachedRedirect::moved(url, max_age),
+            (None, Some(max_age), true) => CachedRedirect::permanent(url, max_age),
+            (None, None, false) => CachedRedirect::found(url),
+            (None, None, true) => CachedRedirect::temporary(url),
+        };
+        if self.localized.is_empty() {
+            redirect
+        } else {
+            redirect.vary("Accept-Language")
+        }
+    }
+}
+
+/// What changed when the redirect map was updated
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct UpdateSummary {
+    /// Number of redirects after the update
+    pub redirects: usize,
+    /// Redirects that weren't in the old map
+    pub added: usize,
+    /// Redirects from the old map that are gone
+    pub removed: usize,
+    pub cnames_created: usize,
+    /// Records that were changed back to what rustref creates after drifting
+    pub cnames_updated: usize,
+    pub cnames_deleted: usize,
+    /// Number of DNS changes that failed
+    pub cloudflare_errors: usize,
+    /// What went wrong with each failed DNS change
+    pub dns_errors: Vec<String>,
+}
+
+impl UpdateSummary {
+    /// Count the redirects added and removed going from `old` to `new`
+    fn from_diff(old: &HashMap<String, RedirectTarget>, new: &[SiteRedirect]) -> UpdateSummary {
+        let added = new.iter().filter(|r| !old.contains_key(&r.short)).count();
+        let kept = new.iter().filter(|r| old.contains_key(&r.short)).count();
+        UpdateSummary {
+            redirects: new.len(),
+            added,
+            removed: old.len() - kept,
+            ..UpdateSummary::default()
+        }
+    }
+
+    /// Whether the redirects were updated, but some DNS changes failed
+    pub fn has_warnings(&self) -> bool {
+        self.cloudflare_errors > 0
+    }
+}
+
+/// Outcome of reloading the redirect config
+#[derive(Debug)]
+pub enum ConfigReload {
+    /// The config is the same as the one being served, so nothing was done
+    Unchanged,
+    Updated(UpdateSummary),
+}
+
+/// How many previous configs are kept for rollbacks when `config_history` isn't set
+pub const DEFAULT_CONFIG_HISTORY: usize = 5;
+
+/// A config that was being served, see `ConfigHistory`
+#[derive(Debug, Clone)]
+pub struct ConfigSnapshot {
+    pub redirects: Vec<SiteRedirect>,
+    pub meta: Option<ConfigMeta>,
+    pub commit_hash: String,
+    pub commit_url: String,
+}
+
+impl ConfigSnapshot {
+    pub fn of(data: &RedirectData) -> ConfigSnapshot {
+        ConfigSnapshot {
+            redirects: data.sorted_redirects().into_iter().cloned().collect(),
+            meta: data.meta.clone(),
+            commit_hash: data.commit_hash.clone(),
+            commit_url: data.commit_url.clone(),
+        }
+    }
+}
+
+/// The configs served before the last webhook updates, newest last, so a bad update can be
+/// rolled back with `POST /admin/rollback`. Only the last `capacity` are kept.
+#[derive(Debug)]
+pub struct ConfigHistory {
+    capacity: usize,
+    snapshots: Mutex<VecDeque<ConfigSnapshot>>,
+}
+
+impl ConfigHistory {
+    pub fn new(capacity: usize) -> ConfigHistory {
+        ConfigHistory {
+            capacity,
+            snapshots: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Keep the number of configs in the `config_history` env var, or
+    /// `DEFAULT_CONFIG_HISTORY`. `0` disables rollbacks.
+    pub fn from_env() -> ConfigHistory {
+        let capacity = dotenv::var("config_history")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(DEFAULT_CONFIG_HISTORY);
+        ConfigHistory::new(capacity)
+    }
+
+    /// Remember `snapshot`, forgetting the oldest one if the history is full
+    pub fn push(&self, snapshot: ConfigSnapshot) -> Result<()> {
+        if self.capacity == 0 {
+            return Ok(());
+        }
+        let mut snapshots = self.snapshots.lock()?;
+        if snapshots.len() == self.capacity {
+            snapshots.pop_front();
+        }
+        snapshots.push_back(snapshot);
+        Ok(())
+    }
+
+    /// Take the newest snapshot out of the history
+    pub fn pop(&self) -> Result<Option<ConfigSnapshot>> {
+        Ok(self.snapshots.lock()?.pop_back())
+    }
+}
+
+pub fn update_redirect_map(
+    redirs: State<RedirectMap>,
+    statuses: State<StatusMap>,
+    dns: State<DnsApi>,
+    cf_metrics: State<CloudflareMetrics>,
+    history: State<ConfigHistory>,
+    store: State<RedirectStorage>,
+    delivery: &Delivery,
+) -> Result<ConfigReload> {
+    // download new redirect config from github
+    println!("{} downloading updated redirect file...", delivery);
+    // every file comes with its blob SHA, so the SHAs remembered as applied are the ones of
+    // the content that was loaded. Each file is only downloaded once: the config is loaded
+    // here to learn the SHAs of its includes, and again from `downloaded` by the reload.
+    let downloaded = RefCell::new(BTreeMap::new());
+    let download = |path: &Path| -> Result<String> {
+        if let Some(blob) = downloaded.borrow().get(path) {
+            return Ok(blob.content.clone());
+        }
+        let blob = download_file(path, delivery)?;
+        let content = blob.content.clone();
+        downloaded.borrow_mut().insert(path.to_path_buf(), blob);
+        Ok(content)
+    };
+    load_config(Path::new("redirects.toml"), &download)?;
+    let blobs = blob_shas(&downloaded.borrow());
+    let dns_options = DnsOptions::from_env();
+    skip_applied_blobs(&redirs, blobs, delivery, || {
+        reload_config(
+            &redirs,
+            &statuses,
+            dns.inner().as_ref(),
+            &cf_metrics,
+            &store,
+            &history,
+            delivery,
+            &download,
+            &dns_options,
+        )
+    })
+}
+
+/// Download the config file at `path` in the repository with its blob SHA. Falls back to
+/// its raw URL, without a SHA, if the contents API fails.
+fn download_file(path: &Path, delivery: &Delivery) -> Result<ConfigBlob> {
+    let path = path.to_string_lossy();
+    match download_blob(&GH_REPO.contents_url(&path)) {
+        Ok(blob) => Ok(blob),
+        Err(e) => {
+            println!("{} downloading the {} blob failed: {}", delivery, path, e);
+            Ok(ConfigBlob {
+                sha: None,
+                content: download_config(&GH_REPO.raw_url(&path))?,
+            })
+        }
+    }
+}
+
+/// The blob SHAs of the `downloaded` config files by path, or `None` if a file has none
+fn blob_shas(downloaded: &BTreeMap<PathBuf, ConfigBlob>) -> Option<BTreeMap<String, String>> {
+    downloaded
+        .iter()
+        .map(|(path, blob)| {
+            let sha = blob.sha.clone()?;
+            Some((path.to_string_lossy().into_owned(), sha))
+        })
+        .collect()
+}
+
+/// `ConfigReload::Unchanged` without running `reload` if `blobs`, the Github blob SHAs of
+/// redirects.toml and its includes by path, are the ones the current redirects were loaded
+/// from. Otherwise runs `reload` and remembers `blobs` as applied if it succeeds.
+fn skip_applied_blobs<F>(
+    redirs: &RedirectMap,
+    blobs: Option<BTreeMap<String, String>>,
+    delivery: &Delivery,
+    reload: F,
+) -> Result<ConfigReload>
+where
+    F: FnOnce() -> Result<ConfigReload>,
+{
+    if let Some(ref shas) = blobs {
+        if redirs.read()?.config_blobs.as_ref() == Some(shas) {
+            println!(
+                "{} the blobs of all {} config files are already applied, skipping",
+                delivery,
+                shas.len()
+            );
+            return Ok(ConfigReload::Unchanged);
+        }
+    }
+    let reload = reload()?;
+    redirs.write()?.config_blobs = blobs;
+    Ok(reload)
+}
+
+/// Serve the newest config in `history` again, updating the CNAMEs with `dns` like a
+/// webhook update would. Failed DNS changes are listed in the summary.
+/// Returns `None` if there is no previous config to go back to.
+pub fn rollback<D: DnsProvider>(
+    redirs: &RedirectMap,
+    history: &ConfigHistory,
+    dns: Option<&Mutex<D>>,
+    cf_metrics: &CloudflareMetrics,
+    dns_options: &DnsOptions,
+) -> Result<Option<UpdateSummary>> {
+    let snapshot = match history.pop()? {
+        Some(snapshot) => snapshot,
+        None => return Ok(None),
+    };
+    let delivery = Delivery(Some("rollback".to_string()));
+    println!("{} rolling back to {} redirects", delivery, snapshot.redirects.len());
+    let summary = dns_changes(
+        redirs,
+        dns,
+        cf_metrics,
+        &snapshot.redirects,
+        dns_options.delete_removed,
+        &delivery,
+    )?;
+
+    let data = &mut *redirs.write()?;
+    data.set_redirects(&snapshot.redirects);
+    data.meta = snapshot.meta;
+    data.commit_hash = snapshot.commit_hash;
+    data.commit_url = snapshot.commit_url;
+    Ok(Some(summary))
+}
+
+/// Load `redirects.toml` and its includes with `read`, then apply it: verify the
+/// redirects, update the CNAMEs with `dns`, swap the redirect map, remembering the config
+/// it replaced in `history`, and save the config to `store`. The CNAMEs of removed
+/// redirects are only deleted if `dns_options.delete_removed` is set.
+/// Returns `ConfigReload::Unchanged` without any checks or DNS calls if the config
+/// is the same as the one being served.
+#[cfg_attr(feature = "cargo-clippy", allow(too_many_arguments))]
+fn reload_config<D, F>(
+    redirs: &RedirectMap,
+    statuses: &StatusMap,
+    dns: Option<&Mutex<D>>,
+    cf_metrics: &CloudflareMetrics,
+    store: &RedirectStorage,
+    history: &ConfigHistory,
+    delivery: &Delivery,
+    read: &F,
+    dns_options: &DnsOptions,
+) -> Result<ConfigReload>
+where
+    D: DnsProvider,
+    F: Fn(&Path) -> Result<String>,
+{
+    let (config, files) = load_config(Path::new("redirects.toml"), read)?;
+    check_not_empty(&config.redirect, allow_empty_config_from_env())?;
+    if config_unchanged(&*redirs.read()?, &config) {
+        println!("{} config is unchanged, skipping update", delivery);
+        // the redirects may have moved between files
+        redirs.write()?.config_files = config_paths(&files);
+        return Ok(ConfigReload::Unchanged);
+    }
+    let mut new_redirects = config.redirect;
+    {
+        #[cfg(feature = "async-check")]
+        let verify = verify_redirects_async;
+        #[cfg(not(feature = "async-check"))]
+        let verify = verify_redirects_with;
+
+        // check without holding the lock, so /api/status isn't blocked by the network
+        let options = VerifyOptions::from_env();
+        let mut checked = HashMap::new();
+        let verified = verify(&mut new_redirects, &options, &mut checked);
+        statuses.write()?.extend(checked);
+        with_errors(verified, prefix_map_errors(config.meta.as_ref(), &options))?;
+    }
+
+    // before setting the new redirects, make sure that the DNS was updated successfully
+    let summary = dns_changes(
+        redirs,
+        dns,
+        cf_metrics,
+        &new_redirects,
+        dns_options.delete_removed,
+        delivery,
+    )?;
+    if dns_options.abort_on_errors && summary.has_warnings() {
+        println!("{} DNS changes failed, keeping the current redirects", delivery);
+        return Err(Error::DnsUpdate(summary.dns_errors));
+    }
+
+    // update the map, then unlock asap
+    {
+        let data = &mut *redirs.write()?;
+        history.push(ConfigSnapshot::of(data))?;
+        data.set_redirects(&new_redirects);
+        data.meta = config.meta.clone();
+        data.config_files = config_paths(&files);
+        println!("{} map: {:#?}", delivery, &data.map);
+    }
+
+    // save the config and its includes so next server restart we get the latest config
+    store::update(store, |store| {
+        store.replace_files(&files, &new_redirects, config.meta.as_ref())
+    })?;
+    Ok(ConfigReload::Updated(summary))
+}
+
+/// Summary of going from the served redirects to `new_redirects`, after creating the CNAMEs
+/// that changed with `dns` if there is one. The CNAMEs of removed redirects are only
+/// deleted if `delete_removed` is set.
+fn dns_changes<D: DnsProvider>(
+    redirs: &RedirectMap,
+    dns: Option<&Mutex<D>>,
+    cf_metrics: &CloudflareMetrics,
+    new_redirects: &[SiteRedirect],
+    delete_removed: bool,
+    delivery: &Delivery,
+) -> Result<UpdateSummary> {
+    // disabled redirects aren't served, so to the map and the DNS they are removed
+    let enabled: Vec<SiteRedirect> = new_redirects.iter().filter(|r| r.enabled).cloned().collect();
+    let mut summary = UpdateSummary::from_diff(&redirs.read()?.map, &enabled);
+    let removed: Vec<String> = if delete_removed {
+        redirs
+            .read()?
+            .map
+            .keys()
+            .filter(|short| !enabled.iter().any(|r| r.short == **short))
+            .cloned()
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    match dns {
+        Some(dns) => update_dns(dns, cf_metrics, &enabled, &removed, &mut summary, delivery)?,
+        None => println!(
+            "{} Warning: no DNS provider configured, skipping DNS updates",
+            delivery
+        ),
+    }
+    Ok(summary)
+}
+
+/// Whether `config` is the one `data` is already serving. `config.redirect` must be
+/// sorted, as `load_config` leaves it.
+fn config_unchanged(data: &RedirectData, config: &TomlConfig) -> bool {
+    data.meta == config.meta && data.sorted_redirects().into_iter().eq(config.redirect.iter())
+}
+
+/// Check the URL of every current redirect again, using at most `options.concurrency`
+/// connections at once, and record the results in `statuses`.
+/// Returns the shorts whose URL was reachable (or never checked) before and isn't anymore.
+pub fn recheck_urls(
+    redirs: &RedirectMap,
+    statuses: &StatusMap,
+    options: &VerifyOptions,
+) -> Result<Vec<String>> {
+    // copy the urls out so the redirect map isn't locked while checking
+    let targets: Vec<(String, String, Vec<String>)> = redirs
+        .read()?
+        .map
+        .iter()
+        .map(|(short, target)| (short.clone(), target.url.clone(), target.accept_status.clone()))
+        .collect();
+
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(options.concurrency)
+        .build()?;
+    let checked: Vec<(String, UrlStatus)> = pool.install(|| {
+        targets
+            .par_iter()
+            .map(|&(ref short, ref url, ref accepted)| {
+                let url = url_to_check(url);
+                let checked = check_url_status(&url, options.follow_redirects);
+                (short.clone(), accepting(accepted, &url, checked).0)
+            })
+            .collect()
+    });
+
+    let statuses = &mut *statuses.write()?;
+    let mut newly_unreachable = Vec::new();
+    for (short, status) in checked {
+        let was_reachable = statuses.get(&short).map_or(true, |s| s.reachable);
+        if was_reachable && !status.reachable {
+            newly_unreachable.push(short.clone());
+        }
+        statuses.insert(short, status);
+    }
+    newly_unreachable.sort();
+    Ok(newly_unreachable)
+}
+
+/// Start a thread that calls `recheck_urls` every `interval`, logging redirects
+/// that became unreachable
+pub fn spawn_rechecker(
+    interval: Duration,
+    redirs: RedirectMap,
+    statuses: StatusMap,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        match recheck_urls(&redirs, &statuses, &VerifyOptions::from_env()) {
+            Ok(ref dead) if dead.is_empty() => (),
+            Ok(dead) => {
+                for short in dead {
+                    println!("Warning: the URL of redirect `{}` is no longer reachable", short);
+                }
+            }
+            Err(e) => println!("error re-checking redirect URLs: {}", e),
+        }
+    })
+}
+
+/// `User-Agent` of outbound requests when the `user_agent` env var isn't set, so target
+/// sites can tell the URL checks apart from other traffic
+pub const DEFAULT_USER_AGENT: &str =
+    concat!("rustref-redirect-checker/", env!("CARGO_PKG_VERSION"));
+
+lazy_static! {
+    /// `User-Agent` sent with URL checks and config downloads, from the `user_agent` env var
+    static ref USER_AGENT: String =
+        dotenv::var("user_agent").unwrap_or_else(|_| DEFAULT_USER_AGENT.to_string());
+    /// Client for outbound requests that follow up to 10 redirects
+    static ref FOLLOWING_CLIENT: reqwest::Client = build_client(RedirectPolicy::default());
+    /// Client for URL checks that don't follow redirects
+    static ref DIRECT_CLIENT: reqwest::Client = build_client(RedirectPolicy::none());
+}
+
+/// Headers sent with every outbound request
+fn default_headers() -> Headers {
+    let mut headers = Headers::new();
+    headers.set(UserAgent::new(USER_AGENT.clone()));
+    headers
+}
+
+/// Client with the default headers and redirect `policy`. Only fails if the TLS backend
+/// can't be initialized, in which case no outbound request could be made anyway.
+fn build_client(policy: RedirectPolicy) -> reqwest::Client {
+    reqwest::Client::builder()
+        .redirect(policy)
+        .default_headers(default_headers())
+        .build()
+        .expect("could not build the HTTP client")
+}
+
+/// The shared client for outbound requests, following up to 10 redirects or none at all.
+/// Built on first use and reused, so connections are pooled between requests.
+pub fn http_client(follow_redirects: bool) -> &'static reqwest::Client {
+    if follow_redirects {
+        &FOLLOWING_CLIENT
+    } else {
+        &DIRECT_CLIENT
+    }
+}
+
+/// Most redirects `probe_hops` walks, as many as the following client follows
+const MAX_REDIRECT_HOPS: usize = 10;
+
+/// Probe the TLS version of every host `url` was redirected through on its way to
+/// `final_url`, see `tls::probe_min_version`. reqwest follows redirects without telling
+/// which hosts it passed, so they are walked again without following them.
+fn probe_hops(url: &str, final_url: &reqwest::Url) -> std::result::Result<(), RedirectError> {
+    let mut hop = match reqwest::Url::parse(url) {
+        Ok(hop) => hop,
+        Err(_) => return Ok(()),
+    };
+    for _ in 0..MAX_REDIRECT_HOPS {
+        if hop == *final_url {
+            break;
+        }
+        let location = match http_client(false).get(hop.clone()).send() {
+            Ok(resp) => resp.headers().get::<Location>().map(|l| l.to_string()),
+            Err(_) => None,
+        };
+        hop = match location.and_then(|location| hop.join(&location).ok()) {
+            Some(next) => next,
+            None => break,
+        };
+        tls::probe_min_version(hop.as_str())?;
+    }
+    Ok(())
+}
+
+/// Download the redirect config at `url`, failing if the response isn't a 2xx
+fn download_config(url: &str) -> Result<String> {
+    tls::probe_min_version(url)?;
+    let mut resp = http_client(true).get(url).send()?;
+    probe_hops(url, resp.url())?;
+    if !resp.status().is_success() {
+        return Err(Error::ConfigDownload(
+            url.to_string(),
+            resp.status().as_u16(),
+        ));
+    }
+    Ok(resp.text()?)
+}
+
+/// A file downloaded from the Github contents API, so its content is known to be the
+/// version of the blob SHA next to it
+#[derive(Debug, Clone, PartialEq)]
+struct ConfigBlob {
+    /// Blob SHA of the file, `None` if the response has none
+    sha: Option<String>,
+    content: String,
+}
+
+/// Download the file at `url` in the Github contents API, decoding its base64 `content`
+fn download_blob(url: &str) -> Result<ConfigBlob> {
+    tls::probe_min_version(url)?;
+    let mut resp = http_client(true).get(url).send()?;
+    probe_hops(url, resp.url())?;
+    if !resp.status().is_success() {
+        return Err(Error::ConfigDownload(
+            url.to_string(),
+            resp.status().as_u16(),
+        ));
+    }
+    let contents: serde_json::Value = resp.json()?;
+    // Github wraps the base64 in lines of 60 characters
+    let encoded: String = contents["content"]
+        .as_str()
+        .ok_or_else(|| Error::ConfigContent(url.to_string()))?
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect();
+    let content = base64::decode(&encoded)
+        .ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .ok_or_else(|| Error::ConfigContent(url.to_string()))?;
+    Ok(ConfigBlob {
+        sha: contents["sha"].as_str().map(String::from),
+        content,
+    })
+}
+
+/// Cloudflare zone the redirect CNAMEs are created in. Every CNAME points at the apex of
+/// the zone, which is proxied to this server.
+pub const ZONE: &str = "rustref.com";
+
+/// Name of the CNAME record for the redirect `short`
+fn cname_name(short: &str) -> String {
+    format!("{}.{}", short, ZONE)
+}
+
+/// The CNAME records the enabled redirects need, in BIND zone file format, sorted by name.
+/// Wildcard redirects have no record of their own.
+/// Lets a zone be previewed or set up by hand without touching the DNS provider.
+pub fn zonefile(redirects: &[SiteRedirect]) -> String {
+    let mut names: Vec<String> = redirects
+        .iter()
+        .filter(|r| r.enabled && !is_wildcard_short(&r.short))
+        .map(|r| cname_name(&r.short))
+        .collect();
+    names.sort();
+    let mut zonefile = format!("; CNAME records for the {} redirects\n", ZONE);
+    for name in names {
+        zonefile.push_str(&format!("{}.\tIN\tCNAME\t{}.\n", name, ZONE));
+    }
+    zonefile
+}
+
+/// A sitemap listing the index page and the host of every enabled redirect, sorted
+pub fn sitemap(redirects: &[&SiteRedirect]) -> String {
+    let mut hosts: Vec<String> = redirects
+        .iter()
+        .filter(|r| r.enabled && !is_wildcard_short(&r.short))
+        .map(|r| cname_name(&r.short))
+        .collect();
+    hosts.sort();
+    hosts.insert(0, ZONE.to_string());
+    let mut sitemap = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n",
+    );
+    for host in hosts {
+        sitemap.push_str(&format!("  <url><loc>https://{}/</loc></url>\n", xml_escape(&host)));
+    }
+    sitemap.push_str("</urlset>\n");
+    sitemap
+}
+
+/// Escape the characters with a special meaning in XML text
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
 }
 
-#[derive(Debug, Deserialize, Serialize, Eq, PartialEq, Ord, PartialOrd)]
-struct SiteRedirect {
-    short: String,
-    url: String,
-}
+/// robots.txt keeping crawlers away from the `/redirect/` routes, pointing them at the sitemap
+pub fn robots_txt() -> String {
+    format!(
+        "User-agent: *\nDisallow: /redirect/\nSitemap: https://{}/sitemap.xml\n",
+        ZONE
+    )
+}
+
+/// `Error::MissingApex` unless one of the A/AAAA/CNAME `record_names` is the apex `zone`
+pub fn verify_apex(zone: &str, record_names: &[String]) -> Result<()> {
+    let zone = zone.trim_right_matches('.');
+    if record_names
+        .iter()
+        .any(|name| name.trim_right_matches('.').eq_ignore_ascii_case(zone))
+    {
+        Ok(())
+    } else {
+        Err(Error::MissingApex(zone.to_string()))
+    }
+}
+
+/// What to do at startup about a DNS provider that can't reach the zone
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DnsCheck {
+    /// Exit, so bad credentials are caught at boot instead of on the first webhook
+    Fail,
+    /// Log the problem and keep going
+    Warn,
+    /// Don't contact the provider, e.g. for offline development
+    Skip,
+}
+
+impl DnsCheck {
+    /// From env `dns_startup_check`: `fail`, `warn` or `off` (the default), so the server
+    /// only contacts the provider at boot when asked to
+    pub fn from_env() -> DnsCheck {
+        match dotenv::var("dns_startup_check").as_ref().map(|s| s.as_str()) {
+            Ok("fail") => DnsCheck::Fail,
+            Ok("warn") => DnsCheck::Warn,
+            _ => DnsCheck::Skip,
+        }
+    }
+}
+
+/// Look up `ZONE` with `provider` to make sure its credentials work, and check that the
+/// zone has the apex record the redirect CNAMEs point at.
+/// Errors only with `DnsCheck::Fail`; with `Warn` the error is just logged.
+pub fn check_dns_access<D: DnsProvider + ?Sized>(provider: &D, check: DnsCheck) -> Result<()> {
+    if check == DnsCheck::Skip {
+        return Ok(());
+    }
+    let checked = provider.zone_id(ZONE).and_then(|zone_id| {
+        provider.check_apex(&zone_id, ZONE)?;
+        Ok(zone_id)
+    });
+    match checked {
+        Ok(zone_id) => {
+            println!("DNS zone {} is accessible (id {})", ZONE, zone_id);
+            Ok(())
+        }
+        Err(ref e) if check == DnsCheck::Warn => {
+            println!("Warning: DNS zone {} check failed, DNS updates may fail: {}", ZONE, e);
+            Ok(())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Create the CNAME record for a single redirect
+pub fn create_cname<D: DnsProvider>(dns: &Mutex<D>, short: &str) -> Result<()> {
+    let provider = dns.lock()?;
+    let zone_id = provider.zone_id(ZONE)?;
+    provider.create_cname(&zone_id, &cname_name(short), ZONE)?;
+    Ok(())
+}
+
+/// Delete the CNAME record of a single redirect, if there is one
+pub fn delete_cname<D: DnsProvider>(dns: &Mutex<D>, short: &str) -> Result<()> {
+    let provider = dns.lock()?;
+    let zone_id = provider.zone_id(ZONE)?;
+    let name = cname_name(short);
+    for record in provider.list_cnames(&zone_id)?.iter().filter(|r| r.name == name) {
+        provider.delete_cname(&zone_id, record)?;
+    }
+    provider.purge_cache(&zone_id)?;
+    Ok(())
+}
+
+/// Create CNAME records for any redirects that don't have one yet, correct the records that
+/// drifted from what would be created (see `drifted_cnames`) and delete the records
+/// of the `removed` shorts, then purge the cache.
+/// The number of records changed and failures are added to `summary`.
+/// Every API call is recorded in `metrics`.
+fn update_dns<D: DnsProvider>(
+    dns: &Mutex<D>,
+    metrics: &CloudflareMetrics,
+    redirects: &[SiteRedirect],
+    removed: &[String],
+    summary: &mut UpdateSummary,
+    delivery: &Delivery,
+) -> Result<()> {
+    // get current CNAME records:
+    let provider = dns.lock()?;
+    let zone_id = metrics.record("zone_id", || provider.zone_id(ZONE))?;
+    println!("{} zone id: {}", delivery, &zone_id);
+    let records = metrics.record("list_dns", || provider.list_cnames(&zone_id))?;
+    let cnames: Vec<String> = records.iter().map(|r| r.name.clone()).collect();
+
+    // create the CNAME records for new redirects, and delete the ones of removed redirects.
+    // failures are collected in the summary, the caller decides whether they abort the update
+    // a wildcard can't be part of a DNS label, wildcards are served through a `*` record
+    // set up by hand
+    for new_redir in redirects
+        .iter()
+        .filter(|r| !is_wildcard_short(&r.short) && !cnames.contains(&cname_name(&r.short)))
+    {
+        println!("{} new redirect: {:?}", delivery, new_redir);
+        let name = cname_name(&new_redir.short);
+        match metrics.record("create_dns", || provider.create_cname(&zone_id, &name, ZONE)) {
+            Ok(_) => summary.cnames_created += 1,
+            Err(e) => {
+                let message = format!("creating {}: {}", name, e);
+                println!("{} DNS error {}", delivery, message);
+                summary.cloudflare_errors += 1;
+                summary.dns_errors.push(message);
+            }
+        }
+    }
+    for record in drifted_cnames(&records, redirects, provider.proxied()) {
+        println!("{} correcting drifted record: {}", delivery, record.name);
+        match metrics.record("update_dns", || provider.update_cname(&zone_id, record, ZONE)) {
+            Ok(_) => summary.cnames_updated += 1,
+            Err(e) => {
+                let message = format!("updating {}: {}", record.name, e);
+                println!("{} DNS error {}", delivery, message);
+                summary.cloudflare_errors += 1;
+                summary.dns_errors.push(message);
+            }
+        }
+    }
+    for short in removed {
+        let name = cname_name(short);
+        if !cnames.contains(&name) {
+            continue;
+        }
+        println!("{} removed redirect: {}", delivery, short);
+        // the records were listed above, so they're deleted by ID without listing them again
+        for record in records.iter().filter(|r| r.name == name) {
+            match metrics.record("delete_dns", || provider.delete_cname(&zone_id, record)) {
+                Ok(_) => summary.cnames_deleted += 1,
+                Err(e) => {
+                    let message = format!("deleting {}: {}", name, e);
+                    println!("{} DNS error {}", delivery, message);
+                    summary.cloudflare_errors += 1;
+                    summary.dns_errors.push(message);
+                }
+            }
+        }
+    }
+
+    // clear the cache
+    metrics.record("purge_cache", || provider.purge_cache(&zone_id))?;
+
+    Ok(())
+}
+
+/// The `records` of `redirects` that differ from the records rustref creates, sorted by
+/// name: pointing somewhere other than the zone apex, or proxied differently than `proxied`
+/// if the provider has a proxy. Records that aren't for one of `redirects` are left alone.
+fn drifted_cnames<'a>(
+    records: &'a [CnameRecord],
+    redirects: &[SiteRedirect],
+    proxied: Option<bool>,
+) -> Vec<&'a CnameRecord> {
+    let names: Vec<String> = redirects
+        .iter()
+        .filter(|r| !is_wildcard_short(&r.short))
+        .map(|r| cname_name(&r.short))
+        .collect();
+    let mut drifted: Vec<&CnameRecord> = records
+        .iter()
+        .filter(|record| names.contains(&record.name))
+        .filter(|record| {
+            let content = record.content.trim_right_matches('.');
+            let proxy_differs = match (proxied, record.proxied) {
+                (Some(wanted), Some(actual)) => wanted != actual,
+                _ => false,
+            };
+            !content.eq_ignore_ascii_case(ZONE) || proxy_differs
+        })
+        .collect();
+    drifted.sort_by(|a, b| a.name.cmp(&b.name));
+    drifted
+}
+
+fn verify_redirects(redirects: &mut [SiteRedirect]) -> Result<()> {
+    verify_redirects_with(redirects, &VerifyOptions::from_env(), &mut HashMap::new())
+}
+
+/// Sort `redirects` and return a `DuplicateRule` error for every short used more than once
+fn duplicate_errors(redirects: &mut [SiteRedirect]) -> Vec<RedirectError> {
+    redirects.sort();
+    redirects
+        .windows(2)
+        .filter(|w| w[0].short == w[1].short)
+        .map(|w| RedirectError::DuplicateRule(w[0].short.clone()))
+        .collect()
+}
+
+/// Verify `redirects` using `options`, recording the result of each URL check in `statuses`
+fn verify_redirects_with(
+    redirects: &mut [SiteRedirect],
+    options: &VerifyOptions,
+    statuses: &mut HashMap<String, UrlStatus>,
+) -> Result<()> {
+    let mut errors = config_errors(redirects, options);
+
+    // verify URLs are valid syntactically, and that the URL is online.
+    // use a dedicated pool so only `options.concurrency` connections are open at once
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(options.concurrency)
+        .build()?;
+    let checked: Vec<(String, UrlStatus, Option<RedirectError>)> = pool.install(|| {
+        redirects
+            .par_iter()
+            .filter(|x| x.enabled && !missing_scheme(&x.url))
+            .map(|x| {
+                let url = url_to_check(&x.url);
+                let checked = check_url_status(&url, options.follow_redirects);
+                let (status, result) = accepting(&x.accept_status, &url, checked);
+                let error = match result {
+                    Ok(final_url) => indirect_error(&url, &final_url),
+                    Err(e) => Some(e),
+                };
+                (x.short.clone(), status, error)
+            })
+            .collect()
+    });
+    record_checks(checked, options, statuses, &mut errors);
+
+    if !errors.is_empty() {
+        Err(Error::RedirectErrors(errors))
+    } else {
+        Ok(())
+    }
+}
+
+/// Like `verify_redirects_with`, but checks the URLs with the async client on a single
+/// event loop instead of blocking a thread per request.
+/// At most `options.concurrency` requests are in flight at once.
+#[cfg(feature = "async-check")]
+fn verify_redirects_async(
+    redirects: &mut [SiteRedirect],
+    options: &VerifyOptions,
+    statuses: &mut HashMap<String, UrlStatus>,
+) -> Result<()> {
+    use futures::{stream, Future, Stream};
+    use reqwest::unstable::async::Client;
+    use tokio_core::reactor::Core;
+
+    let mut errors = config_errors(redirects, options);
+
+    let mut core = Core::new()?;
+    let policy = if options.follow_redirects {
+        RedirectPolicy::default()
+    } else {
+        RedirectPolicy::none()
+    };
+    let client = Client::builder()
+        .redirect(policy)
+        .default_headers(default_headers())
+        .build(&core.handle())?;
+
+    // the TLS probe blocks, so it runs on a pool before the event loop starts
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(options.concurrency)
+        .build()?;
+    let outdated: Vec<(String, UrlStatus, Option<RedirectError>)> = pool.install(|| {
+        redirects
+            .par_iter()
+            .filter(|x| x.enabled && !missing_scheme(&x.url))
+            .filter_map(|x| {
+                let url = url_to_check(&x.url);
+                let error = tls::probe_min_version(&url).err()?;
+                let (status, result) = url_status(&url, None, Err(error));
+                Some((x.short.clone(), status, result.err()))
+            })
+            .collect()
+    });
+    // scoped so the borrow of `outdated` ends before it is recorded
+    let checked = {
+        let checkable = |x: &SiteRedirect| {
+            x.enabled && !missing_scheme(&x.url) && !outdated.iter().any(|o| o.0 == x.short)
+        };
+        let checks = redirects.iter().filter(|x| checkable(x)).map(|x| {
+            let short = x.short.clone();
+            let url = url_to_check(&x.url);
+            let accepted = x.accept_status.clone();
+            client.get(&url).send().then(move |response| {
+                let (code, result) = match response {
+                    Ok(resp) => {
+                        let location = resp.headers().get::<Location>().map(|l| l.to_string());
+                        let location = location.as_ref().map(String::as_str);
+                        response_result(&url, resp.status(), resp.url(), location)
+                    }
+                    Err(_e) => (None, Err(RedirectError::BadUrl(url.clone()))),
+                };
+                let (status, result) = accepting(&accepted, &url, url_status(&url, code, result));
+                let error = match result {
+                    Ok(final_url) => indirect_error(&url, &final_url),
+                    Err(e) => Some(e),
+                };
+                Ok::<_, ()>((short, status, error))
+            })
+        });
+        core
+            .run(stream::iter_ok(checks).buffer_unordered(options.concurrency).collect())
+            .unwrap_or_else(|()| unreachable!("failed URL checks are returned as errors"))
+    };
+    // the event loop followed the redirects, so the hosts they passed are probed afterwards
+    let checked: Vec<(String, UrlStatus, Option<RedirectError>)> = pool.install(|| {
+        checked
+            .into_par_iter()
+            .map(|check| {
+                let redirect = redirects.iter().find(|r| r.short == check.0);
+                match redirect.map(|r| url_to_check(&r.url)) {
+                    Some(url) => probe_redirected(&url, check),
+                    None => check,
+                }
+            })
+            .collect()
+    });
+    record_checks(checked, options, statuses, &mut errors);
+    record_checks(outdated, options, statuses, &mut errors);
+
+    if !errors.is_empty() {
+        Err(Error::RedirectErrors(errors))
+    } else {
+        Ok(())
+    }
+}
+
+/// Probe the hosts the checked redirect to `url` passed on its way to the URL it was
+/// redirected to, failing the check if one of them fails the TLS probe
+#[cfg(feature = "async-check")]
+fn probe_redirected(
+    url: &str,
+    check: (String, UrlStatus, Option<RedirectError>),
+) -> (String, UrlStatus, Option<RedirectError>) {
+    let (short, mut status, error) = check;
+    let hops = status
+        .redirected_to
+        .as_ref()
+        .and_then(|to| reqwest::Url::parse(to).ok())
+        .map(|to| probe_hops(url, &to));
+    match hops {
+        Some(Err(e)) => {
+            status.reachable = false;
+            (short, status, Some(e))
+        }
+        _ => (short, status, error),
+    }
+}
+
+/// Problems with `redirects` that can be found without any network requests:
+/// reserved shorts, duplicate rules, and hosts that aren't allowed. Sorts `redirects`.
+fn config_errors(redirects: &mut [SiteRedirect], options: &VerifyOptions) -> Vec<RedirectError> {
+    // verify that no short collides with the server's own routes
+    let mut errors: Vec<RedirectError> = redirects
+        .iter()
+        .filter(|r| options.is_reserved(&r.short))
+        .map(|r| RedirectError::ReservedShort(r.short.clone()))
+        .collect();
+
+    // verify that we have no duplicate redirect rules
+    errors.extend(duplicate_errors(redirects));
+
+    // verify that status overrides are redirect statuses, and accepted statuses are statuses
+    errors.extend(status_errors(redirects));
+    errors.extend(accept_status_errors(redirects));
+
+    // verify that every target, including translations, has a scheme and is on an
+    // allowed host
+    for redirect in redirects.iter() {
+        let urls = Some(&redirect.url).into_iter().chain(redirect.localized.values());
+        errors.extend(urls.filter_map(|url| target_error(url, options)));
+    }
+    errors
+}
+
+/// `MissingScheme`, `WildcardInHost` or `DisallowedHost` if a redirect can't go to `url`
+fn target_error(url: &str, options: &VerifyOptions) -> Option<RedirectError> {
+    if missing_scheme(url) {
+        Some(RedirectError::MissingScheme(url.to_string()))
+    } else if wildcard_in_host(url) {
+        Some(RedirectError::WildcardInHost(url.to_string()))
+    } else if !url_allowed(url, options) {
+        Some(RedirectError::DisallowedHost(url.to_string()))
+    } else {
+        None
+    }
+}
+
+/// Problems with the `redirect_prefix_map` targets of `meta`, checked like redirect targets:
+/// each needs a scheme and an allowed host, and has to be reachable
+fn prefix_map_errors(meta: Option<&ConfigMeta>, options: &VerifyOptions) -> Vec<RedirectError> {
+    let mut errors = Vec::new();
+    let targets = meta.into_iter().flat_map(|meta| meta.redirect_prefix_map.values());
+    for url in targets {
+        if let Some(error) = target_error(url, options) {
+            errors.push(error);
+            continue;
+        }
+        match check_url_status(url, options.follow_redirects).1 {
+            Ok(final_url) => match indirect_error(url, &final_url) {
+                Some(error) if options.fail_on_indirect => errors.push(error),
+                Some(error) => println!("{}Warning: {}", request_id::log_prefix(), error),
+                None => (),
+            },
+            Err(error) => errors.push(error),
+        }
+    }
+    errors
+}
+
+/// `result` of verifying the redirects, failing with `errors` as well
+fn with_errors(result: Result<()>, errors: Vec<RedirectError>) -> Result<()> {
+    match result {
+        Ok(()) if errors.is_empty() => Ok(()),
+        Ok(()) => Err(Error::RedirectErrors(errors)),
+        Err(Error::RedirectErrors(mut redirect_errors)) => {
+            redirect_errors.extend(errors);
+            Err(Error::RedirectErrors(redirect_errors))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Fully validate `config`: its redirects and the targets of its `redirect_prefix_map`
+fn verify_config(config: &mut TomlConfig) -> Result<()> {
+    let result = verify_redirects(&mut config.redirect);
+    with_errors(result, prefix_map_errors(config.meta.as_ref(), &VerifyOptions::from_env()))
+}
+
+/// Whether `url` looks like a host or a path on one, e.g. `example.com/docs`, that is
+/// missing its `https://`. Other malformed URLs are left to the URL check.
+fn missing_scheme(url: &str) -> bool {
+    !url.contains("://") && url.chars().next().map_or(false, |c| c.is_ascii_alphanumeric())
+}
+
+/// Store the URL check results of each short in `statuses` and add their errors to `errors`.
+/// `Indirect` errors are only a warning unless `options.fail_on_indirect` is set.
+fn record_checks(
+    checked: Vec<(String, UrlStatus, Option<RedirectError>)>,
+    options: &VerifyOptions,
+    statuses: &mut HashMap<String, UrlStatus>,
+    errors: &mut Vec<RedirectError>,
+) {
+    for (short, status, error) in checked {
+        statuses.insert(short, status);
+        match error {
+            Some(RedirectError::Indirect(ref from, ref to)) if !options.fail_on_indirect => {
+                println!("{}Warning: {} redirects to {}", request_id::log_prefix(), from, to)
+            }
+            Some(e) => errors.push(e),
+            None => (),
+        }
+    }
+}
+
+/// Whether the host of `url` is allowed by `options`.
+/// URLs that can't be parsed are left to the URL check to report.
+fn url_allowed(url: &str, options: &VerifyOptions) -> bool {
+    match reqwest::Url::parse(url) {
+        Ok(parsed) => parsed
+            .host_str()
+            .map_or(false, |host| options.host_allowed(host)),
+        Err(_) => true,
+    }
+}
+
+/// Result of checking a single URL, see `check_urls`
+#[derive(Debug, Clone, Serialize)]
+pub struct UrlCheck {
+    pub url: String,
+    pub ok: bool,
+    /// HTTP status code of the response, if there was one
+    pub status: Option<u16>,
+    pub error: Option<String>,
+}
+
+/// Check each of `urls` like a redirect target, without touching the redirect map.
+/// At most `options.concurrency` URLs are checked at once; results are in the order of `urls`.
+pub fn check_urls(urls: &[String], options: &VerifyOptions) -> Result<Vec<UrlCheck>> {
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(options.concurrency)
+        .build()?;
+    Ok(pool.install(|| urls.par_iter().map(|url| url_check(url, options)).collect()))
+}
+
+fn url_check(url: &str, options: &VerifyOptions) -> UrlCheck {
+    let (status, error) = if url_allowed(url, options) {
+        let (status, result) = check_url_status(url, options.follow_redirects);
+        let error = match result {
+            Ok(final_url) => indirect_error(url, &final_url).filter(|_| options.fail_on_indirect),
+            Err(e) => Some(e),
+        };
+        (status.status, error)
+    } else {
+        (None, Some(RedirectError::DisallowedHost(url.to_string())))
+    };
+    UrlCheck {
+        url: url.to_string(),
+        ok: error.is_none(),
+        status,
+        error: error.map(|e| e.to_string()),
+    }
+}
+
+/// Fully validate a single redirect, e.g. before adding it at runtime. A CNAME record is
+/// created for it, so its short also has to be usable as a subdomain.
+pub fn verify_redirect(redirect: &SiteRedirect) -> Result<()> {
+    if !is_dns_label(&redirect.short) {
+        let error = RedirectError::InvalidShort(redirect.short.clone());
+        return Err(Error::RedirectErrors(vec![error]));
+    }
+    verify_redirects(&mut [redirect.clone()])
+}
+
+/// Append `path` to `url` with exactly one slash between them, so a target URL ending
+/// in `/` doesn't produce `https://doc.rust-lang.org//std`
+pub fn join_url(url: &str, path: &str) -> String {
+    let path = path.trim_left_matches('/');
+    if path.is_empty() {
+        return url.to_string();
+    }
+    format!("{}/{}", url.trim_right_matches('/'), path)
+}
+
+/// Percent-encode `value` for use inside a query string: everything but the unreserved
+/// characters is encoded, so `&`, `=` and `#` can't start another parameter or a fragment
+pub fn encode_query_value(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Whether `status` can be sent with a `Location` header to redirect a client
+pub fn is_redirect_status(status: u16) -> bool {
+    match status {
+        300 | 301 | 302 | 303 | 307 | 308 => true,
+        _ => false,
+    }
+}
+
+/// The inclusive range of statuses in an `accept_status` entry: a status like `403`, or a
+/// range like `401-403`. `None` if it's neither.
+fn status_range(entry: &str) -> Option<(u16, u16)> {
+    let mut parts = entry.splitn(2, '-');
+    let low: u16 = parts.next()?.trim().parse().ok()?;
+    let high: u16 = match parts.next() {
+        Some(high) => high.trim().parse().ok()?,
+        None => low,
+    };
+    if 100 <= low && low <= high && high <= 599 {
+        Some((low, high))
+    } else {
+        None
+    }
+}
+
+/// Whether `status` is in one of the `accept_status` entries of a redirect
+pub fn status_accepted(accepted: &[String], status: u16) -> bool {
+    accepted
+        .iter()
+        .filter_map(|entry| status_range(entry))
+        .any(|(low, high)| low <= status && status <= high)
+}
+
+/// `InvalidAcceptStatus` errors for `accept_status` entries that aren't a status or range
+fn accept_status_errors(redirects: &[SiteRedirect]) -> Vec<RedirectError> {
+    redirects
+        .iter()
+        .flat_map(|r| {
+            r.accept_status
+                .iter()
+                .filter(|entry| status_range(entry).is_none())
+                .map(move |entry| {
+                    RedirectError::InvalidAcceptStatus(r.short.clone(), entry.clone())
+                })
+        })
+        .collect()
+}
+
+/// The result of a URL check, passed if it failed with a status in `accepted`
+fn accepting(
+    accepted: &[String],
+    url: &str,
+    checked: (UrlStatus, std::result::Result<String, RedirectError>),
+) -> (UrlStatus, std::result::Result<String, RedirectError>) {
+    let (mut status, result) = checked;
+    match status.status {
+        Some(code) if result.is_err() && status_accepted(accepted, code) => {
+            status.reachable = true;
+            (status, Ok(url.to_string()))
+        }
+        _ => (status, result),
+    }
+}
+
+/// `InvalidStatus` errors for redirects whose `status` isn't a redirect status
+fn status_errors(redirects: &[SiteRedirect]) -> Vec<RedirectError> {
+    redirects
+        .iter()
+        .filter_map(|r| match r.status {
+            Some(status) if !is_redirect_status(status) => {
+                Some(RedirectError::InvalidStatus(r.short.clone(), status))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Whether `short` is a valid DNS label: 1 to 63 lowercase letters, digits, or hyphens,
+/// not starting or ending with a hyphen
+fn is_dns_label(short: &str) -> bool {
+    let valid_chars = short
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+    valid_chars && !short.is_empty() && short.len() <= 63 && !short.starts_with('-')
+        && !short.ends_with('-')
+}
+
+/// Replaced with the part of the key a wildcard short matched, in the URLs of that redirect
+pub const WILDCARD_PLACEHOLDER: &str = "{wildcard}";
+
+/// Whether `short` is a wildcard like `blog-*`: a DNS label with a single `*` standing in for
+/// one or more characters of it
+pub fn is_wildcard_short(short: &str) -> bool {
+    short.matches('*').count() == 1 && is_dns_label(&short.replace('*', "x"))
+}
+
+/// The part of `key` matched by the `*` of the wildcard short `pattern`, e.g. `rust` for
+/// `blog-rust` and `blog-*`. `None` if `pattern` isn't a wildcard or doesn't match.
+pub fn wildcard_capture<'a>(pattern: &str, key: &'a str) -> Option<&'a str> {
+    let star = pattern.find('*')?;
+    let (prefix, suffix) = (&pattern[..star], &pattern[star + 1..]);
+    if key.len() > prefix.len() + suffix.len() && key.starts_with(prefix)
+        && key.ends_with(suffix)
+    {
+        let captured = &key[prefix.len()..key.len() - suffix.len()];
+        // a wildcard stands in for part of a single label, and not for another wildcard
+        if captured.contains('.') || captured.contains('*') {
+            None
+        } else {
+            Some(captured)
+        }
+    } else {
+        None
+    }
+}
+
+/// Find the wildcard redirect matching `key`, for keys without a redirect of their own.
+/// The longest matching wildcard wins, so `blog-rust-*` is picked over `blog-*`.
+/// Returns the wildcard short and its target with `WILDCARD_PLACEHOLDER` filled in, or the
+/// matched part appended as a path to URLs without the placeholder.
+pub fn find_wildcard(
+    map: &HashMap<String, RedirectTarget>,
+    key: &str,
+) -> Option<(String, RedirectTarget)> {
+    let (pattern, target, captured) = map
+        .iter()
+        .filter_map(|(pattern, target)| {
+            wildcard_capture(pattern, key).map(|captured| (pattern, target, captured))
+        })
+        .max_by(|a, b| a.0.len().cmp(&b.0.len()).then_with(|| b.0.cmp(a.0)))?;
+    let fill = |url: &str| {
+        if url.contains(WILDCARD_PLACEHOLDER) {
+            url.replace(WILDCARD_PLACEHOLDER, captured)
+        } else {
+            join_url(url, captured)
+        }
+    };
+    let mut target = target.clone();
+    target.url = fill(&target.url);
+    for url in target.localized.values_mut() {
+        *url = fill(url);
+    }
+    Some((pattern.clone(), target))
+}
+
+/// Whether `url` has `WILDCARD_PLACEHOLDER` before its path. Such a URL can't be parsed, so
+/// the allowlist can't check its host, and the key would pick the host it goes to.
+fn wildcard_in_host(url: &str) -> bool {
+    let start = url.find("://").map_or(0, |i| i + 3);
+    let end = url[start..]
+        .find(|c| c == '/' || c == '?' || c == '#')
+        .map_or(url.len(), |i| start + i);
+    url[..end].contains(WILDCARD_PLACEHOLDER)
+}
+
+/// The URL checked for a redirect to `url`: with the wildcard placeholder left out, so
+/// `https://blog.example.com/{wildcard}` is checked as the blog root
+fn url_to_check(url: &str) -> String {
+    url.replace(WILDCARD_PLACEHOLDER, "")
+}
+
+/// Verify that `url` is syntactically valid, and that the page is reachable.
+/// Returns the final URL after following any redirects.
+fn check_url(url: &str) -> std::result::Result<String, RedirectError> {
+    check_url_status(url, true).1
+}
+
+/// Check `url` like `check_url`, also returning a `UrlStatus` describing the check.
+/// Redirects are only followed if `follow_redirects` is set.
+fn check_url_status(
+    url: &str,
+    follow_redirects: bool,
+) -> (UrlStatus, std::result::Result<String, RedirectError>) {
+    if let Err(e) = tls::probe_min_version(url) {
+        return url_status(url, None, Err(e));
+    }
+    let response = http_client(follow_redirects).get(url).send();
+    let (code, result) = match response {
+        Ok(resp) => {
+            if let Err(e) = probe_hops(url, resp.url()) {
+                return url_status(url, None, Err(e));
+            }
+            let location = resp.headers().get::<Location>().map(|l| l.to_string());
+            let location = location.as_ref().map(String::as_str);
+            response_result(url, resp.status(), resp.url(), location)
+        }
+        Err(_e) => (None, Err(RedirectError::BadUrl(url.to_string()))),
+    };
+    url_status(url, code, result)
+}
+
+/// Result of checking `url` from the response's `status`, the URL it ended up at, and its
+/// `Location` header, which is only set if redirects weren't followed
+fn response_result(
+    url: &str,
+    status: reqwest::StatusCode,
+    final_url: &reqwest::Url,
+    location: Option<&str>,
+) -> (Option<u16>, std::result::Result<String, RedirectError>) {
+    let result = if status.is_success() {
+        Ok(final_url.to_string())
+    } else if let (true, Some(location)) = (status.is_redirection(), location) {
+        Err(RedirectError::InvalidPage(format!(
+            "{}: {}, redirects to {}, use that URL instead",
+            url, status, location
+        )))
+    } else {
+        Err(RedirectError::InvalidPage(format!("{}: {}", url, status)))
+    };
+    (Some(status.as_u16()), result)
+}
+
+/// Describe the outcome of checking `url` as a `UrlStatus`
+fn url_status(
+    url: &str,
+    code: Option<u16>,
+    result: std::result::Result<String, RedirectError>,
+) -> (UrlStatus, std::result::Result<String, RedirectError>) {
+    let redirected_to = match result {
+        Ok(ref final_url) if indirect_error(url, final_url).is_some() => Some(final_url.clone()),
+        _ => None,
+    };
+    let status = UrlStatus {
+        last_checked: SystemTime::now(),
+        reachable: result.is_ok(),
+        status: code,
+        redirected_to,
+    };
+    (status, result)
+}
+
+/// `RedirectError::Indirect` if `url` ended up somewhere else after following redirects.
+/// Differences that only come from normalizing the URL (like a trailing `/`) don't count.
+fn indirect_error(url: &str, final_url: &str) -> Option<RedirectError> {
+    let same = match (reqwest::Url::parse(url), reqwest::Url::parse(final_url)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => url == final_url,
+    };
+    if same {
+        None
+    } else {
+        Some(RedirectError::Indirect(url.to_string(), final_url.to_string()))
+    }
+}
+
+/// Shorts further than this many edits from the requested key aren't suggested
+const MAX_SUGGESTION_DISTANCE: usize = 2;
+
+/// Maximum number of suggestions on the 404 page
+const MAX_SUGGESTIONS: usize = 5;
+
+/// The shorts closest to `key`, for suggesting a redirect when `key` doesn't exist.
+/// Closest first, ties sorted alphabetically.
+pub fn suggest_shorts<'a, I>(key: &str, shorts: I) -> Vec<String>
+where
+    I: IntoIterator<Item = &'a String>,
+{
+    let mut close: Vec<(usize, &String)> = shorts
+        .into_iter()
+        .map(|short| (levenshtein(key, short), short))
+        .filter(|&(distance, _)| distance <= MAX_SUGGESTION_DISTANCE)
+        .collect();
+    close.sort();
+    close
+        .into_iter()
+        .take(MAX_SUGGESTIONS)
+        .map(|(_, short)| short.clone())
+        .collect()
+}
+
+/// Number of single character insertions, deletions or substitutions to turn `a` into `b`
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + if a_char == *b_char { 0 } else { 1 };
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Strong ETag for `redirects`, a hash of their JSON representation.
+/// Changes whenever any redirect changes, so clients can poll with `If-None-Match`.
+pub fn redirects_etag(redirects: &[&SiteRedirect]) -> String {
+    let json = serde_json::to_vec(redirects).expect("redirects serialize to JSON");
+    let hash = Sha256::digest(&json);
+    let hex: String = hash.iter().take(16).map(|b| format!("{:02x}", b)).collect();
+    format!("\"{}\"", hex)
+}
+
+pub fn vec_redirects_to_hashmap(slice: &[SiteRedirect]) -> HashMap<String, RedirectTarget> {
+    let mut map = HashMap::with_capacity(slice.len());
+    for redir in slice.iter().filter(|r| r.enabled) {
+        map.insert(redir.short.clone(), RedirectTarget::from(redir));
+    }
+    map
+}
+
+/// Group redirects by category, with each group sorted by `short`.
+/// Redirects without a category are put in the `DEFAULT_CATEGORY` group.
+pub fn group_by_category(slice: &[SiteRedirect]) -> BTreeMap<String, Vec<SiteRedirect>> {
+    let mut groups: BTreeMap<String, Vec<SiteRedirect>> = BTreeMap::new();
+    for redir in slice {
+        let category = redir
+            .category
+            .clone()
+            .unwrap_or_else(|| DEFAULT_CATEGORY.to_string());
+        groups.entry(category).or_insert_with(Vec::new).push(redir.clone());
+    }
+    for group in groups.values_mut() {
+        group.sort();
+    }
+    groups
+}
+
+/// Show the line of `source` that `err` happened on, with a marker under the offending column
+pub fn toml_error_snippet(source: &str, err: &toml::de::Error) -> Option<String> {
+    let (line, col) = err.line_col()?;
+    let text = source.lines().nth(line)?;
+    let number = (line + 1).to_string();
+    let gutter = " ".repeat(number.len());
+    Some(format!(
+        "{} |\n{} | {}\n{} | {}^",
+        gutter,
+        number,
+        text,
+        gutter,
+        " ".repeat(col)
+    ))
+}
+
+/// Whether a config without any redirects may be served, from env `allow_empty_config`.
+/// Allowed unless it's set to `false`.
+pub fn allow_empty_config_from_env() -> bool {
+    dotenv::var("allow_empty_config")
+        .map(|s| s != "false")
+        .unwrap_or(true)
+}
+
+/// Warn about a config without any redirects, since every request would silently get a 404.
+/// Fails with `Error::EmptyConfig` instead unless `allow_empty` is set.
+pub fn check_not_empty(redirects: &[SiteRedirect], allow_empty: bool) -> Result<()> {
+    if !redirects.is_empty() {
+        return Ok(());
+    }
+    if allow_empty {
+        println!(
+            "{}Warning: the config has no redirects, every redirect request will get a 404",
+            request_id::log_prefix()
+        );
+        Ok(())
+    } else {
+        Err(Error::EmptyConfig)
+    }
+}
+
+/// Parse a redirect config, rejecting config versions newer than this server supports.
+/// The `[redirects]` table is merged into `redirect`.
+pub fn config_from_str(toml_str: &str) -> Result<TomlConfig> {
+    let mut config = toml::from_str::<TomlConfig>(toml_str)?;
+    let table = std::mem::replace(&mut config.redirects, BTreeMap::new());
+    for (short, redirect) in table {
+        config.redirect.push(redirect.into_site_redirect(short)?);
+    }
+    if let Some(ref meta) = config.meta {
+        if meta.version == 0 || meta.version > SUPPORTED_CONFIG_VERSION {
+            return Err(Error::UnsupportedVersion(meta.version));
+        }
+    }
+    let errors = status_errors(&config.redirect);
+    if !errors.is_empty() {
+        return Err(Error::RedirectErrors(errors));
+    }
+    Ok(config)
+}
+
+/// Load the redirect config at `path`, merging in the redirects of the files it includes.
+/// Fails with `DuplicateRule` errors if a short is used more than once across all of the
+/// files, since only one of them could end up in the redirect map.
+pub fn config_from_file<P: AsRef<Path>>(path: P) -> Result<TomlConfig> {
+    config_and_paths_from_file(path).map(|(config, _paths)| config)
+}
+
+/// Like `config_from_file`, but also returns the paths of the files that were read, see
+/// `config_paths`
+pub fn config_and_paths_from_file<P: AsRef<Path>>(path: P) -> Result<(TomlConfig, Vec<String>)> {
+    let read = |path: &Path| -> Result<String> {
+        let mut toml_string = String::new();
+        File::open(path).and_then(|mut f| f.read_to_string(&mut toml_string))?;
+        Ok(toml_string)
+    };
+    let (config, files) = load_config(path.as_ref(), &read)?;
+    Ok((config, config_paths(&files)))
+}
+
+/// Paths of the config `files` relative to the directory of the first one, the config
+/// itself, like they are in the repository: `redirects.toml`, `more/extra.toml`
+fn config_paths(files: &[(PathBuf, String)]) -> Vec<String> {
+    let root = files
+        .first()
+        .and_then(|&(ref path, _)| path.parent())
+        .unwrap_or_else(|| Path::new(""));
+    files
+        .iter()
+        .map(|&(ref path, _)| path.strip_prefix(root).unwrap_or(path))
+        .map(|path| path.to_string_lossy().into_owned())
+        .collect()
+}
+
+/// Load the config at `path` using `read`, recursively merging the redirects of the
+/// files in its `include` list. Returns the config and every file that was read, starting
+/// with `path` itself.
+fn load_config<F>(path: &Path, read: &F) -> Result<(TomlConfig, Vec<(PathBuf, String)>)>
+where
+    F: Fn(&Path) -> Result<String>,
+{
+    let mut files = Vec::new();
+    let root = normalize_path(path).parent().map(Path::to_path_buf).unwrap_or_default();
+    let mut config = load_included(path, &root, read, &mut Vec::new(), &mut files)?;
+    let duplicates = duplicate_errors(&mut config.redirect);
+    if duplicates.is_empty() {
+        Ok((config, files))
+    } else {
+        Err(Error::RedirectErrors(duplicates))
+    }
+}
+
+/// Load the config at `path` and its includes, which have to stay inside `root`.
+/// `stack` holds the files currently being loaded, so an include cycle is reported instead
+/// of recursing forever.
+fn load_included<F>(
+    path: &Path,
+    root: &Path,
+    read: &F,
+    stack: &mut Vec<PathBuf>,
+    files: &mut Vec<(PathBuf, String)>,
+) -> Result<TomlConfig>
+where
+    F: Fn(&Path) -> Result<String>,
+{
+    let path = normalize_path(path);
+    if let Some(start) = stack.iter().position(|p| *p == path) {
+        let mut cycle = stack[start..].to_vec();
+        cycle.push(path);
+        return Err(Error::IncludeCycle(cycle));
+    }
+    let toml_str = read(&path)?;
+    let mut config = config_from_str(&toml_str)?;
+    files.push((path.clone(), toml_str));
+
+    let dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+    stack.push(path);
+    for include in &config.include {
+        // includes are read from disk or Github, neither may reach outside the config
+        let include_path = Path::new(include);
+        let joined = normalize_path(&dir.join(include_path));
+        if include_path.is_absolute() || include_path.has_root() || !is_inside(&joined, root) {
+            return Err(Error::IncludeOutsideRoot(include.clone()));
+        }
+        let included = load_included(&joined, root, read, stack, files)?;
+        config.redirect.extend(included.redirect);
+    }
+    stack.pop();
+    Ok(config)
+}
+
+/// Resolve the `.` and `..` components of `path` without touching the file system, so
+/// the same file reached through different relative paths is recognised
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match normalized.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    normalized.pop();
+                }
+                _ => normalized.push(".."),
+            },
+            other => normalized.push(other.as_os_str()),
+        }
+    }
+    normalized
+}
+
+/// Whether the normalized `path` is in the directory `root` or below it
+fn is_inside(path: &Path, root: &Path) -> bool {
+    path.strip_prefix(root)
+        .map(|rest| rest.components().all(|c| c != Component::ParentDir))
+        .unwrap_or(false)
+}
+
+pub fn redirects_from_str(toml_str: &str) -> Result<Vec<SiteRedirect>> {
+    Ok(config_from_str(toml_str)?.redirect)
+}
+
+/// Serialize `redirects` and the optional `meta` table in the redirects.toml format
+pub fn redirects_to_toml(redirects: &[SiteRedirect], meta: Option<&ConfigMeta>) -> Result<String> {
+    let config = TomlConfig {
+        include: Vec::new(),
+        meta: meta.cloned(),
+        redirect: redirects.to_vec(),
+        redirects: BTreeMap::new(),
+    };
+    Ok(toml::to_string(&config)?)
+}
+
+pub fn redirects_from_file<P: AsRef<Path>>(path: P) -> Result<Vec<SiteRedirect>> {
+    Ok(config_from_file(path)?.redirect)
+}
+
+/// Load and merge the redirects of every `*.toml` file in the directory `path`.
+/// Other files are ignored. Fails with `DuplicateRule` errors if a short is used more
+/// than once across all of the files.
+pub fn redirects_from_dir<P: AsRef<Path>>(path: P) -> Result<Vec<SiteRedirect>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let file = entry?.path();
+        if file.is_file() && file.extension().map_or(false, |ext| ext == "toml") {
+            files.push(file);
+        }
+    }
+    // read in a fixed order so the result doesn't depend on the file system
+    files.sort();
+
+    let mut redirects = Vec::new();
+    for file in files {
+        redirects.extend(redirects_from_file(&file)?);
+    }
+    let duplicates = duplicate_errors(&mut redirects);
+    if duplicates.is_empty() {
+        Ok(redirects)
+    } else {
+        Err(Error::RedirectErrors(duplicates))
+    }
+}
+
+/// Load the redirects in `path` and run the full validation on them.
+/// Returns the number of redirects if they are all valid.
+pub fn validate_file<P: AsRef<Path>>(path: P) -> Result<usize> {
+    let mut config = config_from_file(path)?;
+    verify_config(&mut config)?;
+    Ok(config.redirect.len())
+}
+
+/// Parse and fully validate a redirect config without applying it.
+/// Returns the number of redirects if they are all valid.
+pub fn validate_str(toml_str: &str) -> Result<usize> {
+    let mut config = config_from_str(toml_str)?;
+    verify_config(&mut config)?;
+    Ok(config.redirect.len())
+}
+
+/// Run the full validation, URL checks included, on redirects that are already loaded and
+/// the `redirect_prefix_map` of their `meta`.
+/// Returns the number of redirects if they are all valid.
+pub fn validate_loaded(redirects: &[SiteRedirect], meta: Option<&ConfigMeta>) -> Result<usize> {
+    let mut config = TomlConfig {
+        include: Vec::new(),
+        meta: meta.cloned(),
+        redirect: redirects.to_vec(),
+        redirects: BTreeMap::new(),
+    };
+    verify_config(&mut config)?;
+    Ok(config.redirect.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_utils::{http_response, mock_server, recording_mock_server, MockDns, MockStore};
+
+    #[test]
+    fn verify_toml_parses() {
+        let toml_str = include_str!("../redirects.toml");
+        let parsed = toml::from_str::<TomlConfig>(&toml_str);
+        assert!(parsed.is_ok());
+        let redir_vec = parsed.unwrap().redirect;
+        assert!(redir_vec.len() > 0);
+        for redir in &redir_vec {
+            assert!(redir.short.len() > 0);
+            assert!(redir.url.len() > 0);
+        }
+    }
+
+    #[test]
+    fn verify_production_redirects_valid() {
+        let toml_str = include_str!("../redirects.toml");
+        let parsed = toml::from_str::<TomlConfig>(&toml_str);
+        assert!(parsed.is_ok());
+        let mut redir_vec = parsed.unwrap().redirect;
+        if let Err(e) = verify_redirects(&mut redir_vec) {
+            panic!("redirects.toml is invalid, {}", e);
+        }
+    }
+
+    #[test]
+    fn malformed_urls() {
+        let bad1 = SiteRedirect {
+            short: "bad1".to_string(),
+            url: "@#hello/test".to_string(),
+            category: None,
+            description: None,
+            permanent: false,
+            preserve_method: false,
+            indexable: false,
+            status: None,
+            cache_seconds: None,
+            strip_prefix: None,
+            accept_status: Vec::new(),
+            enabled: true,
+            localized: BTreeMap::new(),
+        };
+        let bad2 = SiteRedirect {
+            short: "bad2".to_string(),
+            url: "/example.com".to_string(),
+            category: None,
+            description: None,
+            permanent: false,
+            preserve_method: false,
+            indexable: false,
+            status: None,
+            cache_seconds: None,
+            strip_prefix: None,
+            accept_status: Vec::new(),
+            enabled: true,
+            localized: BTreeMap::new(),
+        };
+        let bad3 = SiteRedirect {
+            short: "bad3".to_string(),
+            url: "http://example".to_string(),
+            category: None,
+            description: None,
+            permanent: false,
+            preserve_method: false,
+            indexable: false,
+            status: None,
+            cache_seconds: None,
+            strip_prefix: None,
+            accept_status: Vec::new(),
+            enabled: true,
+            localized: BTreeMap::new(),
+        };
+        let bad4 = SiteRedirect {
+            short: "bad4".to_string(),
+            url: "test".to_string(),
+            category: None,
+            description: None,
+            permanent: false,
+            preserve_method: false,
+            indexable: false,
+            status: None,
+            cache_seconds: None,
+            strip_prefix: None,
+            accept_status: Vec::new(),
+            enabled: true,
+            localized: BTreeMap::new(),
+        };
+        let mut vector = vec![bad1, bad2, bad3, bad4];
+        match verify_redirects(&mut vector) {
+            Ok(_) => panic!("should fail"),
+            Err(Error::RedirectErrors(e)) => {
+                if e.len() == vector.len() {
+                    return;
+                }
+                let mut fail_str = String::from("only these URLs failed\n");
+                for fail in e {
+                    fail_str.push_str(&format!("{:?}", fail));
+                    fail_str.push('\n');
+                }
+                panic!(fail_str);
+            }
+            Err(e) => panic!("unexpected error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn scheme_less_urls() {
+        assert!(missing_scheme("example.com"));
+        assert!(missing_scheme("doc.rust-lang.org/std/"));
+        assert!(missing_scheme("localhost:8000"));
+        assert!(!missing_scheme("https://example.com"));
+        assert!(!missing_scheme("http://example.com"));
+        // not host-like, left to the URL check
+        assert!(!missing_scheme("/example.com"));
+        assert!(!missing_scheme("@#hello/test"));
+
+        let mut redirects = config_from_str(
+            r#"
+            [[redirect]]
+            short = "book"
+            url = "doc.rust-lang.org/book"
+            localized = { ja = "doc.rust-jp.rs/book-ja" }
+        "#,
+        ).unwrap()
+            .redirect;
+        let errors = config_errors(&mut redirects, &VerifyOptions::default());
+        let found: Vec<(&str, &str)> = errors.iter().map(|e| (e.kind(), e.subject())).collect();
+        assert_eq!(
+            found,
+            vec![
+                ("MissingScheme", "doc.rust-lang.org/book"),
+                ("MissingScheme", "doc.rust-jp.rs/book-ja"),
+            ]
+        );
+        assert_eq!(
+            errors[0].to_string(),
+            "missing scheme, did you mean https://doc.rust-lang.org/book?"
+        );
+
+        // reported once, without also failing the URL check
+        match verify_redirects(&mut redirects) {
+            Err(Error::RedirectErrors(e)) => assert_eq!(e.len(), 2),
+            other => panic!("expected MissingScheme errors, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn concurrency_cap_reports_all_errors() {
+        let bad_urls = ["@#hello/test", "/example.com", "http://example", "test"];
+        for &concurrency in &[1, 2, 8] {
+            let mut vector: Vec<SiteRedirect> = bad_urls
+                .iter()
+                .enumerate()
+                .map(|(i, url)| SiteRedirect {
+                    short: format!("bad{}", i),
+                    url: url.to_string(),
+                    category: None,
+                    description: None,
+                    permanent: false,
+                    preserve_method: false,
+                    indexable: false,
+                    status: None,
+                    cache_seconds: None,
+                    strip_prefix: None,
+                    accept_status: Vec::new(),
+                    enabled: true,
+                    localized: BTreeMap::new(),
+                })
+                .collect();
+            let options = VerifyOptions {
+                concurrency,
+                ..VerifyOptions::default()
+            };
+            match verify_redirects_with(&mut vector, &options, &mut HashMap::new()) {
+                Err(Error::RedirectErrors(e)) => assert_eq!(e.len(), bad_urls.len()),
+                other => panic!("unexpected result with {} threads: {:?}", concurrency, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_duplicate_redirects() {
+        let bad1 = SiteRedirect {
+            short: "same".to_string(),
+            url: "https://nocduro.com".to_string(),
+            category: None,
+            description: None,
+            permanent: false,
+            preserve_method: false,
+            indexable: false,
+            status: None,
+            cache_seconds: None,
+            strip_prefix: None,
+            accept_status: Vec::new(),
+            enabled: true,
+            localized: BTreeMap::new(),
+        };
+        let bad2 = SiteRedirect {
+            short: "same".to_string(),
+            url: "https://google.com".to_string(),
+            category: None,
+            description: None,
+            permanent: false,
+            preserve_method: false,
+            indexable: false,
+            status: None,
+            cache_seconds: None,
+            strip_prefix: None,
+            accept_status: Vec::new(),
+            enabled: true,
+            localized: BTreeMap::new(),
+        };
+        let bad3 = SiteRedirect {
+            short: "bad2".to_string(),
+            url: "https://google.com".to_string(),
+            category: None,
+            description: None,
+            permanent: false,
+            preserve_method: false,
+            indexable: false,
+            status: None,
+            cache_seconds: None,
+            strip_prefix: None,
+            accept_status: Vec::new(),
+            enabled: true,
+            localized: BTreeMap::new(),
+        };
+        let mut vector = vec![bad1, bad2, bad3];
+        match verify_redirects(&mut vector) {
+            Ok(_) => panic!("unexpected pass"),
+            Err(Error::RedirectErrors(e)) => {
+                if e.len() == 1 {
+                    return;
+                }
+                panic!("Expected 1 failure for the duplicate");
+            }
+            Err(e) => panic!("unexpected error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn check_url_404() {
+        assert!(check_url("https://nocduro.com/invalid_page_name").is_err());
+    }
+
+    #[test]
+    #[ignore]
+    /// this url is valid for some reason!?
+    fn check_url_misspell() {
+        assert!(check_url("htp://nocduro.com").is_err())
+    }
+
+    #[test]
+    fn check_url_valid() {
+        assert!(check_url("https://nocduro.com/").is_ok());
+        assert!(check_url("https://doc.rust-lang.org/").is_ok());
+        assert!(check_url("https://doc.rust-lang.org").is_ok());
+    }
+
+    #[test]
+    fn indirect_url_detection() {
+        // normalizing the URL doesn't count as a redirect
+        let normalized = indirect_error("https://doc.rust-lang.org", "https://doc.rust-lang.org/");
+        assert!(normalized.is_none());
+        let same = indirect_error("https://doc.rust-lang.org/std", "https://doc.rust-lang.org/std");
+        assert!(same.is_none());
+        match indirect_error("http://doc.rust-lang.org/", "https://doc.rust-lang.org/") {
+            Some(RedirectError::Indirect(from, to)) => {
+                assert_eq!(from, "http://doc.rust-lang.org/");
+                assert_eq!(to, "https://doc.rust-lang.org/");
+            }
+            other => panic!("expected indirect error, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn check_url_direct() {
+        let final_url = check_url("https://doc.rust-lang.org/").unwrap();
+        assert!(indirect_error("https://doc.rust-lang.org/", &final_url).is_none());
+    }
+
+    #[test]
+    fn check_url_follows_redirect() {
+        // http is redirected to https
+        let (status, result) = check_url_status("http://doc.rust-lang.org/", true);
+        let final_url = result.unwrap();
+        assert!(final_url.starts_with("https://"));
+        assert_eq!(status.redirected_to, Some(final_url));
+    }
+
+    #[test]
+    #[cfg(feature = "async-check")]
+    fn async_check_reports_same_errors() {
+        let server = mock_server(|path| {
+            if path == "/good" {
+                http_response("200 OK", &[], "ok")
+            } else {
+                http_response("404 Not Found", &[], "")
+            }
+        });
+        let config = format!(
+            r#"
+            [[redirect]]
+            short = "good"
+            url = "{0}/good"
+
+            [[redirect]]
+            short = "bad"
+            url = "{0}/bad"
+        "#,
+            server
+        );
+        let options = VerifyOptions::default();
+
+        let mut redirects = redirects_from_str(&config).unwrap();
+        let mut statuses = HashMap::new();
+        let errors = match verify_redirects_async(&mut redirects, &options, &mut statuses) {
+            Err(Error::RedirectErrors(errors)) => errors,
+            other => panic!("expected redirect errors, got {:?}", other),
+        };
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind(), "InvalidPage");
+        assert!(statuses["good"].reachable);
+        assert_eq!(statuses["bad"].status, Some(404));
+
+        // the blocking checker finds the same problems
+        let mut redirects = redirects_from_str(&config).unwrap();
+        match verify_redirects_with(&mut redirects, &options, &mut HashMap::new()) {
+            Err(Error::RedirectErrors(sync_errors)) => {
+                let messages = |e: &[RedirectError]| -> Vec<String> {
+                    e.iter().map(|e| e.to_string()).collect()
+                };
+                assert_eq!(messages(&sync_errors), messages(&errors));
+            }
+            other => panic!("expected redirect errors, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn allowed_host_matching() {
+        let options = VerifyOptions {
+            allowed_hosts: vec!["*.rust-lang.org".to_string(), "github.com".to_string()],
+            ..VerifyOptions::default()
+        };
+        assert!(options.host_allowed("github.com"));
+        assert!(options.host_allowed("GitHub.com"));
+        assert!(options.host_allowed("doc.rust-lang.org"));
+        assert!(options.host_allowed("nested.doc.rust-lang.org"));
+        assert!(!options.host_allowed("rust-lang.org"));
+        assert!(!options.host_allowed("evil-rust-lang.org"));
+        assert!(!options.host_allowed("gist.github.com"));
+        assert!(!options.host_allowed("phishing.example.com"));
+
+        // an empty allowlist allows everything
+        assert!(VerifyOptions::default().host_allowed("phishing.example.com"));
+
+        assert!(url_allowed("https://doc.rust-lang.org/std", &options));
+        assert!(!url_allowed("https://phishing.example.com/login", &options));
+    }
+
+    #[test]
+    fn reserved_short_rejected() {
+        let toml_str = r#"
+            [[redirect]]
+            short = "Admin"
+            url = "https://doc.rust-lang.org/"
+
+            [[redirect]]
+            short = "metrics"
+            url = "https://doc.rust-lang.org/"
+
+            [[redirect]]
+            short = "api"
+            url = "https://doc.rust-lang.org/"
+
+            [[redirect]]
+            short = "health"
+            url = "https://doc.rust-lang.org/"
+        "#;
+        let mut redirects = redirects_from_str(toml_str).unwrap();
+        let errors = config_errors(&mut redirects, &VerifyOptions::default());
+        let found: Vec<(&str, &str)> = errors.iter().map(|e| (e.kind(), e.subject())).collect();
+        assert!(found.contains(&("ReservedShort", "Admin")));
+        assert!(found.contains(&("ReservedShort", "metrics")));
+        assert!(found.contains(&("ReservedShort", "api")));
+        assert!(!found.contains(&("ReservedShort", "health")));
+
+        let options = VerifyOptions {
+            reserved_shorts: vec!["health".to_string()],
+            ..VerifyOptions::default()
+        };
+        let errors = config_errors(&mut redirects, &options);
+        let reserved: Vec<&str> = errors
+            .iter()
+            .filter(|e| e.kind() == "ReservedShort")
+            .map(|e| e.subject())
+            .collect();
+        assert_eq!(reserved, vec!["health"]);
+    }
+
+    #[test]
+    fn disallowed_host_rejected() {
+        let mut redirects = redirects_from_str(
+            r#"
+            [[redirect]]
+            short = "doc"
+            url = "https://doc.rust-lang.org/"
+
+            [[redirect]]
+            short = "phish"
+            url = "https://phishing.example.com/login"
+        "#,
+        ).unwrap();
+        let options = VerifyOptions {
+            allowed_hosts: vec!["*.rust-lang.org".to_string()],
+            ..VerifyOptions::default()
+        };
+        match verify_redirects_with(&mut redirects, &options, &mut HashMap::new()) {
+            Err(Error::RedirectErrors(errors)) => {
+                assert!(errors.iter().any(|e| match *e {
+                    RedirectError::DisallowedHost(ref url) => {
+                        url == "https://phishing.example.com/login"
+                    }
+                    _ => false,
+                }));
+                assert!(errors.iter().all(|e| e.subject() != "https://doc.rust-lang.org/"));
+            }
+            other => panic!("expected a disallowed host error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn indirect_is_warning_unless_configured() {
+        let redirect = SiteRedirect {
+            short: "doc".to_string(),
+            url: "http://doc.rust-lang.org/".to_string(),
+            category: None,
+            description: None,
+            permanent: false,
+            preserve_method: false,
+            indexable: false,
+            status: None,
+            cache_seconds: None,
+            strip_prefix: None,
+            accept_status: Vec::new(),
+            enabled: true,
+            localized: BTreeMap::new(),
+        };
+        let mut redirects = vec![redirect];
+        let options = VerifyOptions::default();
+        assert!(verify_redirects_with(&mut redirects, &options, &mut HashMap::new()).is_ok());
+
+        let options = VerifyOptions {
+            fail_on_indirect: true,
+            ..VerifyOptions::default()
+        };
+        match verify_redirects_with(&mut redirects, &options, &mut HashMap::new()) {
+            Err(Error::RedirectErrors(e)) => assert_eq!(e[0].kind(), "Indirect"),
+            other => panic!("expected indirect error, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn check_url_redirect_policy() {
+        let server = mock_server(|path| {
+            if path == "/old" {
+                http_response("301 Moved Permanently", &[("Location", "/new")], "")
+            } else {
+                http_response("200 OK", &[], "ok")
+            }
+        });
+        let old = format!("{}/old", server);
+
+        let (status, result) = check_url_status(&old, true);
+        assert_eq!(result.unwrap(), format!("{}/new", server));
+        assert_eq!(status.status, Some(200));
+        assert!(status.reachable);
+
+        let (status, result) = check_url_status(&old, false);
+        match result {
+            Err(RedirectError::InvalidPage(message)) => {
+                assert!(message.contains("301"), "{}", message);
+                assert!(message.contains("/new"), "{}", message);
+            }
+            other => panic!("expected an invalid page, got {:?}", other),
+        }
+        assert_eq!(status.status, Some(301));
+        assert!(!status.reachable);
+    }
+
+    #[test]
+    fn redirect_hops_probed() {
+        // nothing listens on port 1, so the HTTPS hop fails the TLS probe
+        let server = mock_server(|path| match path {
+            "/old" => http_response("301 Moved Permanently", &[("Location", "/new")], ""),
+            "/insecure" => {
+                http_response("302 Found", &[("Location", "https://127.0.0.1:1/new")], "")
+            }
+            _ => http_response("200 OK", &[], "ok"),
+        });
+        let url = |path: &str| format!("{}{}", server, path);
+        let parsed = |path: &str| reqwest::Url::parse(&url(path)).unwrap();
+
+        assert!(probe_hops(&url("/old"), &parsed("/new")).is_ok());
+        assert!(probe_hops(&url("/new"), &parsed("/new")).is_ok());
+        match probe_hops(&url("/insecure"), &parsed("/elsewhere")) {
+            Err(RedirectError::TlsProbeFailed(ref hop, _)) => {
+                assert_eq!(hop, "https://127.0.0.1:1/new")
+            }
+            other => panic!("expected TlsProbeFailed, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn checks_send_user_agent() {
+        let (server, requests) = recording_mock_server(|_path| http_response("200 OK", &[], "ok"));
+        let (_, result) = check_url_status(&server, true);
+        assert!(result.is_ok());
+        download_config(&server).unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        let header = format!("user-agent: {}", DEFAULT_USER_AGENT).to_lowercase();
+        for request in requests.iter() {
+            assert!(request.to_lowercase().contains(&header), "{}", request);
+        }
+        assert!(DEFAULT_USER_AGENT.starts_with("rustref-redirect-checker/"));
+    }
+
+    #[test]
+    fn check_url_records_status() {
+        let before = SystemTime::now();
+        let (status, result) = check_url_status("https://doc.rust-lang.org/", true);
+        assert!(result.is_ok());
+        assert!(status.reachable);
+        assert_eq!(status.status, Some(200));
+        assert!(status.last_checked >= before);
+        assert!(status.last_checked <= SystemTime::now());
+    }
+
+    #[test]
+    fn recheck_updates_statuses() {
+        use std::sync::{Arc, RwLock};
+        use RedirectData;
+
+        let server = mock_server(|path| {
+            if path == "/good" {
+                http_response("200 OK", &[], "ok")
+            } else {
+                http_response("404 Not Found", &[], "")
+            }
+        });
+        let redirects = redirects_from_str(&format!(
+            r#"
+            [[redirect]]
+            short = "good"
+            url = "{0}/good"
+
+            [[redirect]]
+            short = "bad"
+            url = "{0}/bad"
+        "#,
+            server
+        )).unwrap();
+        let redirs = Arc::new(RwLock::new(RedirectData::new(&redirects, "", "")));
+        let statuses = StatusMap::default();
+        let options = VerifyOptions::default();
+
+        let dead = recheck_urls(&redirs, &statuses, &options).unwrap();
+        assert_eq!(dead, vec!["bad".to_string()]);
+        {
+            let statuses = statuses.read().unwrap();
+            assert!(statuses["good"].reachable);
+            assert_eq!(statuses["good"].status, Some(200));
+            assert!(!statuses["bad"].reachable);
+            assert_eq!(statuses["bad"].status, Some(404));
+        }
+
+        // already known to be unreachable, so it isn't reported again
+        assert!(recheck_urls(&redirs, &statuses, &options).unwrap().is_empty());
+    }
+
+    #[test]
+    fn verify_records_status_per_redirect() {
+        let mut redirects = redirects_from_str(
+            r#"
+            [[redirect]]
+            short = "doc"
+            url = "https://doc.rust-lang.org/"
+
+            [[redirect]]
+            short = "missing"
+            url = "https://nocduro.com/invalid_page_name"
+        "#,
+        ).unwrap();
+        let mut statuses = HashMap::new();
+        let options = VerifyOptions::default();
+        assert!(verify_redirects_with(&mut redirects, &options, &mut statuses).is_err());
+        assert!(statuses["doc"].reachable);
+        assert!(!statuses["missing"].reachable);
+        assert_eq!(statuses["missing"].status, Some(404));
+    }
+
+    #[test]
+    fn accepted_status_passes_check() {
+        let server = mock_server(|_path| http_response("403 Forbidden", &[], "members only"));
+        let config = |accept: &str| {
+            format!(
+                r#"
+                [[redirect]]
+                short = "private"
+                url = "{}/docs"
+                accept_status = [{}]
+            "#,
+                server, accept
+            )
+        };
+        let options = VerifyOptions::default();
+        let verify = |toml_str: String| {
+            let mut redirects = redirects_from_str(&toml_str).unwrap();
+            let mut statuses = HashMap::new();
+            let result = verify_redirects_with(&mut redirects, &options, &mut statuses);
+            (result, statuses["private"].clone())
+        };
+
+        let (result, status) = verify(config(""));
+        assert!(result.is_err());
+        assert!(!status.reachable);
+        let (result, status) = verify(config(r#""401""#));
+        assert!(result.is_err());
+        assert!(!status.reachable);
+
+        // statuses can be written as integers too
+        for accept in &["403", "404, 403", r#""403""#, r#""401-403""#, r#""404", "400 - 403""#] {
+            let (result, status) = verify(config(accept));
+            assert!(result.is_ok(), "{}: {:?}", accept, result);
+            assert!(status.reachable);
+            assert_eq!(status.status, Some(403));
+        }
+
+        let mut redirects = redirects_from_str(&config(r#""4xx", "403-401""#)).unwrap();
+        let errors = config_errors(&mut redirects, &options);
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].kind(), "InvalidAcceptStatus");
+        let mut redirects = redirects_from_str(&config("999")).unwrap();
+        let errors = config_errors(&mut redirects, &options);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].subject(), "private");
+        assert!(status_accepted(&["401-403".to_string()], 402));
+        assert!(!status_accepted(&["401-403".to_string()], 404));
+    }
+
+    #[test]
+    fn update_summary_counts() {
+        let old = vec_redirects_to_hashmap(
+            &redirects_from_str(
+                r#"
+                [[redirect]]
+                short = "book"
+                url = "https://doc.rust-lang.org/book"
+
+                [[redirect]]
+                short = "old"
+                url = "https://example.com/old"
+            "#,
+            ).unwrap(),
+        );
+        let new = redirects_from_str(
+            r#"
+            [[redirect]]
+            short = "book"
+            url = "https://doc.rust-lang.org/stable/book"
+
+            [[redirect]]
+            short = "std"
+            url = "https://doc.rust-lang.org/std"
+
+            [[redirect]]
+            short = "cargo"
+            url = "https://doc.rust-lang.org/cargo"
+        "#,
+        ).unwrap();
+        let summary = UpdateSummary::from_diff(&old, &new);
+        assert_eq!(
+            summary,
+            UpdateSummary {
+                redirects: 3,
+                added: 2,
+                removed: 1,
+                cnames_created: 0,
+                cnames_updated: 0,
+                cnames_deleted: 0,
+                cloudflare_errors: 0,
+                dns_errors: Vec::new(),
+            }
+        );
+    }
+
+    #[test]
+    fn dns_records_follow_redirects() {
+        let dns = Mutex::new(MockDns::with_cnames(&[
+            "std.rustref.com",
+            "old.rustref.com",
+            "www.rustref.com",
+        ]));
+        let redirects = redirects_from_str(
+            r#"
+            [[redirect]]
+            short = "std"
+            url = "https://doc.rust-lang.org/std"
+
+            [[redirect]]
+            short = "book"
+            url = "https://doc.rust-lang.org/book"
+        "#,
+        ).unwrap();
+        // `www` isn't a redirect, so it's left alone. `gone` has no record to delete
+        let removed = vec!["old".to_string(), "gone".to_string()];
+        let mut summary = UpdateSummary::default();
+        let metrics = CloudflareMetrics::new();
+        update_dns(&dns, &metrics, &redirects, &removed, &mut summary, &Delivery::default())
+            .unwrap();
+
+        let dns = dns.into_inner().unwrap();
+        assert_eq!(
+            dns.calls(),
+            vec![
+                "zone_id rustref.com",
+                "list_cnames",
+                "create_cname book.rustref.com",
+                "delete_cname old.rustref.com",
+                "purge_cache",
+            ]
+        );
+        assert_eq!(dns.cnames(), vec!["book.rustref.com", "std.rustref.com", "www.rustref.com"]);
+        assert_eq!(summary.cnames_created, 1);
+        assert_eq!(summary.cnames_deleted, 1);
+        assert_eq!(summary.cloudflare_errors, 0);
+        assert_eq!(metrics.count("create_dns", true), 1);
+
+        // single redirects added and removed by the admin routes
+        let dns = Mutex::new(dns);
+        create_cname(&dns, "nomicon").unwrap();
+        delete_cname(&dns, "std").unwrap();
+        let cnames = dns.lock().unwrap().cnames();
+        assert_eq!(cnames, vec!["book.rustref.com", "nomicon.rustref.com", "www.rustref.com"]);
+    }
+
+    #[test]
+    fn drifted_records_detected() {
+        let record = |name: &str, content: &str, proxied: Option<bool>| CnameRecord {
+            id: format!("id-{}", name),
+            name: name.to_string(),
+            content: content.to_string(),
+            proxied,
+        };
+        let records = vec![
+            record("std.rustref.com", "rustref.com", Some(true)),
+            record("book.rustref.com", "example.com", Some(true)),
+            record("cargo.rustref.com", "rustref.com.", Some(false)),
+            record("www.rustref.com", "elsewhere.com", Some(false)),
+        ];
+        let redirects = redirects_from_str(
+            r#"
+            [[redirect]]
+            short = "std"
+            url = "https://doc.rust-lang.org/std"
+
+            [[redirect]]
+            short = "book"
+            url = "https://doc.rust-lang.org/book"
+
+            [[redirect]]
+            short = "cargo"
+            url = "https://doc.rust-lang.org/cargo"
+        "#,
+        ).unwrap();
+
+        let drifted = |proxied: Option<bool>| -> Vec<String> {
+            drifted_cnames(&records, &redirects, proxied)
+                .into_iter()
+                .map(|record| record.name.clone())
+                .collect()
+        };
+        // `www` isn't a redirect, so whatever it points at is left alone
+        assert_eq!(drifted(Some(true)), vec!["book.rustref.com", "cargo.rustref.com"]);
+        assert_eq!(drifted(Some(false)), vec!["book.rustref.com", "std.rustref.com"]);
+        // hosts without a proxy only compare the content
+        assert_eq!(drifted(None), vec!["book.rustref.com"]);
+
+        let dns = Mutex::new(MockDns::with_records(&records).proxying(true));
+        let mut summary = UpdateSummary::default();
+        let metrics = CloudflareMetrics::new();
+        update_dns(&dns, &metrics, &redirects, &[], &mut summary, &Delivery::default()).unwrap();
+        let dns = dns.into_inner().unwrap();
+        assert_eq!(
+            dns.calls(),
+            vec![
+                "zone_id rustref.com",
+                "list_cnames",
+                "update_cname book.rustref.com",
+                "update_cname cargo.rustref.com",
+                "purge_cache",
+            ]
+        );
+        assert_eq!(summary.cnames_updated, 2);
+        assert_eq!(summary.cnames_created, 0);
+        assert_eq!(dns.record("book.rustref.com").unwrap().content, "rustref.com");
+        assert_eq!(dns.record("cargo.rustref.com").unwrap().proxied, Some(true));
+        assert_eq!(metrics.count("update_dns", true), 2);
+    }
+
+    /// Reload `toml_str` into an empty redirect map, with the DNS changes of the `failing`
+    /// records failing. Returns the result and the number of redirects served afterwards.
+    fn reload_with_failures(
+        toml_str: &str,
+        failing: &[&str],
+        abort_on_errors: bool,
+    ) -> (Result<ConfigReload>, usize) {
+        use std::sync::{Arc, RwLock};
+
+        let redirs = Arc::new(RwLock::new(RedirectData::new(&[], "", "")));
+        let provider = Mutex::new(MockDns::default().fail_on(failing));
+        let read = |_: &Path| Ok(toml_str.to_string());
+        let result = reload_config(
+            &redirs,
+            &StatusMap::default(),
+            Some(&provider),
+            &CloudflareMetrics::new(),
+            &None,
+            &ConfigHistory::new(DEFAULT_CONFIG_HISTORY),
+            &Delivery::default(),
+            &read,
+            &DnsOptions {
+                abort_on_errors,
+                ..DnsOptions::default()
+            },
+        );
+        let served = redirs.read().unwrap().map.len();
+        (result, served)
+    }
+
+    #[test]
+    fn dns_failures_reported() {
+        let server = mock_server(|_path| http_response("200 OK", &[], "ok"));
+        let toml_str = format!(
+            r#"
+            [[redirect]]
+            short = "std"
+            url = "{0}/std"
+
+            [[redirect]]
+            short = "book"
+            url = "{0}/book"
+        "#,
+            server
+        );
+        let summary = |result: Result<ConfigReload>| match result {
+            Ok(ConfigReload::Updated(summary)) => summary,
+            other => panic!("expected an update, got {:?}", other),
+        };
+        let dns_errors = |result: Result<ConfigReload>| match result {
+            Err(Error::DnsUpdate(errors)) => errors,
+            other => panic!("expected a DNS update error, got {:?}", other),
+        };
+
+        // no errors
+        for &abort in &[false, true] {
+            let (result, served) = reload_with_failures(&toml_str, &[], abort);
+            let summary = summary(result);
+            assert!(!summary.has_warnings());
+            assert_eq!(summary.cnames_created, 2);
+            assert!(summary.dns_errors.is_empty());
+            assert_eq!(served, 2);
+        }
+
+        // some errors: the redirects are updated with warnings, unless aborting
+        let (result, served) = reload_with_failures(&toml_str, &["book.rustref.com"], false);
+        let summary_with_errors = summary(result);
+        assert!(summary_with_errors.has_warnings());
+        assert_eq!(summary_with_errors.cnames_created, 1);
+        assert_eq!(summary_with_errors.cloudflare_errors, 1);
+        assert!(summary_with_errors.dns_errors[0].contains("book.rustref.com"));
+        assert_eq!(served, 2);
+
+        let (result, served) = reload_with_failures(&toml_str, &["book.rustref.com"], true);
+        assert_eq!(dns_errors(result).len(), 1);
+        assert_eq!(served, 0);
+
+        // all errors
+        let all = ["book.rustref.com", "std.rustref.com"];
+        let (result, served) = reload_with_failures(&toml_str, &all, false);
+        let summary_with_errors = summary(result);
+        assert_eq!(summary_with_errors.cnames_created, 0);
+        assert_eq!(summary_with_errors.cloudflare_errors, 2);
+        assert_eq!(served, 2);
+
+        let (result, served) = reload_with_failures(&toml_str, &all, true);
+        assert_eq!(dns_errors(result).len(), 2);
+        assert_eq!(served, 0);
+    }
+
+    #[test]
+    fn removed_cnames_kept_by_default() {
+        use std::sync::{Arc, RwLock};
+
+        let server = mock_server(|_path| http_response("200 OK", &[], "ok"));
+        let redirect = |short: &str| {
+            format!("[[redirect]]\nshort = \"{0}\"\nurl = \"{1}/{0}\"\n", short, server)
+        };
+        let toml_str = redirect("std");
+        let old = redirects_from_str(&format!("{}{}", toml_str, redirect("old"))).unwrap();
+        let read = |_: &Path| Ok(toml_str.clone());
+        for &delete_removed in &[false, true] {
+            let redirs = Arc::new(RwLock::new(RedirectData::new(&old, "", "")));
+            let dns = MockDns::with_cnames(&["old.rustref.com", "std.rustref.com"]);
+            let provider = Mutex::new(dns.clone());
+            let reload = reload_config(
+                &redirs,
+                &StatusMap::default(),
+                Some(&provider),
+                &CloudflareMetrics::new(),
+                &None,
+                &ConfigHistory::new(DEFAULT_CONFIG_HISTORY),
+                &Delivery::default(),
+                &read,
+                &DnsOptions {
+                    delete_removed,
+                    ..DnsOptions::default()
+                },
+            );
+            match reload {
+                Ok(ConfigReload::Updated(summary)) => {
+                    assert_eq!(summary.removed, 1);
+                    assert_eq!(summary.cnames_deleted, if delete_removed { 1 } else { 0 });
+                }
+                other => panic!("expected an update, got {:?}", other),
+            }
+            assert_eq!(redirs.read().unwrap().map.len(), 1);
+            let kept = dns.cnames().contains(&"old.rustref.com".to_string());
+            assert_eq!(kept, !delete_removed);
+        }
+    }
+
+    #[test]
+    fn suggest_similar_shorts() {
+        assert_eq!(levenshtein("book", "book"), 0);
+        assert_eq!(levenshtein("bok", "book"), 1);
+        assert_eq!(levenshtein("kitten", "sitting"), 3);
+
+        let shorts: Vec<String> = ["book", "books", "cargo", "nomicon", "std"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert_eq!(suggest_shorts("bok", &shorts), vec!["book", "books"]);
+        assert_eq!(suggest_shorts("carg", &shorts), vec!["cargo"]);
+        assert!(suggest_shorts("zzzzzz", &shorts).is_empty());
+    }
+
+    #[test]
+    fn unchanged_config_skips_dns() {
+        use std::sync::{Arc, RwLock};
+
+        let dns = MockDns::default();
+        let toml_str = r#"
+            [[redirect]]
+            short = "std"
+            url = "https://doc.rust-lang.org/stable/std"
+
+            [[redirect]]
+            short = "book"
+            url = "https://doc.rust-lang.org/stable/book/"
+        "#;
+        let redirects = redirects_from_str(toml_str).unwrap();
+        let redirs = Arc::new(RwLock::new(RedirectData::new(&redirects, "", "")));
+        let read = |_: &Path| Ok(toml_str.to_string());
+        let cf_metrics = CloudflareMetrics::new();
+        let delivery = Delivery::default();
+        let statuses = StatusMap::default();
+        let provider = Mutex::new(dns.clone());
+        let reload = reload_config(
+            &redirs,
+            &statuses,
+            Some(&provider),
+            &cf_metrics,
+            &None,
+            &ConfigHistory::new(DEFAULT_CONFIG_HISTORY),
+            &delivery,
+            &read,
+            &DnsOptions::default(),
+        );
+        match reload {
+            Ok(ConfigReload::Unchanged) => (),
+            other => panic!("expected an unchanged config, got {:?}", other),
+        }
+        assert!(dns.calls().is_empty());
+
+        // a different `[meta]` table is a change
+        let data = redirs.read().unwrap();
+        let mut config = config_from_str(&format!("[meta]\nversion = 1\n{}", toml_str)).unwrap();
+        config.redirect.sort();
+        assert!(!config_unchanged(&data, &config));
+        config.meta = None;
+        assert!(config_unchanged(&data, &config));
+        config.redirect.pop();
+        assert!(!config_unchanged(&data, &config));
+    }
+
+    #[test]
+    fn rollback_restores_previous_config() {
+        use std::sync::{Arc, RwLock};
+
+        let server = mock_server(|_path| http_response("200 OK", &[], "ok"));
+        let config = |shorts: &[&str]| -> String {
+            shorts
+                .iter()
+                .map(|s| format!("[[redirect]]\nshort = \"{0}\"\nurl = \"{1}/{0}\"\n", s, server))
+                .collect()
+        };
+        let redirs = Arc::new(RwLock::new(RedirectData::new(&[], "", "")));
+        let history = ConfigHistory::new(DEFAULT_CONFIG_HISTORY);
+        let dns = MockDns::default();
+        let provider = Mutex::new(dns.clone());
+        let cf_metrics = CloudflareMetrics::new();
+        let saved = MockStore::default();
+        let store: RedirectStorage = Some(Mutex::new(Box::new(saved.clone())));
+        let dns_options = DnsOptions {
+            delete_removed: true,
+            ..DnsOptions::default()
+        };
+        let update = |toml_str: String| {
+            let read = |_: &Path| Ok(toml_str.clone());
+            reload_config(
+                &redirs,
+                &StatusMap::default(),
+                Some(&provider),
+                &cf_metrics,
+                &store,
+                &history,
+                &Delivery::default(),
+                &read,
+                &dns_options,
+            ).unwrap();
+        };
+        let served = || {
+            let mut shorts: Vec<String> = redirs.read().unwrap().map.keys().cloned().collect();
+            shorts.sort();
+            shorts
+        };
+
+        update(config(&["book", "std"]));
+        update(config(&["nomicon", "std"]));
+        // an unchanged config isn't remembered
+        update(config(&["nomicon", "std"]));
+        assert_eq!(served(), vec!["nomicon", "std"]);
+        assert_eq!(saved.shorts(), vec!["nomicon", "std"]);
+        assert_eq!(saved.calls(), vec!["replace_all 2", "replace_all 2"]);
+
+        let roll_back = || rollback(&redirs, &history, Some(&provider), &cf_metrics, &dns_options);
+        let summary = roll_back().unwrap().unwrap();
+        assert_eq!(served(), vec!["book", "std"]);
+        assert_eq!((summary.added, summary.removed), (1, 1));
+        assert!(dns.cnames().contains(&"book.rustref.com".to_string()));
+        assert!(!dns.cnames().contains(&"nomicon.rustref.com".to_string()));
+
+        // back to the empty map the server started with, then nothing is left
+        roll_back().unwrap().unwrap();
+        assert!(served().is_empty());
+        assert!(roll_back().unwrap().is_none());
+    }
+
+    #[test]
+    fn config_history_capacity() {
+        let snapshot = |hash: &str| ConfigSnapshot {
+            redirects: Vec::new(),
+            meta: None,
+            commit_hash: hash.to_string(),
+            commit_url: String::new(),
+        };
+        let history = ConfigHistory::new(2);
+        for hash in &["a", "b", "c"] {
+            history.push(snapshot(hash)).unwrap();
+        }
+        assert_eq!(history.pop().unwrap().unwrap().commit_hash, "c");
+        assert_eq!(history.pop().unwrap().unwrap().commit_hash, "b");
+        assert!(history.pop().unwrap().is_none());
+
+        let disabled = ConfigHistory::new(0);
+        disabled.push(snapshot("a")).unwrap();
+        assert!(disabled.pop().unwrap().is_none());
+    }
+
+    #[test]
+    fn apex_record_check() {
+        let records = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+
+        assert!(verify_apex("rustref.com", &records(&["rustref.com", "std.rustref.com"])).is_ok());
+        // trailing dots and case don't matter for DNS names
+        assert!(verify_apex("rustref.com", &records(&["RustRef.com."])).is_ok());
+
+        match verify_apex("rustref.com", &records(&["std.rustref.com", "www.rustref.com"])) {
+            Err(Error::MissingApex(ref zone)) => assert_eq!(zone, "rustref.com"),
+            other => panic!("expected MissingApex, got {:?}", other),
+        }
+        assert!(verify_apex("rustref.com", &[]).is_err());
+    }
+
+    #[test]
+    fn startup_dns_check() {
+        let dns = MockDns::default();
+        assert!(check_dns_access(&dns, DnsCheck::Fail).is_ok());
+        assert_eq!(dns.calls(), vec!["zone_id rustref.com"]);
+
+        let broken = MockDns::default().fail_on(&[ZONE]);
+        assert!(check_dns_access(&broken, DnsCheck::Fail).is_err());
+        assert!(check_dns_access(&broken, DnsCheck::Warn).is_ok());
+
+        let offline = MockDns::default().fail_on(&[ZONE]);
+        assert!(check_dns_access(&offline, DnsCheck::Skip).is_ok());
+        assert!(offline.calls().is_empty());
+    }
+
+    #[test]
+    fn download_config_ok() {
+        let server = mock_server(|_| {
+            http_response(
+                "200 OK",
+                &[],
+                "[[redirect]]\nshort = \"std\"\nurl = \"https://doc.rust-lang.org/std\"\n",
+            )
+        });
+        let toml_str = download_config(&format!("{}/redirects.toml", server)).unwrap();
+        assert_eq!(redirects_from_str(&toml_str).unwrap()[0].short, "std");
+    }
+
+    #[test]
+    fn download_config_not_found() {
+        let server = mock_server(|_| http_response("404 Not Found", &[], "404: Not Found"));
+        let url = format!("{}/redirects.toml", server);
+        match download_config(&url) {
+            Err(Error::ConfigDownload(failed_url, status)) => {
+                assert_eq!(failed_url, url);
+                assert_eq!(status, 404);
+            }
+            other => panic!("expected download error, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn config_blob_download() {
+        // `[[redirect]]\nshort = "std"\n` wrapped like Github does
+        let body = r#"{
+            "name": "redirects.toml",
+            "sha": "3d21ec5",
+            "encoding": "base64",
+            "content": "W1tyZWRpcmVjdF1dCnNob3J0ID0g\nInN0ZCIK\n"
+        }"#;
+        let server = mock_server(move |_| http_response("200 OK", &[], body));
+        let url = format!("{}/repos/nocduro/rustref/contents/redirects.toml", server);
+        let blob = download_blob(&url).unwrap();
+        assert_eq!(blob.sha, Some("3d21ec5".to_string()));
+        assert_eq!(blob.content, "[[redirect]]\nshort = \"std\"\n");
+
+        let server = mock_server(|_| {
+            http_response("200 OK", &[], r#"{"sha": "3d21ec5", "content": "not base64!"}"#)
+        });
+        match download_blob(&server) {
+            Err(Error::ConfigContent(failed_url)) => assert_eq!(failed_url, server),
+            other => panic!("expected a content error, got {:?}", other),
+        }
+
+        let server = mock_server(|_| http_response("404 Not Found", &[], "{}"));
+        assert!(download_blob(&server).is_err());
+    }
+
+    #[test]
+    fn applied_blobs_skip_reload() {
+        use std::cell::Cell;
+        use std::sync::{Arc, RwLock};
+
+        let redirs = Arc::new(RwLock::new(RedirectData::new(&[], "", "")));
+        let delivery = Delivery::default();
+        let reloads = Cell::new(0);
+        let reload = || -> Result<ConfigReload> {
+            reloads.set(reloads.get() + 1);
+            Ok(ConfigReload::Updated(UpdateSummary::default()))
+        };
+        let skipped = |result: Result<ConfigReload>| match result {
+            Ok(ConfigReload::Unchanged) => true,
+            Ok(ConfigReload::Updated(_)) => false,
+            Err(e) => panic!("reload failed: {}", e),
+        };
+        // blob SHAs of redirects.toml and the file it includes
+        let blobs = |root: &str, include: &str| {
+            let mut blobs = BTreeMap::new();
+            blobs.insert("redirects.toml".to_string(), root.to_string());
+            blobs.insert("more/extra.toml".to_string(), include.to_string());
+            Some(blobs)
+        };
+        let skip = |blobs| skipped(skip_applied_blobs(&redirs, blobs, &delivery, &reload));
+
+        // new blobs are loaded and remembered, the same ones again are skipped
+        assert!(!skip(blobs("a", "x")));
+        assert!(skip(blobs("a", "x")));
+        assert_eq!(reloads.get(), 1);
+        assert!(!skip(blobs("b", "x")));
+        assert_eq!(reloads.get(), 2);
 
-pub fn update_redirect_map(redirs: State<RedirectMap>, cf: State<CloudflareApi>) -> Result<()> {
-    // download new redirect config from github
-    println!("downloading updated redirect file...");
-    let toml_str = reqwest::get(
-        "https://raw.githubusercontent.com/nocduro/rustref/master/redirects.toml",
-    )?.text()?;
-    let mut new_redirects = toml::from_str::<TomlConfig>(&toml_str)?.redirect;
-    verify_redirects(&mut new_redirects)?;
-
-    // before setting the new redirects, make sure that cloudflare was updated successfully
-    // get current CNAME records:
-    let cf_api = cf.lock()?;
-    let zone_id = cloudflare::zones::get_zoneid(&cf_api, "rustref.com")?;
-    println!("zone id: {}", &zone_id);
-    let cname_records = dns::list_dns_of_type(&cf_api, &zone_id, dns::RecordType::CNAME)?;
-    // println!("dns: {:#?}", &cname_records);
+        // a push that only changes an include is applied too
+        assert!(!skip(blobs("b", "y")));
+        assert_eq!(reloads.get(), 3);
 
-    let cf_errors: Vec<_> = new_redirects
-        .iter()
-        .filter(|r| {
-            // filter out existing redirects that already have CNAME entries
-            !cname_records
-                .iter()
-                .any(|x| x.name == format!("{}.rustref.com", r.short))
-        })
-        .map(|new_redir| {
-            // create the CNAME record for new redirects
-            println!("new redirect: {:?}", new_redir);
-            dns::create_proxied_dns_entry(
-                &cf_api,
-                &zone_id,
-                dns::RecordType::CNAME,
-                &format!("{}.rustref.com", new_redir.short),
-                "rustref.com",
-            )
-        })
-        .filter_map(|x| x.err())
-        .collect();
+        // without known blobs the config is always reloaded
+        assert!(!skip(None));
+        assert!(!skip(None));
+        assert_eq!(reloads.get(), 5);
 
-    // just print out cloudflare errors for now
-    for e in cf_errors {
-        println!("Cloudflare error with: {:?}", e)
+        // replacing the redirects some other way forgets the blobs
+        assert!(!skip(blobs("c", "x")));
+        redirs.write().unwrap().set_redirects(&[]);
+        assert!(!skip(blobs("c", "x")));
+        assert_eq!(reloads.get(), 7);
+
+        // a failed reload isn't remembered
+        let failing = || -> Result<ConfigReload> { Err(Error::Dns("down".to_string())) };
+        assert!(skip_applied_blobs(&redirs, blobs("d", "x"), &delivery, failing).is_err());
+        assert!(!skip(blobs("d", "x")));
     }
 
-    // clear Cloudflare's cache
-    cloudflare::zones::purge::purge_everything(&cf_api, &zone_id)?;
+    #[test]
+    fn dns_labels() {
+        assert!(is_dns_label("std"));
+        assert!(is_dns_label("rust-by-example2"));
+        assert!(!is_dns_label(""));
+        assert!(!is_dns_label("-std"));
+        assert!(!is_dns_label("std-"));
+        assert!(!is_dns_label("Std"));
+        assert!(!is_dns_label("std.lib"));
+        assert!(!is_dns_label(&"a".repeat(64)));
+    }
 
-    // update the map, then unlock asap
-    {
-        let redir_map = &mut redirs.write()?.map;
-        *redir_map = vec_redirects_to_hashmap(&new_redirects);
-        println!("map: {:#?}", &redir_map);
+    #[test]
+    fn wildcard_matching() {
+        assert!(is_wildcard_short("blog-*"));
+        assert!(is_wildcard_short("*-docs"));
+        assert!(!is_wildcard_short("blog"));
+        assert!(!is_wildcard_short("blog-**"));
+        assert!(!is_wildcard_short("-*"));
+
+        assert_eq!(wildcard_capture("blog-*", "blog-rust"), Some("rust"));
+        assert_eq!(wildcard_capture("*-docs", "tokio-docs"), Some("tokio"));
+        assert_eq!(wildcard_capture("blog-*", "blog-"), None);
+        assert_eq!(wildcard_capture("blog-*", "news-rust"), None);
+        assert_eq!(wildcard_capture("blog-*", "blog-a.b"), None);
+        assert_eq!(wildcard_capture("blog", "blog"), None);
+        assert_eq!(wildcard_capture("blog-*", "blog-*"), None);
+
+        let redirects = redirects_from_str(
+            r#"
+            [[redirect]]
+            short = "blog-*"
+            url = "https://blog.example.com/"
+
+            [[redirect]]
+            short = "blog-rust-*"
+            url = "https://rust.example.com/{wildcard}/index.html"
+        "#,
+        ).unwrap();
+        let map = vec_redirects_to_hashmap(&redirects);
+        let (short, target) = find_wildcard(&map, "blog-2018").unwrap();
+        assert_eq!(short, "blog-*");
+        assert_eq!(target.url, "https://blog.example.com/2018");
+        let (short, target) = find_wildcard(&map, "blog-rust-async").unwrap();
+        assert_eq!(short, "blog-rust-*");
+        assert_eq!(target.url, "https://rust.example.com/async/index.html");
+        assert!(find_wildcard(&map, "news").is_none());
+
+        let mut redirects = redirects;
+        let errors = config_errors(&mut redirects, &VerifyOptions::default());
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(zonefile(&redirects), "; CNAME records for the rustref.com redirects\n");
     }
 
-    // TODO: overwrite "redirects.toml" so next server restart we get the latest config from file
-    Ok(())
-}
+    #[test]
+    fn wildcard_host_rejected() {
+        let mut redirects = redirects_from_str(
+            r#"
+            [[redirect]]
+            short = "site-*"
+            url = "https://{wildcard}.example.com/"
 
-fn verify_redirects(redirects: &mut [SiteRedirect]) -> Result<()> {
-    // verify that we have no duplicate redirect rules
-    redirects.sort();
-    let mut errors: Vec<RedirectError> = redirects
-        .windows(2)
-        .filter(|w| w[0].short == w[1].short)
-        .map(|w| RedirectError::DuplicateRule(w[0].short.clone()))
-        .collect();
+            [[redirect]]
+            short = "scheme-*"
+            url = "{wildcard}://example.com/"
 
-    // verify URLs are valid syntactically, and that the URL is online
-    errors.extend(
-        redirects
-            .par_iter()
-            .filter_map(|x| check_url(&x.url).err())
-            .collect::<Vec<RedirectError>>(),
-    );
+            [[redirect]]
+            short = "path-*"
+            url = "https://example.com/{wildcard}?from={wildcard}"
+        "#,
+        ).unwrap();
+        let errors = config_errors(&mut redirects, &VerifyOptions::default());
+        let found: Vec<(&str, &str)> = errors.iter().map(|e| (e.kind(), e.subject())).collect();
+        assert_eq!(found.len(), 2, "{:?}", found);
+        assert!(found.contains(&("WildcardInHost", "https://{wildcard}.example.com/")));
+        assert!(found.contains(&("WildcardInHost", "{wildcard}://example.com/")));
+    }
 
-    if !errors.is_empty() {
-        Err(Error::RedirectErrors(errors))
-    } else {
-        Ok(())
+    #[test]
+    fn toml_round_trip() {
+        let redirects = redirects_from_str(include_str!("../redirects.toml")).unwrap();
+        let toml_str = redirects_to_toml(&redirects, None).unwrap();
+        assert_eq!(redirects_from_str(&toml_str).unwrap(), redirects);
     }
-}
 
-/// Verify that `url` is syntactically valid, and that the page is reachable
-fn check_url(url: &str) -> std::result::Result<(), RedirectError> {
-    let resp = reqwest::get(url).map_err(|_e| RedirectError::BadUrl(url.to_string()))?;
-    if resp.status().is_success() {
-        Ok(())
-    } else {
-        Err(RedirectError::InvalidPage(format!(
-            "{}: {}",
-            url,
-            resp.status()
-        )))
+    #[test]
+    fn whitespace_trimmed() {
+        let redirects = redirects_from_str(
+            r#"
+            [[redirect]]
+            short = "cook "
+            url = "  https://rust-lang-nursery.github.io/rust-cookbook/\t"
+        "#,
+        ).unwrap();
+        assert_eq!(redirects[0].short, "cook");
+        assert_eq!(
+            redirects[0].url,
+            "https://rust-lang-nursery.github.io/rust-cookbook/"
+        );
+        assert!(is_dns_label(&redirects[0].short));
+
+        let blank = r#"
+            [[redirect]]
+            short = "   "
+            url = "https://doc.rust-lang.org"
+        "#;
+        match redirects_from_str(blank) {
+            Err(Error::Toml(e)) => assert!(e.to_string().contains("must not be empty")),
+            other => panic!("expected a TOML error, got {:?}", other),
+        }
     }
-}
 
-fn vec_redirects_to_hashmap(slice: &[SiteRedirect]) -> HashMap<String, String> {
-    let mut map = HashMap::with_capacity(slice.len());
-    for redir in slice {
-        map.insert(redir.short.clone(), redir.url.clone());
+    #[test]
+    fn disabled_redirects_not_served() {
+        let toml_str = r#"
+            [[redirect]]
+            short = "std"
+            url = "https://doc.rust-lang.org/std"
+
+            [[redirect]]
+            short = "down"
+            url = "http://127.0.0.1:9/"
+            enabled = false
+        "#;
+        let mut redirects = config_from_str(toml_str).unwrap().redirect;
+        assert!(redirects[0].enabled);
+        assert!(!redirects[1].enabled);
+
+        let map = vec_redirects_to_hashmap(&redirects);
+        assert!(map.contains_key("std"));
+        assert!(!map.contains_key("down"));
+        assert!(!zonefile(&redirects).contains("down.rustref.com"));
+        // still listed, so it can be shown as disabled
+        assert_eq!(group_by_category(&redirects)[DEFAULT_CATEGORY].len(), 2);
+
+        // the unreachable URL of the disabled redirect isn't checked
+        redirects.remove(0);
+        let mut statuses = HashMap::new();
+        let options = VerifyOptions::default();
+        assert!(verify_redirects_with(&mut redirects, &options, &mut statuses).is_ok());
+        assert!(statuses.is_empty());
+
+        // the flag is only written back when it's off
+        let toml_str = redirects_to_toml(&redirects, None).unwrap();
+        assert!(toml_str.contains("enabled = false"));
+        let enabled = vec![SiteRedirect::default()];
+        assert!(!redirects_to_toml(&enabled, None).unwrap().contains("enabled"));
     }
-    map
-}
 
-pub fn redirects_from_file<P: AsRef<Path>>(path: P) -> Result<HashMap<String, String>> {
-    let mut toml_string = String::new();
-    File::open(path.as_ref()).and_then(|mut f| f.read_to_string(&mut toml_string))?;
-    let toml_config = toml::from_str::<TomlConfig>(&toml_string)?;
-    Ok(vec_redirects_to_hashmap(&toml_config.redirect))
-}
+    #[test]
+    fn zonefile_records() {
+        let config = config_from_str(
+            r#"
+            [[redirect]]
+            short = "std"
+            url = "https://doc.rust-lang.org/std"
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+            [[redirect]]
+            short = "book"
+            url = "https://doc.rust-lang.org/book"
+        "#,
+        ).unwrap();
+        assert_eq!(
+            zonefile(&config.redirect),
+            "; CNAME records for the rustref.com redirects\n\
+             book.rustref.com.\tIN\tCNAME\trustref.com.\n\
+             std.rustref.com.\tIN\tCNAME\trustref.com.\n"
+        );
+        assert_eq!(zonefile(&[]), "; CNAME records for the rustref.com redirects\n");
+    }
 
     #[test]
-    fn verify_toml_parses() {
-        let toml_str = include_str!("../redirects.toml");
-        let parsed = toml::from_str::<TomlConfig>(&toml_str);
-        assert!(parsed.is_ok());
-        let redir_vec = parsed.unwrap().redirect;
-        assert!(redir_vec.len() > 0);
-        for redir in &redir_vec {
-            assert!(redir.short.len() > 0);
-            assert!(redir.url.len() > 0);
+    fn sitemap_hosts() {
+        let mut redirects = redirects_from_str(
+            r#"
+            [[redirect]]
+            short = "std"
+            url = "https://doc.rust-lang.org/std"
+
+            [[redirect]]
+            short = "book"
+            url = "https://doc.rust-lang.org/book"
+
+            [[redirect]]
+            short = "down"
+            url = "https://example.com"
+            enabled = false
+        "#,
+        ).unwrap();
+        redirects[0].short = "a&b".to_string();
+        let sitemap = sitemap(&redirects.iter().collect::<Vec<_>>());
+        assert_eq!(
+            sitemap,
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n  \
+             <url><loc>https://rustref.com/</loc></url>\n  \
+             <url><loc>https://a&amp;b.rustref.com/</loc></url>\n  \
+             <url><loc>https://book.rustref.com/</loc></url>\n\
+             </urlset>\n"
+        );
+        assert!(robots_txt().contains("Disallow: /redirect/\n"));
+    }
+
+    #[test]
+    fn config_meta_block() {
+        let toml_str = r#"
+            [meta]
+            version = 1
+            maintainers = ["nocduro"]
+
+            [[redirect]]
+            short = "book"
+            url = "https://doc.rust-lang.org/book"
+        "#;
+        let config = config_from_str(toml_str).unwrap();
+        assert_eq!(
+            config.meta,
+            Some(ConfigMeta {
+                version: 1,
+                maintainers: vec!["nocduro".to_string()],
+                ..ConfigMeta::default()
+            })
+        );
+        assert_eq!(config.redirect.len(), 1);
+
+        // the meta block survives being written back
+        let written = redirects_to_toml(&config.redirect, config.meta.as_ref()).unwrap();
+        assert_eq!(config_from_str(&written).unwrap().meta, config.meta);
+    }
+
+    #[test]
+    fn prefix_map_targets_checked() {
+        let server = mock_server(|path| {
+            if path == "/book" {
+                http_response("200 OK", &[], "ok")
+            } else {
+                http_response("404 Not Found", &[], "missing")
+            }
+        });
+        let mut meta = ConfigMeta::default();
+        let prefixes = [
+            ("/book", format!("{}/book", server)),
+            ("/gone", format!("{}/gone", server)),
+            ("/bare", "doc.rust-lang.org/book".to_string()),
+        ];
+        for &(prefix, ref url) in &prefixes {
+            meta.redirect_prefix_map.insert(prefix.to_string(), url.clone());
+        }
+
+        let errors = prefix_map_errors(Some(&meta), &VerifyOptions::default());
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+        assert!(errors.iter().any(|e| match *e {
+            RedirectError::MissingScheme(ref url) => url == "doc.rust-lang.org/book",
+            _ => false,
+        }));
+        assert!(errors.iter().any(|e| match *e {
+            RedirectError::InvalidPage(ref message) => message.contains("/gone"),
+            _ => false,
+        }));
+
+        // prefixes are held to the host allowlist like redirects
+        let options = VerifyOptions {
+            allowed_hosts: vec!["doc.rust-lang.org".to_string()],
+            ..VerifyOptions::default()
+        };
+        meta.redirect_prefix_map.remove("/gone");
+        meta.redirect_prefix_map.remove("/bare");
+        let errors = prefix_map_errors(Some(&meta), &options);
+        assert_eq!(errors.len(), 1);
+        match errors[0] {
+            RedirectError::DisallowedHost(ref url) => assert!(url.ends_with("/book")),
+            ref other => panic!("expected a DisallowedHost error, got {:?}", other),
         }
+        assert!(prefix_map_errors(None, &options).is_empty());
     }
 
     #[test]
-    fn verify_production_redirects_valid() {
-        let toml_str = include_str!("../redirects.toml");
-        let parsed = toml::from_str::<TomlConfig>(&toml_str);
-        assert!(parsed.is_ok());
-        let mut redir_vec = parsed.unwrap().redirect;
-        match verify_redirects(&mut redir_vec) {
-            Ok(_) => (),
+    fn longest_prefix_wins() {
+        let config = config_from_str(
+            r#"
+            [meta]
+            version = 1
+
+            [meta.redirect_prefix_map]
+            "/book" = "https://doc.rust-lang.org/book"
+            "/book/nightly/" = "https://doc.rust-lang.org/nightly/book/"
+            cargo = "https://doc.rust-lang.org/cargo"
+        "#,
+        ).unwrap();
+        let meta = config.meta.unwrap();
+        assert_eq!(meta.redirect_prefix_map.len(), 3);
+
+        let url = |path: &str| meta.prefix_url(path);
+        assert_eq!(url("book"), Some("https://doc.rust-lang.org/book".to_string()));
+        assert_eq!(
+            url("/book/ch01-00-getting-started.html"),
+            Some("https://doc.rust-lang.org/book/ch01-00-getting-started.html".to_string())
+        );
+        assert_eq!(
+            url("book/nightly/ch02.html"),
+            Some("https://doc.rust-lang.org/nightly/book/ch02.html".to_string())
+        );
+        assert_eq!(
+            url("cargo/reference/"),
+            Some("https://doc.rust-lang.org/cargo/reference".to_string())
+        );
+    }
+
+    #[test]
+    fn path_matching_no_prefix() {
+        let mut meta = ConfigMeta::default();
+        assert_eq!(meta.prefix_url("book"), None);
+
+        meta.redirect_prefix_map
+            .insert("/book".to_string(), "https://doc.rust-lang.org/book".to_string());
+        // prefixes match whole segments only
+        assert_eq!(meta.prefix_url("bookshelf/ch01.html"), None);
+        assert_eq!(meta.prefix_url("std/book"), None);
+        assert_eq!(meta.prefix_url(""), None);
+    }
+
+    #[test]
+    fn config_without_meta() {
+        let toml_str = r#"
+            [[redirect]]
+            short = "book"
+            url = "https://doc.rust-lang.org/book"
+        "#;
+        let config = config_from_str(toml_str).unwrap();
+        assert_eq!(config.meta, None);
+        assert_eq!(config.redirect.len(), 1);
+    }
+
+    #[test]
+    fn redirects_table() {
+        let toml_str = r#"
+            [redirects]
+            std = "https://doc.rust-lang.org/std"
+            book = { url = "https://doc.rust-lang.org/book", permanent = true }
+
+            [redirects.cargo]
+            url = " https://doc.rust-lang.org/cargo "
+            category = "Tools"
+        "#;
+        let mut redirects = redirects_from_str(toml_str).unwrap();
+        redirects.sort();
+        let shorts: Vec<&str> = redirects.iter().map(|r| r.short.as_str()).collect();
+        assert_eq!(shorts, vec!["book", "cargo", "std"]);
+        assert!(redirects[0].permanent);
+        assert_eq!(redirects[1].url, "https://doc.rust-lang.org/cargo");
+        assert_eq!(redirects[1].category, Some("Tools".to_string()));
+        assert_eq!(redirects[2].url, "https://doc.rust-lang.org/std");
+        assert!(redirects[2].enabled);
+
+        // fields are checked like in `[[redirect]]`
+        assert!(redirects_from_str("[redirects]\nstd = { permanent = true }").is_err());
+        assert!(redirects_from_str("[redirects]\nstd = 1").is_err());
+    }
+
+    #[test]
+    fn mixed_redirect_forms() {
+        let toml_str = r#"
+            [[redirect]]
+            short = "std"
+            url = "https://doc.rust-lang.org/std"
+
+            [redirects]
+            book = "https://doc.rust-lang.org/book"
+        "#;
+        let mut redirects = redirects_from_str(toml_str).unwrap();
+        redirects.sort();
+        let shorts: Vec<&str> = redirects.iter().map(|r| r.short.as_str()).collect();
+        assert_eq!(shorts, vec!["book", "std"]);
+
+        // written back in the `[[redirect]]` form only
+        let written = redirects_to_toml(&redirects, None).unwrap();
+        assert!(!written.contains("[redirects]"));
+        assert_eq!(redirects_from_str(&written).unwrap().len(), 2);
+
+        let duplicate = format!("{}std = \"https://doc.rust-lang.org/stable/std\"\n", toml_str);
+        let read = |_: &Path| Ok(duplicate.clone());
+        match load_config(Path::new("redirects.toml"), &read) {
             Err(Error::RedirectErrors(e)) => {
-                let fail_str: String = e.iter().map(|f| format!("{:?}\n", f)).collect();
-                panic!(fail_str);
+                assert_eq!(e.len(), 1);
+                match e[0] {
+                    RedirectError::DuplicateRule(ref short) => assert_eq!(short, "std"),
+                    ref other => panic!("unexpected redirect error: {:?}", other),
+                }
             }
-            Err(e) => panic!("invalid redirect error: {:?}", e),
+            other => panic!("expected a duplicate error, got {:?}", other.map(|c| c.0.redirect)),
         }
     }
 
     #[test]
-    fn malformed_urls() {
-        let bad1 = SiteRedirect {
-            short: "bad1".to_string(),
-            url: "@#hello/test".to_string(),
-        };
-        let bad2 = SiteRedirect {
-            short: "bad2".to_string(),
-            url: "/example.com".to_string(),
-        };
-        let bad3 = SiteRedirect {
-            short: "bad3".to_string(),
-            url: "http://example".to_string(),
-        };
-        let bad4 = SiteRedirect {
-            short: "bad4".to_string(),
-            url: "test".to_string(),
+    fn empty_config() {
+        let config = config_from_str("[meta]\nversion = 1\n").unwrap();
+        assert!(config.redirect.is_empty());
+        assert!(check_not_empty(&config.redirect, true).is_ok());
+        match check_not_empty(&config.redirect, false) {
+            Err(Error::EmptyConfig) => (),
+            other => panic!("expected an empty config error, got {:?}", other),
+        }
+
+        let redirects = redirects_from_str(include_str!("../redirects.toml")).unwrap();
+        assert!(check_not_empty(&redirects, false).is_ok());
+    }
+
+    #[test]
+    fn config_unsupported_version() {
+        let toml_str = r#"
+            [meta]
+            version = 99
+
+            [[redirect]]
+            short = "book"
+            url = "https://doc.rust-lang.org/book"
+        "#;
+        match config_from_str(toml_str) {
+            Err(Error::UnsupportedVersion(99)) => (),
+            other => panic!("expected UnsupportedVersion, got {:?}", other.map(|c| c.meta)),
+        }
+    }
+
+    #[test]
+    fn broken_toml_reports_line() {
+        let toml_str = "[[redirect]]\nshort = \"std\"\nurl = https://doc.rust-lang.org/std\n";
+        let err = match redirects_from_str(toml_str) {
+            Err(Error::Toml(e)) => e,
+            other => panic!("expected toml error, got: {:?}", other),
         };
-        let mut vector = vec![bad1, bad2, bad3, bad4];
-        match verify_redirects(&mut vector) {
-            Ok(_) => panic!("should fail"),
+        let message = Error::Toml(err.clone()).to_string();
+        assert!(message.contains("line 3"), "no line number in: {}", message);
+
+        let snippet = toml_error_snippet(toml_str, &err).expect("error has a position");
+        assert!(snippet.contains("3 | url = https://doc.rust-lang.org/std"));
+        assert!(snippet.ends_with('^'));
+    }
+
+    #[test]
+    fn group_redirects_by_category() {
+        let toml_str = r#"
+            [[redirect]]
+            short = "nomicon"
+            url = "https://doc.rust-lang.org/nomicon"
+            category = "books"
+
+            [[redirect]]
+            short = "book"
+            url = "https://doc.rust-lang.org/stable/book/"
+            category = "books"
+
+            [[redirect]]
+            short = "awesome"
+            url = "https://github.com/rust-unofficial/awesome-rust"
+        "#;
+        let redirects = toml::from_str::<TomlConfig>(toml_str).unwrap().redirect;
+        let groups = group_by_category(&redirects);
+        assert_eq!(groups.len(), 2);
+
+        let books: Vec<&str> = groups["books"].iter().map(|r| r.short.as_str()).collect();
+        assert_eq!(books, vec!["book", "nomicon"]);
+
+        // no category falls into the default group
+        assert_eq!(groups[DEFAULT_CATEGORY].len(), 1);
+        assert_eq!(groups[DEFAULT_CATEGORY][0].short, "awesome");
+    }
+
+    #[test]
+    fn validate_file_duplicates() {
+        match validate_file("test_data/duplicate_redirects.toml") {
+            Ok(_) => panic!("unexpected pass"),
             Err(Error::RedirectErrors(e)) => {
-                if e.len() == vector.len() {
-                    return;
+                assert_eq!(e.len(), 1);
+                match e[0] {
+                    RedirectError::DuplicateRule(ref short) => assert_eq!(short, "same"),
+                    ref other => panic!("unexpected redirect error: {:?}", other),
                 }
-                let mut fail_str = String::from("only these URLs failed\n");
-                for fail in e {
-                    fail_str.push_str(&format!("{:?}", fail));
-                    fail_str.push('\n');
-                }
-                panic!(fail_str);
             }
             Err(e) => panic!("unexpected error: {:?}", e),
         }
     }
 
     #[test]
-    fn test_duplicate_redirects() {
-        let bad1 = SiteRedirect {
-            short: "same".to_string(),
-            url: "https://nocduro.com".to_string(),
-        };
-        let bad2 = SiteRedirect {
-            short: "same".to_string(),
-            url: "https://google.com".to_string(),
-        };
-        let bad3 = SiteRedirect {
-            short: "bad2".to_string(),
-            url: "https://google.com".to_string(),
+    fn redirects_from_custom_path() {
+        let path = std::env::temp_dir().join("rustref_custom_redirects.toml");
+        fs::write(
+            &path,
+            "[[redirect]]\nshort = \"staging\"\nurl = \"https://example.com/\"\n",
+        ).unwrap();
+        let redirects = redirects_from_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(redirects.len(), 1);
+        assert_eq!(redirects[0].short, "staging");
+    }
+
+    #[test]
+    fn redirects_from_file_duplicates() {
+        // caught while loading, before any URL is checked
+        match redirects_from_file("test_data/duplicate_redirects.toml") {
+            Err(Error::RedirectErrors(e)) => {
+                assert_eq!(e.len(), 1);
+                match e[0] {
+                    RedirectError::DuplicateRule(ref short) => assert_eq!(short, "same"),
+                    ref other => panic!("unexpected redirect error: {:?}", other),
+                }
+            }
+            other => panic!("expected a duplicate error, got {:?}", other),
+        }
+        assert!(redirects_from_file("redirects.toml").is_ok());
+    }
+
+    #[test]
+    fn redirects_merged_from_dir() {
+        let redirects = redirects_from_dir("test_data/redirects.d").unwrap();
+        let shorts: Vec<&str> = redirects.iter().map(|r| r.short.as_str()).collect();
+        assert_eq!(shorts, vec!["book", "cargo", "nomicon"]);
+    }
+
+    #[test]
+    fn redirects_from_dir_duplicates() {
+        match redirects_from_dir("test_data/duplicates.d") {
+            Err(Error::RedirectErrors(e)) => {
+                assert_eq!(e.len(), 1);
+                match e[0] {
+                    RedirectError::DuplicateRule(ref short) => assert_eq!(short, "std"),
+                    ref other => panic!("unexpected redirect error: {:?}", other),
+                }
+            }
+            other => panic!("expected a duplicate error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn config_includes() {
+        let config = config_from_file("test_data/includes/simple.toml").unwrap();
+        let shorts: Vec<&str> = config.redirect.iter().map(|r| r.short.as_str()).collect();
+        assert_eq!(shorts, vec!["book", "std"]);
+        assert_eq!(config.meta.map(|m| m.version), Some(1));
+    }
+
+    #[test]
+    fn nested_config_includes() {
+        let config = config_from_file("test_data/includes/nested.toml").unwrap();
+        let shorts: Vec<&str> = config.redirect.iter().map(|r| r.short.as_str()).collect();
+        assert_eq!(shorts, vec!["book", "cargo", "nomicon", "std"]);
+    }
+
+    #[test]
+    fn cyclic_config_includes() {
+        match config_from_file("test_data/includes/cycle_a.toml") {
+            Err(Error::IncludeCycle(cycle)) => assert_eq!(
+                cycle,
+                vec![
+                    PathBuf::from("test_data/includes/cycle_a.toml"),
+                    PathBuf::from("test_data/includes/cycle_b.toml"),
+                    PathBuf::from("test_data/includes/cycle_a.toml"),
+                ]
+            ),
+            other => panic!("expected an include cycle, got {:?}", other.map(|c| c.redirect)),
+        }
+    }
+
+    #[test]
+    fn config_include_duplicates() {
+        match config_from_file("test_data/includes/duplicate.toml") {
+            Err(Error::RedirectErrors(e)) => {
+                assert_eq!(e.len(), 1);
+                match e[0] {
+                    RedirectError::DuplicateRule(ref short) => assert_eq!(short, "std"),
+                    ref other => panic!("unexpected redirect error: {:?}", other),
+                }
+            }
+            other => panic!("expected a duplicate error, got {:?}", other.map(|c| c.redirect)),
+        }
+    }
+
+    #[test]
+    fn includes_stay_inside_root() {
+        let read = |path: &Path| -> Result<String> {
+            Ok(match path.to_str() {
+                Some("config/redirects.toml") => r#"include = ["docs/books.toml"]"#.to_string(),
+                Some("config/docs/books.toml") => r#"include = ["../tools.toml"]"#.to_string(),
+                Some("config/escape.toml") => r#"include = ["docs/../../secret.toml"]"#.into(),
+                Some("config/absolute.toml") => r#"include = ["/etc/passwd"]"#.to_string(),
+                _ => "[[redirect]]\nshort = \"std\"\nurl = \"https://doc.rust-lang.org/std\"\n"
+                    .to_string(),
+            })
         };
-        let mut vector = vec![bad1, bad2, bad3];
-        match verify_redirects(&mut vector) {
-            Ok(_) => panic!("unexpected pass"),
+        // `..` is fine as long as it stays below the top config's directory
+        let (config, files) = load_config(Path::new("config/redirects.toml"), &read).unwrap();
+        assert_eq!(config.redirect.len(), 1);
+        assert_eq!(files[2].0, PathBuf::from("config/tools.toml"));
+        assert_eq!(
+            config_paths(&files),
+            vec!["redirects.toml", "docs/books.toml", "tools.toml"]
+        );
+
+        for path in &["config/escape.toml", "config/absolute.toml"] {
+            match load_config(Path::new(path), &read) {
+                Err(Error::IncludeOutsideRoot(_)) => (),
+                other => panic!("expected IncludeOutsideRoot, got {:?}", other.map(|c| c.1)),
+            }
+        }
+    }
+
+    #[test]
+    fn normalize_include_paths() {
+        assert_eq!(normalize_path(Path::new("a/./b/../c.toml")), PathBuf::from("a/c.toml"));
+        assert_eq!(normalize_path(Path::new("a/../../c.toml")), PathBuf::from("../c.toml"));
+    }
+
+    #[test]
+    fn validate_missing_file() {
+        match validate_file("test_data/does_not_exist.toml") {
+            Err(Error::Io(_)) => (),
+            other => panic!("expected io error, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn redirect_target_from_site_redirect() {
+        let redirects = redirects_from_str(
+            r#"
+            [[redirect]]
+            short = "nomicon"
+            url = "https://doc.rust-lang.org/nomicon"
+            category = "books"
+            description = "The Dark Arts of Unsafe Rust"
+        "#,
+        ).unwrap();
+        let map = vec_redirects_to_hashmap(&redirects);
+        let target = &map["nomicon"];
+        assert_eq!(target.url, "https://doc.rust-lang.org/nomicon");
+        assert_eq!(target.category, Some("books".to_string()));
+        assert_eq!(
+            target.description,
+            Some("The Dark Arts of Unsafe Rust".to_string())
+        );
+        assert!(!target.permanent);
+    }
+
+    #[test]
+    fn invalid_status_rejected() {
+        let toml_str = r#"
+            [[redirect]]
+            short = "see"
+            url = "https://doc.rust-lang.org/std"
+            status = 303
+
+            [[redirect]]
+            short = "ok"
+            url = "https://doc.rust-lang.org/std"
+            status = 200
+        "#;
+        match config_from_str(toml_str) {
             Err(Error::RedirectErrors(e)) => {
-                if e.len() == 1 {
-                    return;
+                assert_eq!(e.len(), 1);
+                match e[0] {
+                    RedirectError::InvalidStatus(ref short, status) => {
+                        assert_eq!(short, "ok");
+                        assert_eq!(status, 200);
+                    }
+                    ref other => panic!("unexpected redirect error: {:?}", other),
                 }
-                panic!("Expected 1 failure for the duplicate");
             }
-            Err(e) => panic!("unexpected error: {:?}", e),
+            other => panic!("expected an invalid status, got {:?}", other.map(|c| c.redirect)),
         }
+        assert!(is_redirect_status(308));
+        assert!(!is_redirect_status(304));
     }
 
     #[test]
-    fn check_url_404() {
-        assert!(check_url("https://nocduro.com/invalid_page_name").is_err());
+    fn join_url_slashes() {
+        let expected = "https://doc.rust-lang.org/std/vec";
+        assert_eq!(join_url("https://doc.rust-lang.org/std", "vec"), expected);
+        assert_eq!(join_url("https://doc.rust-lang.org/std/", "vec"), expected);
+        assert_eq!(join_url("https://doc.rust-lang.org/std", "/vec"), expected);
+        assert_eq!(join_url("https://doc.rust-lang.org/std/", "/vec"), expected);
+        // without a path the URL is left alone
+        assert_eq!(join_url("https://doc.rust-lang.org/", ""), "https://doc.rust-lang.org/");
+        assert_eq!(join_url("https://doc.rust-lang.org/", "/"), "https://doc.rust-lang.org/");
     }
 
     #[test]
-    #[ignore]
-    /// this url is valid for some reason!?
-    fn check_url_misspell() {
-        assert!(check_url("htp://nocduro.com").is_err())
+    fn search_url_encodes_key() {
+        let meta = ConfigMeta {
+            version: 1,
+            maintainers: Vec::new(),
+            fallback_search_url: Some("https://doc.rust-lang.org/std/?search={key}".to_string()),
+            redirect_prefix_map: BTreeMap::new(),
+        };
+        assert_eq!(
+            meta.search_url("a&admin=1").unwrap(),
+            "https://doc.rust-lang.org/std/?search=a%26admin%3D1"
+        );
+        assert_eq!(
+            meta.search_url("vec#push ü").unwrap(),
+            "https://doc.rust-lang.org/std/?search=vec%23push%20%C3%BC"
+        );
+        assert_eq!(encode_query_value("Vec-1.0_x~"), "Vec-1.0_x~");
     }
 
     #[test]
-    fn check_url_valid() {
-        assert!(check_url("https://nocduro.com/").is_ok());
-        assert!(check_url("https://doc.rust-lang.org/").is_ok());
-        assert!(check_url("https://doc.rust-lang.org").is_ok());
+    fn strip_path_prefix() {
+        let redirects = redirects_from_str(
+            r#"
+            [[redirect]]
+            short = "ex"
+            url = "https://doc.rust-lang.org/stable/rust-by-example"
+            strip_prefix = "old"
+
+            [[redirect]]
+            short = "std"
+            url = "https://doc.rust-lang.org/stable/std"
+        "#,
+        ).unwrap();
+        let map = vec_redirects_to_hashmap(&redirects);
+        let ex = &map["ex"];
+        assert_eq!(ex.strip_prefix, Some("old".to_string()));
+        assert_eq!(ex.strip_path("old/foo.html"), "foo.html");
+        assert_eq!(ex.strip_path("old/nested/foo.html"), "nested/foo.html");
+        assert_eq!(ex.strip_path("old"), "");
+        // only whole segments at the start are stripped
+        assert_eq!(ex.strip_path("older.html"), "older.html");
+        assert_eq!(ex.strip_path("new/old/foo.html"), "new/old/foo.html");
+        // without a prefix the path is unchanged
+        assert_eq!(map["std"].strip_path("old/foo.html"), "old/foo.html");
+    }
+
+    #[test]
+    fn permanent_redirect_max_age() {
+        let redirects = redirects_from_str(
+            r#"
+            [[redirect]]
+            short = "std"
+            url = "https://doc.rust-lang.org/stable/std"
+            permanent = true
+
+            [[redirect]]
+            short = "book"
+            url = "https://doc.rust-lang.org/stable/book/"
+            permanent = true
+            cache_seconds = 60
+
+            [[redirect]]
+            short = "nightly"
+            url = "https://doc.rust-lang.org/nightly/std"
+            cache_seconds = 60
+        "#,
+        ).unwrap();
+        let map = vec_redirects_to_hashmap(&redirects);
+        assert_eq!(
+            map["std"].max_age(),
+            Some(Duration::from_secs(DEFAULT_PERMANENT_CACHE_SECONDS))
+        );
+        assert_eq!(map["book"].max_age(), Some(Duration::from_secs(60)));
+        // temporary redirects are never cached
+        assert_eq!(map["nightly"].max_age(), None);
     }
 }
@@ -0,0 +1,107 @@
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{Data, Request, Response};
+use uuid::Uuid;
+
+use std::cell::RefCell;
+use std::fmt;
+
+/// Header a request ID is read from and sent back in
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// Longest incoming request ID that is kept, longer ones are replaced
+pub const MAX_REQUEST_ID_LEN: usize = 128;
+
+thread_local! {
+    /// ID of the request currently handled by this thread.
+    /// Rocket handles a request on a single thread, from `on_request` to `on_response`.
+    static REQUEST_ID: RefCell<Option<String>> = RefCell::new(None);
+}
+
+/// ID of the request being handled on this thread, if any
+pub fn current() -> Option<String> {
+    REQUEST_ID.with(|id| id.borrow().clone())
+}
+
+/// Prefix for log lines, e.g. `[request 4f1c...] `, so they can be matched with a request.
+/// Empty outside of a request.
+pub fn log_prefix() -> LogPrefix {
+    LogPrefix(current())
+}
+
+pub struct LogPrefix(Option<String>);
+
+impl fmt::Display for LogPrefix {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(ref id) => write!(f, "[request {}] ", id),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Tags every request with an ID, taken from its `X-Request-Id` header if it has a usable
+/// one and generated otherwise. The ID is sent back in the `X-Request-Id` response header
+/// and is available to log lines through `current` while the request is handled.
+pub struct RequestIdFairing;
+
+impl Fairing for RequestIdFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request ID",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _data: &Data) {
+        let id = request
+            .headers()
+            .get_one(REQUEST_ID_HEADER)
+            .and_then(incoming_id)
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
+        REQUEST_ID.with(|current| *current.borrow_mut() = Some(id));
+    }
+
+    fn on_response(&self, _request: &Request, response: &mut Response) {
+        if let Some(id) = REQUEST_ID.with(|current| current.borrow_mut().take()) {
+            response.set_raw_header(REQUEST_ID_HEADER, id);
+        }
+    }
+}
+
+/// The incoming request ID `header`, unless it's empty, too long or has characters that
+/// don't belong in a log line
+fn incoming_id(header: &str) -> Option<String> {
+    let header = header.trim();
+    let usable = !header.is_empty()
+        && header.len() <= MAX_REQUEST_ID_LEN
+        && header
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c));
+    if usable {
+        Some(header.to_string())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn incoming_ids() {
+        assert_eq!(incoming_id("abc-123"), Some("abc-123".to_string()));
+        assert_eq!(incoming_id(" req.42:a_b "), Some("req.42:a_b".to_string()));
+        assert_eq!(incoming_id(""), None);
+        assert_eq!(incoming_id("two words"), None);
+        assert_eq!(incoming_id("line\nbreak"), None);
+        assert_eq!(incoming_id(&"a".repeat(MAX_REQUEST_ID_LEN)).map(|id| id.len()), Some(128));
+        assert_eq!(incoming_id(&"a".repeat(MAX_REQUEST_ID_LEN + 1)), None);
+    }
+
+    #[test]
+    fn log_prefix_outside_request() {
+        assert_eq!(LogPrefix(None).to_string(), "");
+        assert_eq!(LogPrefix(Some("42".to_string())).to_string(), "[request 42] ");
+    }
+}
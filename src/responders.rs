@@ -0,0 +1,323 @@
+use redirect_utils::SiteRedirect;
+use Error;
+
+use rocket::http::Status;
+use rocket::request::Request;
+use rocket::response::{self, Redirect, Responder, Response};
+use rocket_contrib::{Json, Template};
+
+use std::time::Duration;
+
+/// Responds with `405 Method Not Allowed` and an `Allow` header listing the accepted methods
+#[derive(Debug)]
+pub struct MethodNotAllowed(pub &'static str);
+
+impl<'r> Responder<'r> for MethodNotAllowed {
+    fn respond_to(self, _req: &Request) -> response::Result<'r> {
+        Response::build()
+            .status(Status::MethodNotAllowed)
+            .raw_header("Allow", self.0)
+            .ok()
+    }
+}
+
+/// Responds with `429 Too Many Requests` and a `Retry-After` header with the given wait,
+/// rounded up to whole seconds
+#[derive(Debug)]
+pub struct TooManyRequests(pub Duration);
+
+impl<'r> Responder<'r> for TooManyRequests {
+    fn respond_to(self, _req: &Request) -> response::Result<'r> {
+        let seconds = self.0.as_secs() + if self.0.subsec_nanos() > 0 { 1 } else { 0 };
+        Response::build()
+            .status(Status::TooManyRequests)
+            .raw_header("Retry-After", seconds.to_string())
+            .ok()
+    }
+}
+
+/// Responds with `503 Service Unavailable` and a plain text notice while the redirects are
+/// in maintenance mode
+#[derive(Debug)]
+pub struct UnderMaintenance;
+
+impl<'r> Responder<'r> for UnderMaintenance {
+    fn respond_to(self, req: &Request) -> response::Result<'r> {
+        let notice = "rustref is down for maintenance, back soon!\n";
+        Response::build_from(notice.respond_to(req)?)
+            .status(Status::ServiceUnavailable)
+            .ok()
+    }
+}
+
+/// A `Redirect` that tells clients how long they may cache it with a `Cache-Control` header.
+///
+/// Requests with `?via=html` get a page that redirects with a meta refresh instead, for
+/// clients behind proxies that drop 3xx responses.
+#[derive(Debug)]
+pub struct CachedRedirect {
+    redirect: Redirect,
+    /// Where `redirect` goes, for the meta refresh page
+    url: String,
+    /// Sent instead of the status of `redirect`
+    status: Option<Status>,
+    max_age: Option<Duration>,
+    vary: Option<&'static str>,
+    robots: Option<&'static str>,
+    /// Short of the matched redirect, sent as `X-Rustref-Key`
+    key: Option<String>,
+    /// Commit of the config the redirect is from, sent as `X-Rustref-Commit`
+    commit: Option<String>,
+}
+
+impl CachedRedirect {
+    /// Wrap `redirect` to `url` without any of the optional headers
+    fn new(redirect: Redirect, url: &str) -> CachedRedirect {
+        CachedRedirect {
+            redirect,
+            url: url.to_string(),
+            status: None,
+            max_age: None,
+            vary: None,
+            robots: None,
+            key: None,
+            commit: None,
+        }
+    }
+
+    /// 302 redirect to `url`, without caching
+    pub fn found(url: &str) -> CachedRedirect {
+        CachedRedirect::new(Redirect::found(url), url)
+    }
+
+    /// 301 redirect to `url`, cacheable for `max_age`
+    pub fn moved(url: &str, max_age: Duration) -> CachedRedirect {
+        CachedRedirect {
+            max_age: Some(max_age),
+            ..CachedRedirect::new(Redirect::moved(url), url)
+        }
+    }
+
+    /// 307 redirect to `url` that keeps the request method, without caching
+    pub fn temporary(url: &str) -> CachedRedirect {
+        CachedRedirect::new(Redirect::temporary(url), url)
+    }
+
+    /// 308 redirect to `url` that keeps the request method, cacheable for `max_age`
+    pub fn permanent(url: &str, max_age: Duration) -> CachedRedirect {
+        CachedRedirect {
+            max_age: Some(max_age),
+            ..CachedRedirect::new(Redirect::permanent(url), url)
+        }
+    }
+
+    /// Redirect to `url` with any 3xx `status`, cacheable for `max_age` if given
+    pub fn with_status(url: &str, status: Status, max_age: Option<Duration>) -> CachedRedirect {
+        CachedRedirect {
+            status: Some(status),
+            max_age,
+            ..CachedRedirect::new(Redirect::found(url), url)
+        }
+    }
+
+    /// Set the `Vary` header, for redirects whose target depends on a request header
+    pub fn vary(mut self, header: &'static str) -> CachedRedirect {
+        self.vary = Some(header);
+        self
+    }
+
+    /// Set the `X-Robots-Tag` header, e.g. `noindex` to keep the redirect out of search engines
+    pub fn robots(mut self, tag: &'static str) -> CachedRedirect {
+        self.robots = Some(tag);
+        self
+    }
+
+    /// Identify the redirect rule that matched with the `X-Rustref-Key` header, and the config
+    /// version with `X-Rustref-Commit` (left out if `commit_hash` is empty)
+    pub fn matched(mut self, key: &str, commit_hash: &str) -> CachedRedirect {
+        self.key = Some(key.to_string());
+        if !commit_hash.is_empty() {
+            self.commit = Some(commit_hash.to_string());
+        }
+        self
+    }
+}
+
+impl<'r> Responder<'r> for CachedRedirect {
+    fn respond_to(self, req: &Request) -> response::Result<'r> {
+        let mut response = if via_html(req) {
+            // a 200 page must not be cached like the redirect, or caches would serve it to
+            // clients that asked for a plain redirect
+            let mut response =
+                Template::render("redirect", json!({ "url": self.url })).respond_to(req)?;
+            response.set_raw_header("Cache-Control", "no-cache");
+            response
+        } else {
+            let mut response = self.redirect.respond_to(req)?;
+            if let Some(status) = self.status {
+                response.set_status(status);
+            }
+            if let Some(max_age) = self.max_age {
+                response.set_raw_header("Cache-Control", format!("max-age={}", max_age.as_secs()));
+            }
+            response
+        };
+        if let Some(vary) = self.vary {
+            response.set_raw_header("Vary", vary);
+        }
+        if let Some(robots) = self.robots {
+            response.set_raw_header("X-Robots-Tag", robots);
+        }
+        if let Some(key) = self.key {
+            response.set_raw_header("X-Rustref-Key", key);
+        }
+        if let Some(commit) = self.commit {
+            response.set_raw_header("X-Rustref-Commit", commit);
+        }
+        Ok(response)
+    }
+}
+
+/// Whether the request's query has `via=html`
+fn via_html(req: &Request) -> bool {
+    req.uri()
+        .query()
+        .map_or(false, |query| query.split('&').any(|pair| pair == "via=html"))
+}
+
+/// Adds an `ETag` header to the inner response, or responds with `304 Not Modified`
+/// and no body if the request's `If-None-Match` header already matches the tag
+#[derive(Debug)]
+pub struct ETagged<R> {
+    pub etag: String,
+    pub inner: R,
+}
+
+impl<'r, R: Responder<'r>> Responder<'r> for ETagged<R> {
+    fn respond_to(self, req: &Request) -> response::Result<'r> {
+        let not_modified = req
+            .headers()
+            .get("If-None-Match")
+            .any(|value| etag_matches(value, &self.etag));
+        if not_modified {
+            return Response::build()
+                .status(Status::NotModified)
+                .raw_header("ETag", self.etag)
+                .ok();
+        }
+        Response::build_from(self.inner.respond_to(req)?)
+            .raw_header("ETag", self.etag)
+            .ok()
+    }
+}
+
+/// Whether an `If-None-Match` header value (a list of tags, or `*`) matches `etag`
+fn etag_matches(header: &str, etag: &str) -> bool {
+    header
+        .split(',')
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_left_matches("W/") == etag)
+}
+
+/// A single problem found while validating a redirect config
+#[derive(Debug, Serialize)]
+pub struct Problem {
+    pub kind: String,
+    pub subject: String,
+    pub message: String,
+}
+
+/// The index page, or the redirects sorted by `short` like `/api/redirects` for clients that
+/// prefer JSON (see `PrefersJson`). Either way the response varies by `Accept`.
+pub enum IndexResponse {
+    Page(Template),
+    Redirects(Vec<SiteRedirect>),
+}
+
+impl<'r> Responder<'r> for IndexResponse {
+    fn respond_to(self, req: &Request) -> response::Result<'r> {
+        let response = match self {
+            IndexResponse::Page(page) => page.respond_to(req)?,
+            IndexResponse::Redirects(redirects) => Json(redirects).respond_to(req)?,
+        };
+        Response::build_from(response)
+            .raw_header("Vary", "Accept")
+            .ok()
+    }
+}
+
+/// Result of validating a redirect config.
+///
+/// Browsers (`Accept: text/html`) get a readable page, everything else gets JSON.
+/// Responds with 422 if any problems were found.
+#[derive(Debug, Serialize)]
+pub struct ValidationReport {
+    pub valid: bool,
+    pub problems: Vec<Problem>,
+}
+
+impl ValidationReport {
+    pub fn from_result<T>(result: Result<T, Error>) -> ValidationReport {
+        let problems = match result {
+            Ok(_) => Vec::new(),
+            Err(Error::RedirectErrors(errors)) => errors
+                .iter()
+                .map(|e| Problem {
+                    kind: e.kind().to_string(),
+                    subject: e.subject().to_string(),
+                    message: e.to_string(),
+                })
+                .collect(),
+            Err(e) => vec![Problem {
+                kind: "Config".to_string(),
+                subject: String::new(),
+                message: e.to_string(),
+            }],
+        };
+        ValidationReport {
+            valid: problems.is_empty(),
+            problems,
+        }
+    }
+}
+
+impl<'r> Responder<'r> for ValidationReport {
+    fn respond_to(self, req: &Request) -> response::Result<'r> {
+        let status = if self.valid {
+            Status::Ok
+        } else {
+            Status::UnprocessableEntity
+        };
+        let wants_html = req.accept().map_or(false, |accept| {
+            let media = accept.preferred().media_type();
+            media.top() == "text" && media.sub() == "html"
+        });
+        let response = if wants_html {
+            Template::render("validation", &self).respond_to(req)?
+        } else {
+            Json(self).respond_to(req)?
+        };
+        Response::build_from(response).status(status).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket;
+    use rocket::local::Client;
+
+    #[test]
+    fn too_many_requests_retry_after() {
+        let client = Client::new(rocket::ignite()).expect("valid rocket instance");
+        let request = client.post("/github/webhook");
+        let remaining = Duration::from_millis(59_500);
+        let response = TooManyRequests(remaining).respond_to(request.inner()).unwrap();
+        assert_eq!(response.status(), Status::TooManyRequests);
+        // partial seconds are rounded up
+        assert_eq!(response.headers().get_one("Retry-After"), Some("60"));
+
+        let response = TooManyRequests(Duration::from_secs(5)).respond_to(request.inner()).unwrap();
+        assert_eq!(response.headers().get_one("Retry-After"), Some("5"));
+    }
+}
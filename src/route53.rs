@@ -0,0 +1,363 @@
+use dns_provider::{CnameRecord, DnsProvider};
+use {Error, Result};
+
+use dotenv;
+use rusoto_core::Region;
+use rusoto_route53::{
+    Change, ChangeBatch, ChangeResourceRecordSetsRequest, ListHostedZonesByNameRequest,
+    ListResourceRecordSetsRequest, ResourceRecord, ResourceRecordSet, Route53, Route53Client,
+};
+
+use std::fmt::Display;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+/// TTL of the CNAME records created in Route53
+pub const CNAME_TTL_SECONDS: i64 = 300;
+
+/// Manages the redirect CNAMEs in an AWS Route53 hosted zone.
+///
+/// Route53 only serves DNS, so there is no cache to purge.
+pub struct Route53Provider {
+    client: Box<Route53 + Send>,
+    /// CNAME record sets from the last `list_cnames`, deletes send them back as listed
+    listed: Mutex<Vec<ResourceRecordSet>>,
+}
+
+impl Route53Provider {
+    pub fn new(client: Box<Route53 + Send>) -> Route53Provider {
+        Route53Provider {
+            client,
+            listed: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Client for the region in the `aws_region` env var (default `us-east-1`), with the
+    /// credentials from the usual AWS env vars or profile
+    pub fn from_env() -> Route53Provider {
+        let region = dotenv::var("aws_region")
+            .ok()
+            .and_then(|r| Region::from_str(&r).ok())
+            .unwrap_or(Region::UsEast1);
+        Route53Provider::new(Box::new(Route53Client::new(region)))
+    }
+
+    /// Every CNAME record set in the zone, following the pagination
+    fn cname_record_sets(&self, zone_id: &str) -> Result<Vec<ResourceRecordSet>> {
+        let mut request = ListResourceRecordSetsRequest {
+            hosted_zone_id: zone_id.to_string(),
+            ..ListResourceRecordSetsRequest::default()
+        };
+        let mut record_sets = Vec::new();
+        loop {
+            let response = self
+                .client
+                .list_resource_record_sets(&request)
+                .sync()
+                .map_err(dns_error)?;
+            record_sets.extend(
+                response
+                    .resource_record_sets
+                    .into_iter()
+                    .filter(|set| set.type_ == "CNAME"),
+            );
+            if !response.is_truncated {
+                return Ok(record_sets);
+            }
+            request.start_record_name = response.next_record_name;
+            request.start_record_type = response.next_record_type;
+            request.start_record_identifier = response.next_record_identifier;
+        }
+    }
+
+    /// The record set `name` as `list_cnames` last listed it
+    fn listed_record_set(&self, name: &str) -> Option<ResourceRecordSet> {
+        self.listed
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|set| same_name(&set.name, name))
+            .cloned()
+    }
+
+    fn change(&self, request: &ChangeResourceRecordSetsRequest) -> Result<()> {
+        self.client
+            .change_resource_record_sets(request)
+            .sync()
+            .map_err(dns_error)?;
+        Ok(())
+    }
+}
+
+impl DnsProvider for Route53Provider {
+    fn zone_id(&self, zone: &str) -> Result<String> {
+        let request = ListHostedZonesByNameRequest {
+            dns_name: Some(zone.to_string()),
+            max_items: Some("1".to_string()),
+            ..ListHostedZonesByNameRequest::default()
+        };
+        let response = self
+            .client
+            .list_hosted_zones_by_name(&request)
+            .sync()
+            .map_err(dns_error)?;
+        // the zones are listed starting at `zone`, so the first one is the next zone by name
+        // if `zone` doesn't exist
+        response
+            .hosted_zones
+            .into_iter()
+            .find(|z| same_name(&z.name, zone))
+            .map(|z| z.id.trim_left_matches("/hostedzone/").to_string())
+            .ok_or_else(|| Error::Dns(format!("no Route53 hosted zone named {}", zone)))
+    }
+
+    fn list_cnames(&self, zone_id: &str) -> Result<Vec<CnameRecord>> {
+        let record_sets = self.cname_record_sets(zone_id)?;
+        *self.listed.lock().unwrap() = record_sets.clone();
+        Ok(record_sets
+            .into_iter()
+            .map(|set| CnameRecord {
+                // record sets are changed by name, they have no ID
+                id: set.name.trim_right_matches('.').to_string(),
+                name: set.name.trim_right_matches('.').to_string(),
+                content: set
+                    .resource_records
+                    .and_then(|records| records.into_iter().next())
+                    .map_or(String::new(), |record| record.value),
+                proxied: None,
+            })
+            .collect())
+    }
+
+    fn create_cname(&self, zone_id: &str, name: &str, target: &str) -> Result<()> {
+        self.change(&change_request(
+            zone_id,
+            "CREATE",
+            cname_record_set(name, target),
+        ))
+    }
+
+    fn delete_cname(&self, zone_id: &str, record: &CnameRecord) -> Result<()> {
+        // Route53 only deletes a record set that matches exactly, TTL and values included.
+        // Records are listed before they're deleted, so the listing only has to be fetched
+        // again for a record that wasn't listed.
+        let set = match self.listed_record_set(&record.name) {
+            Some(set) => set,
+            None => match self
+                .cname_record_sets(zone_id)?
+                .into_iter()
+                .find(|set| same_name(&set.name, &record.name))
+            {
+                Some(set) => set,
+                None => return Ok(()),
+            },
+        };
+        self.change(&change_request(zone_id, "DELETE", set))?;
+        self.listed
+            .lock()
+            .unwrap()
+            .retain(|set| !same_name(&set.name, &record.name));
+        Ok(())
+    }
+
+    fn update_cname(&self, zone_id: &str, record: &CnameRecord, target: &str) -> Result<()> {
+        self.change(&change_request(
+            zone_id,
+            "UPSERT",
+            cname_record_set(&record.name, target),
+        ))
+    }
+
+    fn purge_cache(&self, _zone_id: &str) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// A CNAME record set `name` pointing at `target`
+fn cname_record_set(name: &str, target: &str) -> ResourceRecordSet {
+    ResourceRecordSet {
+        name: name.to_string(),
+        type_: "CNAME".to_string(),
+        ttl: Some(CNAME_TTL_SECONDS),
+        resource_records: Some(vec![ResourceRecord {
+            value: target.to_string(),
+        }]),
+        ..ResourceRecordSet::default()
+    }
+}
+
+/// Request applying `action` (`CREATE`, `UPSERT` or `DELETE`) to `record_set`
+fn change_request(
+    zone_id: &str,
+    action: &str,
+    record_set: ResourceRecordSet,
+) -> ChangeResourceRecordSetsRequest {
+    ChangeResourceRecordSetsRequest {
+        hosted_zone_id: zone_id.to_string(),
+        change_batch: ChangeBatch {
+            changes: vec![Change {
+                action: action.to_string(),
+                resource_record_set: record_set,
+            }],
+            comment: Some("managed by rustref".to_string()),
+        },
+    }
+}
+
+/// Whether two DNS names are the same, ignoring case and the trailing dot Route53 adds
+fn same_name(a: &str, b: &str) -> bool {
+    a.trim_right_matches('.')
+        .eq_ignore_ascii_case(b.trim_right_matches('.'))
+}
+
+fn dns_error<E: Display>(err: E) -> Error {
+    Error::Dns(format!("Route53 error: {}", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusoto_core::signature::SignedRequest;
+    use rusoto_mock::{MockCredentialsProvider, MockRequestDispatcher};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// A provider whose requests all get `body` back
+    fn mock_provider(body: &str) -> Route53Provider {
+        let dispatcher = MockRequestDispatcher::default().with_body(body);
+        let client = Route53Client::new_with(dispatcher, MockCredentialsProvider, Region::UsEast1);
+        Route53Provider::new(Box::new(client))
+    }
+
+    #[test]
+    fn create_cname_request() {
+        let request = change_request(
+            "Z123",
+            "CREATE",
+            cname_record_set("std.rustref.com", "rustref.com"),
+        );
+        assert_eq!(request.hosted_zone_id, "Z123");
+        let change = &request.change_batch.changes[0];
+        assert_eq!(change.action, "CREATE");
+        let set = &change.resource_record_set;
+        assert_eq!(set.name, "std.rustref.com");
+        assert_eq!(set.type_, "CNAME");
+        assert_eq!(set.ttl, Some(CNAME_TTL_SECONDS));
+        let values: Vec<&str> = set
+            .resource_records
+            .as_ref()
+            .unwrap()
+            .iter()
+            .map(|r| r.value.as_str())
+            .collect();
+        assert_eq!(values, vec!["rustref.com"]);
+    }
+
+    #[test]
+    fn zone_id_from_hosted_zones() {
+        let provider = mock_provider(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+            <ListHostedZonesByNameResponse xmlns="https://route53.amazonaws.com/doc/2013-04-01/">
+                <HostedZones>
+                    <HostedZone>
+                        <Id>/hostedzone/Z123</Id>
+                        <Name>rustref.com.</Name>
+                        <CallerReference>rustref</CallerReference>
+                        <ResourceRecordSetCount>3</ResourceRecordSetCount>
+                    </HostedZone>
+                </HostedZones>
+                <DNSName>rustref.com</DNSName>
+                <IsTruncated>false</IsTruncated>
+                <MaxItems>1</MaxItems>
+            </ListHostedZonesByNameResponse>"#,
+        );
+        assert_eq!(provider.zone_id("rustref.com").unwrap(), "Z123");
+        // the next zone by name isn't mistaken for a missing one
+        match provider.zone_id("example.com") {
+            Err(Error::Dns(_)) => (),
+            other => panic!("expected a missing zone, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn list_only_cnames() {
+        let provider = mock_provider(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+            <ListResourceRecordSetsResponse xmlns="https://route53.amazonaws.com/doc/2013-04-01/">
+                <ResourceRecordSets>
+                    <ResourceRecordSet>
+                        <Name>rustref.com.</Name>
+                        <Type>A</Type>
+                        <TTL>300</TTL>
+                        <ResourceRecords>
+                            <ResourceRecord><Value>192.0.2.1</Value></ResourceRecord>
+                        </ResourceRecords>
+                    </ResourceRecordSet>
+                    <ResourceRecordSet>
+                        <Name>std.rustref.com.</Name>
+                        <Type>CNAME</Type>
+                        <TTL>300</TTL>
+                        <ResourceRecords>
+                            <ResourceRecord><Value>rustref.com</Value></ResourceRecord>
+                        </ResourceRecords>
+                    </ResourceRecordSet>
+                </ResourceRecordSets>
+                <IsTruncated>false</IsTruncated>
+                <MaxItems>100</MaxItems>
+            </ListResourceRecordSetsResponse>"#,
+        );
+        assert_eq!(
+            provider.list_cnames("Z123").unwrap(),
+            vec![CnameRecord {
+                id: "std.rustref.com".to_string(),
+                name: "std.rustref.com".to_string(),
+                content: "rustref.com".to_string(),
+                proxied: None,
+            }]
+        );
+        assert!(provider.purge_cache("Z123").is_ok());
+    }
+
+    #[test]
+    fn deletes_use_listed_record_set() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let body = r#"<?xml version="1.0" encoding="UTF-8"?>
+            <ListResourceRecordSetsResponse xmlns="https://route53.amazonaws.com/doc/2013-04-01/">
+                <ResourceRecordSets>
+                    <ResourceRecordSet>
+                        <Name>std.rustref.com.</Name>
+                        <Type>CNAME</Type>
+                        <TTL>60</TTL>
+                        <ResourceRecords>
+                            <ResourceRecord><Value>rustref.com</Value></ResourceRecord>
+                        </ResourceRecords>
+                    </ResourceRecordSet>
+                </ResourceRecordSets>
+                <IsTruncated>false</IsTruncated>
+                <MaxItems>100</MaxItems>
+            </ListResourceRecordSetsResponse>"#;
+        let dispatcher = MockRequestDispatcher::default()
+            .with_body(body)
+            .with_request_checker(move |_request: &SignedRequest| {
+                counter.fetch_add(1, Ordering::SeqCst);
+            });
+        let client = Route53Client::new_with(dispatcher, MockCredentialsProvider, Region::UsEast1);
+        let provider = Route53Provider::new(Box::new(client));
+        provider.list_cnames("Z123").unwrap();
+
+        // the TTL the zone has, not the one rustref creates records with
+        let set = provider.listed_record_set("std.rustref.com").unwrap();
+        assert_eq!(set.ttl, Some(60));
+        assert_eq!(set.resource_records.unwrap()[0].value, "rustref.com");
+        assert!(provider.listed_record_set("book.rustref.com").is_none());
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn dns_names_compared_loosely() {
+        assert!(same_name("std.rustref.com.", "std.rustref.com"));
+        assert!(same_name("STD.rustref.com", "std.rustref.com."));
+        assert!(!same_name("std.rustref.com", "book.rustref.com"));
+    }
+}
@@ -0,0 +1,244 @@
+use redirect_utils::SUPPORTED_CONFIG_VERSION;
+
+use serde_json::Value;
+
+/// JSON Schema (draft-07) of redirects.toml, for editors to validate configs against.
+///
+/// Written by hand to match `TomlConfig`, `ConfigMeta` and `SiteRedirect`; the tests check
+/// that every field of those is described.
+pub fn config_schema() -> Value {
+    json!({
+        "$schema": "http://json-schema.org/draft-07/schema#",
+        "title": "rustref redirects.toml",
+        "type": "object",
+        "additionalProperties": false,
+        "properties": {
+            "include": {
+                "description": "Other config files whose redirects are merged into this one, \
+                                relative to this file",
+                "type": "array",
+                "items": { "type": "string" }
+            },
+            "meta": {
+                "type": "object",
+                "additionalProperties": false,
+                "required": ["version"],
+                "properties": {
+                    "version": {
+                        "description": "Version of the config format",
+                        "type": "integer",
+                        "minimum": 1,
+                        "maximum": SUPPORTED_CONFIG_VERSION
+                    },
+                    "maintainers": {
+                        "type": "array",
+                        "items": { "type": "string" }
+                    },
+                    "fallback_search_url": {
+                        "description": "Where unknown keys are redirected instead of getting \
+                                        a 404, with `{key}` replaced by the key",
+                        "type": "string",
+                        "format": "uri-template"
+                    },
+                    "redirect_prefix_map": {
+                        "description": "URLs that paths starting with a prefix are redirected \
+                                        to, with the rest of the path appended. The longest \
+                                        matching prefix wins.",
+                        "type": "object",
+                        "additionalProperties": { "type": "string", "format": "uri" }
+                    }
+                }
+            },
+            "redirect": {
+                "type": "array",
+                "items": redirect_schema()
+            },
+            "redirects": {
+                "description": "Redirects keyed by `short`, with just the URL or a table like \
+                                a `[[redirect]]` without `short`",
+                "type": "object",
+                "additionalProperties": {
+                    "oneOf": [
+                        { "type": "string", "format": "uri" },
+                        table_redirect_schema()
+                    ]
+                }
+            }
+        }
+    })
+}
+
+/// Schema of a table in `[redirects]`: a `[[redirect]]` whose `short` is the key
+fn table_redirect_schema() -> Value {
+    let mut schema = redirect_schema();
+    schema["required"] = json!(["url"]);
+    if let Some(properties) = schema["properties"].as_object_mut() {
+        properties.remove("short");
+    }
+    schema
+}
+
+/// Schema of a single `[[redirect]]`
+fn redirect_schema() -> Value {
+    json!({
+        "type": "object",
+        "additionalProperties": false,
+        "required": ["short", "url"],
+        "properties": {
+            "short": {
+                "description": "Subdomain of the redirect, e.g. `std` for std.rustref.com, or \
+                                a wildcard like `blog-*` matching part of one",
+                "type": "string",
+                "pattern": "^([a-z0-9]([a-z0-9-]{0,61}[a-z0-9])?|[a-z0-9-]*\\*[a-z0-9-]*)$"
+            },
+            "url": {
+                "description": "Where the redirect goes. For wildcards `{wildcard}` is \
+                                replaced by the part of the key the `*` matched.",
+                "type": "string",
+                "format": "uri"
+            },
+            "category": {
+                "description": "Heading the redirect is listed under on the index page",
+                "type": "string"
+            },
+            "description": {
+                "description": "Short explanation of where the redirect goes",
+                "type": "string"
+            },
+            "permanent": {
+                "description": "Send a 301 instead of a 302",
+                "type": "boolean",
+                "default": false
+            },
+            "preserve_method": {
+                "description": "Send a 307 (308 if permanent) so clients keep the request \
+                                method and body",
+                "type": "boolean",
+                "default": false
+            },
+            "indexable": {
+                "description": "Let search engines index the redirect",
+                "type": "boolean",
+                "default": false
+            },
+            "status": {
+                "description": "Send this redirect status, overriding `permanent` and \
+                                `preserve_method`",
+                "type": "integer",
+                "enum": [300, 301, 302, 303, 307, 308]
+            },
+            "cache_seconds": {
+                "description": "How long clients may cache a permanent redirect",
+                "type": "integer",
+                "minimum": 0
+            },
+            "strip_prefix": {
+                "description": "Leading part of the incoming path to drop before appending \
+                                it to `url`",
+                "type": "string"
+            },
+            "accept_status": {
+                "description": "Statuses besides 2xx that pass the URL check, like `403` or \
+                                `\"401-403\"`",
+                "type": "array",
+                "items": {
+                    "oneOf": [
+                        { "type": "integer", "minimum": 100, "maximum": 599 },
+                        {
+                            "type": "string",
+                            "pattern": "^\\s*[1-5][0-9]{2}\\s*(-\\s*[1-5][0-9]{2}\\s*)?$"
+                        }
+                    ]
+                }
+            },
+            "enabled": {
+                "description": "Set to false to keep the redirect in the config without serving \
+                                it or creating its CNAME",
+                "type": "boolean",
+                "default": true
+            },
+            "localized": {
+                "description": "Translated versions of `url`, keyed by language tag",
+                "type": "object",
+                "additionalProperties": { "type": "string", "format": "uri" }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use redirect_utils::{ConfigMeta, SiteRedirect, TomlConfig};
+    use serde_json;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn schema_is_json() {
+        let text = serde_json::to_string(&config_schema()).unwrap();
+        let schema: Value = serde_json::from_str(&text).unwrap();
+        let redirect = &schema["properties"]["redirect"]["items"];
+        assert_eq!(redirect["required"], json!(["short", "url"]));
+        assert!(redirect["properties"]["short"].is_object());
+        assert!(redirect["properties"]["url"].is_object());
+        let accept = &redirect["properties"]["accept_status"]["items"]["oneOf"];
+        assert_eq!(accept[0]["type"], "integer");
+        assert_eq!(accept[1]["type"], "string");
+
+        let table = &schema["properties"]["redirects"]["additionalProperties"]["oneOf"][1];
+        assert_eq!(table["required"], json!(["url"]));
+        assert!(table["properties"]["short"].is_null());
+    }
+
+    #[test]
+    fn schema_covers_every_field() {
+        let mut redirect = SiteRedirect {
+            short: "std".to_string(),
+            url: "https://doc.rust-lang.org/std/".to_string(),
+            category: Some("Docs".to_string()),
+            description: Some("The standard library".to_string()),
+            permanent: true,
+            preserve_method: true,
+            indexable: true,
+            status: Some(308),
+            cache_seconds: Some(60),
+            strip_prefix: Some("old".to_string()),
+            accept_status: vec!["401-403".to_string()],
+            enabled: false,
+            ..SiteRedirect::default()
+        };
+        redirect
+            .localized
+            .insert("ja".to_string(), "https://doc.rust-jp.rs/".to_string());
+        let mut prefixes = BTreeMap::new();
+        prefixes.insert("/book".to_string(), "https://doc.rust-lang.org/book".to_string());
+        let config = TomlConfig {
+            include: vec!["common.toml".to_string()],
+            meta: Some(ConfigMeta {
+                version: 1,
+                maintainers: vec!["nocduro".to_string()],
+                fallback_search_url: Some("https://doc.rust-lang.org/?search={key}".to_string()),
+                redirect_prefix_map: prefixes,
+            }),
+            redirect: vec![redirect],
+            redirects: BTreeMap::new(),
+        };
+        let config = serde_json::to_value(&config).unwrap();
+        let schema = config_schema();
+
+        let fields = |value: &Value| -> Vec<String> {
+            value.as_object().unwrap().keys().cloned().collect()
+        };
+        for field in fields(&config) {
+            assert!(schema["properties"][&field].is_object(), "{} missing", field);
+        }
+        for field in fields(&config["meta"]) {
+            let meta = &schema["properties"]["meta"]["properties"];
+            assert!(meta[&field].is_object(), "meta.{} missing", field);
+        }
+        let redirect = &schema["properties"]["redirect"]["items"]["properties"];
+        for field in fields(&config["redirect"][0]) {
+            assert!(redirect[&field].is_object(), "redirect.{} missing", field);
+        }
+    }
+}
@@ -0,0 +1,49 @@
+use dotenv;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::StatusClass;
+use rocket::{Request, Response};
+
+/// `Content-Security-Policy` used when the `content_security_policy` env var isn't set.
+/// The templates use an inline `<style>` block, so inline styles are allowed.
+pub const DEFAULT_CSP: &str = "default-src 'self'; style-src 'self' 'unsafe-inline'";
+
+/// Adds security headers to every response except redirects, which have no body:
+/// `X-Content-Type-Options` everywhere, plus `X-Frame-Options` and a
+/// `Content-Security-Policy` on HTML pages.
+pub struct SecurityHeaders {
+    csp: String,
+}
+
+impl SecurityHeaders {
+    pub fn new(csp: &str) -> SecurityHeaders {
+        SecurityHeaders {
+            csp: csp.to_string(),
+        }
+    }
+
+    /// Read the policy from the `content_security_policy` env var, defaulting to `DEFAULT_CSP`
+    pub fn from_env() -> SecurityHeaders {
+        let csp = dotenv::var("content_security_policy").unwrap_or_else(|_| DEFAULT_CSP.into());
+        SecurityHeaders::new(&csp)
+    }
+}
+
+impl Fairing for SecurityHeaders {
+    fn info(&self) -> Info {
+        Info {
+            name: "Security headers",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, _request: &Request, response: &mut Response) {
+        if response.status().class() == StatusClass::Redirection {
+            return;
+        }
+        response.set_raw_header("X-Content-Type-Options", "nosniff");
+        if response.content_type().map_or(false, |ct| ct.is_html()) {
+            response.set_raw_header("X-Frame-Options", "DENY");
+            response.set_raw_header("Content-Security-Policy", self.csp.clone());
+        }
+    }
+}
@@ -0,0 +1,200 @@
+use ctrlc;
+use dotenv;
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use rocket::{Outcome, State};
+
+use std::process;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// How long to wait for in-flight updates on SIGTERM, used when the
+/// `shutdown_timeout_seconds` env var isn't set
+pub const DEFAULT_SHUTDOWN_TIMEOUT_SECONDS: u64 = 30;
+
+/// Coordinates shutting down with in-flight redirect updates, so the process isn't
+/// killed halfway through changing the Cloudflare records.
+///
+/// Updates (webhooks and rollbacks) hold a `Busy` token while they run, and only one token
+/// is handed out at a time so they can't interleave their DNS changes and map swaps.
+/// Once `shut_down` is called no new tokens are handed out, and `shut_down` waits for the
+/// outstanding one to be dropped.
+#[derive(Debug, Default)]
+pub struct Shutdown {
+    state: Mutex<ShutdownState>,
+    idle: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct ShutdownState {
+    shutting_down: bool,
+    updating: bool,
+}
+
+/// Why `Shutdown::busy` didn't start an update
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Refused {
+    ShuttingDown,
+    /// Another update holds the `Busy` token
+    Updating,
+}
+
+/// Marks an update as in progress until dropped
+#[derive(Debug)]
+pub struct Busy<'a> {
+    shutdown: &'a Shutdown,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown::default()
+    }
+
+    fn state(&self) -> MutexGuard<ShutdownState> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Start an update, unless the server is shutting down or another update is running
+    pub fn busy(&self) -> Result<Busy, Refused> {
+        let mut state = self.state();
+        if state.shutting_down {
+            return Err(Refused::ShuttingDown);
+        }
+        if state.updating {
+            return Err(Refused::Updating);
+        }
+        state.updating = true;
+        Ok(Busy { shutdown: self })
+    }
+
+    /// Whether `shut_down` was called
+    #[cfg(test)]
+    pub fn is_shutting_down(&self) -> bool {
+        self.state().shutting_down
+    }
+
+    /// Stop accepting updates and wait up to `timeout` for the in-flight one to finish.
+    /// Returns `false` if it was still running when the timeout ran out.
+    pub fn shut_down(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state();
+        state.shutting_down = true;
+        while state.updating {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            state = match self.idle.wait_timeout(state, deadline - now) {
+                Ok((state, _)) => state,
+                Err(poisoned) => poisoned.into_inner().0,
+            };
+        }
+        true
+    }
+}
+
+impl<'a> Drop for Busy<'a> {
+    fn drop(&mut self) {
+        self.shutdown.state().updating = false;
+        self.shutdown.idle.notify_all();
+    }
+}
+
+/// Request guard holding a `Busy` token for the managed `Arc<Shutdown>` while the
+/// handler runs. Fails with 409 Conflict while another update is running, and with
+/// 503 Service Unavailable once the server is shutting down.
+impl<'a, 'r> FromRequest<'a, 'r> for Busy<'r> {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Busy<'r>, ()> {
+        let shutdown: &'r Shutdown = match request.guard::<State<Arc<Shutdown>>>() {
+            Outcome::Success(shutdown) => shutdown.inner(),
+            _ => return Outcome::Failure((Status::InternalServerError, ())),
+        };
+        match shutdown.busy() {
+            Ok(busy) => Outcome::Success(busy),
+            Err(Refused::Updating) => Outcome::Failure((Status::Conflict, ())),
+            Err(Refused::ShuttingDown) => Outcome::Failure((Status::ServiceUnavailable, ())),
+        }
+    }
+}
+
+/// Read the `shutdown_timeout_seconds` env var, falling back to
+/// `DEFAULT_SHUTDOWN_TIMEOUT_SECONDS`
+pub fn timeout_from_env() -> Duration {
+    let seconds = match dotenv::var("shutdown_timeout_seconds") {
+        Ok(s) => s.parse().unwrap_or_else(|_| {
+            println!("Warning: invalid shutdown_timeout_seconds `{}`, using default", s);
+            DEFAULT_SHUTDOWN_TIMEOUT_SECONDS
+        }),
+        Err(_) => DEFAULT_SHUTDOWN_TIMEOUT_SECONDS,
+    };
+    Duration::from_secs(seconds)
+}
+
+/// On SIGTERM or Ctrl-C, stop accepting webhooks and exit once the in-flight updates
+/// are done, or after `timeout`
+pub fn exit_on_signal(shutdown: Arc<Shutdown>, timeout: Duration) {
+    let result = ctrlc::set_handler(move || {
+        println!("shutting down, waiting up to {:?} for updates to finish", timeout);
+        if shutdown.shut_down(timeout) {
+            process::exit(0);
+        }
+        eprintln!("error: updates still running after {:?}, exiting anyway", timeout);
+        process::exit(1);
+    });
+    if let Err(e) = result {
+        println!("Warning: could not install the shutdown handler: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+
+    #[test]
+    fn shutdown_waits_for_busy() {
+        let shutdown = Arc::new(Shutdown::new());
+        assert!(shutdown.shut_down(Duration::from_millis(0)));
+        assert!(shutdown.is_shutting_down());
+        assert_eq!(shutdown.busy().err(), Some(Refused::ShuttingDown));
+
+        let shutdown = Arc::new(Shutdown::new());
+        let (started_tx, started_rx) = mpsc::channel();
+        let worker = {
+            let shutdown = Arc::clone(&shutdown);
+            thread::spawn(move || {
+                let _busy = shutdown.busy().expect("not shutting down yet");
+                started_tx.send(()).unwrap();
+                thread::sleep(Duration::from_millis(100));
+            })
+        };
+        started_rx.recv().unwrap();
+        assert!(shutdown.shut_down(Duration::from_secs(10)));
+        assert_eq!(shutdown.busy().err(), Some(Refused::ShuttingDown));
+        worker.join().unwrap();
+    }
+
+    #[test]
+    fn one_update_at_a_time() {
+        let shutdown = Shutdown::new();
+        let busy = shutdown.busy().unwrap();
+        assert_eq!(shutdown.busy().err(), Some(Refused::Updating));
+        drop(busy);
+        assert!(shutdown.busy().is_ok());
+    }
+
+    #[test]
+    fn shutdown_times_out() {
+        let shutdown = Shutdown::new();
+        let busy = shutdown.busy();
+        assert!(busy.is_ok());
+        assert!(!shutdown.shut_down(Duration::from_millis(20)));
+        drop(busy);
+        assert!(shutdown.shut_down(Duration::from_millis(0)));
+    }
+}
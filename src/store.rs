@@ -0,0 +1,498 @@
+use redirect_utils::{self, ConfigMeta, SiteRedirect};
+use {RedirectStorage, Result};
+
+use dotenv;
+#[cfg(feature = "sqlite")]
+use rusqlite::types::ToSql;
+#[cfg(feature = "sqlite")]
+use rusqlite::Connection;
+#[cfg(feature = "sqlite")]
+use serde_json;
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Where the redirects are kept between restarts.
+///
+/// Only `all` and `replace_all` are required; `upsert` and `delete` default to rewriting
+/// every redirect, so stores that can change a single one should override them.
+pub trait RedirectStore: fmt::Debug {
+    /// Prepare the storage, e.g. create its tables, and read every redirect in it.
+    /// Called once at startup.
+    fn load(&mut self) -> Result<Vec<SiteRedirect>> {
+        self.all()
+    }
+
+    /// Every stored redirect, sorted by `short`
+    fn all(&self) -> Result<Vec<SiteRedirect>>;
+
+    /// Replace every stored redirect with `redirects`. Stores that can keep the config's
+    /// `[meta]` table save `meta` along with them.
+    fn replace_all(
+        &mut self,
+        redirects: &[SiteRedirect],
+        meta: Option<&ConfigMeta>,
+    ) -> Result<()>;
+
+    /// Replace every stored redirect with the config read from `files`: the config file
+    /// first, then its includes relative to its directory, which gave `redirects` and
+    /// `meta`. Stores that keep files write each of them back, the others `replace_all`.
+    fn replace_files(
+        &mut self,
+        _files: &[(PathBuf, String)],
+        redirects: &[SiteRedirect],
+        meta: Option<&ConfigMeta>,
+    ) -> Result<()> {
+        self.replace_all(redirects, meta)
+    }
+
+    /// Store `redirect`, replacing the stored redirect with the same `short` if there is one
+    fn upsert(&mut self, redirect: &SiteRedirect) -> Result<()> {
+        let mut redirects = self.all()?;
+        redirects.retain(|r| r.short != redirect.short);
+        redirects.push(redirect.clone());
+        redirects.sort_by(|a, b| a.short.cmp(&b.short));
+        self.replace_all(&redirects, None)
+    }
+
+    /// Remove the redirect `short`. Returns whether it was stored.
+    fn delete(&mut self, short: &str) -> Result<bool> {
+        let mut redirects = self.all()?;
+        let count = redirects.len();
+        redirects.retain(|r| r.short != short);
+        if redirects.len() == count {
+            return Ok(false);
+        }
+        self.replace_all(&redirects, None)?;
+        Ok(true)
+    }
+}
+
+/// Run `change` on the redirects' store, if there is one
+pub fn update<F>(store: &RedirectStorage, change: F) -> Result<()>
+where
+    F: FnOnce(&mut (RedirectStore + Send)) -> Result<()>,
+{
+    if let Some(ref store) = *store {
+        change(&mut **store.lock()?)?;
+    }
+    Ok(())
+}
+
+/// The store named by the `store_backend` env var: `toml`, the default, keeps the
+/// redirects in the redirects file at `path`. `sqlite`,
+/// which needs the `sqlite` feature, opens the database at `sqlite_path` (default
+/// `redirects.db`).
+pub fn store_from_env(path: &Path) -> Box<RedirectStore + Send> {
+    let backend = dotenv::var("store_backend").unwrap_or_else(|_| "toml".to_string());
+    match backend.as_str() {
+        "toml" => Box::new(TomlStore::new(path)),
+        #[cfg(feature = "sqlite")]
+        "sqlite" => {
+            let path = dotenv::var("sqlite_path").unwrap_or_else(|_| "redirects.db".to_string());
+            match SqliteStore::open(&path) {
+                Ok(store) => Box::new(store),
+                Err(e) => {
+                    eprintln!("error: can't open the SQLite database {}: {}", path, e);
+                    process::exit(1);
+                }
+            }
+        }
+        other => {
+            eprintln!(
+                "error: unknown store_backend `{}`, expected `toml` or `sqlite` \
+                 (with the `sqlite` feature)",
+                other
+            );
+            process::exit(1);
+        }
+    }
+}
+
+/// Redirects kept in a redirects.toml file. Config reloads write back the file and its
+/// includes as they were read; other writes replace any `include`s with the redirects
+/// they had, so the file holds the whole config.
+///
+/// The store is only written behind its `RedirectStorage` lock, so writes can't interleave,
+/// and each one replaces the file atomically.
+#[derive(Debug)]
+pub struct TomlStore {
+    path: PathBuf,
+}
+
+impl TomlStore {
+    pub fn new<P: Into<PathBuf>>(path: P) -> TomlStore {
+        TomlStore { path: path.into() }
+    }
+
+    /// Change the redirects in the file with `change`, keeping its `[meta]` table
+    fn edit<F, T>(&mut self, change: F) -> Result<T>
+    where
+        F: FnOnce(&mut Vec<SiteRedirect>) -> T,
+    {
+        let config = redirect_utils::config_from_file(&self.path)?;
+        let mut redirects = config.redirect;
+        let result = change(&mut redirects);
+        self.replace_all(&redirects, config.meta.as_ref())?;
+        Ok(result)
+    }
+}
+
+impl RedirectStore for TomlStore {
+    fn all(&self) -> Result<Vec<SiteRedirect>> {
+        let mut redirects = redirect_utils::config_from_file(&self.path)?.redirect;
+        redirects.sort_by(|a, b| a.short.cmp(&b.short));
+        Ok(redirects)
+    }
+
+    fn replace_all(
+        &mut self,
+        redirects: &[SiteRedirect],
+        meta: Option<&ConfigMeta>,
+    ) -> Result<()> {
+        let toml_str = redirect_utils::redirects_to_toml(redirects, meta)?;
+        write_atomic(&self.path, &toml_str)?;
+        Ok(())
+    }
+
+    fn replace_files(
+        &mut self,
+        files: &[(PathBuf, String)],
+        _redirects: &[SiteRedirect],
+        _meta: Option<&ConfigMeta>,
+    ) -> Result<()> {
+        // included files are stored relative to the config file like where they were read
+        let root = files
+            .first()
+            .and_then(|&(ref file, _)| file.parent())
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        let dir = self.path.parent().map(Path::to_path_buf).unwrap_or_default();
+        for (i, &(ref file, ref toml_str)) in files.iter().enumerate() {
+            let local = if i == 0 {
+                self.path.clone()
+            } else {
+                dir.join(file.strip_prefix(&root).unwrap_or(file))
+            };
+            if let Some(parent) = local.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            write_atomic(&local, toml_str)?;
+        }
+        Ok(())
+    }
+
+    fn upsert(&mut self, redirect: &SiteRedirect) -> Result<()> {
+        self.edit(|redirects| {
+            let existing = redirects.iter().position(|r| r.short == redirect.short);
+            match existing {
+                Some(i) => redirects[i] = redirect.clone(),
+                None => redirects.push(redirect.clone()),
+            }
+        })
+    }
+
+    fn delete(&mut self, short: &str) -> Result<bool> {
+        self.edit(|redirects| {
+            let count = redirects.len();
+            redirects.retain(|r| r.short != short);
+            redirects.len() != count
+        })
+    }
+}
+
+/// Temporary files `write_atomic` made in this process, keeps their names unique
+static TMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// Write `contents` to a temporary file next to `path`, then rename it over `path` so
+/// readers see either the old or the new file, never half of one. The temporary file is
+/// named after the process and a counter, so two writers never share one.
+fn write_atomic(path: &Path, contents: &str) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(
+        ".{}.{}.tmp",
+        process::id(),
+        TMP_FILES.fetch_add(1, Ordering::SeqCst)
+    ));
+    fs::write(&tmp, contents)?;
+    fs::rename(&tmp, path)
+}
+
+/// Redirects stored in a SQLite database, one row per redirect. Rows hold the redirect as
+/// JSON, so adding a field to `SiteRedirect` doesn't need a migration.
+#[cfg(feature = "sqlite")]
+#[derive(Debug)]
+pub struct SqliteStore {
+    conn: Connection,
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<SqliteStore> {
+        Ok(SqliteStore {
+            conn: Connection::open(path)?,
+        })
+    }
+
+    /// A database that only lives as long as the store, for tests
+    #[cfg(test)]
+    pub fn in_memory() -> Result<SqliteStore> {
+        Ok(SqliteStore {
+            conn: Connection::open_in_memory()?,
+        })
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl RedirectStore for SqliteStore {
+    fn load(&mut self) -> Result<Vec<SiteRedirect>> {
+        self.conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS redirects (
+                short TEXT PRIMARY KEY NOT NULL,
+                redirect TEXT NOT NULL
+            )",
+        )?;
+        self.all()
+    }
+
+    fn all(&self) -> Result<Vec<SiteRedirect>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT redirect FROM redirects ORDER BY short")?;
+        let rows = stmt.query_map(&[], |row| row.get::<_, String>(0))?;
+        let mut redirects = Vec::new();
+        for json in rows {
+            redirects.push(serde_json::from_str(&json?)?);
+        }
+        Ok(redirects)
+    }
+
+    /// Runs in a transaction, so readers never see a half replaced set of redirects.
+    /// The `[meta]` table isn't stored, it's read from the redirects file.
+    fn replace_all(
+        &mut self,
+        redirects: &[SiteRedirect],
+        _meta: Option<&ConfigMeta>,
+    ) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        tx.execute("DELETE FROM redirects", &[])?;
+        for redirect in redirects {
+            let json = serde_json::to_string(redirect)?;
+            let params: &[&ToSql] = &[&redirect.short, &json];
+            tx.execute("INSERT INTO redirects (short, redirect) VALUES (?1, ?2)", params)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn upsert(&mut self, redirect: &SiteRedirect) -> Result<()> {
+        let json = serde_json::to_string(redirect)?;
+        let params: &[&ToSql] = &[&redirect.short, &json];
+        self.conn.execute(
+            "INSERT OR REPLACE INTO redirects (short, redirect) VALUES (?1, ?2)",
+            params,
+        )?;
+        Ok(())
+    }
+
+    fn delete(&mut self, short: &str) -> Result<bool> {
+        let short = short.to_string();
+        let params: &[&ToSql] = &[&short];
+        let deleted = self
+            .conn
+            .execute("DELETE FROM redirects WHERE short = ?1", params)?;
+        Ok(deleted > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::sync::mpsc;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+    use test_utils::MockStore;
+
+    fn redirect(short: &str, url: &str) -> SiteRedirect {
+        SiteRedirect {
+            short: short.to_string(),
+            url: url.to_string(),
+            ..SiteRedirect::default()
+        }
+    }
+
+    #[test]
+    fn default_upsert_and_delete() {
+        let mut store = MockStore::default();
+        store.upsert(&redirect("std", "https://doc.rust-lang.org/std/")).unwrap();
+        store.upsert(&redirect("book", "https://doc.rust-lang.org/book/")).unwrap();
+        store.upsert(&redirect("std", "https://doc.rust-lang.org/stable/std/")).unwrap();
+        let shorts: Vec<String> = store.all().unwrap().into_iter().map(|r| r.short).collect();
+        assert_eq!(shorts, vec!["book", "std"]);
+        assert_eq!(store.all().unwrap()[1].url, "https://doc.rust-lang.org/stable/std/");
+
+        assert!(store.delete("std").unwrap());
+        assert!(!store.delete("std").unwrap());
+        assert_eq!(store.all().unwrap(), vec![redirect("book", "https://doc.rust-lang.org/book/")]);
+        // deleting something that isn't stored doesn't rewrite the store
+        assert_eq!(store.calls().len(), 4);
+    }
+
+    #[test]
+    fn toml_store_keeps_meta() {
+        let path = env::temp_dir().join(format!("rustref-store-{}.toml", process::id()));
+        fs::write(
+            &path,
+            r#"
+            [meta]
+            version = 1
+            maintainers = ["nocduro"]
+
+            [[redirect]]
+            short = "std"
+            url = "https://doc.rust-lang.org/std/"
+        "#,
+        ).unwrap();
+        let mut store = TomlStore::new(&path);
+        store.upsert(&redirect("book", "https://doc.rust-lang.org/book/")).unwrap();
+        assert!(store.delete("std").unwrap());
+        assert!(!store.delete("std").unwrap());
+        let stored = store.load().unwrap();
+        assert_eq!(stored, vec![redirect("book", "https://doc.rust-lang.org/book/")]);
+
+        let config = redirect_utils::config_from_file(&path).unwrap();
+        assert_eq!(config.meta.unwrap().maintainers, vec!["nocduro"]);
+
+        store.replace_all(&[redirect("std", "https://doc.rust-lang.org/std/")], None).unwrap();
+        let config = redirect_utils::config_from_file(&path).unwrap();
+        assert!(config.meta.is_none());
+        assert_eq!(config.redirect[0].short, "std");
+        // the temporary files were renamed over the store's file
+        assert!(tmp_files(&path).is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+
+    /// Temporary files `write_atomic` left next to `path`
+    fn tmp_files(path: &Path) -> Vec<PathBuf> {
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        fs::read_dir(path.parent().unwrap())
+            .unwrap()
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|p| {
+                let file = p.file_name().unwrap().to_string_lossy();
+                file.starts_with(&name) && file.ends_with(".tmp")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn concurrent_toml_writes() {
+        let path = env::temp_dir().join(format!("rustref-store-locked-{}.toml", process::id()));
+        let toml_store: Box<RedirectStore + Send> = Box::new(TomlStore::new(&path));
+        let storage: Arc<RedirectStorage> = Arc::new(Some(Mutex::new(toml_store)));
+
+        let (locked_tx, locked_rx) = mpsc::channel();
+        let first = {
+            let storage = storage.clone();
+            thread::spawn(move || {
+                update(&storage, |store| {
+                    locked_tx.send(()).unwrap();
+                    // give the second write time to queue up behind the lock
+                    thread::sleep(Duration::from_millis(50));
+                    store.replace_all(&[redirect("std", "https://doc.rust-lang.org/std/")], None)
+                })
+            })
+        };
+        locked_rx.recv().unwrap();
+        let second = {
+            let storage = storage.clone();
+            thread::spawn(move || {
+                update(&storage, |store| {
+                    store.replace_all(&[redirect("book", "https://doc.rust-lang.org/book/")], None)
+                })
+            })
+        };
+        assert!(first.join().unwrap().is_ok());
+        assert!(second.join().unwrap().is_ok());
+
+        // the write that got the lock last is the one kept
+        let stored = TomlStore::new(&path).all().unwrap();
+        assert_eq!(stored, vec![redirect("book", "https://doc.rust-lang.org/book/")]);
+        assert!(tmp_files(&path).is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn toml_store_writes_includes() {
+        let dir = env::temp_dir().join(format!("rustref-store-includes-{}", process::id()));
+        let main = "include = [\"nested/tools.toml\"]\n\n[[redirect]]\nshort = \"std\"\n\
+                    url = \"https://doc.rust-lang.org/std/\"\n";
+        let tools = "[[redirect]]\nshort = \"cargo\"\nurl = \"https://doc.rust-lang.org/cargo/\"\n";
+        let files = vec![
+            (PathBuf::from("redirects.toml"), main.to_string()),
+            (PathBuf::from("nested/tools.toml"), tools.to_string()),
+        ];
+        let mut store = TomlStore::new(dir.join("redirects.toml"));
+        store.replace_files(&files, &[], None).unwrap();
+
+        assert_eq!(fs::read_to_string(dir.join("redirects.toml")).unwrap(), main);
+        assert_eq!(fs::read_to_string(dir.join("nested/tools.toml")).unwrap(), tools);
+        let shorts: Vec<String> = store.all().unwrap().into_iter().map(|r| r.short).collect();
+        assert_eq!(shorts, vec!["cargo", "std"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite_round_trip() {
+        let mut store = SqliteStore::in_memory().unwrap();
+        assert!(store.load().unwrap().is_empty());
+
+        let mut book = redirect("book", "https://doc.rust-lang.org/book/");
+        book.category = Some("Docs".to_string());
+        book.permanent = true;
+        book.enabled = false;
+        book.localized.insert("ja".to_string(), "https://doc.rust-jp.rs/book/".to_string());
+        let std_docs = redirect("std", "https://doc.rust-lang.org/std/");
+        store.upsert(&std_docs).unwrap();
+        store.upsert(&book).unwrap();
+        assert_eq!(store.all().unwrap(), vec![book.clone(), std_docs]);
+
+        let moved = redirect("std", "https://doc.rust-lang.org/stable/std/");
+        store.upsert(&moved).unwrap();
+        assert_eq!(store.all().unwrap(), vec![book.clone(), moved]);
+
+        assert!(store.delete("std").unwrap());
+        assert!(!store.delete("std").unwrap());
+        assert_eq!(store.all().unwrap(), vec![book]);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite_survives_reopening() {
+        let path = env::temp_dir().join(format!("rustref-store-{}.db", process::id()));
+        let _ = fs::remove_file(&path);
+        let redirects = vec![
+            redirect("book", "https://doc.rust-lang.org/book/"),
+            redirect("std", "https://doc.rust-lang.org/std/"),
+        ];
+        {
+            let mut store = SqliteStore::open(&path).unwrap();
+            store.load().unwrap();
+            store.replace_all(&redirects, None).unwrap();
+        }
+        let mut store = SqliteStore::open(&path).unwrap();
+        assert_eq!(store.load().unwrap(), redirects);
+
+        store.replace_all(&redirects[1..], None).unwrap();
+        assert_eq!(store.all().unwrap(), &redirects[1..]);
+        fs::remove_file(&path).unwrap();
+    }
+}
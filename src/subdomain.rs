@@ -0,0 +1,75 @@
+use redirect_utils::ZONE;
+
+use rocket::request::{self, FromRequest, Request};
+use rocket::Outcome;
+
+/// The redirect key from the `Host` header of a request to a subdomain of `ZONE`,
+/// e.g. `cook` for cook.rustref.com.
+///
+/// Forwards requests to the apex (rustref.com or www.rustref.com) and to other hosts, so
+/// they fall through to the path based routes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Subdomain(pub String);
+
+impl<'a, 'r> FromRequest<'a, 'r> for Subdomain {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Subdomain, ()> {
+        match request.headers().get_one("Host").and_then(|host| subdomain_of(host, ZONE)) {
+            Some(key) => Outcome::Success(Subdomain(key)),
+            None => Outcome::Forward(()),
+        }
+    }
+}
+
+/// The leftmost label of `host` if it's a subdomain of `zone`, ignoring a port, case and a
+/// leading `www.` like `normalize_key` does
+fn subdomain_of(host: &str, zone: &str) -> Option<String> {
+    let host = host.split(':').next().unwrap_or("").trim_right_matches('.');
+    let host = host.to_ascii_lowercase();
+    let suffix = format!(".{}", zone.to_ascii_lowercase());
+    if !host.ends_with(&suffix) {
+        return None;
+    }
+    let labels = &host[..host.len() - suffix.len()];
+    let labels = if labels.starts_with("www.") {
+        &labels[4..]
+    } else {
+        labels
+    };
+    match labels.split('.').next() {
+        Some(label) if !label.is_empty() && label != "www" => Some(label.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(host: &str) -> Option<String> {
+        subdomain_of(host, "rustref.com")
+    }
+
+    #[test]
+    fn subdomain_keys() {
+        assert_eq!(key("cook.rustref.com"), Some("cook".to_string()));
+        assert_eq!(key("COOK.RustRef.com"), Some("cook".to_string()));
+        assert_eq!(key("cook.rustref.com:8000"), Some("cook".to_string()));
+        assert_eq!(key("cook.rustref.com."), Some("cook".to_string()));
+        assert_eq!(key("www.cook.rustref.com"), Some("cook".to_string()));
+        assert_eq!(key("a.b.rustref.com"), Some("a".to_string()));
+    }
+
+    #[test]
+    fn apex_and_other_hosts_forwarded() {
+        assert_eq!(key("rustref.com"), None);
+        assert_eq!(key("www.rustref.com"), None);
+        assert_eq!(key("rustref.com:8000"), None);
+        assert_eq!(key(".rustref.com"), None);
+        assert_eq!(key("localhost:8000"), None);
+        assert_eq!(key("127.0.0.1"), None);
+        assert_eq!(key("cook.notrustref.com"), None);
+        assert_eq!(key("cook.rustref.com.evil.example"), None);
+    }
+}
@@ -0,0 +1,300 @@
+use dns_provider::{CnameRecord, DnsProvider};
+use redirect_utils::{ConfigMeta, SiteRedirect, ZONE};
+use store::RedirectStore;
+use {Error, Result};
+
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Start an HTTP server on a random local port. Every request is answered with the raw
+/// HTTP response returned by `handler`, which gets the request path.
+/// Returns the base url of the server, e.g. `http://127.0.0.1:41234`.
+pub fn mock_server<F>(handler: F) -> String
+where
+    F: Fn(&str) -> String + Send + 'static,
+{
+    recording_mock_server(handler).0
+}
+
+/// Like `mock_server`, but also returns every raw request received so far, body included
+pub fn recording_mock_server<F>(handler: F) -> (String, Arc<Mutex<Vec<String>>>)
+where
+    F: Fn(&str) -> String + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind mock server");
+    let addr = listener.local_addr().expect("mock server address");
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let recorded = Arc::clone(&requests);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(s) => s,
+                Err(_) => continue,
+            };
+            let request = read_request(&mut stream);
+            // request line looks like `GET /path HTTP/1.1`
+            let path = request.split_whitespace().nth(1).unwrap_or("/").to_string();
+            recorded.lock().unwrap().push(request);
+            let _ = stream.write_all(handler(&path).as_bytes());
+        }
+    });
+    (format!("http://{}", addr), requests)
+}
+
+/// Read a request's head and, going by its `Content-Length`, its body
+fn read_request(stream: &mut TcpStream) -> String {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        let len = stream.read(&mut buf).unwrap_or(0);
+        if len == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..len]);
+        let text = String::from_utf8_lossy(&request).into_owned();
+        let head_len = match text.find("\r\n\r\n") {
+            Some(i) => i + 4,
+            None => continue,
+        };
+        let body_len = text[..head_len]
+            .lines()
+            .filter_map(|line| {
+                let mut parts = line.splitn(2, ':');
+                let name = parts.next()?;
+                if name.trim().eq_ignore_ascii_case("content-length") {
+                    parts.next()?.trim().parse::<usize>().ok()
+                } else {
+                    None
+                }
+            })
+            .next()
+            .unwrap_or(0);
+        if request.len() >= head_len + body_len {
+            break;
+        }
+    }
+    String::from_utf8_lossy(&request).into_owned()
+}
+
+/// A raw HTTP response with `status` (e.g. `404 Not Found`), extra `headers`, and `body`
+pub fn http_response(status: &str, headers: &[(&str, &str)], body: &str) -> String {
+    let mut response = format!("HTTP/1.1 {}\r\n", status);
+    for &(name, value) in headers {
+        response.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    response.push_str(&format!(
+        "Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    ));
+    response
+}
+
+/// In-memory `DnsProvider` that logs every call, e.g. `create_cname std.rustref.com`.
+/// Clones share the records and the log.
+#[derive(Debug, Clone, Default)]
+pub struct MockDns {
+    cnames: Arc<Mutex<Vec<CnameRecord>>>,
+    calls: Arc<Mutex<Vec<String>>>,
+    /// Record names whose creation or deletion fails, or zones whose lookup fails
+    failing: Arc<Mutex<Vec<String>>>,
+    /// Whether created records are proxied, `None` to act like a host without a proxy
+    proxied: Option<bool>,
+}
+
+impl MockDns {
+    /// A provider whose zone already has the CNAME records `names`, pointing at the zone
+    pub fn with_cnames(names: &[&str]) -> MockDns {
+        MockDns::with_records(
+            &names
+                .iter()
+                .map(|name| CnameRecord {
+                    id: name.to_string(),
+                    name: name.to_string(),
+                    content: ZONE.to_string(),
+                    proxied: None,
+                })
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    /// A provider whose zone already has `records`
+    pub fn with_records(records: &[CnameRecord]) -> MockDns {
+        let mock = MockDns::default();
+        mock.cnames.lock().unwrap().extend_from_slice(records);
+        mock
+    }
+
+    /// Make the provider proxy the records it creates, or not
+    pub fn proxying(mut self, proxied: bool) -> MockDns {
+        self.proxied = Some(proxied);
+        self
+    }
+
+    /// Make creating or deleting the records `names`, or looking up the zones `names`, fail
+    pub fn fail_on(self, names: &[&str]) -> MockDns {
+        self.failing
+            .lock()
+            .unwrap()
+            .extend(names.iter().map(|n| n.to_string()));
+        self
+    }
+
+    pub fn cnames(&self) -> Vec<String> {
+        let mut cnames: Vec<String> = self
+            .cnames
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|record| record.name.clone())
+            .collect();
+        cnames.sort();
+        cnames
+    }
+
+    /// The record named `name`, if there is one
+    pub fn record(&self, name: &str) -> Option<CnameRecord> {
+        self.cnames
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|record| record.name == name)
+            .cloned()
+    }
+
+    pub fn calls(&self) -> Vec<String> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    fn log(&self, call: String) {
+        self.calls.lock().unwrap().push(call);
+    }
+
+    fn check_failing(&self, name: &str) -> Result<()> {
+        if self.failing.lock().unwrap().iter().any(|n| n == name) {
+            Err(Error::Dns(format!("mock failure for {}", name)))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl DnsProvider for MockDns {
+    fn zone_id(&self, zone: &str) -> Result<String> {
+        self.log(format!("zone_id {}", zone));
+        self.check_failing(zone)?;
+        Ok("zone".to_string())
+    }
+
+    fn list_cnames(&self, _zone_id: &str) -> Result<Vec<CnameRecord>> {
+        self.log("list_cnames".to_string());
+        Ok(self.cnames.lock().unwrap().clone())
+    }
+
+    fn create_cname(&self, _zone_id: &str, name: &str, target: &str) -> Result<()> {
+        self.log(format!("create_cname {}", name));
+        self.check_failing(name)?;
+        self.cnames.lock().unwrap().push(CnameRecord {
+            id: name.to_string(),
+            name: name.to_string(),
+            content: target.to_string(),
+            proxied: self.proxied,
+        });
+        Ok(())
+    }
+
+    fn delete_cname(&self, _zone_id: &str, record: &CnameRecord) -> Result<()> {
+        self.log(format!("delete_cname {}", record.name));
+        self.check_failing(&record.name)?;
+        self.cnames.lock().unwrap().retain(|r| r.id != record.id);
+        Ok(())
+    }
+
+    fn update_cname(&self, _zone_id: &str, record: &CnameRecord, target: &str) -> Result<()> {
+        self.log(format!("update_cname {}", record.name));
+        self.check_failing(&record.name)?;
+        for r in self.cnames.lock().unwrap().iter_mut() {
+            if r.id == record.id {
+                r.content = target.to_string();
+                r.proxied = self.proxied;
+            }
+        }
+        Ok(())
+    }
+
+    fn proxied(&self) -> Option<bool> {
+        self.proxied
+    }
+
+    fn purge_cache(&self, _zone_id: &str) -> Result<()> {
+        self.log("purge_cache".to_string());
+        Ok(())
+    }
+}
+
+/// In-memory `RedirectStore` that only implements the required methods and logs every
+/// write, e.g. `replace_all 3`. Clones share the redirects and the log.
+#[derive(Debug, Clone, Default)]
+pub struct MockStore {
+    redirects: Arc<Mutex<Vec<SiteRedirect>>>,
+    calls: Arc<Mutex<Vec<String>>>,
+    read_only: bool,
+}
+
+impl MockStore {
+    /// A store that already has `redirects`
+    pub fn with_redirects(redirects: &[SiteRedirect]) -> MockStore {
+        let mock = MockStore::default();
+        *mock.redirects.lock().unwrap() = redirects.to_vec();
+        mock
+    }
+
+    /// A store with `redirects` that fails every write, like a read-only redirects file
+    pub fn read_only(redirects: &[SiteRedirect]) -> MockStore {
+        MockStore {
+            read_only: true,
+            ..MockStore::with_redirects(redirects)
+        }
+    }
+
+    /// Shorts of the stored redirects
+    pub fn shorts(&self) -> Vec<String> {
+        self.redirects
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|r| r.short.clone())
+            .collect()
+    }
+
+    pub fn calls(&self) -> Vec<String> {
+        self.calls.lock().unwrap().clone()
+    }
+}
+
+impl RedirectStore for MockStore {
+    fn all(&self) -> Result<Vec<SiteRedirect>> {
+        Ok(self.redirects.lock().unwrap().clone())
+    }
+
+    fn replace_all(
+        &mut self,
+        redirects: &[SiteRedirect],
+        _meta: Option<&ConfigMeta>,
+    ) -> Result<()> {
+        self.calls
+            .lock()
+            .unwrap()
+            .push(format!("replace_all {}", redirects.len()));
+        if self.read_only {
+            let error = io::Error::new(io::ErrorKind::PermissionDenied, "read-only mock store");
+            return Err(Error::Io(error));
+        }
+        let mut redirects = redirects.to_vec();
+        redirects.sort_by(|a, b| a.short.cmp(&b.short));
+        *self.redirects.lock().unwrap() = redirects;
+        Ok(())
+    }
+}
@@ -0,0 +1,194 @@
+use errors::RedirectError;
+
+use native_tls::{Protocol, TlsConnector};
+use reqwest::Url;
+
+use std::collections::HashMap;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The only protocols offered when probing a host. reqwest 0.8 can't be told to refuse
+/// older versions itself, so hosts are probed with these before they are requested.
+const MIN_PROTOCOLS: &[Protocol] = &[Protocol::Tlsv12];
+
+/// How long connecting to and shaking hands with a host may take, in seconds
+const PROBE_TIMEOUT_SECONDS: u64 = 10;
+
+/// How long a host that passed the probe isn't probed again, in seconds: 1 hour
+const PASSED_PROBE_SECONDS: u64 = 60 * 60;
+
+/// Most hosts remembered as having passed the probe
+const MAX_PASSED_HOSTS: usize = 1024;
+
+lazy_static! {
+    /// `host:port` of the hosts that completed a TLS 1.2 handshake, with when they did
+    static ref MODERN_HOSTS: Mutex<HashMap<String, Instant>> = Mutex::new(HashMap::new());
+}
+
+/// Probe whether the host of `url` speaks TLS 1.2 or newer, before it is requested.
+///
+/// This is a probe, not enforcement: reqwest 0.8 can't refuse older versions on the
+/// connection it makes itself, so a host that changes its setup between the probe and the
+/// request isn't caught. Plain `http://` URLs and URLs that can't be parsed are left to the
+/// request. A host that can't be reached fails with `TlsProbeFailed`, and one that passes
+/// isn't probed again for `PASSED_PROBE_SECONDS`.
+pub fn probe_min_version(url: &str) -> Result<(), RedirectError> {
+    let parsed = match Url::parse(url) {
+        Ok(parsed) => parsed,
+        Err(_) => return Ok(()),
+    };
+    if parsed.scheme() != "https" {
+        return Ok(());
+    }
+    let host = match parsed.host_str() {
+        Some(host) => host.to_string(),
+        None => return Ok(()),
+    };
+    let port = parsed.port_or_known_default().unwrap_or(443);
+    let address = format!("{}:{}", host, port);
+    if recently_passed(&address) {
+        return Ok(());
+    }
+
+    let stream = connect(&address)
+        .map_err(|reason| RedirectError::TlsProbeFailed(url.to_string(), reason))?;
+    handshake(&host, stream)
+        .map_err(|reason| RedirectError::OutdatedTls(url.to_string(), reason))?;
+    remember_passed(address);
+    Ok(())
+}
+
+/// Whether `address` passed the probe less than `PASSED_PROBE_SECONDS` ago
+fn recently_passed(address: &str) -> bool {
+    let max_age = Duration::from_secs(PASSED_PROBE_SECONDS);
+    MODERN_HOSTS
+        .lock()
+        .map(|hosts| hosts.get(address).map_or(false, |passed| passed.elapsed() < max_age))
+        .unwrap_or(false)
+}
+
+/// Remember that `address` passed the probe. When `MAX_PASSED_HOSTS` are remembered,
+/// the expired ones are forgotten first, and if that isn't enough the oldest one.
+fn remember_passed(address: String) {
+    let mut hosts = match MODERN_HOSTS.lock() {
+        Ok(hosts) => hosts,
+        Err(_) => return,
+    };
+    if hosts.len() >= MAX_PASSED_HOSTS && !hosts.contains_key(&address) {
+        let max_age = Duration::from_secs(PASSED_PROBE_SECONDS);
+        hosts.retain(|_, passed| passed.elapsed() < max_age);
+        if hosts.len() >= MAX_PASSED_HOSTS {
+            let oldest = hosts
+                .iter()
+                .min_by_key(|&(_, passed)| *passed)
+                .map(|(host, _)| host.clone());
+            if let Some(oldest) = oldest {
+                hosts.remove(&oldest);
+            }
+        }
+    }
+    hosts.insert(address, Instant::now());
+}
+
+/// Open a TCP connection to `address`, or say why it can't be reached
+fn connect(address: &str) -> Result<TcpStream, String> {
+    let timeout = Duration::from_secs(PROBE_TIMEOUT_SECONDS);
+    let addr = address
+        .to_socket_addrs()
+        .map_err(|e| e.to_string())?
+        .next()
+        .ok_or_else(|| format!("{} has no address", address))?;
+    let stream = TcpStream::connect_timeout(&addr, timeout).map_err(|e| e.to_string())?;
+    stream
+        .set_read_timeout(Some(timeout))
+        .and_then(|_| stream.set_write_timeout(Some(timeout)))
+        .map_err(|e| e.to_string())?;
+    Ok(stream)
+}
+
+/// Shake hands with `host` over `stream` offering only `MIN_PROTOCOLS`
+fn handshake(host: &str, stream: TcpStream) -> Result<(), String> {
+    let mut builder = TlsConnector::builder().map_err(|e| e.to_string())?;
+    builder
+        .supported_protocols(MIN_PROTOCOLS)
+        .map_err(|e| e.to_string())?;
+    let connector = builder.build().map_err(|e| e.to_string())?;
+    connector
+        .connect(host, stream)
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn only_modern_protocols_offered() {
+        assert_eq!(MIN_PROTOCOLS.len(), 1);
+        match MIN_PROTOCOLS[0] {
+            Protocol::Tlsv12 => (),
+            _ => panic!("only TLS 1.2 and newer may be offered"),
+        }
+    }
+
+    #[test]
+    fn plain_http_not_probed() {
+        // nothing listens there, so a probe would have to give up on the connection
+        assert!(probe_min_version("http://127.0.0.1:1/").is_ok());
+        assert!(probe_min_version("not a url").is_ok());
+    }
+
+    #[test]
+    fn unreachable_host_fails() {
+        match probe_min_version("https://127.0.0.1:1/") {
+            Err(RedirectError::TlsProbeFailed(ref failed, _)) => {
+                assert_eq!(failed, "https://127.0.0.1:1/")
+            }
+            other => panic!("expected TlsProbeFailed, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn passed_hosts_bounded() {
+        for i in 0..MAX_PASSED_HOSTS + 10 {
+            remember_passed(format!("host-{}.test:443", i));
+        }
+        assert!(MODERN_HOSTS.lock().unwrap().len() <= MAX_PASSED_HOSTS);
+        assert!(recently_passed(&format!("host-{}.test:443", MAX_PASSED_HOSTS + 9)));
+    }
+
+    #[test]
+    fn failed_handshake_reported() {
+        // answers the ClientHello with plain HTTP, like a server without TLS 1.2 would fail
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            if let Ok((mut stream, _)) = listener.accept() {
+                let mut hello = [0u8; 512];
+                let _ = stream.read(&mut hello);
+                let _ = stream.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n");
+            }
+        });
+        let url = format!("https://127.0.0.1:{}/", port);
+        match probe_min_version(&url) {
+            Err(RedirectError::OutdatedTls(ref failed, _)) => assert_eq!(*failed, url),
+            other => panic!("expected OutdatedTls, got {:?}", other),
+        }
+    }
+
+    #[test]
+    #[ignore] // needs network access to badssl.com
+    fn old_tls_refused() {
+        assert!(probe_min_version("https://tls-v1-2.badssl.com:1012/").is_ok());
+        match probe_min_version("https://tls-v1-0.badssl.com:1010/") {
+            Err(RedirectError::OutdatedTls(..)) => (),
+            other => panic!("expected OutdatedTls, got {:?}", other),
+        }
+    }
+}